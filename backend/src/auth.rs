@@ -7,6 +7,12 @@ use serde::{Deserialize, Serialize};
 use sqlx::{SqlitePool, Row};
 use uuid::Uuid;
 
+use crate::bots::{claims_for_api_token, remove_user_tokens, set_tokens_role, ApiTokens, Scope, API_TOKEN_PREFIX};
+use crate::errors::{ApiError, ErrorCode};
+use crate::events::{self, MemberEvent, MemberJoinedEvent, ServerEvent};
+use crate::outgoing_webhooks;
+use crate::webhooks::WEBHOOK_USER_ID;
+
 // ── Models ──────────────────────────────────────────────
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,6 +21,41 @@ pub struct Claims {
     pub username: String,
     pub role: String,      // "user" or "admin"
     pub exp: usize,
+    /// Set for API tokens only; session JWTs are not scoped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+    #[serde(default)]
+    pub is_bot: bool,
+}
+
+impl Claims {
+    pub fn is_api_token(&self) -> bool {
+        self.scopes.is_some()
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.iter().any(|s| s == scope.as_str()))
+    }
+
+    /// Scopes only narrow what a token can do; role checks still apply on top.
+    pub fn require_scope(&self, scope: Scope) -> Result<(), ApiError> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(ApiError::with_details(ErrorCode::MissingScope, format!("Requires scope '{}'", scope.as_str())))
+        }
+    }
+
+    /// For endpoints that act on the account itself, which tokens never may.
+    pub fn require_session(&self) -> Result<(), ApiError> {
+        if self.is_api_token() {
+            Err(ApiError::with_details(ErrorCode::MissingScope, "Requires a user session"))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -82,6 +123,8 @@ pub fn create_token(user_id: &str, username: &str, role: &str) -> String {
         username: username.to_string(),
         role: role.to_string(),
         exp: expiration,
+        scopes: None,
+        is_bot: false,
     };
 
     encode(
@@ -106,6 +149,15 @@ pub fn validate_token(token: &str) -> Option<Claims> {
 pub fn extract_claims(req: &HttpRequest) -> Option<Claims> {
     let auth_header = req.headers().get("Authorization")?.to_str().ok()?;
     let token = auth_header.strip_prefix("Bearer ")?;
+    claims_for_bearer(req, token)
+}
+
+/// Accepts a session JWT or a bot API token.
+pub fn claims_for_bearer(req: &HttpRequest, token: &str) -> Option<Claims> {
+    if token.starts_with(API_TOKEN_PREFIX) {
+        let api_tokens = req.app_data::<web::Data<ApiTokens>>()?;
+        return claims_for_api_token(api_tokens.get_ref(), token);
+    }
     validate_token(token)
 }
 
@@ -166,17 +218,31 @@ pub(crate) async fn allocate_unique_username(pool: &SqlitePool, preferred: &str)
     format!("discord-{}", Uuid::new_v4().as_simple())
 }
 
+/// Queue `member_joined` for outgoing webhooks on the transaction that created the account,
+/// commit, then broadcast it.
+async fn commit_new_member(
+    mut tx: sqlx::Transaction<'_, sqlx::Sqlite>,
+    broadcaster: &crate::ws::Broadcaster,
+    joined: MemberJoinedEvent,
+) -> Result<(), sqlx::Error> {
+    let event = ServerEvent::MemberJoined(joined);
+    outgoing_webhooks::enqueue(&mut tx, &event).await?;
+    tx.commit().await?;
+    events::broadcast(broadcaster, &event);
+    Ok(())
+}
+
 // ── Handlers ────────────────────────────────────────────
 
 pub async fn register(
+    req: HttpRequest,
     pool: web::Data<SqlitePool>,
     body: web::Json<AuthPayload>,
+    broadcaster: web::Data<crate::ws::Broadcaster>,
 ) -> HttpResponse {
     let username = body.username.trim();
     if username.is_empty() || body.password.len() < 4 {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Username must be non-empty and password at least 4 characters"
-        }));
+        return ErrorCode::InvalidRegistration.respond(&req);
     }
 
     // Check if duplicate
@@ -187,23 +253,33 @@ pub async fn register(
         .unwrap_or(0);
 
     if exists > 0 {
-        return HttpResponse::Conflict().json(serde_json::json!({
-            "error": "Username already taken"
-        }));
+        return ErrorCode::UsernameTaken.respond(&req);
     }
 
     let id = Uuid::new_v4().to_string();
     let password_hash = hash(&body.password, DEFAULT_COST).expect("hash failed");
     let role = "user"; // Default role
 
-    sqlx::query("INSERT INTO users (id, username, password_hash, role) VALUES (?, ?, ?, ?)")
+    let Ok(mut tx) = pool.begin().await else {
+        return ErrorCode::InternalError.respond(&req);
+    };
+    let inserted = sqlx::query("INSERT INTO users (id, username, password_hash, role) VALUES (?, ?, ?, ?)")
         .bind(&id)
         .bind(username)
         .bind(&password_hash)
         .bind(role)
-        .execute(pool.get_ref())
-        .await
-        .expect("insert user failed");
+        .execute(&mut *tx)
+        .await;
+    let joined = MemberJoinedEvent {
+        user_id: id.clone(),
+        username: username.to_string(),
+        avatar_color: 0,
+        avatar_url: None,
+        role: role.to_string(),
+    };
+    if inserted.is_err() || commit_new_member(tx, broadcaster.get_ref(), joined).await.is_err() {
+        return ErrorCode::InternalError.respond(&req);
+    }
 
     let token = create_token(&id, username, role);
 
@@ -220,6 +296,7 @@ pub async fn register(
 }
 
 pub async fn login(
+    req: HttpRequest,
     pool: web::Data<SqlitePool>,
     body: web::Json<AuthPayload>,
 ) -> HttpResponse {
@@ -252,38 +329,36 @@ pub async fn login(
                 banner_url,
             })
         } else {
-            HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Invalid password" }))
+            ErrorCode::InvalidPassword.respond(&req)
         }
     } else {
-        HttpResponse::Unauthorized().json(serde_json::json!({ "error": "User not found" }))
+        ErrorCode::UnknownUser.respond(&req)
     }
 }
 
 /// Core logic: validate a Discord user token, create/update local user, return AuthResponse.
 pub(crate) async fn do_discord_token_login(
     pool: &SqlitePool,
+    broadcaster: &crate::ws::Broadcaster,
     discord_token: &str,
-) -> Result<AuthResponse, String> {
+) -> Result<AuthResponse, ApiError> {
     let client = Client::new();
     let discord_user_response = client
         .get(format!("{}/users/@me", discord_api_base_url()))
         .header("Authorization", discord_token)
         .send()
         .await
-        .map_err(|_| "Discord API indisponible".to_string())?;
+        .map_err(|_| ApiError::new(ErrorCode::DiscordUnavailable))?;
 
     if !discord_user_response.status().is_success() {
-        let details = discord_user_response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Token Discord invalide".to_string());
-        return Err(format!("Token Discord invalide ou expiré: {details}"));
+        let details = discord_user_response.text().await.unwrap_or_default();
+        return Err(ApiError::with_details(ErrorCode::DiscordTokenInvalid, details));
     }
 
     let discord_user: DiscordUser = discord_user_response
         .json()
         .await
-        .map_err(|_| "Réponse Discord invalide".to_string())?;
+        .map_err(|_| ApiError::new(ErrorCode::DiscordBadResponse))?;
 
     let discord_avatar = discord_avatar_url(&discord_user);
 
@@ -326,6 +401,9 @@ pub(crate) async fn do_discord_token_login(
             let generated_password = Uuid::new_v4().to_string();
             let password_hash = hash(generated_password, DEFAULT_COST).expect("hash failed");
 
+            let Ok(mut tx) = pool.begin().await else {
+                return Err(ApiError::new(ErrorCode::DiscordAccountCreateFailed));
+            };
             let insert_result = sqlx::query("INSERT INTO users (id, username, password_hash, role, avatar_color, about, avatar_url, banner_url, discord_id, discord_access_token) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
                 .bind(&user_id)
                 .bind(&username)
@@ -337,11 +415,18 @@ pub(crate) async fn do_discord_token_login(
                 .bind(&banner_url)
                 .bind(&discord_user.id)
                 .bind(discord_token)
-                .execute(pool)
+                .execute(&mut *tx)
                 .await;
 
-            if insert_result.is_err() {
-                return Err("Impossible de créer l'utilisateur Discord local".to_string());
+            let joined = MemberJoinedEvent {
+                user_id: user_id.clone(),
+                username: username.clone(),
+                avatar_color,
+                avatar_url: discord_avatar.clone(),
+                role: role.clone(),
+            };
+            if insert_result.is_err() || commit_new_member(tx, broadcaster, joined).await.is_err() {
+                return Err(ApiError::new(ErrorCode::DiscordAccountCreateFailed));
             }
 
             (
@@ -370,18 +455,18 @@ pub(crate) async fn do_discord_token_login(
 
 /// POST /api/auth/discord/token — Login with a Discord user token.
 pub async fn login_discord_token(
+    req: HttpRequest,
     pool: web::Data<SqlitePool>,
     body: web::Json<DiscordUserTokenPayload>,
+    broadcaster: web::Data<crate::ws::Broadcaster>,
 ) -> HttpResponse {
     let discord_token = body.discord_token.trim().to_string();
     if discord_token.is_empty() {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "discord_token manquant"
-        }));
+        return ErrorCode::DiscordTokenMissing.respond(&req);
     }
-    match do_discord_token_login(pool.get_ref(), &discord_token).await {
+    match do_discord_token_login(pool.get_ref(), broadcaster.get_ref(), &discord_token).await {
         Ok(auth) => HttpResponse::Ok().json(auth),
-        Err(msg) => HttpResponse::Unauthorized().json(serde_json::json!({ "error": msg })),
+        Err(err) => err.respond(&req),
     }
 }
 
@@ -389,8 +474,11 @@ pub async fn login_discord_token(
 pub async fn get_discord_me(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
 
     let row = sqlx::query(
         "SELECT discord_access_token FROM users WHERE id = ?",
@@ -401,15 +489,13 @@ pub async fn get_discord_me(req: HttpRequest, pool: web::Data<SqlitePool>) -> Ht
     .unwrap_or(None);
 
     let Some(row) = row else {
-        return HttpResponse::NotFound().finish();
+        return ErrorCode::UserNotFound.respond(&req);
     };
 
     let access_token: Option<String> = row.try_get("discord_access_token").unwrap_or(None);
 
     let Some(access_token) = access_token else {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Aucun token Discord lié"
-        }));
+        return ErrorCode::DiscordNotLinked.respond(&req);
     };
 
     let response = match Client::new()
@@ -419,7 +505,7 @@ pub async fn get_discord_me(req: HttpRequest, pool: web::Data<SqlitePool>) -> Ht
         .await
     {
         Ok(res) => res,
-        Err(_) => return HttpResponse::BadGateway().json(serde_json::json!({ "error": "Discord API indisponible" })),
+        Err(_) => return ErrorCode::DiscordUnavailable.respond(&req),
     };
 
     let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
@@ -439,14 +525,15 @@ pub async fn discord_proxy(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
 
     let path = body.path.trim();
     if path.is_empty() || !path.starts_with('/') || path.starts_with("//") || path.starts_with("/http") {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Discord path invalide"
-        }));
+        return ErrorCode::InvalidDiscordPath.respond(&req);
     }
 
     let method = body
@@ -457,7 +544,7 @@ pub async fn discord_proxy(
         .to_uppercase();
     let allowed = ["GET", "POST", "PUT", "PATCH", "DELETE"];
     if !allowed.contains(&method.as_str()) {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Method not allowed" }));
+        return ErrorCode::MethodNotAllowed.respond(&req);
     }
 
     let row = sqlx::query(
@@ -469,15 +556,13 @@ pub async fn discord_proxy(
     .unwrap_or(None);
 
     let Some(row) = row else {
-        return HttpResponse::NotFound().finish();
+        return ErrorCode::UserNotFound.respond(&req);
     };
 
     let access_token: Option<String> = row.try_get("discord_access_token").unwrap_or(None);
 
     let Some(access_token) = access_token else {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Aucun token Discord lié"
-        }));
+        return ErrorCode::DiscordNotLinked.respond(&req);
     };
 
     let method_obj = match method.as_str() {
@@ -500,9 +585,7 @@ pub async fn discord_proxy(
     let response = match request_builder.send().await {
         Ok(res) => res,
         Err(_) => {
-            return HttpResponse::BadGateway().json(serde_json::json!({
-                "error": "Discord API indisponible"
-            }))
+            return ErrorCode::DiscordUnavailable.respond(&req)
         }
     };
 
@@ -522,10 +605,10 @@ pub async fn get_me(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
 
-    let row = sqlx::query("SELECT username, role, avatar_color, about, avatar_url, banner_url FROM users WHERE id = ?")
+    let row = sqlx::query("SELECT username, role, avatar_color, about, avatar_url, banner_url, is_bot, last_seen_at FROM users WHERE id = ?")
         .bind(&claims.sub)
         .fetch_optional(pool.get_ref())
         .await
@@ -538,6 +621,9 @@ pub async fn get_me(
          let about: String = row.try_get("about").unwrap_or_default();
          let avatar_url: Option<String> = row.try_get("avatar_url").unwrap_or(None);
          let banner_url: Option<String> = row.try_get("banner_url").unwrap_or(None);
+         let is_bot: bool = row.try_get("is_bot").unwrap_or(false);
+         let last_seen_at: Option<String> = row.try_get("last_seen_at").unwrap_or(None);
+         let storage = crate::uploads::storage_usage(pool.get_ref(), &claims.sub, &role).await;
 
          HttpResponse::Ok().json(serde_json::json!({
              "user_id": claims.sub,
@@ -547,9 +633,12 @@ pub async fn get_me(
              "about": about,
              "avatar_url": avatar_url,
              "banner_url": banner_url,
+             "is_bot": is_bot,
+             "last_seen_at": last_seen_at,
+             "storage": storage,
          }))
     } else {
-        HttpResponse::NotFound().finish()
+        ErrorCode::UserNotFound.respond(&req)
     }
 }
 
@@ -561,8 +650,11 @@ pub async fn update_profile(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
 
     // ... existing update logic ...
     // Build UPDATE dynamically — avoid Separated API which can produce broken SQL
@@ -627,7 +719,7 @@ pub async fn update_profile(
     match query.execute(pool.get_ref()).await {
         Ok(_) => {
             // Fetch updated user to broadcast
-            let user_row = sqlx::query("SELECT username, role, about, avatar_color, avatar_url, banner_url FROM users WHERE id = ?")
+            let user_row = sqlx::query("SELECT username, role, about, avatar_color, avatar_url, banner_url, is_bot FROM users WHERE id = ?")
                 .bind(&claims.sub)
                 .fetch_optional(pool.get_ref())
                 .await
@@ -641,25 +733,28 @@ pub async fn update_profile(
                  let avatar_color: i32 = row.try_get("avatar_color").unwrap_or(0);
                  let avatar_url: Option<String> = row.try_get("avatar_url").unwrap_or(None);
                  let banner_url: Option<String> = row.try_get("banner_url").unwrap_or(None);
-
-                 let event = serde_json::json!({
-                     "type": "join", // handled as upsert by frontend
-                     "user_id": claims.sub,
-                     "username": username,
-                     "role": role,
-                     "about": about,
-                     "avatar_color": avatar_color,
-                     "avatar_url": avatar_url,
-                     "banner_url": banner_url
+                 let is_bot: bool = row.try_get("is_bot").unwrap_or(false);
+
+                 // Join is handled as an upsert by the frontend
+                 let event = ServerEvent::Join(MemberEvent {
+                     user_id: claims.sub.clone(),
+                     username,
+                     avatar_color,
+                     avatar_url,
+                     banner_url,
+                     status: None,
+                     role: Some(role),
+                     about: Some(about),
+                     is_bot,
                  });
-                 let _ = broadcaster.send(event.to_string());
+                 events::broadcast(broadcaster.get_ref(), &event);
             }
 
             HttpResponse::Ok().json(serde_json::json!({ "status": "updated" }))
         },
         Err(e) => {
             eprintln!("Profile update error: {:?}", e);
-            HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Update failed (username might be taken)" }))
+            ErrorCode::ProfileUpdateFailed.respond(&req)
         }
     }
 }
@@ -686,6 +781,7 @@ pub struct ServerUser {
     pub id: String,
     pub username: String,
     pub role: String,
+    pub is_bot: bool,
 }
 
 /// GET /api/server/roles — List roles (Admin only)
@@ -695,11 +791,14 @@ pub async fn list_server_roles(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let rows = sqlx::query("SELECT name, color FROM roles ORDER BY CASE WHEN name='admin' THEN 0 WHEN name='user' THEN 1 ELSE 2 END, name ASC")
@@ -717,7 +816,7 @@ pub async fn list_server_roles(
                 .collect();
             HttpResponse::Ok().json(roles)
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(_) => ErrorCode::InternalError.respond(&req),
     }
 }
 
@@ -729,22 +828,25 @@ pub async fn create_server_role(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let role_name = body.name.trim().to_lowercase();
     if role_name.len() < 2 || role_name.len() > 24 {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Role name must be 2 to 24 chars" }));
+        return ErrorCode::InvalidRoleName.respond(&req);
     }
     if !role_name
         .chars()
         .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
     {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Role name can only contain a-z, 0-9, _ and -" }));
+        return ErrorCode::InvalidRoleName.respond(&req);
     }
 
     let color = body
@@ -755,7 +857,7 @@ pub async fn create_server_role(
         .to_string();
 
     if color.len() != 7 || !color.starts_with('#') || !color.chars().skip(1).all(|c| c.is_ascii_hexdigit()) {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid role color (expected #RRGGBB)" }));
+        return ErrorCode::InvalidRoleColor.respond(&req);
     }
 
     let result = sqlx::query("INSERT INTO roles (name, color) VALUES (?, ?)")
@@ -766,7 +868,7 @@ pub async fn create_server_role(
 
     match result {
         Ok(_) => HttpResponse::Ok().json(serde_json::json!({ "status": "role created" })),
-        Err(_) => HttpResponse::Conflict().json(serde_json::json!({ "error": "Role already exists" })),
+        Err(_) => ErrorCode::RoleExists.respond(&req),
     }
 }
 
@@ -779,16 +881,19 @@ pub async fn delete_server_role(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let role_name = path.into_inner().trim().to_lowercase();
     if role_name == "admin" || role_name == "user" {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "This role is protected" }));
+        return ErrorCode::RoleProtected.respond(&req);
     }
 
     let _ = sqlx::query("UPDATE users SET role = 'user' WHERE role = ?")
@@ -808,10 +913,10 @@ pub async fn delete_server_role(
             if res.rows_affected() > 0 {
                 HttpResponse::Ok().json(serde_json::json!({ "status": "role deleted" }))
             } else {
-                HttpResponse::NotFound().json(serde_json::json!({ "error": "Role not found" }))
+                ErrorCode::RoleNotFound.respond(&req)
             }
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(_) => ErrorCode::InternalError.respond(&req),
     }
 }
 
@@ -822,14 +927,17 @@ pub async fn list_server_users(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
-    let rows = sqlx::query("SELECT id, username, role FROM users ORDER BY username ASC")
+    let rows = sqlx::query("SELECT id, username, role, is_bot FROM users ORDER BY username ASC")
         .fetch_all(pool.get_ref())
         .await;
 
@@ -841,11 +949,12 @@ pub async fn list_server_users(
                     id: row.get("id"),
                     username: row.get("username"),
                     role: row.get("role"),
+                    is_bot: row.try_get("is_bot").unwrap_or(false),
                 })
                 .collect();
             HttpResponse::Ok().json(users)
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(_) => ErrorCode::InternalError.respond(&req),
     }
 }
 
@@ -857,14 +966,18 @@ pub async fn update_user_role(
     body: web::Json<UpdateRole>,
     broadcaster: web::Data<crate::ws::Broadcaster>,
     access_cache: web::Data<crate::ws::AccessCache>,
+    api_tokens: web::Data<ApiTokens>,
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let target_id = path.into_inner();
@@ -877,7 +990,7 @@ pub async fn update_user_role(
         .unwrap_or(0);
 
     if role_exists <= 0 {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid role" }));
+        return ErrorCode::InvalidRole.respond(&req);
     }
 
     let result = sqlx::query("UPDATE users SET role = ? WHERE id = ?")
@@ -889,7 +1002,7 @@ pub async fn update_user_role(
     match result {
         Ok(_) => {
             // Fetch updated user to broadcast
-            let user_row = sqlx::query("SELECT username, role, about, avatar_color, avatar_url, banner_url FROM users WHERE id = ?")
+            let user_row = sqlx::query("SELECT username, role, about, avatar_color, avatar_url, banner_url, is_bot FROM users WHERE id = ?")
                 .bind(&target_id)
                 .fetch_optional(pool.get_ref())
                 .await
@@ -903,43 +1016,64 @@ pub async fn update_user_role(
                  let avatar_color: i32 = row.try_get("avatar_color").unwrap_or(0);
                  let avatar_url: Option<String> = row.try_get("avatar_url").unwrap_or(None);
                  let banner_url: Option<String> = row.try_get("banner_url").unwrap_or(None);
+                 let is_bot: bool = row.try_get("is_bot").unwrap_or(false);
 
                   crate::ws::cache_set_user_role(access_cache.get_ref(), &target_id, &role);
-
-                 let event = serde_json::json!({
-                     "type": "join", // handled as upsert by frontend
-                     "user_id": target_id,
-                     "username": username,
-                     "role": role,
-                     "about": about,
-                     "avatar_color": avatar_color,
-                     "avatar_url": avatar_url,
-                     "banner_url": banner_url
+                  set_tokens_role(api_tokens.get_ref(), &target_id, &role);
+
+                 // Join is handled as an upsert by the frontend
+                 let event = ServerEvent::Join(MemberEvent {
+                     user_id: target_id.clone(),
+                     username,
+                     avatar_color,
+                     avatar_url,
+                     banner_url,
+                     status: None,
+                     role: Some(role),
+                     about: Some(about),
+                     is_bot,
                  });
-                 let _ = broadcaster.send(event.to_string());
+                 events::broadcast(broadcaster.get_ref(), &event);
             }
             HttpResponse::Ok().json(serde_json::json!({ "status": "role updated" }))
         },
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(_) => ErrorCode::InternalError.respond(&req),
     }
 }
 
-/// DELETE /api/users/{id} — Delete a user (Admin only)
+/// DELETE /api/users/{id} — Delete a user or bot (Admin only)
 pub async fn delete_user(
     req: HttpRequest,
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
+    api_tokens: web::Data<ApiTokens>,
+    storage: web::Data<crate::storage::UploadStorage>,
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let target_id = path.into_inner();
+    // Owns every webhook message
+    if target_id == WEBHOOK_USER_ID {
+        return ErrorCode::UserProtected.respond(&req);
+    }
+
+    // Their uploads go with them; shared files are released once they are gone
+    let upload_hashes: Vec<String> =
+        sqlx::query_scalar("SELECT sha256 FROM uploads WHERE user_id = ? AND sha256 IS NOT NULL")
+            .bind(&target_id)
+            .fetch_all(pool.get_ref())
+            .await
+            .unwrap_or_default();
 
     // Delete messages first
     let _ = sqlx::query("DELETE FROM messages WHERE user_id = ?")
@@ -956,11 +1090,13 @@ pub async fn delete_user(
     match result {
         Ok(res) => {
             if res.rows_affected() > 0 {
+                remove_user_tokens(api_tokens.get_ref(), &target_id);
+                crate::uploads::release_blobs(pool.get_ref(), &storage, &upload_hashes).await;
                 HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
             } else {
-                HttpResponse::NotFound().json(serde_json::json!({ "error": "User not found" }))
+                ErrorCode::UserNotFound.respond(&req)
             }
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(_) => ErrorCode::InternalError.respond(&req),
     }
 }
@@ -1,5 +1,4 @@
 use sqlx::sqlite::SqlitePoolOptions;
-use sqlx::Row;
 use std::io::{self, Write};
 
 #[tokio::main]
@@ -0,0 +1,4 @@
+fn main() {
+    let schema = backend::events::protocol_schema();
+    println!("{}", serde_json::to_string_pretty(&schema).expect("schema serializes"));
+}
@@ -0,0 +1,406 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+use crate::auth::{extract_claims, Claims};
+use crate::errors::{ApiError, ErrorCode};
+use crate::webhooks::WEBHOOK_USER_ID;
+
+/// API tokens start with this so `extract_claims` can tell them from JWTs.
+pub const API_TOKEN_PREFIX: &str = "vxb_";
+
+/// What an API token may do. Session JWTs are not scoped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    RoomsRead,
+    MessagesWrite,
+    PinsManage,
+    Moderation,
+}
+
+impl Scope {
+    pub const ALL: &'static [Scope] = &[Scope::RoomsRead, Scope::MessagesWrite, Scope::PinsManage, Scope::Moderation];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::RoomsRead => "rooms:read",
+            Scope::MessagesWrite => "messages:write",
+            Scope::PinsManage => "pins:manage",
+            Scope::Moderation => "moderation",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Scope> {
+        Scope::ALL.iter().copied().find(|scope| scope.as_str() == value)
+    }
+}
+
+/// An API token as seen by `extract_claims`, keyed by the token's hash.
+#[derive(Debug, Clone)]
+pub struct ApiTokenEntry {
+    pub token_id: String,
+    pub user_id: String,
+    pub username: String,
+    pub role: String,
+    pub scopes: Vec<String>,
+}
+
+/// All live API tokens, kept in memory so `extract_claims` stays synchronous.
+pub type ApiTokens = Arc<RwLock<HashMap<String, ApiTokenEntry>>>;
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Random secret with a recognizable prefix, e.g. `vxb_…` for API tokens.
+pub(crate) fn generate_token(prefix: &str) -> String {
+    format!(
+        "{}{}{}",
+        prefix,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+fn split_scopes(raw: &str) -> Vec<String> {
+    raw.split_whitespace().map(|s| s.to_string()).collect()
+}
+
+pub async fn load_api_tokens(pool: &SqlitePool) -> ApiTokens {
+    let rows = sqlx::query(
+        "SELECT t.id, t.user_id, t.token_hash, t.scopes, u.username, u.role \
+         FROM api_tokens t JOIN users u ON t.user_id = u.id"
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    let tokens = rows
+        .iter()
+        .map(|row| {
+            let entry = ApiTokenEntry {
+                token_id: row.get("id"),
+                user_id: row.get("user_id"),
+                username: row.get("username"),
+                role: row.get("role"),
+                scopes: split_scopes(row.get("scopes")),
+            };
+            (row.get::<String, _>("token_hash"), entry)
+        })
+        .collect();
+
+    Arc::new(RwLock::new(tokens))
+}
+
+pub fn claims_for_api_token(tokens: &ApiTokens, token: &str) -> Option<Claims> {
+    let guard = tokens.read().unwrap();
+    let entry = guard.get(&hash_token(token))?;
+    Some(Claims {
+        sub: entry.user_id.clone(),
+        username: entry.username.clone(),
+        role: entry.role.clone(),
+        // API tokens do not expire; they are revoked instead
+        exp: 0,
+        scopes: Some(entry.scopes.clone()),
+        is_bot: true,
+    })
+}
+
+/// Keep token claims in step with `PATCH /api/users/{id}/role`.
+pub fn set_tokens_role(tokens: &ApiTokens, user_id: &str, role: &str) {
+    let mut guard = tokens.write().unwrap();
+    for entry in guard.values_mut().filter(|entry| entry.user_id == user_id) {
+        entry.role = role.to_string();
+    }
+}
+
+pub fn remove_user_tokens(tokens: &ApiTokens, user_id: &str) {
+    tokens.write().unwrap().retain(|_, entry| entry.user_id != user_id);
+}
+
+// ── Admin endpoints ─────────────────────────────────────
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBot {
+    pub username: String,
+    pub avatar_color: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiToken {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_by: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bot {
+    pub id: String,
+    pub username: String,
+    pub role: String,
+    pub avatar_color: i32,
+    pub created_at: String,
+    pub tokens: Vec<ApiTokenInfo>,
+}
+
+/// Bot management needs an admin signed in as themselves, not another token.
+fn require_admin_session(req: &HttpRequest) -> Result<Claims, ApiError> {
+    let Some(claims) = extract_claims(req) else {
+        return Err(ApiError::new(ErrorCode::NotAuthenticated));
+    };
+    claims.require_session()?;
+    if claims.role != "admin" {
+        return Err(ApiError::new(ErrorCode::AdminOnly));
+    }
+    Ok(claims)
+}
+
+async fn fetch_bot_tokens(pool: &SqlitePool, bot_id: &str) -> Vec<ApiTokenInfo> {
+    let rows = sqlx::query(
+        "SELECT id, name, scopes, created_by, created_at FROM api_tokens WHERE user_id = ? ORDER BY created_at ASC"
+    )
+    .bind(bot_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    rows.iter()
+        .map(|row| ApiTokenInfo {
+            id: row.get("id"),
+            name: row.get("name"),
+            scopes: split_scopes(row.get("scopes")),
+            created_by: row.try_get("created_by").unwrap_or(None),
+            created_at: row.get("created_at"),
+        })
+        .collect()
+}
+
+/// GET /api/bots — List bot users and their tokens (Admin only)
+pub async fn list_bots(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    if let Err(err) = require_admin_session(&req) {
+        return err.respond(&req);
+    }
+
+    let rows = sqlx::query(
+        "SELECT id, username, role, avatar_color, created_at FROM users WHERE is_bot = 1 AND id != ? ORDER BY username ASC"
+    )
+    .bind(WEBHOOK_USER_ID)
+    .fetch_all(pool.get_ref())
+    .await
+    .unwrap_or_default();
+
+    let mut bots = Vec::with_capacity(rows.len());
+    for row in rows {
+        let id: String = row.get("id");
+        let tokens = fetch_bot_tokens(pool.get_ref(), &id).await;
+        bots.push(Bot {
+            id,
+            username: row.get("username"),
+            role: row.get("role"),
+            avatar_color: row.try_get("avatar_color").unwrap_or(0),
+            created_at: row.get("created_at"),
+            tokens,
+        });
+    }
+
+    HttpResponse::Ok().json(bots)
+}
+
+/// POST /api/bots — Create a bot user (Admin only)
+pub async fn create_bot(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    body: web::Json<CreateBot>,
+) -> HttpResponse {
+    if let Err(err) = require_admin_session(&req) {
+        return err.respond(&req);
+    }
+
+    let username = body.username.trim();
+    if username.is_empty() || username.len() > 32 {
+        return ErrorCode::InvalidRegistration.respond(&req);
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let avatar_color = body.avatar_color.unwrap_or(0);
+    if let Err(code) = insert_bot_user(pool.get_ref(), &id, username, avatar_color).await {
+        return code.respond(&req);
+    }
+
+    let created_at: String = sqlx::query_scalar("SELECT created_at FROM users WHERE id = ?")
+        .bind(&id)
+        .fetch_one(pool.get_ref())
+        .await
+        .unwrap_or_default();
+
+    HttpResponse::Created().json(Bot {
+        id,
+        username: username.to_string(),
+        role: "user".to_string(),
+        avatar_color,
+        created_at,
+        tokens: Vec::new(),
+    })
+}
+
+/// Store a bot account; only a clash with an existing username is the caller's fault.
+async fn insert_bot_user(pool: &SqlitePool, id: &str, username: &str, avatar_color: i32) -> Result<(), ErrorCode> {
+    // "!" is not a bcrypt hash, so password login always fails for bots
+    let result = sqlx::query(
+        "INSERT INTO users (id, username, password_hash, role, avatar_color, is_bot) VALUES (?, ?, '!', 'user', ?, 1)"
+    )
+    .bind(id)
+    .bind(username)
+    .bind(avatar_color)
+    .execute(pool)
+    .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(ErrorCode::UsernameTaken),
+        Err(e) => {
+            eprintln!("Bot insert error: {:?}", e);
+            Err(ErrorCode::InternalError)
+        }
+    }
+}
+
+/// POST /api/bots/{id}/tokens — Issue an API token; the plain token is only returned here (Admin only)
+pub async fn create_api_token(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: web::Json<CreateApiToken>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let claims = match require_admin_session(&req) {
+        Ok(c) => c,
+        Err(err) => return err.respond(&req),
+    };
+
+    let bot_id = path.into_inner();
+    let name = body.name.trim();
+    if name.is_empty() {
+        return ErrorCode::InvalidTokenName.respond(&req);
+    }
+
+    let mut scopes: Vec<String> = Vec::new();
+    for raw in &body.scopes {
+        let Some(scope) = Scope::parse(raw.trim()) else {
+            return ApiError::with_details(ErrorCode::InvalidScope, format!("Unknown scope '{}'", raw)).respond(&req);
+        };
+        if !scopes.iter().any(|s| s == scope.as_str()) {
+            scopes.push(scope.as_str().to_string());
+        }
+    }
+    if scopes.is_empty() {
+        return ApiError::with_details(ErrorCode::InvalidScope, "At least one scope is required").respond(&req);
+    }
+
+    let bot = sqlx::query("SELECT username, role FROM users WHERE id = ? AND id != ? AND is_bot = 1")
+        .bind(&bot_id)
+        .bind(WEBHOOK_USER_ID)
+        .fetch_optional(pool.get_ref())
+        .await
+        .unwrap_or(None);
+    let Some(bot) = bot else {
+        return ErrorCode::BotNotFound.respond(&req);
+    };
+
+    let token = generate_token(API_TOKEN_PREFIX);
+    let token_hash = hash_token(&token);
+    let token_id = Uuid::new_v4().to_string();
+    let created_at = chrono::Utc::now().to_rfc3339();
+
+    let result = sqlx::query(
+        "INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, created_by, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&token_id)
+    .bind(&bot_id)
+    .bind(name)
+    .bind(&token_hash)
+    .bind(scopes.join(" "))
+    .bind(&claims.sub)
+    .bind(&created_at)
+    .execute(pool.get_ref())
+    .await;
+
+    if result.is_err() {
+        return ErrorCode::InternalError.respond(&req);
+    }
+
+    api_tokens.write().unwrap().insert(
+        token_hash,
+        ApiTokenEntry {
+            token_id: token_id.clone(),
+            user_id: bot_id,
+            username: bot.get("username"),
+            role: bot.get("role"),
+            scopes: scopes.clone(),
+        },
+    );
+
+    HttpResponse::Created().json(serde_json::json!({
+        "id": token_id,
+        "name": name,
+        "scopes": scopes,
+        "created_by": claims.sub,
+        "created_at": created_at,
+        "token": token,
+    }))
+}
+
+/// DELETE /api/bots/{id}/tokens/{token_id} — Revoke an API token (Admin only)
+pub async fn revoke_api_token(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<(String, String)>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    if let Err(err) = require_admin_session(&req) {
+        return err.respond(&req);
+    }
+
+    let (bot_id, token_id) = path.into_inner();
+    let result = sqlx::query("DELETE FROM api_tokens WHERE id = ? AND user_id = ?")
+        .bind(&token_id)
+        .bind(&bot_id)
+        .execute(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(res) if res.rows_affected() > 0 => {
+            api_tokens.write().unwrap().retain(|_, entry| entry.token_id != token_id);
+            HttpResponse::Ok().json(serde_json::json!({ "status": "revoked" }))
+        }
+        Ok(_) => ErrorCode::ApiTokenNotFound.respond(&req),
+        Err(_) => ErrorCode::InternalError.respond(&req),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_taken_usernames_are_reported_as_taken() {
+        let pool = crate::db::test_pool().await;
+        assert_eq!(insert_bot_user(&pool, "b1", "helper", 0).await, Ok(()));
+        assert_eq!(insert_bot_user(&pool, "b2", "helper", 0).await, Err(ErrorCode::UsernameTaken));
+
+        sqlx::query("DROP TABLE users").execute(&pool).await.unwrap();
+        assert_eq!(insert_bot_user(&pool, "b3", "other", 0).await, Err(ErrorCode::InternalError));
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::OnceLock;
+
+use actix_web::web;
+use bytes::Bytes;
+use bytestring::ByteString;
+use flate2::write::ZlibEncoder;
+use flate2::Compression as ZlibLevel;
+use serde::Deserialize;
+
+use crate::errors::{ApiError, ErrorCode};
+use crate::events::{ClientEvent, ClientEventError, ServerEvent};
+
+/// Serialization used on a `/ws` connection, picked with `?encoding=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+/// Compression applied to server frames, picked with `?compress=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Every frame is a complete zlib stream, so frames can be inflated independently.
+    /// Done by us in the payload; the `permessage-deflate` extension (RFC 7692) is
+    /// negotiated separately, see [`crate::permessage_deflate`].
+    Zlib,
+}
+
+pub const ENCODINGS: &[&str] = &["json", "msgpack", "cbor"];
+pub const COMPRESSIONS: &[&str] = &["zlib"];
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MsgPack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Encoding> {
+        match value {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MsgPack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+}
+
+impl Compression {
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zlib => Some("zlib"),
+        }
+    }
+}
+
+/// What a connection negotiated at upgrade time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WireFormat {
+    pub encoding: Encoding,
+    pub compression: Compression,
+}
+
+#[derive(Deserialize)]
+struct WireQuery {
+    encoding: Option<String>,
+    compress: Option<String>,
+}
+
+impl WireFormat {
+    /// Reads `encoding` and `compress` from the upgrade request's query string.
+    pub fn from_query(query: &str) -> Result<WireFormat, ApiError> {
+        let params = web::Query::<WireQuery>::from_query(query)
+            .map(|q| q.into_inner())
+            .unwrap_or(WireQuery {
+                encoding: None,
+                compress: None,
+            });
+
+        let encoding = match params.encoding.as_deref() {
+            None | Some("") => Encoding::Json,
+            Some(value) => Encoding::parse(value).ok_or_else(|| {
+                ApiError::with_details(
+                    ErrorCode::UnsupportedEncoding,
+                    format!("Unknown encoding '{}', expected one of {}", value, ENCODINGS.join(", ")),
+                )
+            })?,
+        };
+        let compression = match params.compress.as_deref() {
+            None | Some("") => Compression::None,
+            Some("zlib") => Compression::Zlib,
+            Some(value) => {
+                return Err(ApiError::with_details(
+                    ErrorCode::UnsupportedEncoding,
+                    format!("Unknown compression '{}', expected one of {}", value, COMPRESSIONS.join(", ")),
+                ))
+            }
+        };
+
+        Ok(WireFormat { encoding, compression })
+    }
+
+    /// Plain JSON goes out as text frames; everything else is binary.
+    pub fn is_binary(self) -> bool {
+        self.encoding != Encoding::Json || self.compression != Compression::None
+    }
+
+    fn cache_slot(self) -> usize {
+        let encoding = match self.encoding {
+            Encoding::Json => 0,
+            Encoding::MsgPack => 1,
+            Encoding::Cbor => 2,
+        };
+        let compression = match self.compression {
+            Compression::None => 0,
+            Compression::Zlib => 1,
+        };
+        encoding * 2 + compression
+    }
+
+    /// Decodes a binary client frame in the negotiated encoding. `compress` never applies
+    /// to client frames, and text frames are always JSON.
+    pub fn decode_binary(self, bytes: &[u8]) -> Result<ClientEvent, ClientEventError> {
+        let value: serde_json::Value = match self.encoding {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| ClientEventError::Malformed(e.to_string()))?,
+            Encoding::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| ClientEventError::Malformed(e.to_string()))?,
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(|e| ClientEventError::Malformed(e.to_string()))?,
+        };
+        ClientEvent::from_value(value)
+    }
+}
+
+/// A server frame ready to hand to `actix_ws::Session`.
+#[derive(Debug, Clone)]
+pub enum OutFrame {
+    Text(ByteString),
+    Binary(Bytes),
+}
+
+impl OutFrame {
+    pub async fn send(self, session: &mut actix_ws::Session) -> bool {
+        match self {
+            OutFrame::Text(text) => session.text(text).await.is_ok(),
+            OutFrame::Binary(bytes) => session.binary(bytes).await.is_ok(),
+        }
+    }
+}
+
+fn serialize(event: &ServerEvent, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json => event.to_json().into_bytes(),
+        // Named fields keep the same shape as the JSON envelope
+        Encoding::MsgPack => rmp_serde::to_vec_named(event).expect("server events always serialize"),
+        Encoding::Cbor => {
+            let mut out = Vec::new();
+            ciborium::into_writer(event, &mut out).expect("server events always serialize");
+            out
+        }
+    }
+}
+
+fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::with_capacity(bytes.len() / 2), ZlibLevel::default());
+    encoder.write_all(bytes).expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+/// Encodes a single event for one connection.
+pub fn encode(event: &ServerEvent, wire: WireFormat) -> OutFrame {
+    let bytes = serialize(event, wire.encoding);
+    let bytes = match wire.compression {
+        Compression::None => bytes,
+        Compression::Zlib => compress(&bytes),
+    };
+    if wire.is_binary() {
+        OutFrame::Binary(Bytes::from(bytes))
+    } else {
+        let text = String::from_utf8(bytes).expect("JSON is UTF-8");
+        OutFrame::Text(ByteString::from(text))
+    }
+}
+
+/// A broadcast event, encoded at most once per wire format no matter how many
+/// connections receive it.
+#[derive(Debug)]
+pub struct BroadcastFrame {
+    pub event: ServerEvent,
+    /// When set, only these users' connections receive the frame.
+    recipients: Option<HashSet<String>>,
+    encoded: [OnceLock<OutFrame>; 6],
+}
+
+impl BroadcastFrame {
+    pub fn new(event: ServerEvent) -> BroadcastFrame {
+        BroadcastFrame {
+            event,
+            recipients: None,
+            encoded: Default::default(),
+        }
+    }
+
+    pub fn with_recipients(mut self, user_ids: HashSet<String>) -> BroadcastFrame {
+        self.recipients = Some(user_ids);
+        self
+    }
+
+    /// Whether a connection authenticated as `user_id` should get this frame.
+    pub fn is_for(&self, user_id: &str) -> bool {
+        self.recipients
+            .as_ref()
+            .is_none_or(|recipients| recipients.contains(user_id))
+    }
+
+    pub fn encoded(&self, wire: WireFormat) -> OutFrame {
+        self.encoded[wire.cache_slot()]
+            .get_or_init(|| encode(&self.event, wire))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Lang;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn sample() -> ServerEvent {
+        ServerEvent::error(&ApiError::new(ErrorCode::RoomNotFound), Lang::En, Some("n1".to_string()))
+    }
+
+    fn wire(encoding: Encoding, compression: Compression) -> WireFormat {
+        WireFormat { encoding, compression }
+    }
+
+    fn decode(frame: OutFrame, wire: WireFormat) -> serde_json::Value {
+        let bytes = match frame {
+            OutFrame::Text(text) => {
+                assert!(!wire.is_binary());
+                text.as_bytes().to_vec()
+            }
+            OutFrame::Binary(bytes) => {
+                assert!(wire.is_binary());
+                bytes.to_vec()
+            }
+        };
+        let bytes = match wire.compression {
+            Compression::None => bytes,
+            Compression::Zlib => {
+                let mut out = Vec::new();
+                ZlibDecoder::new(bytes.as_slice()).read_to_end(&mut out).unwrap();
+                out
+            }
+        };
+        match wire.encoding {
+            Encoding::Json => serde_json::from_slice(&bytes).unwrap(),
+            Encoding::MsgPack => rmp_serde::from_slice(&bytes).unwrap(),
+            Encoding::Cbor => ciborium::from_reader(bytes.as_slice()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn every_format_round_trips_to_the_json_shape() {
+        let event = sample();
+        let expected = serde_json::to_value(&event).unwrap();
+        for encoding in [Encoding::Json, Encoding::MsgPack, Encoding::Cbor] {
+            for compression in [Compression::None, Compression::Zlib] {
+                let wire = wire(encoding, compression);
+                assert_eq!(decode(encode(&event, wire), wire), expected, "{:?}", wire);
+            }
+        }
+    }
+
+    #[test]
+    fn broadcast_frames_are_cached_per_format() {
+        let frame = BroadcastFrame::new(sample());
+        let expected = serde_json::to_value(&frame.event).unwrap();
+        let cbor = wire(Encoding::Cbor, Compression::Zlib);
+        assert_eq!(decode(frame.encoded(cbor), cbor), expected);
+        assert_eq!(decode(frame.encoded(WireFormat::default()), WireFormat::default()), expected);
+        assert!(frame.encoded[cbor.cache_slot()].get().is_some());
+        assert!(frame.encoded[wire(Encoding::MsgPack, Compression::None).cache_slot()].get().is_none());
+    }
+
+    #[test]
+    fn binary_client_frames_are_decoded() {
+        let value = serde_json::json!({"type": "presence", "status": "idle"});
+        let msgpack = rmp_serde::to_vec_named(&value).unwrap();
+        let event = wire(Encoding::MsgPack, Compression::None).decode_binary(&msgpack).unwrap();
+        assert!(matches!(event, ClientEvent::Presence(p) if p.status == "idle"));
+
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&value, &mut cbor).unwrap();
+        let event = wire(Encoding::Cbor, Compression::Zlib).decode_binary(&cbor).unwrap();
+        assert!(matches!(event, ClientEvent::Presence(_)));
+
+        let garbage = wire(Encoding::Cbor, Compression::None).decode_binary(b"\xff\x00");
+        assert!(matches!(garbage, Err(ClientEventError::Malformed(_))));
+    }
+
+    #[test]
+    fn query_selects_the_wire_format() {
+        assert_eq!(WireFormat::from_query("").unwrap(), WireFormat::default());
+        assert_eq!(WireFormat::from_query("encoding=&compress=").unwrap(), WireFormat::default());
+        assert_eq!(
+            WireFormat::from_query("encoding=msgpack&compress=zlib").unwrap(),
+            wire(Encoding::MsgPack, Compression::Zlib)
+        );
+        assert_eq!(WireFormat::from_query("token=abc&encoding=cbor").unwrap(), wire(Encoding::Cbor, Compression::None));
+
+        let err = WireFormat::from_query("encoding=xml").unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnsupportedEncoding);
+        let err = WireFormat::from_query("compress=gzip").unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnsupportedEncoding);
+    }
+
+    #[test]
+    fn targeted_frames_only_reach_their_recipients() {
+        assert!(BroadcastFrame::new(sample()).is_for("anyone"));
+        let frame = BroadcastFrame::new(sample()).with_recipients(HashSet::from(["u1".to_string()]));
+        assert!(frame.is_for("u1"));
+        assert!(!frame.is_for("u2"));
+    }
+}
@@ -0,0 +1,565 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use sqlx::{Row, SqlitePool};
+use uuid::Uuid;
+
+use crate::auth::{extract_claims, Claims};
+use crate::bots::Scope;
+use crate::errors::{ApiError, ErrorCode};
+use crate::events::{EphemeralEvent, SendMessagePayload};
+use crate::messages::{create_message, fetch_stored_message, pin_stored_message, remove_stored_message, StoredMessage};
+use crate::rooms::{clean_topic, set_room_topic};
+use crate::storage::UploadStorage;
+use crate::ws::{can_user_access_room_cached, get_user_role_cached, AccessCache, Broadcaster};
+
+const MAX_TIMEOUT_MS: i64 = 28 * 24 * 60 * 60 * 1000;
+const MAX_DICE: u32 = 20;
+const MAX_DICE_SIDES: u32 = 1000;
+
+/// Kind of value a command argument accepts, as listed by `GET /api/commands`.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArgKind {
+    /// Whole number within `min..=max`.
+    Integer { min: i64, max: i64 },
+    /// `30s`, `10m`, `2h`, `7d`, or `off`.
+    Duration,
+    /// Username, with or without a leading `@`.
+    User,
+    /// Dice notation such as `2d6`.
+    Dice,
+    /// Free text; always the last argument and takes the rest of the line.
+    Text,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub kind: ArgKind,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub args: &'static [ArgSpec],
+    pub admin_only: bool,
+    /// Scope an API token needs to run the command.
+    pub scope: Scope,
+}
+
+impl CommandSpec {
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in self.args {
+            if arg.required {
+                usage.push_str(&format!(" <{}>", arg.name));
+            } else {
+                usage.push_str(&format!(" [{}]", arg.name));
+            }
+        }
+        usage
+    }
+
+    fn is_available(&self, role: &str, claims: Option<&Claims>) -> bool {
+        (!self.admin_only || role == "admin") && claims.is_none_or(|c| c.has_scope(self.scope))
+    }
+}
+
+/// Every command the server understands; handlers are dispatched by name in `run`.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        description: "List the commands you can use",
+        args: &[],
+        admin_only: false,
+        scope: Scope::MessagesWrite,
+    },
+    CommandSpec {
+        name: "roll",
+        description: "Roll dice and post the result",
+        args: &[ArgSpec {
+            name: "dice",
+            kind: ArgKind::Dice,
+            required: false,
+            description: "Dice to roll, 1d6 by default",
+        }],
+        admin_only: false,
+        scope: Scope::MessagesWrite,
+    },
+    CommandSpec {
+        name: "pin",
+        description: "Pin the message you reply to, or the latest message",
+        args: &[],
+        admin_only: true,
+        scope: Scope::PinsManage,
+    },
+    CommandSpec {
+        name: "purge",
+        description: "Delete the latest messages in this room",
+        args: &[ArgSpec {
+            name: "count",
+            kind: ArgKind::Integer { min: 1, max: 100 },
+            required: true,
+            description: "How many messages to delete",
+        }],
+        admin_only: true,
+        scope: Scope::Moderation,
+    },
+    CommandSpec {
+        name: "topic",
+        description: "Set the room topic",
+        args: &[ArgSpec {
+            name: "text",
+            kind: ArgKind::Text,
+            required: false,
+            description: "New topic; leave empty to clear it",
+        }],
+        admin_only: true,
+        scope: Scope::Moderation,
+    },
+    CommandSpec {
+        name: "timeout",
+        description: "Stop a user from posting for a while",
+        args: &[
+            ArgSpec {
+                name: "user",
+                kind: ArgKind::User,
+                required: true,
+                description: "User to time out",
+            },
+            ArgSpec {
+                name: "duration",
+                kind: ArgKind::Duration,
+                required: true,
+                description: "How long, e.g. 10m or 2h, or off to lift it",
+            },
+        ],
+        admin_only: true,
+        scope: Scope::Moderation,
+    },
+];
+
+fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+/// Split `/name args` into the command name and its arguments.
+/// Anything else, including paths like `/usr/bin`, is a plain message.
+pub(crate) fn parse_invocation(content: &str) -> Option<(String, String)> {
+    let rest = content.trim_start().strip_prefix('/')?;
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let name = &rest[..end];
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return None;
+    }
+    Some((name.to_ascii_lowercase(), rest[end..].trim().to_string()))
+}
+
+struct TargetUser {
+    id: String,
+    username: String,
+    role: String,
+}
+
+enum ArgValue {
+    Integer(i64),
+    /// Milliseconds; `None` for `off`.
+    Duration(Option<i64>),
+    User(TargetUser),
+    Dice { count: u32, sides: u32 },
+    Text(String),
+}
+
+fn parse_duration(token: &str) -> Option<Option<i64>> {
+    if token.eq_ignore_ascii_case("off") {
+        return Some(None);
+    }
+    let split = token.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = token.split_at(split);
+    let amount: i64 = amount.parse().ok()?;
+    let unit_ms = match unit {
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return None,
+    };
+    let ms = amount.checked_mul(unit_ms)?;
+    (ms > 0 && ms <= MAX_TIMEOUT_MS).then_some(Some(ms))
+}
+
+fn parse_dice(token: &str) -> Option<(u32, u32)> {
+    let (count, sides) = token.to_ascii_lowercase().split_once('d').map(|(c, s)| (c.to_string(), s.to_string()))?;
+    let count: u32 = if count.is_empty() { 1 } else { count.parse().ok()? };
+    let sides: u32 = sides.parse().ok()?;
+    ((1..=MAX_DICE).contains(&count) && (2..=MAX_DICE_SIDES).contains(&sides)).then_some((count, sides))
+}
+
+async fn parse_value(pool: &SqlitePool, kind: ArgKind, token: &str) -> Result<ArgValue, String> {
+    match kind {
+        ArgKind::Integer { min, max } => token
+            .parse::<i64>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .map(ArgValue::Integer)
+            .ok_or_else(|| format!("expected a number from {} to {}", min, max)),
+        ArgKind::Duration => parse_duration(token)
+            .map(ArgValue::Duration)
+            .ok_or_else(|| "expected a duration such as 10m, 2h or off, up to 28d".to_string()),
+        ArgKind::Dice => parse_dice(token)
+            .map(|(count, sides)| ArgValue::Dice { count, sides })
+            .ok_or_else(|| format!("expected dice such as 2d6, up to {}d{}", MAX_DICE, MAX_DICE_SIDES)),
+        ArgKind::User => {
+            let username = token.strip_prefix('@').unwrap_or(token);
+            let row = sqlx::query("SELECT id, username, role FROM users WHERE username = ?")
+                .bind(username)
+                .fetch_optional(pool)
+                .await
+                .unwrap_or(None);
+            match row {
+                Some(row) => Ok(ArgValue::User(TargetUser {
+                    id: row.get("id"),
+                    username: row.get("username"),
+                    role: row.get("role"),
+                })),
+                None => Err(format!("unknown user @{}", username)),
+            }
+        }
+        ArgKind::Text => Ok(ArgValue::Text(token.to_string())),
+    }
+}
+
+fn usage_error(spec: &CommandSpec, problem: &str) -> ApiError {
+    ApiError::with_details(ErrorCode::InvalidCommandArgs, format!("{}. Usage: {}", problem, spec.usage()))
+}
+
+/// Parse arguments in the order of `spec.args`; optional ones that are absent are `None`.
+async fn parse_args(pool: &SqlitePool, spec: &CommandSpec, raw: &str) -> Result<Vec<Option<ArgValue>>, ApiError> {
+    let mut rest = raw.trim();
+    let mut values = Vec::with_capacity(spec.args.len());
+    for arg in spec.args {
+        let token = if matches!(arg.kind, ArgKind::Text) {
+            std::mem::take(&mut rest)
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (token, remaining) = rest.split_at(end);
+            rest = remaining.trim_start();
+            token
+        };
+        if token.is_empty() {
+            if arg.required {
+                return Err(usage_error(spec, &format!("Missing <{}>", arg.name)));
+            }
+            values.push(None);
+            continue;
+        }
+        let value = parse_value(pool, arg.kind, token)
+            .await
+            .map_err(|problem| usage_error(spec, &format!("Invalid <{}>: {}", arg.name, problem)))?;
+        values.push(Some(value));
+    }
+    if !rest.is_empty() {
+        return Err(usage_error(spec, "Too many arguments"));
+    }
+    Ok(values)
+}
+
+/// Shared state a command needs, borrowed from the WS session or the REST handler.
+pub(crate) struct CommandContext<'a> {
+    pub pool: &'a SqlitePool,
+    pub broadcaster: &'a Broadcaster,
+    pub access_cache: &'a AccessCache,
+    pub storage: &'a UploadStorage,
+    pub max_message_chars: usize,
+}
+
+pub(crate) enum CommandOutcome {
+    /// Reply shown only to the invoker; nothing is stored.
+    Ephemeral(EphemeralEvent),
+    /// The command posted a regular message, to broadcast like any other.
+    Posted(Box<StoredMessage>),
+}
+
+/// Check permissions, parse arguments and run a command sent as `message`.
+/// `claims` are those of the caller, so API tokens are held to the command's scope.
+pub(crate) async fn run(
+    ctx: &CommandContext<'_>,
+    name: &str,
+    raw_args: &str,
+    message: SendMessagePayload,
+    claims: Option<&Claims>,
+) -> Result<CommandOutcome, ApiError> {
+    let spec = find_command(name).ok_or_else(|| {
+        ApiError::with_details(ErrorCode::UnknownCommand, format!("/{} is not a command, see /help", name))
+    })?;
+    if let Some(claims) = claims {
+        claims.require_scope(spec.scope)?;
+    }
+    if !can_user_access_room_cached(ctx.pool, ctx.access_cache, &message.user_id, &message.room_id).await {
+        return Err(ApiError::new(ErrorCode::RoomAccessDenied));
+    }
+    let role = get_user_role_cached(ctx.pool, ctx.access_cache, &message.user_id)
+        .await
+        .unwrap_or_else(|| "user".to_string());
+    if spec.admin_only && role != "admin" {
+        return Err(ApiError::new(ErrorCode::AdminOnly));
+    }
+
+    let mut args = parse_args(ctx.pool, spec, raw_args).await?.into_iter();
+
+    let content = match spec.name {
+        "help" => help_text(&role, claims),
+        "roll" => {
+            let dice = match args.next().flatten() {
+                Some(ArgValue::Dice { count, sides }) => (count, sides),
+                _ => (1, 6),
+            };
+            return roll(ctx, dice, message).await;
+        }
+        "pin" => pin(ctx, &message).await?,
+        "purge" => {
+            let Some(Some(ArgValue::Integer(count))) = args.next() else {
+                return Err(usage_error(spec, "Missing <count>"));
+            };
+            purge(ctx, &message.room_id, count).await
+        }
+        "topic" => {
+            let text = match args.next().flatten() {
+                Some(ArgValue::Text(text)) => text,
+                _ => String::new(),
+            };
+            let topic = clean_topic(&text)?;
+            set_room_topic(ctx.pool, ctx.broadcaster, &message.room_id, topic.as_deref()).await?;
+            match topic {
+                Some(topic) => format!("Topic set to: {}", topic),
+                None => "Topic cleared.".to_string(),
+            }
+        }
+        "timeout" => {
+            let (Some(Some(ArgValue::User(target))), Some(Some(ArgValue::Duration(duration)))) = (args.next(), args.next())
+            else {
+                return Err(usage_error(spec, "Missing arguments"));
+            };
+            timeout(ctx, spec, &message.user_id, target, duration).await?
+        }
+        _ => return Err(ApiError::new(ErrorCode::UnknownCommand)),
+    };
+
+    Ok(CommandOutcome::Ephemeral(EphemeralEvent {
+        room_id: message.room_id,
+        command: spec.name.to_string(),
+        content,
+        nonce: message.nonce,
+    }))
+}
+
+fn help_text(role: &str, claims: Option<&Claims>) -> String {
+    COMMANDS
+        .iter()
+        .filter(|spec| spec.is_available(role, claims))
+        .map(|spec| format!("{} — {}", spec.usage(), spec.description))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn roll(
+    ctx: &CommandContext<'_>,
+    (count, sides): (u32, u32),
+    mut message: SendMessagePayload,
+) -> Result<CommandOutcome, ApiError> {
+    let rolls: Vec<u32> = (0..count)
+        .map(|_| (Uuid::new_v4().as_u128() % sides as u128) as u32 + 1)
+        .collect();
+    let total: u32 = rolls.iter().sum();
+    message.content = if rolls.len() == 1 {
+        format!("🎲 {}d{}: {}", count, sides, total)
+    } else {
+        let parts: Vec<String> = rolls.iter().map(|r| r.to_string()).collect();
+        format!("🎲 {}d{}: {} = {}", count, sides, parts.join(" + "), total)
+    };
+    message.image_url = None;
+    message.attachments.clear();
+
+    let stored = create_message(ctx.pool, ctx.access_cache, message, ctx.max_message_chars).await?;
+    Ok(CommandOutcome::Posted(Box::new(stored)))
+}
+
+async fn pin(ctx: &CommandContext<'_>, message: &SendMessagePayload) -> Result<String, ApiError> {
+    let target: Option<String> = match message.reply_to_id.as_deref().filter(|id| !id.is_empty()) {
+        Some(reply_to_id) => sqlx::query_scalar("SELECT id FROM messages WHERE id = ? AND room_id = ?")
+            .bind(reply_to_id)
+            .bind(&message.room_id)
+            .fetch_optional(ctx.pool)
+            .await
+            .unwrap_or(None),
+        None => sqlx::query_scalar("SELECT id FROM messages WHERE room_id = ? ORDER BY created_at DESC LIMIT 1")
+            .bind(&message.room_id)
+            .fetch_optional(ctx.pool)
+            .await
+            .unwrap_or(None),
+    };
+    let Some(target) = target else {
+        return Err(ApiError::new(ErrorCode::MessageNotFound));
+    };
+
+    pin_stored_message(ctx.pool, ctx.broadcaster, &target, &message.user_id).await?;
+    Ok("Message pinned.".to_string())
+}
+
+async fn purge(ctx: &CommandContext<'_>, room_id: &str, count: i64) -> String {
+    let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM messages WHERE room_id = ? ORDER BY created_at DESC LIMIT ?")
+        .bind(room_id)
+        .bind(count)
+        .fetch_all(ctx.pool)
+        .await
+        .unwrap_or_default();
+
+    let mut deleted = 0;
+    for id in ids {
+        if let Some(msg) = fetch_stored_message(ctx.pool, &id).await {
+            remove_stored_message(ctx.pool, ctx.broadcaster, ctx.storage, &msg).await;
+            deleted += 1;
+        }
+    }
+    format!("Deleted {} message{}.", deleted, if deleted == 1 { "" } else { "s" })
+}
+
+async fn timeout(
+    ctx: &CommandContext<'_>,
+    spec: &CommandSpec,
+    invoker_id: &str,
+    target: TargetUser,
+    duration: Option<i64>,
+) -> Result<String, ApiError> {
+    if target.id == invoker_id {
+        return Err(usage_error(spec, "You cannot time out yourself"));
+    }
+    if target.role == "admin" {
+        return Err(usage_error(spec, "Admins cannot be timed out"));
+    }
+
+    let until = duration.map(|ms| chrono::Utc::now().timestamp_millis() + ms);
+    let result = sqlx::query("UPDATE users SET timeout_until = ? WHERE id = ?")
+        .bind(until)
+        .bind(&target.id)
+        .execute(ctx.pool)
+        .await;
+    if result.is_err() {
+        return Err(ApiError::new(ErrorCode::InternalError));
+    }
+
+    Ok(match until.and_then(chrono::DateTime::from_timestamp_millis) {
+        Some(until) => format!("@{} is timed out until {}.", target.username, until.to_rfc3339()),
+        None => format!("@{} can post again.", target.username),
+    })
+}
+
+/// GET /api/commands — Commands the caller may run, with argument schemas for autocompletion
+pub async fn list_commands(req: HttpRequest, pool: web::Data<SqlitePool>, access_cache: web::Data<AccessCache>) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+
+    let role = get_user_role_cached(pool.get_ref(), access_cache.get_ref(), &claims.sub)
+        .await
+        .unwrap_or_else(|| claims.role.clone());
+
+    let commands: Vec<serde_json::Value> = COMMANDS
+        .iter()
+        .filter(|spec| spec.is_available(&role, Some(&claims)))
+        .map(|spec| {
+            serde_json::json!({
+                "name": spec.name,
+                "description": spec.description,
+                "usage": spec.usage(),
+                "admin_only": spec.admin_only,
+                "args": spec.args,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE_MS: i64 = 60 * 1000;
+
+    #[test]
+    fn invocations_need_a_command_name() {
+        assert_eq!(parse_invocation("/Roll 2d6 "), Some(("roll".to_string(), "2d6".to_string())));
+        assert_eq!(parse_invocation("  /help"), Some(("help".to_string(), String::new())));
+        assert_eq!(parse_invocation("/topic  Hello  world "), Some(("topic".to_string(), "Hello  world".to_string())));
+        assert_eq!(parse_invocation("/usr/bin is a path"), None);
+        assert_eq!(parse_invocation("/ roll"), None);
+        assert_eq!(parse_invocation("/"), None);
+        assert_eq!(parse_invocation("hello /roll"), None);
+    }
+
+    #[test]
+    fn durations_have_a_unit_and_a_ceiling() {
+        assert_eq!(parse_duration("30s"), Some(Some(30 * 1000)));
+        assert_eq!(parse_duration("10m"), Some(Some(10 * MINUTE_MS)));
+        assert_eq!(parse_duration("2h"), Some(Some(120 * MINUTE_MS)));
+        assert_eq!(parse_duration("28d"), Some(Some(MAX_TIMEOUT_MS)));
+        assert_eq!(parse_duration("OFF"), Some(None));
+        assert_eq!(parse_duration("29d"), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("10w"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+    }
+
+    #[test]
+    fn dice_stay_within_limits() {
+        assert_eq!(parse_dice("2d6"), Some((2, 6)));
+        assert_eq!(parse_dice("D20"), Some((1, 20)));
+        assert_eq!(parse_dice("21d6"), None);
+        assert_eq!(parse_dice("1d1"), None);
+        assert_eq!(parse_dice("0d6"), None);
+        assert_eq!(parse_dice("2x6"), None);
+    }
+
+    #[test]
+    fn usage_marks_optional_arguments() {
+        assert_eq!(find_command("timeout").unwrap().usage(), "/timeout <user> <duration>");
+        assert_eq!(find_command("roll").unwrap().usage(), "/roll [dice]");
+    }
+
+    fn details(result: Result<Vec<Option<ArgValue>>, ApiError>) -> String {
+        match result {
+            Ok(_) => panic!("arguments were accepted"),
+            Err(err) => {
+                assert_eq!(err.code, ErrorCode::InvalidCommandArgs);
+                err.details.unwrap_or_default()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn arguments_are_parsed_in_order() {
+        let pool = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+
+        let purge = find_command("purge").unwrap();
+        let values = parse_args(&pool, purge, " 25 ").await.ok().unwrap();
+        assert!(matches!(values.as_slice(), [Some(ArgValue::Integer(25))]));
+        assert!(details(parse_args(&pool, purge, "").await).starts_with("Missing <count>"));
+        assert!(details(parse_args(&pool, purge, "101").await).starts_with("Invalid <count>"));
+        assert!(details(parse_args(&pool, purge, "5 6").await).starts_with("Too many arguments"));
+
+        let topic = find_command("topic").unwrap();
+        let values = parse_args(&pool, topic, "Release day, 5 pm").await.ok().unwrap();
+        assert!(matches!(values.as_slice(), [Some(ArgValue::Text(text))] if text == "Release day, 5 pm"));
+        let values = parse_args(&pool, topic, "").await.ok().unwrap();
+        assert!(matches!(values.as_slice(), [None]));
+    }
+}
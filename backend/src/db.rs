@@ -39,6 +39,14 @@ pub async fn init_db() -> SqlitePool {
         .execute(&pool)
         .await;
 
+    run_migrations(&pool).await;
+
+    println!("✅ Database initialized");
+    pool
+}
+
+/// Apply every migration in order; statements that already ran fail harmlessly.
+pub(crate) async fn run_migrations(pool: &SqlitePool) {
     let migrations = [
         include_str!("../../migrations/001_init.sql"),
         include_str!("../../migrations/002_add_settings.sql"),
@@ -53,13 +61,35 @@ pub async fn init_db() -> SqlitePool {
         include_str!("../../migrations/011_add_message_reactions.sql"),
         include_str!("../../migrations/012_add_perf_indexes.sql"),
         include_str!("../../migrations/013_add_discord_oauth.sql"),
+        include_str!("../../migrations/014_add_message_nonce.sql"),
+        include_str!("../../migrations/015_add_bots_and_api_tokens.sql"),
+        include_str!("../../migrations/016_add_webhooks.sql"),
+        include_str!("../../migrations/017_add_outgoing_webhooks.sql"),
+        include_str!("../../migrations/018_add_room_topics_and_timeouts.sql"),
+        include_str!("../../migrations/019_add_message_mentions.sql"),
+        include_str!("../../migrations/020_add_read_states.sql"),
+        include_str!("../../migrations/021_add_notification_settings.sql"),
+        include_str!("../../migrations/022_add_last_seen.sql"),
+        include_str!("../../migrations/023_add_uploads_and_attachments.sql"),
+        include_str!("../../migrations/024_add_upload_thumbnails.sql"),
+        include_str!("../../migrations/025_add_server_settings.sql"),
+        include_str!("../../migrations/026_add_upload_blobs_and_quotas.sql"),
     ];
 
     for sql in migrations {
-        run_migration_sql(sql, &pool).await;
+        run_migration_sql(sql, pool).await;
     }
+}
 
-    println!("✅ Database initialized");
+/// A fresh in-memory database with the full schema, for tests.
+#[cfg(test)]
+pub(crate) async fn test_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to open an in-memory database");
+    run_migrations(&pool).await;
     pool
 }
 
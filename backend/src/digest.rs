@@ -0,0 +1,281 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+
+use crate::auth::extract_claims;
+use crate::bots::Scope;
+use crate::errors::{ApiError, ErrorCode};
+use crate::events::ReadStateUpdatedEvent;
+use crate::mentions::MENTIONING_MESSAGES_SQL;
+use crate::messages::{enrich_messages, message_from_row, Message, STORED_MESSAGE_COLUMNS};
+use crate::read_states::fetch_read_states;
+use crate::rooms::Room;
+
+/// Newest entries kept in each list of a digest.
+const DIGEST_LIMIT: i64 = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct DigestQuery {
+    /// RFC 3339 start of the period; defaults to the caller's `last_seen_at`.
+    pub since: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Digest {
+    pub since: String,
+    /// Read states of the rooms with unread messages.
+    pub rooms: Vec<ReadStateUpdatedEvent>,
+    pub mentions: Vec<Message>,
+    /// Messages replying to one of the caller's.
+    pub replies: Vec<Message>,
+    pub pins: Vec<Message>,
+    pub new_rooms: Vec<Room>,
+}
+
+/// Remember when a realtime connection of `user_id` closed.
+pub(crate) async fn record_last_seen(pool: &SqlitePool, user_id: &str) {
+    let _ = sqlx::query("UPDATE users SET last_seen_at = ? WHERE id = ?")
+        .bind(Utc::now().to_rfc3339())
+        .bind(user_id)
+        .execute(pool)
+        .await;
+}
+
+/// Messages of accessible rooms matching `condition`, newest `order_by` first.
+/// `binds` fill the placeholders of `condition`, in order.
+async fn fetch_digest_messages(
+    pool: &SqlitePool,
+    role: &str,
+    condition: &str,
+    binds: &[&str],
+    order_by: &str,
+) -> Vec<Message> {
+    let sql = format!(
+        "{} WHERE {} \
+         AND (? = 'admin' OR m.room_id IN (SELECT id FROM rooms WHERE required_role = 'user' OR required_role = ?)) \
+         ORDER BY {} DESC LIMIT ?",
+        STORED_MESSAGE_COLUMNS, condition, order_by
+    );
+
+    let mut qx = sqlx::query(&sql);
+    for value in binds {
+        qx = qx.bind(*value);
+    }
+    let rows = qx
+        .bind(role)
+        .bind(role)
+        .bind(DIGEST_LIMIT)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+
+    let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
+    // The stored nonce is only meaningful to the author
+    for message in messages.iter_mut() {
+        message.nonce = None;
+    }
+    enrich_messages(pool, &mut messages).await;
+    messages
+}
+
+/// GET /api/users/me/digest — What happened since the caller was last seen
+pub async fn get_my_digest(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    query: web::Query<DigestQuery>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
+
+    let since = match query.since.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+        Some(value) => match DateTime::parse_from_rfc3339(value) {
+            Ok(at) => at.with_timezone(&Utc).to_rfc3339(),
+            Err(_) => {
+                return ApiError::with_details(ErrorCode::InvalidPayload, "since must be an RFC 3339 timestamp")
+                    .respond(&req)
+            }
+        },
+        None => {
+            let row = sqlx::query("SELECT last_seen_at, created_at FROM users WHERE id = ?")
+                .bind(&claims.sub)
+                .fetch_optional(pool.get_ref())
+                .await
+                .unwrap_or(None);
+            let Some(row) = row else {
+                return ErrorCode::UserNotFound.respond(&req);
+            };
+            // Never seen yet: everything since the account was created
+            match row.try_get::<Option<String>, _>("last_seen_at").unwrap_or(None) {
+                Some(last_seen) => last_seen,
+                None => {
+                    let created_at: String = row.try_get("created_at").unwrap_or_default();
+                    NaiveDateTime::parse_from_str(&created_at, "%Y-%m-%d %H:%M:%S")
+                        .map(|at| at.and_utc().to_rfc3339())
+                        .unwrap_or(created_at)
+                }
+            }
+        }
+    };
+
+    let pool = pool.get_ref();
+    let rooms = fetch_read_states(pool, &claims.sub, &claims.role, None)
+        .await
+        .into_iter()
+        .filter(|state| state.unread_count > 0)
+        .collect();
+
+    let mentions = fetch_digest_messages(
+        pool,
+        &claims.role,
+        &format!("m.id IN ({}) AND m.user_id != ? AND m.created_at > ?", MENTIONING_MESSAGES_SQL),
+        &[&claims.sub, &claims.role, &claims.sub, &since],
+        "m.created_at",
+    )
+    .await;
+
+    let replies = fetch_digest_messages(
+        pool,
+        &claims.role,
+        "m.reply_to_id IN (SELECT id FROM messages WHERE user_id = ?) AND m.user_id != ? AND m.created_at > ?",
+        &[&claims.sub, &claims.sub, &since],
+        "m.created_at",
+    )
+    .await;
+
+    let pins = fetch_digest_messages(
+        pool,
+        &claims.role,
+        "m.pinned_at > ? AND (m.pinned_by IS NULL OR m.pinned_by != ?)",
+        &[&since, &claims.sub],
+        "m.pinned_at",
+    )
+    .await;
+
+    // rooms.created_at uses SQLite's own format, so compare through datetime()
+    let new_rooms = sqlx::query_as::<_, Room>(
+        "SELECT id, name, kind, required_role, topic, created_at FROM rooms \
+         WHERE datetime(created_at) > datetime(?) \
+         AND (? = 'admin' OR required_role = 'user' OR required_role = ?) \
+         ORDER BY created_at LIMIT ?",
+    )
+    .bind(&since)
+    .bind(&claims.role)
+    .bind(&claims.role)
+    .bind(DIGEST_LIMIT)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    HttpResponse::Ok().json(Digest {
+        since,
+        rooms,
+        mentions,
+        replies,
+        pins,
+        new_rooms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::create_token;
+    use actix_web::{test, App};
+
+    const SINCE: &str = "2026-01-01T12:00:00+00:00";
+
+    async fn digest_pool() -> SqlitePool {
+        let pool = crate::db::test_pool().await;
+        for sql in [
+            "INSERT INTO users (id, username, password_hash, last_seen_at) VALUES \
+             ('alice', 'alice', '', '2026-01-01T12:00:00+00:00'), ('bob', 'bob', '', NULL)",
+            "INSERT INTO rooms (id, name, required_role) VALUES ('staff', 'staff', 'admin')",
+            "UPDATE rooms SET created_at = '2025-06-01 00:00:00'",
+            "INSERT INTO rooms (id, name, created_at) VALUES ('lounge', 'lounge', '2026-01-01 13:00:00')",
+            "INSERT INTO messages (id, room_id, user_id, username, content, created_at, reply_to_id, pinned_at, pinned_by) VALUES \
+             ('a1', 'general', 'alice', 'alice', 'question', '2026-01-01T11:00:00+00:00', NULL, NULL, NULL), \
+             ('old', 'general', 'bob', 'bob', '<@alice>', '2026-01-01T11:30:00+00:00', NULL, NULL, NULL), \
+             ('mention', 'general', 'bob', 'bob', '<@alice>', '2026-01-01T12:30:00+00:00', NULL, NULL, NULL), \
+             ('reply', 'general', 'bob', 'bob', 'answer', '2026-01-01T12:40:00+00:00', 'a1', NULL, NULL), \
+             ('pinned', 'general', 'bob', 'bob', 'rules', '2026-01-01T10:00:00+00:00', NULL, '2026-01-01T12:50:00+00:00', 'bob'), \
+             ('hidden', 'staff', 'bob', 'bob', '@everyone', '2026-01-01T12:30:00+00:00', NULL, NULL, NULL)",
+            "INSERT INTO message_mentions (message_id, kind, target) VALUES \
+             ('old', 'user', 'alice'), ('mention', 'user', 'alice'), ('hidden', 'everyone', '')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    async fn digest(pool: &SqlitePool, user_id: &str, query: &str) -> (u16, serde_json::Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .route("/digest", web::get().to(get_my_digest)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/digest{}", query))
+            .insert_header(("Authorization", format!("Bearer {}", create_token(user_id, user_id, "user"))))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status().as_u16();
+        (status, test::read_body_json(resp).await)
+    }
+
+    fn ids(value: &serde_json::Value, key: &str) -> Vec<String> {
+        value[key]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item.get("id").or(item.get("room_id")).unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn digest_covers_what_happened_since_last_seen() {
+        let pool = digest_pool().await;
+        let (status, body) = digest(&pool, "alice", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["since"], SINCE);
+        assert_eq!(ids(&body, "mentions"), ["mention"]);
+        assert_eq!(ids(&body, "replies"), ["reply"]);
+        assert_eq!(ids(&body, "pins"), ["pinned"]);
+        assert_eq!(ids(&body, "new_rooms"), ["lounge"]);
+        assert_eq!(ids(&body, "rooms"), ["general"]);
+    }
+
+    #[actix_web::test]
+    async fn since_can_be_given_explicitly() {
+        let pool = digest_pool().await;
+        let (status, body) = digest(&pool, "alice", "?since=2026-01-01T11:15:00Z").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["since"], "2026-01-01T11:15:00+00:00");
+        assert_eq!(ids(&body, "mentions"), ["mention", "old"]);
+
+        let (status, body) = digest(&pool, "alice", "?since=yesterday").await;
+        assert_eq!(status, 400);
+        assert_eq!(body["code"], "invalid_payload");
+    }
+
+    #[actix_web::test]
+    async fn closing_a_connection_records_last_seen() {
+        let pool = digest_pool().await;
+        record_last_seen(&pool, "bob").await;
+        let last_seen: Option<String> = sqlx::query_scalar("SELECT last_seen_at FROM users WHERE id = 'bob'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(DateTime::parse_from_rfc3339(&last_seen.unwrap()).is_ok());
+
+        // Bob's own pin is not news to him
+        let (_, body) = digest(&pool, "bob", &format!("?since={}", "2026-01-01T00:00:00Z")).await;
+        assert!(ids(&body, "pins").is_empty());
+    }
+}
@@ -18,6 +18,7 @@ use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::auth::extract_claims;
+use crate::errors::{ApiError, ErrorCode};
 
 const DISCORD_GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=9&encoding=json";
 
@@ -65,6 +66,9 @@ enum GatewayCommand {
     },
 }
 
+// Voice join waiting for VOICE_SERVER_UPDATE: (guild_id, channel_id, reply)
+type PendingVoiceJoin = (String, String, oneshot::Sender<Result<VoiceServerInfo, String>>);
+
 pub struct GatewaySession {
     cmd_tx: mpsc::Sender<GatewayCommand>,
     presence: Arc<Mutex<VoicePresenceState>>,
@@ -137,11 +141,7 @@ async fn run_gateway(
     let mut sequence: Option<u64> = None;
     let mut session_id: Option<String> = None;
     let mut identified = false;
-    let mut pending_voice_join: Option<(
-        String, // guild_id
-        String, // channel_id
-        oneshot::Sender<Result<VoiceServerInfo, String>>,
-    )> = None;
+    let mut pending_voice_join: Option<PendingVoiceJoin> = None;
     // Queued join command waiting for READY event
     let mut queued_join: Option<GatewayCommand> = None;
     let mut voice_token: Option<String> = None;
@@ -629,13 +629,16 @@ pub async fn voice_participants(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
 
     let discord_token = match get_discord_token(pool.get_ref(), &claims.sub).await {
         Ok(t) => t,
         Err(e) => {
-            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e }));
+            return e.respond(&req);
         }
     };
 
@@ -658,19 +661,19 @@ pub async fn voice_participants(
 
 // ── Helper: get Discord token for user ──────────────────
 
-async fn get_discord_token(pool: &SqlitePool, user_id: &str) -> Result<String, String> {
+async fn get_discord_token(pool: &SqlitePool, user_id: &str) -> Result<String, ApiError> {
     let row = sqlx::query("SELECT discord_access_token FROM users WHERE id = ?")
         .bind(user_id)
         .fetch_optional(pool)
         .await
-        .map_err(|_| "Database error".to_string())?;
+        .map_err(|_| ApiError::new(ErrorCode::InternalError))?;
 
-    let row = row.ok_or("User not found")?;
+    let row = row.ok_or(ApiError::new(ErrorCode::UserNotFound))?;
     let token: Option<String> = row
         .try_get("discord_access_token")
         .unwrap_or(None);
 
-    token.ok_or("No Discord token linked".to_string())
+    token.ok_or(ApiError::new(ErrorCode::DiscordNotLinked))
 }
 
 // ── HTTP Handlers ───────────────────────────────────────
@@ -686,13 +689,16 @@ pub async fn voice_join(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
 
     let discord_token = match get_discord_token(pool.get_ref(), &claims.sub).await {
         Ok(t) => t,
         Err(e) => {
-            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e }));
+            return e.respond(&req);
         }
     };
 
@@ -712,9 +718,7 @@ pub async fn voice_join(
         // Gateway task died, remove from map
         let mut map = gateways.lock().await;
         map.remove(&claims.sub);
-        return HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Discord Gateway session lost"
-        }));
+        return ErrorCode::DiscordGatewayLost.respond(&req);
     }
 
     // Wait for the voice server info with a timeout (20s to allow for gateway identify + voice join)
@@ -726,19 +730,15 @@ pub async fn voice_join(
         }
         Ok(Ok(Err(e))) => {
             eprintln!("[discord-gw] HTTP handler returning error from gateway: {e}");
-            HttpResponse::BadGateway().json(serde_json::json!({ "error": e }))
+            ApiError::with_details(ErrorCode::DiscordVoiceFailed, e).respond(&req)
         }
         Ok(Err(_)) => {
             eprintln!("[discord-gw] HTTP handler: oneshot channel dropped");
-            HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal channel error"
-            }))
+            ErrorCode::InternalError.respond(&req)
         }
         Err(_) => {
             eprintln!("[discord-gw] HTTP handler: TIMEOUT — no voice info in 20s");
-            HttpResponse::GatewayTimeout().json(serde_json::json!({
-                "error": "Timeout waiting for Discord voice server info"
-            }))
+            ErrorCode::DiscordVoiceTimeout.respond(&req)
         }
     }
 }
@@ -753,13 +753,16 @@ pub async fn voice_leave(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
 
     let discord_token = match get_discord_token(pool.get_ref(), &claims.sub).await {
         Ok(t) => t,
         Err(e) => {
-            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e }));
+            return e.respond(&req);
         }
     };
 
@@ -777,9 +780,7 @@ pub async fn voice_leave(
     {
         let mut map = gateways.lock().await;
         map.remove(&claims.sub);
-        return HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Discord Gateway session lost"
-        }));
+        return ErrorCode::DiscordGatewayLost.respond(&req);
     }
 
     match tokio::time::timeout(std::time::Duration::from_secs(5), reply_rx).await {
@@ -787,10 +788,8 @@ pub async fn voice_leave(
             HttpResponse::Ok().json(serde_json::json!({ "ok": true }))
         }
         Ok(Ok(Err(e))) => {
-            HttpResponse::BadGateway().json(serde_json::json!({ "error": e }))
+            ApiError::with_details(ErrorCode::DiscordVoiceFailed, e).respond(&req)
         }
-        _ => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Failed to leave voice"
-        })),
+        _ => ApiError::with_details(ErrorCode::DiscordVoiceFailed, "Failed to leave voice").respond(&req),
     }
 }
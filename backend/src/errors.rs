@@ -0,0 +1,528 @@
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, ResponseError};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// ── Error codes ─────────────────────────────────────────
+
+/// Stable, machine-readable error codes shared by HTTP responses and WS `error` events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // General
+    NotAuthenticated,
+    AdminOnly,
+    AccessDenied,
+    InternalError,
+    // Accounts
+    InvalidRegistration,
+    UsernameTaken,
+    UnknownUser,
+    InvalidPassword,
+    UserNotFound,
+    ProfileUpdateFailed,
+    UserProtected,
+    // Discord
+    DiscordTokenMissing,
+    DiscordTokenInvalid,
+    DiscordUnavailable,
+    DiscordBadResponse,
+    DiscordAccountCreateFailed,
+    DiscordNotLinked,
+    InvalidDiscordPath,
+    MethodNotAllowed,
+    DiscordGatewayLost,
+    DiscordVoiceFailed,
+    DiscordVoiceTimeout,
+    QrSessionNotFound,
+    // Roles
+    InvalidRole,
+    InvalidRoleName,
+    InvalidRoleColor,
+    RoleExists,
+    RoleProtected,
+    RoleNotFound,
+    // Rooms
+    RoomNameRequired,
+    InvalidRoomKind,
+    RestrictedRoomAdminOnly,
+    RoomNameTaken,
+    RoomNotFound,
+    RoomAccessDenied,
+    // Messages
+    MessageNotFound,
+    NotMessageOwner,
+    InvalidEmoji,
+    EmptyMessage,
+    MessageTooLong,
+    InvalidReply,
+    InvalidNonce,
+    MessageNotStored,
+    PinFailed,
+    UnpinFailed,
+    PurgeFailed,
+    // Uploads
+    UnsupportedFileType,
+    FileTooLarge,
+    StorageQuotaExceeded,
+    UploadFailed,
+    NoFileProvided,
+    InvalidAttachment,
+    TooManyAttachments,
+    // WebSocket protocol
+    MalformedFrame,
+    UnknownEventType,
+    InvalidPayload,
+    UnsupportedProtocolVersion,
+    UnsupportedEncoding,
+    RateLimited,
+    InvalidStatus,
+    NotConnected,
+    MissingScope,
+    InvalidScope,
+    InvalidTokenName,
+    BotNotFound,
+    ApiTokenNotFound,
+    // Webhooks
+    WebhookNotFound,
+    InvalidWebhookName,
+    InvalidEmbed,
+    InvalidMediaUrl,
+    OutgoingWebhookNotFound,
+    InvalidWebhookUrl,
+    InvalidEventType,
+    // Slash commands
+    UnknownCommand,
+    InvalidCommandArgs,
+    TimedOut,
+    InvalidTopic,
+    // Notifications
+    InvalidNotificationLevel,
+    InvalidMuteDuration,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotAuthenticated
+            | ErrorCode::UnknownUser
+            | ErrorCode::InvalidPassword
+            | ErrorCode::DiscordTokenInvalid => StatusCode::UNAUTHORIZED,
+            ErrorCode::AdminOnly
+            | ErrorCode::AccessDenied
+            | ErrorCode::TimedOut
+            | ErrorCode::RestrictedRoomAdminOnly
+            | ErrorCode::RoomAccessDenied
+            | ErrorCode::NotMessageOwner
+            | ErrorCode::MissingScope => StatusCode::FORBIDDEN,
+            ErrorCode::UserNotFound
+            | ErrorCode::QrSessionNotFound
+            | ErrorCode::RoleNotFound
+            | ErrorCode::RoomNotFound
+            | ErrorCode::MessageNotFound
+            | ErrorCode::BotNotFound
+            | ErrorCode::ApiTokenNotFound
+            | ErrorCode::WebhookNotFound
+            | ErrorCode::OutgoingWebhookNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::UsernameTaken | ErrorCode::RoleExists | ErrorCode::RoomNameTaken | ErrorCode::NotConnected => {
+                StatusCode::CONFLICT
+            }
+            ErrorCode::FileTooLarge | ErrorCode::StorageQuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::DiscordUnavailable | ErrorCode::DiscordBadResponse | ErrorCode::DiscordVoiceFailed => {
+                StatusCode::BAD_GATEWAY
+            }
+            ErrorCode::DiscordVoiceTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::InternalError
+            | ErrorCode::ProfileUpdateFailed
+            | ErrorCode::DiscordAccountCreateFailed
+            | ErrorCode::DiscordGatewayLost
+            | ErrorCode::MessageNotStored
+            | ErrorCode::PinFailed
+            | ErrorCode::UnpinFailed
+            | ErrorCode::PurgeFailed
+            | ErrorCode::UploadFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InvalidRegistration
+            | ErrorCode::DiscordTokenMissing
+            | ErrorCode::DiscordNotLinked
+            | ErrorCode::InvalidDiscordPath
+            | ErrorCode::MethodNotAllowed
+            | ErrorCode::InvalidRole
+            | ErrorCode::InvalidRoleName
+            | ErrorCode::InvalidRoleColor
+            | ErrorCode::RoleProtected
+            | ErrorCode::UserProtected
+            | ErrorCode::RoomNameRequired
+            | ErrorCode::InvalidRoomKind
+            | ErrorCode::InvalidEmoji
+            | ErrorCode::EmptyMessage
+            | ErrorCode::MessageTooLong
+            | ErrorCode::InvalidReply
+            | ErrorCode::InvalidNonce
+            | ErrorCode::UnsupportedFileType
+            | ErrorCode::NoFileProvided
+            | ErrorCode::InvalidAttachment
+            | ErrorCode::TooManyAttachments
+            | ErrorCode::MalformedFrame
+            | ErrorCode::UnknownEventType
+            | ErrorCode::InvalidPayload
+            | ErrorCode::UnsupportedProtocolVersion
+            | ErrorCode::UnsupportedEncoding
+            | ErrorCode::InvalidStatus
+            | ErrorCode::InvalidScope
+            | ErrorCode::InvalidTokenName
+            | ErrorCode::InvalidWebhookName
+            | ErrorCode::InvalidEmbed
+            | ErrorCode::InvalidMediaUrl
+            | ErrorCode::InvalidWebhookUrl
+            | ErrorCode::InvalidEventType
+            | ErrorCode::UnknownCommand
+            | ErrorCode::InvalidCommandArgs
+            | ErrorCode::InvalidTopic
+            | ErrorCode::InvalidNotificationLevel
+            | ErrorCode::InvalidMuteDuration => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    pub fn message(self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::En => self.message_en(),
+            Lang::Fr => self.message_fr(),
+        }
+    }
+
+    fn message_en(self) -> &'static str {
+        match self {
+            ErrorCode::NotAuthenticated => "Not authenticated",
+            ErrorCode::AdminOnly => "Admin only",
+            ErrorCode::AccessDenied => "Access denied",
+            ErrorCode::InternalError => "Internal server error",
+            ErrorCode::InvalidRegistration => "Username must be non-empty and password at least 4 characters",
+            ErrorCode::UsernameTaken => "Username already taken",
+            ErrorCode::UnknownUser => "User not found",
+            ErrorCode::InvalidPassword => "Invalid password",
+            ErrorCode::UserNotFound => "User not found",
+            ErrorCode::ProfileUpdateFailed => "Update failed (username might be taken)",
+            ErrorCode::UserProtected => "This account is reserved and cannot be deleted",
+            ErrorCode::DiscordTokenMissing => "Missing discord_token",
+            ErrorCode::DiscordTokenInvalid => "Invalid or expired Discord token",
+            ErrorCode::DiscordUnavailable => "Discord API unavailable",
+            ErrorCode::DiscordBadResponse => "Invalid response from Discord",
+            ErrorCode::DiscordAccountCreateFailed => "Could not create the local Discord user",
+            ErrorCode::DiscordNotLinked => "No Discord token linked",
+            ErrorCode::InvalidDiscordPath => "Invalid Discord path",
+            ErrorCode::MethodNotAllowed => "Method not allowed",
+            ErrorCode::DiscordGatewayLost => "Discord Gateway session lost",
+            ErrorCode::DiscordVoiceFailed => "Discord voice request failed",
+            ErrorCode::DiscordVoiceTimeout => "Timeout waiting for Discord voice server info",
+            ErrorCode::QrSessionNotFound => "Session not found",
+            ErrorCode::InvalidRole => "Invalid role",
+            ErrorCode::InvalidRoleName => "Role name must be 2 to 24 chars of a-z, 0-9, _ and -",
+            ErrorCode::InvalidRoleColor => "Invalid role color (expected #RRGGBB)",
+            ErrorCode::RoleExists => "Role already exists",
+            ErrorCode::RoleProtected => "This role is protected",
+            ErrorCode::RoleNotFound => "Role not found",
+            ErrorCode::RoomNameRequired => "Room name is required",
+            ErrorCode::InvalidRoomKind => "Room kind must be text or voice",
+            ErrorCode::RestrictedRoomAdminOnly => "Only admins can create restricted rooms",
+            ErrorCode::RoomNameTaken => "Room name already exists",
+            ErrorCode::RoomNotFound => "Room not found",
+            ErrorCode::RoomAccessDenied => "Access denied for this room",
+            ErrorCode::MessageNotFound => "Message not found",
+            ErrorCode::NotMessageOwner => "You can only delete your own messages",
+            ErrorCode::InvalidEmoji => "Invalid emoji",
+            ErrorCode::EmptyMessage => "Message has no content",
+            ErrorCode::MessageTooLong => "Message is too long",
+            ErrorCode::InvalidReply => "The replied-to message is not in this room",
+            ErrorCode::InvalidNonce => "Nonce must be 1 to 64 characters",
+            ErrorCode::MessageNotStored => "Failed to store message",
+            ErrorCode::PinFailed => "Failed to pin message",
+            ErrorCode::UnpinFailed => "Failed to unpin message",
+            ErrorCode::PurgeFailed => "Failed to purge messages",
+            ErrorCode::UnsupportedFileType => "Only PNG, JPEG, GIF, WebP and BMP images are allowed",
+            ErrorCode::FileTooLarge => "File too large (max 8MB)",
+            ErrorCode::StorageQuotaExceeded => "Storage quota exceeded",
+            ErrorCode::UploadFailed => "Failed to save file",
+            ErrorCode::NoFileProvided => "No file provided",
+            ErrorCode::InvalidAttachment => "Attachments must be your own uploads, not attached to another message",
+            ErrorCode::TooManyAttachments => "A message can have at most 10 attachments",
+            ErrorCode::MalformedFrame => "Frame is not a JSON object with a type",
+            ErrorCode::UnknownEventType => "Unknown event type",
+            ErrorCode::InvalidPayload => "Invalid event payload",
+            ErrorCode::UnsupportedProtocolVersion => "Protocol version not supported",
+            ErrorCode::UnsupportedEncoding => "Encoding or compression not supported",
+            ErrorCode::RateLimited => "You are sending too fast, slow down",
+            ErrorCode::InvalidStatus => "Status must be online, idle, dnd or invisible",
+            ErrorCode::NotConnected => "Open /ws or /api/events first",
+            ErrorCode::MissingScope => "This API token is not allowed to do that",
+            ErrorCode::InvalidScope => "Invalid API token scope",
+            ErrorCode::InvalidTokenName => "API token name is required",
+            ErrorCode::BotNotFound => "Bot not found",
+            ErrorCode::ApiTokenNotFound => "API token not found",
+            ErrorCode::WebhookNotFound => "Webhook not found",
+            ErrorCode::InvalidWebhookName => "Webhook name must be 1 to 32 characters",
+            ErrorCode::InvalidEmbed => "Invalid embed",
+            ErrorCode::InvalidMediaUrl => "Media URLs must be http(s) or point to /uploads/",
+            ErrorCode::OutgoingWebhookNotFound => "Outgoing webhook not found",
+            ErrorCode::InvalidWebhookUrl => "Webhook URL must be an absolute http(s) URL",
+            ErrorCode::InvalidEventType => "Unknown event type",
+            ErrorCode::UnknownCommand => "Unknown command",
+            ErrorCode::InvalidCommandArgs => "Invalid command arguments",
+            ErrorCode::TimedOut => "You are timed out and cannot send messages",
+            ErrorCode::InvalidTopic => "Topic must be at most 256 characters",
+            ErrorCode::InvalidNotificationLevel => "Notification level must be all, mentions or nothing",
+            ErrorCode::InvalidMuteDuration => "Mute duration must be between 1 ms and 366 days",
+        }
+    }
+
+    fn message_fr(self) -> &'static str {
+        match self {
+            ErrorCode::NotAuthenticated => "Non authentifié",
+            ErrorCode::AdminOnly => "Réservé aux administrateurs",
+            ErrorCode::AccessDenied => "Accès refusé",
+            ErrorCode::InternalError => "Erreur interne du serveur",
+            ErrorCode::InvalidRegistration => "Le pseudo est requis et le mot de passe doit faire au moins 4 caractères",
+            ErrorCode::UsernameTaken => "Pseudo déjà utilisé",
+            ErrorCode::UnknownUser => "Utilisateur introuvable",
+            ErrorCode::InvalidPassword => "Mot de passe invalide",
+            ErrorCode::UserNotFound => "Utilisateur introuvable",
+            ErrorCode::ProfileUpdateFailed => "Échec de la mise à jour (pseudo peut-être déjà pris)",
+            ErrorCode::UserProtected => "Ce compte est réservé et ne peut pas être supprimé",
+            ErrorCode::DiscordTokenMissing => "discord_token manquant",
+            ErrorCode::DiscordTokenInvalid => "Token Discord invalide ou expiré",
+            ErrorCode::DiscordUnavailable => "Discord API indisponible",
+            ErrorCode::DiscordBadResponse => "Réponse Discord invalide",
+            ErrorCode::DiscordAccountCreateFailed => "Impossible de créer l'utilisateur Discord local",
+            ErrorCode::DiscordNotLinked => "Aucun token Discord lié",
+            ErrorCode::InvalidDiscordPath => "Discord path invalide",
+            ErrorCode::MethodNotAllowed => "Méthode non autorisée",
+            ErrorCode::DiscordGatewayLost => "Session Discord Gateway perdue",
+            ErrorCode::DiscordVoiceFailed => "Échec de la requête vocale Discord",
+            ErrorCode::DiscordVoiceTimeout => "Délai dépassé en attendant le serveur vocal Discord",
+            ErrorCode::QrSessionNotFound => "Session introuvable",
+            ErrorCode::InvalidRole => "Rôle invalide",
+            ErrorCode::InvalidRoleName => "Le nom du rôle doit faire 2 à 24 caractères parmi a-z, 0-9, _ et -",
+            ErrorCode::InvalidRoleColor => "Couleur de rôle invalide (attendu #RRGGBB)",
+            ErrorCode::RoleExists => "Ce rôle existe déjà",
+            ErrorCode::RoleProtected => "Ce rôle est protégé",
+            ErrorCode::RoleNotFound => "Rôle introuvable",
+            ErrorCode::RoomNameRequired => "Le nom du salon est requis",
+            ErrorCode::InvalidRoomKind => "Le type de salon doit être text ou voice",
+            ErrorCode::RestrictedRoomAdminOnly => "Seuls les administrateurs peuvent créer des salons restreints",
+            ErrorCode::RoomNameTaken => "Ce nom de salon existe déjà",
+            ErrorCode::RoomNotFound => "Salon introuvable",
+            ErrorCode::RoomAccessDenied => "Accès refusé à ce salon",
+            ErrorCode::MessageNotFound => "Message introuvable",
+            ErrorCode::NotMessageOwner => "Vous ne pouvez supprimer que vos propres messages",
+            ErrorCode::InvalidEmoji => "Emoji invalide",
+            ErrorCode::EmptyMessage => "Le message est vide",
+            ErrorCode::MessageTooLong => "Le message est trop long",
+            ErrorCode::InvalidReply => "Le message cité n'est pas dans ce salon",
+            ErrorCode::InvalidNonce => "Le nonce doit faire 1 à 64 caractères",
+            ErrorCode::MessageNotStored => "Impossible d'enregistrer le message",
+            ErrorCode::PinFailed => "Impossible d'épingler le message",
+            ErrorCode::UnpinFailed => "Impossible de désépingler le message",
+            ErrorCode::PurgeFailed => "Impossible de purger les messages",
+            ErrorCode::UnsupportedFileType => "Seules les images PNG, JPEG, GIF, WebP et BMP sont autorisées",
+            ErrorCode::FileTooLarge => "Fichier trop volumineux (max 8 Mo)",
+            ErrorCode::StorageQuotaExceeded => "Quota de stockage dépassé",
+            ErrorCode::UploadFailed => "Impossible d'enregistrer le fichier",
+            ErrorCode::NoFileProvided => "Aucun fichier fourni",
+            ErrorCode::InvalidAttachment => "Les pièces jointes doivent être vos propres fichiers, non joints à un autre message",
+            ErrorCode::TooManyAttachments => "Un message peut avoir au plus 10 pièces jointes",
+            ErrorCode::MalformedFrame => "La trame n'est pas un objet JSON avec un type",
+            ErrorCode::UnknownEventType => "Type d'événement inconnu",
+            ErrorCode::InvalidPayload => "Contenu d'événement invalide",
+            ErrorCode::UnsupportedProtocolVersion => "Version de protocole non prise en charge",
+            ErrorCode::UnsupportedEncoding => "Encodage ou compression non pris en charge",
+            ErrorCode::RateLimited => "Vous envoyez trop vite, ralentissez",
+            ErrorCode::InvalidStatus => "Le statut doit être online, idle, dnd ou invisible",
+            ErrorCode::NotConnected => "Ouvrez /ws ou /api/events d'abord",
+            ErrorCode::MissingScope => "Ce jeton d'API n'a pas le droit de faire cela",
+            ErrorCode::InvalidScope => "Portée de jeton d'API invalide",
+            ErrorCode::InvalidTokenName => "Le nom du jeton d'API est requis",
+            ErrorCode::BotNotFound => "Bot introuvable",
+            ErrorCode::ApiTokenNotFound => "Jeton d'API introuvable",
+            ErrorCode::WebhookNotFound => "Webhook introuvable",
+            ErrorCode::InvalidWebhookName => "Le nom du webhook doit faire entre 1 et 32 caractères",
+            ErrorCode::InvalidEmbed => "Embed invalide",
+            ErrorCode::InvalidMediaUrl => "Les URL de médias doivent être en http(s) ou pointer vers /uploads/",
+            ErrorCode::OutgoingWebhookNotFound => "Webhook sortant introuvable",
+            ErrorCode::InvalidWebhookUrl => "L'URL du webhook doit être une URL http(s) absolue",
+            ErrorCode::InvalidEventType => "Type d'événement inconnu",
+            ErrorCode::UnknownCommand => "Commande inconnue",
+            ErrorCode::InvalidCommandArgs => "Arguments de commande invalides",
+            ErrorCode::TimedOut => "Vous êtes exclu temporairement et ne pouvez pas envoyer de messages",
+            ErrorCode::InvalidTopic => "Le sujet ne doit pas dépasser 256 caractères",
+            ErrorCode::InvalidNotificationLevel => "Le niveau de notification doit être all, mentions ou nothing",
+            ErrorCode::InvalidMuteDuration => "La durée de sourdine doit être comprise entre 1 ms et 366 jours",
+        }
+    }
+
+    /// Shorthand for `ApiError::new(code).respond(req)`.
+    pub fn respond(self, req: &HttpRequest) -> HttpResponse {
+        ApiError::new(self).respond(req)
+    }
+}
+
+// ── Localization ────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Fr,
+}
+
+impl Lang {
+    fn from_tag(tag: &str) -> Option<Lang> {
+        let primary = tag.split(['-', '_']).next()?.trim().to_lowercase();
+        match primary.as_str() {
+            "en" => Some(Lang::En),
+            "fr" => Some(Lang::Fr),
+            _ => None,
+        }
+    }
+
+    /// Pick the supported language with the highest `q` weight, English otherwise.
+    pub fn from_accept_language(header: Option<&str>) -> Lang {
+        let Some(header) = header else {
+            return Lang::default();
+        };
+
+        let mut best: Option<(Lang, f32)> = None;
+        for part in header.split(',') {
+            let mut pieces = part.split(';');
+            let Some(lang) = pieces.next().and_then(Lang::from_tag) else {
+                continue;
+            };
+            let weight = pieces
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if weight > 0.0 && best.is_none_or(|(_, w)| weight > w) {
+                best = Some((lang, weight));
+            }
+        }
+
+        best.map(|(lang, _)| lang).unwrap_or_default()
+    }
+
+    pub fn from_request(req: &HttpRequest) -> Lang {
+        let header = req
+            .headers()
+            .get("Accept-Language")
+            .and_then(|v| v.to_str().ok());
+        Lang::from_accept_language(header)
+    }
+}
+
+// ── Error type ──────────────────────────────────────────
+
+/// An error code plus optional free-form details (upstream errors, parser messages).
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub details: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode) -> Self {
+        ApiError { code, details: None }
+    }
+
+    pub fn with_details(code: ErrorCode, details: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            details: Some(details.into()),
+        }
+    }
+
+    pub fn body(&self, lang: Lang) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "error": self.code.message(lang),
+            "code": self.code,
+        });
+        if let Some(details) = &self.details {
+            body["details"] = serde_json::json!(details);
+        }
+        body
+    }
+
+    /// Build the HTTP response, localized from the request's `Accept-Language`.
+    pub fn respond(&self, req: &HttpRequest) -> HttpResponse {
+        HttpResponse::build(self.code.status()).json(self.body(Lang::from_request(req)))
+    }
+}
+
+impl From<ErrorCode> for ApiError {
+    fn from(code: ErrorCode) -> Self {
+        ApiError::new(code)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.details {
+            Some(details) => write!(f, "{}: {}", self.code.message(Lang::En), details),
+            None => f.write_str(self.code.message(Lang::En)),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.code.status()).json(self.body(Lang::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn accept_language_picks_the_heaviest_supported_tag() {
+        assert_eq!(Lang::from_accept_language(None), Lang::En);
+        assert_eq!(Lang::from_accept_language(Some("")), Lang::En);
+        assert_eq!(Lang::from_accept_language(Some("fr-CA")), Lang::Fr);
+        assert_eq!(Lang::from_accept_language(Some("fr_FR")), Lang::Fr);
+        assert_eq!(Lang::from_accept_language(Some("de-DE, fr;q=0.8, en;q=0.5")), Lang::Fr);
+        assert_eq!(Lang::from_accept_language(Some("fr;q=0.4, EN-gb;q=0.9")), Lang::En);
+        // The first of equally weighted tags wins
+        assert_eq!(Lang::from_accept_language(Some("fr, en")), Lang::Fr);
+    }
+
+    #[test]
+    fn unsupported_or_refused_languages_fall_back_to_english() {
+        assert_eq!(Lang::from_accept_language(Some("de, es;q=0.9")), Lang::En);
+        assert_eq!(Lang::from_accept_language(Some("fr;q=0")), Lang::En);
+        assert_eq!(Lang::from_accept_language(Some("*")), Lang::En);
+        assert_eq!(Lang::from_accept_language(Some("fr;q=abc")), Lang::Fr);
+    }
+
+    #[test]
+    fn body_carries_code_localized_message_and_details() {
+        let body = ApiError::with_details(ErrorCode::RoomNotFound, "r1").body(Lang::Fr);
+        assert_eq!(body["code"], "room_not_found");
+        assert_eq!(body["error"], ErrorCode::RoomNotFound.message(Lang::Fr));
+        assert_eq!(body["details"], "r1");
+        assert!(ApiError::new(ErrorCode::AdminOnly).body(Lang::En).get("details").is_none());
+    }
+
+    #[test]
+    fn responses_use_the_code_status_and_request_language() {
+        let req = TestRequest::default()
+            .insert_header(("Accept-Language", "fr"))
+            .to_http_request();
+        let response = ErrorCode::NotAuthenticated.respond(&req);
+        assert_eq!(response.status(), ErrorCode::NotAuthenticated.status());
+        assert_eq!(ErrorCode::NotAuthenticated.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(ErrorCode::AdminOnly.status(), StatusCode::FORBIDDEN);
+        assert_eq!(ErrorCode::RoomNotFound.status(), StatusCode::NOT_FOUND);
+    }
+}
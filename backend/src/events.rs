@@ -0,0 +1,597 @@
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::codec::BroadcastFrame;
+use crate::errors::{ApiError, ErrorCode, Lang};
+use crate::ws::Broadcaster;
+
+// ── Client → server ─────────────────────────────────────
+
+/// Events a client may send over `/ws`, tagged by `type`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    #[serde(alias = "identify")]
+    Join(JoinPayload),
+    Leave(LeavePayload),
+    Message(SendMessagePayload),
+    Typing(TypingPayload),
+    Presence(PresencePayload),
+    Heartbeat(HeartbeatPayload),
+    VoiceJoin(VoicePayload),
+    VoiceLeave(VoicePayload),
+    VoiceState(VoicePayload),
+    VoiceSignal(VoicePayload),
+}
+
+/// Every `type` accepted by [`ClientEvent`], including aliases.
+pub const CLIENT_EVENT_TYPES: &[&str] = &[
+    "join",
+    "identify",
+    "leave",
+    "message",
+    "typing",
+    "presence",
+    "heartbeat",
+    "voice_join",
+    "voice_leave",
+    "voice_state",
+    "voice_signal",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct JoinPayload {
+    pub user_id: String,
+    pub username: String,
+    pub avatar_color: i32,
+    pub protocol_version: Option<u32>,
+    pub avatar_url: Option<String>,
+    pub banner_url: Option<String>,
+    pub status: Option<String>,
+    pub role: Option<String>,
+    pub about: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct LeavePayload {}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SendMessagePayload {
+    pub room_id: String,
+    pub user_id: String,
+    pub username: String,
+    #[serde(default)]
+    pub content: String,
+    pub reply_to_id: Option<String>,
+    /// Legacy single image: must be the `url` of an upload of the author, which becomes the first attachment.
+    pub image_url: Option<String>,
+    /// Uploads from `POST /api/upload`, in display order.
+    #[serde(default)]
+    pub attachments: Vec<AttachmentInput>,
+    /// Ignored: messages carry the author's stored avatar color.
+    pub avatar_color: Option<i32>,
+    /// Client-chosen id echoed back in `ack`/`error`; resending the same nonce never duplicates the message.
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TypingPayload {
+    pub room_id: String,
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PresencePayload {
+    pub status: String,
+    /// Sent by client idle detection: `idle` only replaces `online`, and `online` only ends an automatic idle.
+    #[serde(default)]
+    pub auto: bool,
+}
+
+/// Application-level keepalive for clients that cannot see WebSocket pings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct HeartbeatPayload {}
+
+/// Voice signaling is relayed between peers as-is.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct VoicePayload {
+    pub room_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub muted: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deafened: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screen_sharing: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sdp: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidate: Option<serde_json::Value>,
+}
+
+/// Why an inbound frame could not be turned into a [`ClientEvent`].
+#[derive(Debug)]
+pub enum ClientEventError {
+    Malformed(String),
+    UnknownType(String),
+    InvalidPayload { event_type: String, reason: String },
+}
+
+impl From<ClientEventError> for ApiError {
+    fn from(err: ClientEventError) -> Self {
+        match err {
+            ClientEventError::Malformed(reason) => ApiError::with_details(ErrorCode::MalformedFrame, reason),
+            ClientEventError::UnknownType(event_type) => ApiError::with_details(ErrorCode::UnknownEventType, event_type),
+            ClientEventError::InvalidPayload { event_type, reason } => {
+                ApiError::with_details(ErrorCode::InvalidPayload, format!("{}: {}", event_type, reason))
+            }
+        }
+    }
+}
+
+impl ClientEvent {
+    /// Parse a text frame, telling unknown event types apart from bad payloads.
+    pub fn parse(text: &str) -> Result<ClientEvent, ClientEventError> {
+        let value: serde_json::Value =
+            serde_json::from_str(text).map_err(|e| ClientEventError::Malformed(e.to_string()))?;
+        ClientEvent::from_value(value)
+    }
+
+    /// Same as [`ClientEvent::parse`], for frames already decoded from another encoding.
+    pub fn from_value(value: serde_json::Value) -> Result<ClientEvent, ClientEventError> {
+        let event_type = value
+            .get("type")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| ClientEventError::Malformed("missing 'type'".to_string()))?
+            .to_string();
+
+        if !CLIENT_EVENT_TYPES.contains(&event_type.as_str()) {
+            return Err(ClientEventError::UnknownType(event_type));
+        }
+
+        serde_json::from_value(value).map_err(|e| ClientEventError::InvalidPayload {
+            event_type,
+            reason: e.to_string(),
+        })
+    }
+}
+
+// ── Server → client ─────────────────────────────────────
+
+/// Events the server sends over `/ws`, tagged by `type`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Hello(HelloEvent),
+    Error(ErrorEvent),
+    Ack(AckEvent),
+    HeartbeatAck(HeartbeatAckEvent),
+    /// Also sent as an upsert when a profile or role changes.
+    Join(MemberEvent),
+    Leave(LeaveEvent),
+    Presence(PresenceEvent),
+    PresenceSnapshot(PresenceSnapshotEvent),
+    Message(ChatMessageEvent),
+    Typing(TypingEvent),
+    VoiceJoin(VoicePayload),
+    VoiceLeave(VoicePayload),
+    VoiceState(VoicePayload),
+    VoiceSignal(VoicePayload),
+    RoomUpdated(RoomUpdatedEvent),
+    RoomDeleted(RoomDeletedEvent),
+    MessageDeleted(MessageDeletedEvent),
+    MessagePinned(MessagePinnedEvent),
+    MessageUnpinned(MessageUnpinnedEvent),
+    MessageReactionUpdated(MessageReactionUpdatedEvent),
+    MessagesPurged(MessagesPurgedEvent),
+    /// Sent once, when an account is created.
+    MemberJoined(MemberJoinedEvent),
+    Ephemeral(EphemeralEvent),
+    /// Sent only to the users a new message mentions.
+    Mention(MentionEvent),
+    /// Sent only to the user whose read marker moved, on all their connections.
+    ReadStateUpdated(ReadStateUpdatedEvent),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HelloEvent {
+    pub server_version: String,
+    pub protocol_versions: Vec<u32>,
+    pub heartbeat_interval: u64,
+    pub features: Vec<String>,
+    /// Encoding negotiated with `?encoding=`: `json`, `msgpack` or `cbor`.
+    pub encoding: String,
+    /// `zlib` when negotiated with `?compress=zlib`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ErrorEvent {
+    pub code: ErrorCode,
+    /// Human-readable message, localized from the upgrade request's `Accept-Language`.
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    /// Nonce of the request that failed, if it carried one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol_versions: Option<Vec<u32>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct HeartbeatAckEvent {}
+
+/// Sent only to the connection that sent a `message`, once it is stored.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AckEvent {
+    pub nonce: Option<String>,
+    pub id: String,
+    pub room_id: String,
+    pub created_at: String,
+    /// True when the nonce was already used and no new message was created.
+    pub duplicate: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MemberEvent {
+    pub user_id: String,
+    pub username: String,
+    pub avatar_color: i32,
+    pub avatar_url: Option<String>,
+    pub banner_url: Option<String>,
+    pub status: Option<String>,
+    pub role: Option<String>,
+    pub about: Option<String>,
+    #[serde(default)]
+    pub is_bot: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LeaveEvent {
+    pub user_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PresenceEvent {
+    pub user_id: String,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PresenceSnapshotEvent {
+    pub users: Vec<MemberEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChatMessageEvent {
+    pub id: String,
+    pub room_id: String,
+    pub user_id: String,
+    pub username: String,
+    pub content: String,
+    pub reply_to_id: Option<String>,
+    pub image_url: Option<String>,
+    pub avatar_color: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    pub created_at: String,
+    #[serde(default)]
+    pub is_bot: bool,
+    /// Set on messages posted through an incoming webhook.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub embeds: Vec<Embed>,
+    /// Ids of users mentioned with `<@user_id>`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    /// Roles mentioned with `<@&role>`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mention_roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mention_everyone: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+/// Upload referenced by a new message.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AttachmentInput {
+    pub upload_id: String,
+    #[serde(default)]
+    pub alt_text: Option<String>,
+    #[serde(default)]
+    pub spoiler: bool,
+}
+
+/// File attached to a message.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Attachment {
+    /// Id of the upload.
+    pub id: String,
+    pub url: String,
+    pub filename: String,
+    pub mime_type: String,
+    /// Bytes.
+    pub size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i64>,
+    /// Placeholder to show while the image loads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// Average RGB color, e.g. `0x2ecc71`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dominant_color: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt_text: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub spoiler: bool,
+}
+
+/// Rich card attached to a webhook message.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Embed {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// RGB color of the side bar, e.g. `0x2ecc71`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<EmbedField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub footer: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TypingEvent {
+    pub room_id: String,
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RoomUpdatedEvent {
+    pub room_id: String,
+    pub name: String,
+    pub kind: String,
+    pub required_role: String,
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RoomDeletedEvent {
+    pub room_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessageDeletedEvent {
+    pub id: String,
+    pub room_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessagePinnedEvent {
+    pub id: String,
+    pub room_id: String,
+    pub pinned_at: String,
+    pub pinned_by: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessageUnpinnedEvent {
+    pub id: String,
+    pub room_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessageReactionUpdatedEvent {
+    pub room_id: String,
+    pub message_id: String,
+    pub emoji: String,
+    pub count: usize,
+    pub user_ids: Vec<String>,
+}
+
+/// Also the shape of each entry of `GET /api/users/me/read-states`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ReadStateUpdatedEvent {
+    pub room_id: String,
+    pub last_read_message_id: Option<String>,
+    /// `created_at` of the last read message.
+    pub last_read_at: Option<String>,
+    pub unread_count: i64,
+    pub mention_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MentionEvent {
+    pub message: ChatMessageEvent,
+}
+
+/// Reply to a slash command, sent only to the connection that ran it and never stored.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct EphemeralEvent {
+    pub room_id: String,
+    pub command: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessagesPurgedEvent {
+    pub user_id: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MemberJoinedEvent {
+    pub user_id: String,
+    pub username: String,
+    pub avatar_color: i32,
+    pub avatar_url: Option<String>,
+    pub role: String,
+}
+
+impl ServerEvent {
+    /// Room the event belongs to; room-scoped events only reach users who can access it.
+    pub fn room_id(&self) -> Option<&str> {
+        match self {
+            ServerEvent::Message(e) => Some(&e.room_id),
+            ServerEvent::Typing(e) => Some(&e.room_id),
+            ServerEvent::VoiceJoin(e)
+            | ServerEvent::VoiceLeave(e)
+            | ServerEvent::VoiceState(e)
+            | ServerEvent::VoiceSignal(e) => e.room_id.as_deref(),
+            ServerEvent::RoomUpdated(e) => Some(&e.room_id),
+            ServerEvent::RoomDeleted(e) => Some(&e.room_id),
+            ServerEvent::MessageDeleted(e) => Some(&e.room_id),
+            ServerEvent::MessagePinned(e) => Some(&e.room_id),
+            ServerEvent::MessageUnpinned(e) => Some(&e.room_id),
+            ServerEvent::MessageReactionUpdated(e) => Some(&e.room_id),
+            ServerEvent::Mention(e) => Some(&e.message.room_id),
+            ServerEvent::ReadStateUpdated(e) => Some(&e.room_id),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("server events always serialize")
+    }
+
+    pub fn error(err: &ApiError, lang: Lang, nonce: Option<String>) -> ServerEvent {
+        ServerEvent::Error(ErrorEvent {
+            code: err.code,
+            message: err.code.message(lang).to_string(),
+            details: err.details.clone(),
+            nonce,
+            protocol_versions: None,
+        })
+    }
+}
+
+/// Send an event to every connected client allowed to see it.
+pub fn broadcast(broadcaster: &Broadcaster, event: &ServerEvent) {
+    let _ = broadcaster.send(Arc::new(BroadcastFrame::new(event.clone())));
+}
+
+/// Send an event only to the given users' connections, still subject to room access.
+pub fn broadcast_to(broadcaster: &Broadcaster, event: &ServerEvent, user_ids: HashSet<String>) {
+    let _ = broadcaster.send(Arc::new(BroadcastFrame::new(event.clone()).with_recipients(user_ids)));
+}
+
+/// JSON Schema for both directions of the WebSocket protocol.
+pub fn protocol_schema() -> serde_json::Value {
+    serde_json::json!({
+        "client_events": schema_for!(ClientEvent),
+        "server_events": schema_for!(ServerEvent),
+    })
+}
+
+/// GET /api/protocol/schema — JSON Schema of the WebSocket events
+pub async fn get_protocol_schema() -> actix_web::HttpResponse {
+    actix_web::HttpResponse::Ok().json(protocol_schema())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_events_are_parsed() {
+        let event = ClientEvent::parse(
+            r#"{"type":"message","room_id":"r1","user_id":"u1","username":"alice","content":"hi"}"#,
+        )
+        .unwrap();
+        let ClientEvent::Message(message) = event else {
+            panic!("expected a message");
+        };
+        assert_eq!((message.room_id.as_str(), message.content.as_str()), ("r1", "hi"));
+
+        let event = ClientEvent::parse(r#"{"type":"presence","status":"idle"}"#).unwrap();
+        assert!(matches!(event, ClientEvent::Presence(p) if p.status == "idle"));
+        assert!(matches!(ClientEvent::parse(r#"{"type":"leave"}"#).unwrap(), ClientEvent::Leave(_)));
+    }
+
+    #[test]
+    fn identify_is_an_alias_of_join() {
+        let event = ClientEvent::parse(
+            r#"{"type":"identify","user_id":"u1","username":"alice","avatar_color":2,"protocol_version":1}"#,
+        )
+        .unwrap();
+        let ClientEvent::Join(join) = event else {
+            panic!("expected a join");
+        };
+        assert_eq!(join.protocol_version, Some(1));
+    }
+
+    #[test]
+    fn every_listed_type_is_accepted_by_serde() {
+        for event_type in CLIENT_EVENT_TYPES {
+            let frame = serde_json::json!({ "type": event_type }).to_string();
+            let err = ClientEvent::parse(&frame).err();
+            assert!(
+                !matches!(err, Some(ClientEventError::UnknownType(_))),
+                "{} is listed but not parsed",
+                event_type
+            );
+        }
+    }
+
+    fn parse_error(text: &str) -> ApiError {
+        ApiError::from(ClientEvent::parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn bad_frames_are_told_apart() {
+        assert_eq!(parse_error("not json").code, ErrorCode::MalformedFrame);
+        assert_eq!(parse_error(r#"{"room_id":"r1"}"#).code, ErrorCode::MalformedFrame);
+        let err = parse_error(r#"{"type":"shout"}"#);
+        assert_eq!(err.code, ErrorCode::UnknownEventType);
+        assert_eq!(err.details.as_deref(), Some("shout"));
+        let err = parse_error(r#"{"type":"message","room_id":"r1"}"#);
+        assert_eq!(err.code, ErrorCode::InvalidPayload);
+        assert!(err.details.unwrap().starts_with("message: "));
+    }
+
+    #[test]
+    fn server_events_are_tagged_in_snake_case() {
+        let event = ServerEvent::Leave(LeaveEvent { user_id: "u1".to_string() });
+        assert_eq!(event.to_json(), r#"{"type":"leave","user_id":"u1"}"#);
+        let error = ServerEvent::error(&ApiError::new(ErrorCode::RoomNotFound), Lang::Fr, Some("n1".to_string()));
+        let event: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+        assert_eq!(event["type"], "error");
+        assert_eq!(event["code"], "room_not_found");
+        assert_eq!(event["message"], ErrorCode::RoomNotFound.message(Lang::Fr));
+        assert_eq!(event["nonce"], "n1");
+    }
+}
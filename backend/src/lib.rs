@@ -1,14 +1,30 @@
 pub mod auth;
+pub mod bots;
+pub mod codec;
+pub mod commands;
 pub mod db;
+pub mod digest;
 pub mod discord_gateway;
+pub mod errors;
+pub mod events;
+pub mod mentions;
 pub mod messages;
+pub mod notification_settings;
+pub mod outgoing_webhooks;
+pub mod permessage_deflate;
+pub mod rate_limit;
+pub mod read_states;
 pub mod remote_auth;
 pub mod rooms;
+pub mod server_settings;
+pub mod sse;
+pub mod storage;
+pub mod upload_sweeper;
 pub mod uploads;
+pub mod webhooks;
 pub mod ws;
 
 use actix_cors::Cors;
-use actix_files::Files;
 use actix_web::{web, App, HttpResponse, HttpServer};
 
 /// Run the backend HTTP server. This function blocks until the server shuts down.
@@ -27,14 +43,22 @@ async fn start_server() -> std::io::Result<()> {
     let bind_addr = format!("0.0.0.0:{}", port);
 
     let pool = db::init_db().await;
+    let api_tokens = bots::load_api_tokens(&pool).await;
     let broadcaster = ws::create_broadcaster();
     let online_users = ws::create_online_users();
     let access_cache = ws::create_access_cache();
+    let ws_limits = rate_limit::WsLimits::from_env();
     let qr_sessions = remote_auth::create_qr_sessions();
     let discord_gateways = discord_gateway::create_discord_gateways();
-
-    // Ensure uploads directory exists
-    std::fs::create_dir_all("uploads").ok();
+    outgoing_webhooks::start_dispatcher(pool.clone(), &broadcaster);
+    let upload_storage = match storage::UploadStorage::from_env() {
+        Ok(storage) => storage,
+        Err(err) => {
+            eprintln!("❌ Upload storage is misconfigured: {}", err);
+            std::process::exit(1);
+        }
+    };
+    upload_sweeper::start_sweeper(pool.clone(), upload_storage.clone());
 
     println!("🚀 Backend running at http://{}", bind_addr);
 
@@ -48,14 +72,19 @@ async fn start_server() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(api_tokens.clone()))
             .app_data(web::Data::new(broadcaster.clone()))
             .app_data(web::Data::new(online_users.clone()))
             .app_data(web::Data::new(access_cache.clone()))
+            .app_data(web::Data::new(upload_storage.clone()))
+            .app_data(web::Data::new(ws_limits.clone()))
             .app_data(web::Data::new(qr_sessions.clone()))
             .app_data(web::Data::new(discord_gateways.clone()))
             .route("/api/health", web::get().to(|| async {
                 HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
             }))
+            .route("/api/protocol/schema", web::get().to(events::get_protocol_schema))
+            .route("/api/commands", web::get().to(commands::list_commands))
             // Auth
             .route("/api/register", web::post().to(auth::register))
             .route("/api/login", web::post().to(auth::login))
@@ -65,6 +94,16 @@ async fn start_server() -> std::io::Result<()> {
             .route("/api/auth/discord/qr/cancel", web::post().to(remote_auth::cancel_qr_session))
             .route("/api/users/me", web::get().to(auth::get_me))
             .route("/api/users/me", web::patch().to(auth::update_profile))
+            .route("/api/users/me/presence", web::put().to(sse::set_presence))
+            .route("/api/users/me/mentions", web::get().to(mentions::get_my_mentions))
+            .route("/api/users/me/read-states", web::get().to(read_states::get_my_read_states))
+            .route("/api/users/me/digest", web::get().to(digest::get_my_digest))
+            .route("/api/users/me/notification-settings", web::get().to(notification_settings::get_notification_settings))
+            .route("/api/users/me/notification-settings", web::patch().to(notification_settings::update_notification_settings))
+            .route(
+                "/api/users/me/notification-settings/rooms/{room_id}",
+                web::put().to(notification_settings::update_room_notification_settings),
+            )
             .route("/api/discord/me", web::get().to(auth::get_discord_me))
             .route("/api/discord/proxy", web::post().to(auth::discord_proxy))
             .route("/api/discord/voice/join", web::post().to(discord_gateway::voice_join))
@@ -75,15 +114,27 @@ async fn start_server() -> std::io::Result<()> {
             )
             .route("/api/users/{id}", web::delete().to(auth::delete_user))
             .route("/api/users/{id}/role", web::patch().to(auth::update_user_role))
+            .route("/api/users/{id}/upload-quota", web::patch().to(uploads::update_upload_quota))
             .route("/api/server/roles", web::get().to(auth::list_server_roles))
             .route("/api/server/roles", web::post().to(auth::create_server_role))
             .route("/api/server/roles/{name}", web::delete().to(auth::delete_server_role))
+            .route("/api/server/settings", web::get().to(server_settings::get_server_settings))
+            .route("/api/server/settings", web::patch().to(server_settings::update_server_settings))
             .route("/api/server/users", web::get().to(auth::list_server_users))
+            .route("/api/server/uploads/orphans", web::get().to(upload_sweeper::list_orphaned_uploads))
+            .route("/api/server/uploads/sweep", web::post().to(upload_sweeper::sweep_orphaned_uploads))
+            // Bots
+            .route("/api/bots", web::get().to(bots::list_bots))
+            .route("/api/bots", web::post().to(bots::create_bot))
+            .route("/api/bots/{id}/tokens", web::post().to(bots::create_api_token))
+            .route("/api/bots/{id}/tokens/{token_id}", web::delete().to(bots::revoke_api_token))
             // Rooms
             .route("/api/rooms", web::get().to(rooms::list_rooms))
             .route("/api/rooms", web::post().to(rooms::create_room))
             .route("/api/rooms/{id}", web::patch().to(rooms::update_room))
             .route("/api/rooms/{id}", web::delete().to(rooms::delete_room))
+            .route("/api/rooms/{id}/typing", web::post().to(rooms::send_typing))
+            .route("/api/rooms/{id}/ack", web::post().to(read_states::ack_room))
             // Messages
             .route("/api/messages/{id}", web::delete().to(messages::delete_message))
             .route("/api/messages/{id}/reactions", web::post().to(messages::add_reaction))
@@ -93,13 +144,28 @@ async fn start_server() -> std::io::Result<()> {
             .route("/api/messages/{id}/pin", web::delete().to(messages::unpin_message))
             .route("/api/users/{id}/messages", web::delete().to(messages::delete_user_messages))
             .route("/api/rooms/{room_id}/messages", web::get().to(messages::get_messages))
+            .route("/api/rooms/{room_id}/messages", web::post().to(messages::send_message))
             .route("/api/rooms/{room_id}/pins", web::get().to(messages::get_pinned_messages))
+            // Webhooks
+            .route("/api/rooms/{room_id}/webhooks", web::get().to(webhooks::list_webhooks))
+            .route("/api/rooms/{room_id}/webhooks", web::post().to(webhooks::create_webhook))
+            .route("/api/rooms/{room_id}/webhooks/{id}", web::delete().to(webhooks::delete_webhook))
+            .route("/api/rooms/{room_id}/webhooks/{id}/rotate", web::post().to(webhooks::rotate_webhook))
+            .route("/api/webhooks/{id}/{token}", web::post().to(webhooks::execute_webhook))
+            .route("/api/outgoing-webhooks", web::get().to(outgoing_webhooks::list_outgoing_webhooks))
+            .route("/api/outgoing-webhooks", web::post().to(outgoing_webhooks::create_outgoing_webhook))
+            .route("/api/outgoing-webhooks/{id}", web::patch().to(outgoing_webhooks::update_outgoing_webhook))
+            .route("/api/outgoing-webhooks/{id}", web::delete().to(outgoing_webhooks::delete_outgoing_webhook))
+            .route("/api/outgoing-webhooks/{id}/rotate", web::post().to(outgoing_webhooks::rotate_outgoing_webhook))
+            .route("/api/outgoing-webhooks/{id}/deliveries", web::get().to(outgoing_webhooks::list_deliveries))
             // Uploads
             .route("/api/upload", web::post().to(uploads::upload_image))
             // Serve uploaded files
-            .service(Files::new("/uploads", "uploads"))
-            // WebSocket
+            .route("/uploads/{filename}", web::get().to(uploads::serve_upload))
+            .route("/uploads/{id}/thumb", web::get().to(uploads::serve_thumbnail))
+            // WebSocket, and its server-sent events fallback
             .route("/ws", web::get().to(ws::ws_handler))
+            .route("/api/events", web::get().to(sse::events_stream))
     })
     .bind(&bind_addr)?
     .run()
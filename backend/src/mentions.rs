@@ -0,0 +1,326 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{Acquire, Executor, Row, Sqlite, SqlitePool};
+use std::collections::HashMap;
+
+use crate::auth::extract_claims;
+use crate::bots::Scope;
+use crate::errors::ErrorCode;
+use crate::events::{self, MentionEvent, ServerEvent};
+use crate::messages::{enrich_messages, message_from_row, Message, STORED_MESSAGE_COLUMNS};
+use crate::notification_settings::filter_mention_recipients;
+use crate::ws::Broadcaster;
+
+/// Distinct users and roles one message may mention; any beyond stay plain text.
+const MAX_MENTIONS: usize = 50;
+
+/// Ids of messages mentioning a user; bind the user id, then their role.
+pub(crate) const MENTIONING_MESSAGES_SQL: &str = "SELECT message_id FROM message_mentions \
+     WHERE (kind = 'user' AND target = ?) OR (kind = 'role' AND target = ?) OR kind = 'everyone'";
+
+/// How a message reached one of the users it mentions.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct MentionReason {
+    pub direct: bool,
+    pub role: bool,
+    pub everyone: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ParsedMentions {
+    pub users: Vec<String>,
+    pub roles: Vec<String>,
+    pub everyone: bool,
+}
+
+fn is_mention_target(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Find `<@user_id>`, `<@&role>` and `@everyone` in message content.
+pub(crate) fn parse_mentions(content: &str) -> ParsedMentions {
+    let mut parsed = ParsedMentions::default();
+    let mut rest = content;
+    while let Some(start) = rest.find("<@") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('>') else {
+            break;
+        };
+        let inner = &after[..end];
+        let is_role = inner.starts_with('&');
+        let target = if is_role { inner[1..].to_lowercase() } else { inner.to_string() };
+        if !is_mention_target(&target) {
+            rest = after;
+            continue;
+        }
+        let known = if is_role { &parsed.roles } else { &parsed.users };
+        if !known.contains(&target) && parsed.users.len() + parsed.roles.len() < MAX_MENTIONS {
+            if is_role {
+                parsed.roles.push(target);
+            } else {
+                parsed.users.push(target);
+            }
+        }
+        rest = &after[end + 1..];
+    }
+
+    parsed.everyone = content
+        .split(|c: char| !(c.is_alphanumeric() || c == '@' || c == '_'))
+        .any(|word| word == "@everyone");
+    parsed
+}
+
+/// Store the mentions of a new message. Role and `@everyone` mentions only count when
+/// `can_mention_roles`; unknown users and roles stay plain text.
+pub(crate) async fn record_mentions(conn: impl Acquire<'_, Database = Sqlite>, message_id: &str, content: &str, can_mention_roles: bool) {
+    let Ok(mut conn) = conn.acquire().await else {
+        return;
+    };
+    let parsed = parse_mentions(content);
+
+    for user_id in &parsed.users {
+        let _ = sqlx::query(
+            "INSERT OR IGNORE INTO message_mentions (message_id, kind, target) SELECT ?, 'user', id FROM users WHERE id = ?",
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await;
+    }
+
+    if !can_mention_roles {
+        return;
+    }
+
+    for role in &parsed.roles {
+        let _ = sqlx::query(
+            "INSERT OR IGNORE INTO message_mentions (message_id, kind, target) SELECT ?, 'role', name FROM roles WHERE name = ?",
+        )
+        .bind(message_id)
+        .bind(role)
+        .execute(&mut *conn)
+        .await;
+    }
+
+    if parsed.everyone {
+        let _ = sqlx::query("INSERT OR IGNORE INTO message_mentions (message_id, kind, target) VALUES (?, 'everyone', '')")
+            .bind(message_id)
+            .execute(&mut *conn)
+            .await;
+    }
+}
+
+pub(crate) async fn enrich_messages_with_mentions<'e>(executor: impl Executor<'e, Database = Sqlite>, messages: &mut [Message]) {
+    if messages.is_empty() {
+        return;
+    }
+
+    let mut query = String::from("SELECT message_id, kind, target FROM message_mentions WHERE message_id IN (");
+    for idx in 0..messages.len() {
+        if idx > 0 {
+            query.push(',');
+        }
+        query.push('?');
+    }
+    query.push(')');
+
+    let mut qx = sqlx::query(&query);
+    for message in messages.iter() {
+        qx = qx.bind(&message.id);
+    }
+
+    let rows = qx.fetch_all(executor).await.unwrap_or_default();
+    let mut per_message: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for row in rows {
+        let message_id: String = row.try_get("message_id").unwrap_or_default();
+        let kind: String = row.try_get("kind").unwrap_or_default();
+        let target: String = row.try_get("target").unwrap_or_default();
+        per_message.entry(message_id).or_default().push((kind, target));
+    }
+
+    for message in messages.iter_mut() {
+        let Some(mentions) = per_message.remove(&message.id) else {
+            continue;
+        };
+        for (kind, target) in mentions {
+            match kind.as_str() {
+                "user" => message.mentions.push(target),
+                "role" => message.mention_roles.push(target),
+                "everyone" => message.mention_everyone = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Send a `mention` event to everyone a new message mentions, except its author
+/// and users whose notification settings silence the room.
+pub(crate) async fn notify_mentions(pool: &SqlitePool, broadcaster: &Broadcaster, message: &Message) {
+    if message.mentions.is_empty() && message.mention_roles.is_empty() && !message.mention_everyone {
+        return;
+    }
+
+    let mut reasons: HashMap<String, MentionReason> = HashMap::new();
+    if message.mention_everyone {
+        let everyone: Vec<String> = sqlx::query_scalar("SELECT id FROM users")
+            .fetch_all(pool)
+            .await
+            .unwrap_or_default();
+        for user_id in everyone {
+            reasons.entry(user_id).or_default().everyone = true;
+        }
+    }
+    for role in &message.mention_roles {
+        let members: Vec<String> = sqlx::query_scalar("SELECT id FROM users WHERE role = ?")
+            .bind(role)
+            .fetch_all(pool)
+            .await
+            .unwrap_or_default();
+        for user_id in members {
+            reasons.entry(user_id).or_default().role = true;
+        }
+    }
+    for user_id in &message.mentions {
+        reasons.entry(user_id.clone()).or_default().direct = true;
+    }
+
+    reasons.remove(&message.user_id);
+    let recipients = filter_mention_recipients(pool, &message.room_id, reasons).await;
+    if recipients.is_empty() {
+        return;
+    }
+
+    let mut event = message.to_event();
+    event.nonce = None;
+    // Room access still applies, so users who cannot see the room are never told
+    events::broadcast_to(broadcaster, &ServerEvent::Mention(MentionEvent { message: event }), recipients);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MentionsQuery {
+    /// `created_at` of the oldest mention already loaded.
+    pub before: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// GET /api/users/me/mentions — Messages mentioning the caller, newest first
+pub async fn get_my_mentions(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    query: web::Query<MentionsQuery>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    let sql = format!(
+        "{} WHERE m.id IN ({}) \
+         AND m.user_id != ? \
+         AND (? = 'admin' OR m.room_id IN (SELECT id FROM rooms WHERE required_role = 'user' OR required_role = ?)) \
+         AND (? IS NULL OR m.created_at < ?) \
+         ORDER BY m.created_at DESC LIMIT ?",
+        STORED_MESSAGE_COLUMNS, MENTIONING_MESSAGES_SQL
+    );
+
+    let rows = sqlx::query(&sql)
+        .bind(&claims.sub)
+        .bind(&claims.role)
+        .bind(&claims.sub)
+        .bind(&claims.role)
+        .bind(&claims.role)
+        .bind(&query.before)
+        .bind(&query.before)
+        .bind(limit)
+        .fetch_all(pool.get_ref())
+        .await
+        .unwrap_or_default();
+
+    let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
+    // The stored nonce is only meaningful to the author
+    for message in messages.iter_mut() {
+        message.nonce = None;
+    }
+    enrich_messages(pool.get_ref(), &mut messages).await;
+
+    HttpResponse::Ok().json(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_users_roles_and_everyone() {
+        let parsed = parse_mentions("hi <@u-1> and <@&Mods>, @everyone!");
+        assert_eq!(parsed.users, vec!["u-1"]);
+        assert_eq!(parsed.roles, vec!["mods"]);
+        assert!(parsed.everyone);
+    }
+
+    #[test]
+    fn repeated_mentions_count_once() {
+        let parsed = parse_mentions("<@u1> <@u1> <@&mods> <@&MODS>");
+        assert_eq!(parsed.users, vec!["u1"]);
+        assert_eq!(parsed.roles, vec!["mods"]);
+    }
+
+    #[test]
+    fn malformed_mentions_stay_text() {
+        let parsed = parse_mentions("<@> <@&> <@a b> <@x'y> <@unclosed");
+        assert!(parsed.users.is_empty());
+        assert!(parsed.roles.is_empty());
+        assert!(!parse_mentions("mail me at team@everyone.example").everyone);
+        assert!(!parse_mentions("@everyones").everyone);
+    }
+
+    #[test]
+    fn mentions_are_capped() {
+        let content: String = (0..MAX_MENTIONS + 10).map(|i| format!("<@u{}> ", i)).collect();
+        assert_eq!(parse_mentions(&content).users.len(), MAX_MENTIONS);
+    }
+
+    async fn mention_pool() -> SqlitePool {
+        let pool = crate::db::test_pool().await;
+        for sql in [
+            "INSERT INTO users (id, username, password_hash) VALUES ('alice', 'alice', '')",
+            "INSERT INTO roles (name) VALUES ('mods')",
+            "INSERT INTO messages (id, room_id, user_id, username, content) VALUES ('m1', 'general', 'alice', 'alice', '')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    async fn stored_mentions(pool: &SqlitePool) -> Vec<(String, String)> {
+        sqlx::query_as("SELECT kind, target FROM message_mentions WHERE message_id = 'm1' ORDER BY kind, target")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn roles_and_everyone_need_permission() {
+        let pool = mention_pool().await;
+        record_mentions(&pool, "m1", "<@alice> <@bob> <@&mods> @everyone", false).await;
+        assert_eq!(stored_mentions(&pool).await, vec![("user".to_string(), "alice".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn permitted_authors_reach_roles_and_everyone() {
+        let pool = mention_pool().await;
+        record_mentions(&pool, "m1", "<@alice> <@&mods> <@&ghosts> @everyone", true).await;
+        assert_eq!(
+            stored_mentions(&pool).await,
+            vec![
+                ("everyone".to_string(), String::new()),
+                ("role".to_string(), "mods".to_string()),
+                ("user".to_string(), "alice".to_string()),
+            ]
+        );
+    }
+}
@@ -2,9 +2,23 @@ use actix_web::{web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use sqlx::sqlite::SqliteRow;
-use sqlx::SqlitePool;
+use sqlx::{Acquire, Sqlite, SqlitePool};
 use sqlx::Row;
 use crate::auth::extract_claims;
+use crate::bots::Scope;
+use crate::commands::{self, CommandContext, CommandOutcome};
+use crate::errors::ErrorCode;
+use crate::events::{
+    self, MessageDeletedEvent, MessagePinnedEvent, MessageReactionUpdatedEvent, MessageUnpinnedEvent,
+    Attachment, AttachmentInput, ChatMessageEvent, Embed, MessagesPurgedEvent, SendMessagePayload, ServerEvent,
+};
+use crate::mentions::{enrich_messages_with_mentions, notify_mentions, record_mentions};
+use crate::outgoing_webhooks;
+use crate::rate_limit::WsLimits;
+use crate::storage::UploadStorage;
+use crate::uploads::{check_attachments, delete_message_attachments, enrich_messages_with_attachments, insert_attachments};
+use crate::ws::{can_user_access_room_cached, get_user_role_cached, AccessCache, Broadcaster};
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MessageReaction {
@@ -26,11 +40,54 @@ pub struct Message {
     pub pinned_at: Option<String>,
     pub pinned_by: Option<String>,
     pub avatar_url: Option<String>,
+    pub avatar_color: Option<i32>,
+    #[serde(default)]
+    pub is_bot: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub embeds: Vec<Embed>,
     #[serde(default)]
     pub reactions: Vec<MessageReaction>,
+    #[serde(default)]
+    pub mentions: Vec<String>,
+    #[serde(default)]
+    pub mention_roles: Vec<String>,
+    #[serde(default)]
+    pub mention_everyone: bool,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// Set when the message was just sent, so its author can match it to their request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+impl Message {
+    pub(crate) fn to_event(&self) -> ChatMessageEvent {
+        ChatMessageEvent {
+            id: self.id.clone(),
+            room_id: self.room_id.clone(),
+            user_id: self.user_id.clone(),
+            username: self.username.clone(),
+            content: self.content.clone(),
+            reply_to_id: self.reply_to_id.clone(),
+            image_url: self.image_url.clone(),
+            avatar_color: self.avatar_color,
+            avatar_url: self.avatar_url.clone(),
+            created_at: self.created_at.clone(),
+            is_bot: self.is_bot,
+            webhook_id: self.webhook_id.clone(),
+            embeds: self.embeds.clone(),
+            mentions: self.mentions.clone(),
+            mention_roles: self.mention_roles.clone(),
+            mention_everyone: self.mention_everyone,
+            attachments: self.attachments.clone(),
+            nonce: self.nonce.clone(),
+        }
+    }
 }
 
-fn message_from_row(row: &SqliteRow) -> Message {
+pub(crate) fn message_from_row(row: &SqliteRow) -> Message {
     Message {
         id: row.try_get("id").unwrap_or_default(),
         room_id: row.try_get("room_id").unwrap_or_default(),
@@ -43,10 +100,177 @@ fn message_from_row(row: &SqliteRow) -> Message {
         pinned_at: row.try_get("pinned_at").unwrap_or(None),
         pinned_by: row.try_get("pinned_by").unwrap_or(None),
         avatar_url: row.try_get("avatar_url").unwrap_or(None),
+        avatar_color: row.try_get("avatar_color").unwrap_or(None),
+        is_bot: row.try_get("is_bot").unwrap_or(false),
+        webhook_id: row.try_get("webhook_id").unwrap_or(None),
+        embeds: row
+            .try_get::<Option<String>, _>("embeds")
+            .unwrap_or(None)
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
         reactions: Vec::new(),
+        mentions: Vec::new(),
+        mention_roles: Vec::new(),
+        mention_everyone: false,
+        attachments: Vec::new(),
+        nonce: row.try_get("nonce").unwrap_or(None),
     }
 }
 
+/// Outcome of storing a message from a `message` frame or the REST endpoint.
+pub(crate) enum StoredMessage {
+    Created(Message),
+    /// The client already sent this nonce; the original message is returned instead.
+    Duplicate(Message),
+}
+
+const MAX_NONCE_LEN: usize = 64;
+
+pub(crate) const STORED_MESSAGE_COLUMNS: &str = "SELECT m.id, m.room_id, m.user_id, m.username, m.content, m.reply_to_id, m.created_at, m.image_url, m.pinned_at, m.pinned_by, m.nonce, COALESCE(m.author_avatar_url, u.avatar_url) AS avatar_url, u.avatar_color, u.is_bot, m.webhook_id, m.embeds \
+     FROM messages m LEFT JOIN users u ON m.user_id = u.id";
+
+async fn find_message_by_nonce(pool: &SqlitePool, user_id: &str, nonce: &str) -> Option<Message> {
+    let row = sqlx::query(&format!("{} WHERE m.user_id = ? AND m.nonce = ?", STORED_MESSAGE_COLUMNS))
+        .bind(user_id)
+        .bind(nonce)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)?;
+
+    let mut messages = vec![message_from_row(&row)];
+    enrich_messages(pool, &mut messages).await;
+    messages.pop()
+}
+
+/// Validate and insert a message; the single path used by /ws and the REST endpoint.
+/// `message.user_id` must be the authenticated user; `message.username` is ignored in favour
+/// of the stored one. Nothing should be broadcast unless this returns `Created`.
+pub(crate) async fn create_message(
+    pool: &SqlitePool,
+    access_cache: &AccessCache,
+    message: SendMessagePayload,
+    max_message_chars: usize,
+) -> Result<StoredMessage, ErrorCode> {
+    if let Some(nonce) = &message.nonce {
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(ErrorCode::InvalidNonce);
+        }
+    }
+
+    if !can_user_access_room_cached(pool, access_cache, &message.user_id, &message.room_id).await {
+        return Err(ErrorCode::RoomAccessDenied);
+    }
+
+    // The stored name is the author's, whatever the client put in the payload
+    let author: Option<(String, Option<i64>)> = sqlx::query_as("SELECT username, timeout_until FROM users WHERE id = ?")
+        .bind(&message.user_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None);
+    let Some((username, timeout_until)) = author else {
+        return Err(ErrorCode::UserNotFound);
+    };
+    if timeout_until.is_some_and(|until| until > chrono::Utc::now().timestamp_millis()) {
+        return Err(ErrorCode::TimedOut);
+    }
+
+    // Retries get the same access and timeout errors as a first attempt
+    if let Some(nonce) = &message.nonce {
+        if let Some(existing) = find_message_by_nonce(pool, &message.user_id, nonce).await {
+            return Ok(StoredMessage::Duplicate(existing));
+        }
+    }
+
+    let attachments = check_attachments(pool, &message.user_id, message.image_url.as_deref(), &message.attachments).await?;
+    if message.content.trim().is_empty() && attachments.is_empty() {
+        return Err(ErrorCode::EmptyMessage);
+    }
+    if message.content.chars().count() > max_message_chars {
+        return Err(ErrorCode::MessageTooLong);
+    }
+
+    // Replies must point at a message of the same room
+    if let Some(reply_to_id) = message.reply_to_id.as_deref().filter(|id| !id.is_empty()) {
+        let reply_room: Option<String> = sqlx::query_scalar("SELECT room_id FROM messages WHERE id = ?")
+            .bind(reply_to_id)
+            .fetch_optional(pool)
+            .await
+            .unwrap_or(None);
+        if reply_room.as_deref() != Some(message.room_id.as_str()) {
+            return Err(ErrorCode::InvalidReply);
+        }
+    }
+
+    let author_role = get_user_role_cached(pool, access_cache, &message.user_id).await;
+    let msg_id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+
+    let Ok(mut tx) = pool.begin().await else {
+        return Err(ErrorCode::MessageNotStored);
+    };
+    let result = sqlx::query(
+        "INSERT INTO messages (id, room_id, user_id, username, content, created_at, reply_to_id, nonce) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&msg_id)
+    .bind(&message.room_id)
+    .bind(&message.user_id)
+    .bind(&username)
+    .bind(&message.content)
+    .bind(&now)
+    .bind(message.reply_to_id.as_deref().filter(|id| !id.is_empty()))
+    .bind(&message.nonce)
+    .execute(&mut *tx)
+    .await;
+
+    if let Err(e) = result {
+        drop(tx);
+        // A concurrent retry with the same nonce may have won the unique index
+        if let Some(nonce) = &message.nonce {
+            if let Some(existing) = find_message_by_nonce(pool, &message.user_id, nonce).await {
+                return Ok(StoredMessage::Duplicate(existing));
+            }
+        }
+        eprintln!("Message insert error: {:?}", e);
+        return Err(ErrorCode::MessageNotStored);
+    }
+
+    // A concurrent message may have taken one of the uploads since they were checked
+    if insert_attachments(&mut *tx, &msg_id, &attachments).await.is_err() {
+        return Err(ErrorCode::InvalidAttachment);
+    }
+
+    record_mentions(&mut *tx, &msg_id, &message.content, author_role.as_deref() == Some("admin")).await;
+
+    let Some(message) = fetch_stored_message(&mut *tx, &msg_id).await else {
+        return Err(ErrorCode::MessageNotStored);
+    };
+    let queued = outgoing_webhooks::enqueue(&mut tx, &ServerEvent::Message(message.to_event())).await;
+    if queued.is_err() || tx.commit().await.is_err() {
+        return Err(ErrorCode::MessageNotStored);
+    }
+    Ok(StoredMessage::Created(message))
+}
+
+/// Read back a freshly inserted message in the shape used by history and broadcasts.
+pub(crate) async fn fetch_stored_message(conn: impl Acquire<'_, Database = Sqlite>, message_id: &str) -> Option<Message> {
+    let mut conn = conn.acquire().await.ok()?;
+    let row = sqlx::query(&format!("{} WHERE m.id = ?", STORED_MESSAGE_COLUMNS))
+        .bind(message_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .unwrap_or(None)?;
+    let mut messages = vec![message_from_row(&row)];
+    enrich_messages_with_mentions(&mut *conn, &mut messages).await;
+    enrich_messages_with_attachments(&mut *conn, &mut messages).await;
+    messages.pop()
+}
+
+/// Broadcast a newly stored message, then notify the users it mentions.
+pub(crate) async fn broadcast_message(pool: &SqlitePool, broadcaster: &Broadcaster, message: &Message) {
+    events::broadcast(broadcaster, &ServerEvent::Message(message.to_event()));
+    notify_mentions(pool, broadcaster, message).await;
+}
+
 #[derive(Debug, Deserialize)]
 pub struct SearchQuery {
     pub q: Option<String>,
@@ -57,6 +281,17 @@ pub struct SearchQuery {
     pub limit: Option<i64>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SendMessageInput {
+    #[serde(default)]
+    pub content: String,
+    pub reply_to_id: Option<String>,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentInput>,
+    pub nonce: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ReactionInput {
     pub emoji: String,
@@ -77,6 +312,13 @@ fn normalize_emoji(raw: &str) -> Option<String> {
     Some(trimmed.to_string())
 }
 
+/// Attach reactions, mentions and attachments to messages loaded from the database.
+pub(crate) async fn enrich_messages(pool: &SqlitePool, messages: &mut [Message]) {
+    enrich_messages_with_reactions(pool, messages).await;
+    enrich_messages_with_mentions(pool, messages).await;
+    enrich_messages_with_attachments(pool, messages).await;
+}
+
 async fn enrich_messages_with_reactions(pool: &SqlitePool, messages: &mut [Message]) {
     if messages.is_empty() {
         return;
@@ -164,8 +406,11 @@ pub async fn get_messages(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
 
     let room_id = path.into_inner();
 
@@ -176,51 +421,113 @@ pub async fn get_messages(
         .unwrap_or(None);
 
     let Some(required_role) = room_role else {
-        return HttpResponse::NotFound().json(serde_json::json!({ "error": "Room not found" }));
+        return ErrorCode::RoomNotFound.respond(&req);
     };
 
     if required_role != "user" && claims.role != "admin" && claims.role != required_role {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Access denied for this room" }));
+        return ErrorCode::RoomAccessDenied.respond(&req);
     }
 
-    let rows = sqlx::query(
-        "SELECT m.id, m.room_id, m.user_id, m.username, m.content, m.reply_to_id, m.created_at, m.image_url, m.pinned_at, m.pinned_by, u.avatar_url \
-         FROM messages m LEFT JOIN users u ON m.user_id = u.id \
-         WHERE m.room_id = ? ORDER BY m.created_at ASC LIMIT 200"
-    )
-    .bind(&room_id)
-    .fetch_all(pool.get_ref())
-    .await
-    .unwrap_or_default();
+    let rows = sqlx::query(&format!("{} WHERE m.room_id = ? ORDER BY m.created_at ASC LIMIT 200", STORED_MESSAGE_COLUMNS))
+        .bind(&room_id)
+        .fetch_all(pool.get_ref())
+        .await
+        .unwrap_or_default();
 
     let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
 
-    enrich_messages_with_reactions(pool.get_ref(), &mut messages).await;
+    enrich_messages(pool.get_ref(), &mut messages).await;
 
     HttpResponse::Ok().json(messages)
 }
 
+/// POST /api/rooms/{room_id}/messages — Post a message, same as a `message` frame on /ws
+#[allow(clippy::too_many_arguments)]
+pub async fn send_message(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: web::Json<SendMessageInput>,
+    broadcaster: web::Data<Broadcaster>,
+    access_cache: web::Data<AccessCache>,
+    limits: web::Data<WsLimits>,
+    storage: web::Data<UploadStorage>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
+    }
+
+    let input = body.into_inner();
+    let payload = SendMessagePayload {
+        room_id: path.into_inner(),
+        user_id: claims.sub.clone(),
+        username: claims.username.clone(),
+        content: input.content,
+        reply_to_id: input.reply_to_id,
+        image_url: input.image_url,
+        attachments: input.attachments,
+        avatar_color: None,
+        nonce: input.nonce,
+    };
+
+    if let Some((name, args)) = commands::parse_invocation(&payload.content) {
+        let ctx = CommandContext {
+            pool: pool.get_ref(),
+            broadcaster: broadcaster.get_ref(),
+            access_cache: access_cache.get_ref(),
+            storage: storage.get_ref(),
+            max_message_chars: limits.max_message_chars,
+        };
+        return match commands::run(&ctx, &name, &args, payload, Some(&claims)).await {
+            Ok(CommandOutcome::Ephemeral(event)) => HttpResponse::Ok().json(event),
+            Ok(CommandOutcome::Posted(stored)) => stored_response(pool.get_ref(), &broadcaster, *stored).await,
+            Err(err) => err.respond(&req),
+        };
+    }
+
+    match create_message(pool.get_ref(), access_cache.get_ref(), payload, limits.max_message_chars).await {
+        Ok(stored) => stored_response(pool.get_ref(), &broadcaster, stored).await,
+        Err(code) => code.respond(&req),
+    }
+}
+
+async fn stored_response(pool: &SqlitePool, broadcaster: &Broadcaster, stored: StoredMessage) -> HttpResponse {
+    match stored {
+        StoredMessage::Created(message) => {
+            broadcast_message(pool, broadcaster, &message).await;
+            HttpResponse::Created().json(message)
+        }
+        // Retried nonce: the original message, not broadcast again
+        StoredMessage::Duplicate(message) => HttpResponse::Ok().json(message),
+    }
+}
+
 /// DELETE /api/messages/{id}
 pub async fn delete_message(
     req: actix_web::HttpRequest,
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
     broadcaster: web::Data<crate::ws::Broadcaster>,
+    storage: web::Data<UploadStorage>,
 ) -> HttpResponse {
     use crate::auth::extract_claims;
 
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
+    }
 
     let message_id = path.into_inner();
 
     // 1. Fetch message to check ownership and get room_id
-    let msg_row = sqlx::query(
-        "SELECT m.id, m.room_id, m.user_id, m.username, m.content, m.reply_to_id, m.created_at, m.image_url, m.pinned_at, m.pinned_by, u.avatar_url \
-         FROM messages m LEFT JOIN users u ON m.user_id = u.id WHERE m.id = ?"
-    )
+    let msg_row = sqlx::query(&format!("{} WHERE m.id = ?", STORED_MESSAGE_COLUMNS))
         .bind(&message_id)
         .fetch_optional(pool.get_ref())
         .await
@@ -228,40 +535,90 @@ pub async fn delete_message(
 
     let msg = match msg_row {
         Some(row) => message_from_row(&row),
-        None => return HttpResponse::NotFound().json(serde_json::json!({ "error": "Message not found" })),
+        None => return ErrorCode::MessageNotFound.respond(&req),
     };
 
     // 2. Check permissions
     if msg.user_id != claims.sub && claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "You can only delete your own messages" }));
+        return ErrorCode::NotMessageOwner.respond(&req);
     }
 
-    // 3. Delete uploaded image if any
-    if let Some(ref url) = msg.image_url {
-        let path = url.trim_start_matches('/');
-        std::fs::remove_file(path).ok();
-    }
+    remove_stored_message(pool.get_ref(), broadcaster.get_ref(), storage.get_ref(), &msg).await;
 
-    // 4. Delete related reactions + message from DB
-    let _ = sqlx::query("DELETE FROM message_reactions WHERE message_id = ?")
-        .bind(&message_id)
-        .execute(pool.get_ref())
-        .await;
+    HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
+}
 
-    let _ = sqlx::query("DELETE FROM messages WHERE id = ?")
-        .bind(&message_id)
-        .execute(pool.get_ref())
-        .await;
+/// Whether the stored file `key` is referenced by anything but the message `message_id`.
+async fn legacy_image_in_use(pool: &SqlitePool, key: &str, message_id: &str) -> bool {
+    sqlx::query(
+        "SELECT 1 FROM upload_blobs WHERE path = ?1 \
+         UNION ALL SELECT 1 FROM users WHERE avatar_url LIKE '%/uploads/' || ?1 OR banner_url LIKE '%/uploads/' || ?1 \
+         UNION ALL SELECT 1 FROM webhooks WHERE avatar_url LIKE '%/uploads/' || ?1 \
+         UNION ALL SELECT 1 FROM messages WHERE image_url = '/uploads/' || ?1 AND id != ?2 \
+         LIMIT 1",
+    )
+    .bind(key)
+    .bind(message_id)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None)
+    .is_some()
+}
 
-    // 5. Broadcast
-    let event = serde_json::json!({
-        "type": "message_deleted",
-        "id": message_id,
-        "room_id": msg.room_id
+/// Delete a message with its images, attachments and reactions, then broadcast it;
+/// shared by the delete endpoint and `/purge`.
+pub(crate) async fn remove_stored_message(
+    pool: &SqlitePool,
+    broadcaster: &crate::ws::Broadcaster,
+    storage: &UploadStorage,
+    msg: &Message,
+) {
+    let event = ServerEvent::MessageDeleted(MessageDeletedEvent {
+        id: msg.id.clone(),
+        room_id: msg.room_id.clone(),
     });
-    let _ = broadcaster.send(event.to_string());
+    delete_stored_message(pool, storage, &msg.id, msg.image_url.as_deref(), Some(&event)).await;
+    events::broadcast(broadcaster, &event);
+}
 
-    HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
+/// Delete a message with its images, attachments and reactions, without broadcasting.
+/// `event` is queued for outgoing webhooks in the same write. Returns whether the message was still there.
+async fn delete_stored_message(
+    pool: &SqlitePool,
+    storage: &UploadStorage,
+    message_id: &str,
+    image_url: Option<&str>,
+    event: Option<&ServerEvent>,
+) -> bool {
+    // Delete the legacy uploaded image, unless the file is still in use: shared blobs go with
+    // their last upload, and anything else left over is for the sweeper
+    if let Some(key) = image_url.and_then(|url| url.strip_prefix("/uploads/")) {
+        if !legacy_image_in_use(pool, key, message_id).await {
+            storage.delete(key).await.ok();
+        }
+    }
+    delete_message_attachments(pool, storage, message_id).await;
+
+    // Delete related reactions + message from DB
+    let Ok(mut tx) = pool.begin().await else {
+        return false;
+    };
+    let _ = sqlx::query("DELETE FROM message_reactions WHERE message_id = ?")
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await;
+
+    let deleted = sqlx::query("DELETE FROM messages WHERE id = ?")
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await
+        .is_ok_and(|res| res.rows_affected() > 0);
+    if let (true, Some(event)) = (deleted, event) {
+        if outgoing_webhooks::enqueue(&mut tx, event).await.is_err() {
+            return false;
+        }
+    }
+    tx.commit().await.is_ok() && deleted
 }
 
 /// GET /api/rooms/{room_id}/pins — List pinned messages
@@ -272,8 +629,11 @@ pub async fn get_pinned_messages(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
 
     let room_id = path.into_inner();
 
@@ -284,25 +644,21 @@ pub async fn get_pinned_messages(
         .unwrap_or(None);
 
     let Some(required_role) = room_role else {
-        return HttpResponse::NotFound().json(serde_json::json!({ "error": "Room not found" }));
+        return ErrorCode::RoomNotFound.respond(&req);
     };
     if required_role != "user" && claims.role != "admin" && claims.role != required_role {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Access denied for this room" }));
+        return ErrorCode::RoomAccessDenied.respond(&req);
     }
 
-    let rows = sqlx::query(
-        "SELECT m.id, m.room_id, m.user_id, m.username, m.content, m.reply_to_id, m.created_at, m.image_url, m.pinned_at, m.pinned_by, u.avatar_url \
-         FROM messages m LEFT JOIN users u ON m.user_id = u.id \
-         WHERE m.room_id = ? AND m.pinned_at IS NOT NULL ORDER BY m.pinned_at DESC LIMIT 50"
-    )
-    .bind(&room_id)
-    .fetch_all(pool.get_ref())
-    .await
-    .unwrap_or_default();
+    let rows = sqlx::query(&format!("{} WHERE m.room_id = ? AND m.pinned_at IS NOT NULL ORDER BY m.pinned_at DESC LIMIT 50", STORED_MESSAGE_COLUMNS))
+        .bind(&room_id)
+        .fetch_all(pool.get_ref())
+        .await
+        .unwrap_or_default();
 
     let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
 
-    enrich_messages_with_reactions(pool.get_ref(), &mut messages).await;
+    enrich_messages(pool.get_ref(), &mut messages).await;
 
     HttpResponse::Ok().json(messages)
 }
@@ -317,19 +673,25 @@ pub async fn add_reaction(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
+    }
 
     let message_id = path.into_inner();
     let Some(emoji) = normalize_emoji(&body.emoji) else {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid emoji" }));
+        return ErrorCode::InvalidEmoji.respond(&req);
     };
 
     let Some(room_id) = can_access_message_room(pool.get_ref(), &message_id, &claims.role).await else {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Access denied" }));
+        return ErrorCode::AccessDenied.respond(&req);
     };
 
     let now = chrono::Utc::now().to_rfc3339();
+    let Ok(mut tx) = pool.begin().await else {
+        return ErrorCode::InternalError.respond(&req);
+    };
     let _ = sqlx::query(
         "INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, created_at) VALUES (?, ?, ?, ?)"
     )
@@ -337,29 +699,16 @@ pub async fn add_reaction(
     .bind(&claims.sub)
     .bind(&emoji)
     .bind(&now)
-    .execute(pool.get_ref())
+    .execute(&mut *tx)
     .await;
 
-    let reaction_users = sqlx::query_scalar::<_, String>(
-        "SELECT user_id FROM message_reactions WHERE message_id = ? AND emoji = ? ORDER BY created_at ASC"
-    )
-    .bind(&message_id)
-    .bind(&emoji)
-    .fetch_all(pool.get_ref())
-    .await
-    .unwrap_or_default();
-
-    let event = serde_json::json!({
-        "type": "message_reaction_updated",
-        "room_id": room_id,
-        "message_id": message_id,
-        "emoji": emoji,
-        "count": reaction_users.len(),
-        "user_ids": reaction_users,
-    });
-    let _ = broadcaster.send(event.to_string());
-
-    HttpResponse::Ok().json(event)
+    match reaction_updated(tx, room_id, message_id, emoji).await {
+        Ok(event) => {
+            events::broadcast(broadcaster.get_ref(), &event);
+            HttpResponse::Ok().json(event)
+        }
+        Err(_) => ErrorCode::InternalError.respond(&req),
+    }
 }
 
 /// DELETE /api/messages/{id}/reactions
@@ -372,45 +721,66 @@ pub async fn remove_reaction(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
+    }
 
     let message_id = path.into_inner();
     let Some(emoji) = normalize_emoji(&body.emoji) else {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid emoji" }));
+        return ErrorCode::InvalidEmoji.respond(&req);
     };
 
     let Some(room_id) = can_access_message_room(pool.get_ref(), &message_id, &claims.role).await else {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Access denied" }));
+        return ErrorCode::AccessDenied.respond(&req);
     };
 
+    let Ok(mut tx) = pool.begin().await else {
+        return ErrorCode::InternalError.respond(&req);
+    };
     let _ = sqlx::query("DELETE FROM message_reactions WHERE message_id = ? AND user_id = ? AND emoji = ?")
         .bind(&message_id)
         .bind(&claims.sub)
         .bind(&emoji)
-        .execute(pool.get_ref())
+        .execute(&mut *tx)
         .await;
 
+    match reaction_updated(tx, room_id, message_id, emoji).await {
+        Ok(event) => {
+            events::broadcast(broadcaster.get_ref(), &event);
+            HttpResponse::Ok().json(event)
+        }
+        Err(_) => ErrorCode::InternalError.respond(&req),
+    }
+}
+
+/// Finish a reaction change: read who now reacts with `emoji`, queue the event for
+/// outgoing webhooks and commit. Returns the event to broadcast.
+async fn reaction_updated(
+    mut tx: sqlx::Transaction<'_, Sqlite>,
+    room_id: String,
+    message_id: String,
+    emoji: String,
+) -> Result<ServerEvent, sqlx::Error> {
     let reaction_users = sqlx::query_scalar::<_, String>(
         "SELECT user_id FROM message_reactions WHERE message_id = ? AND emoji = ? ORDER BY created_at ASC"
     )
     .bind(&message_id)
     .bind(&emoji)
-    .fetch_all(pool.get_ref())
-    .await
-    .unwrap_or_default();
-
-    let event = serde_json::json!({
-        "type": "message_reaction_updated",
-        "room_id": room_id,
-        "message_id": message_id,
-        "emoji": emoji,
-        "count": reaction_users.len(),
-        "user_ids": reaction_users,
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let event = ServerEvent::MessageReactionUpdated(MessageReactionUpdatedEvent {
+        room_id,
+        message_id,
+        emoji,
+        count: reaction_users.len(),
+        user_ids: reaction_users,
     });
-    let _ = broadcaster.send(event.to_string());
-
-    HttpResponse::Ok().json(event)
+    outgoing_webhooks::enqueue(&mut tx, &event).await?;
+    tx.commit().await?;
+    Ok(event)
 }
 
 /// POST /api/messages/{id}/pin — Pin message (admin only)
@@ -422,47 +792,63 @@ pub async fn pin_message(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::PinsManage) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
-    let message_id = path.into_inner();
+    match pin_stored_message(pool.get_ref(), broadcaster.get_ref(), &path.into_inner(), &claims.sub).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "status": "pinned" })),
+        Err(code) => code.respond(&req),
+    }
+}
 
+/// Pin a message and broadcast it; shared by the pin endpoint and `/pin`.
+pub(crate) async fn pin_stored_message(
+    pool: &SqlitePool,
+    broadcaster: &crate::ws::Broadcaster,
+    message_id: &str,
+    pinned_by: &str,
+) -> Result<(), ErrorCode> {
     let msg_room: Option<String> = sqlx::query_scalar("SELECT room_id FROM messages WHERE id = ?")
-        .bind(&message_id)
-        .fetch_optional(pool.get_ref())
+        .bind(message_id)
+        .fetch_optional(pool)
         .await
         .unwrap_or(None);
 
     let Some(room_id) = msg_room else {
-        return HttpResponse::NotFound().json(serde_json::json!({ "error": "Message not found" }));
+        return Err(ErrorCode::MessageNotFound);
     };
 
     let now = chrono::Utc::now().to_rfc3339();
+    let event = ServerEvent::MessagePinned(MessagePinnedEvent {
+        id: message_id.to_string(),
+        room_id,
+        pinned_at: now.clone(),
+        pinned_by: pinned_by.to_string(),
+    });
+
+    let Ok(mut tx) = pool.begin().await else {
+        return Err(ErrorCode::PinFailed);
+    };
     let result = sqlx::query("UPDATE messages SET pinned_at = ?, pinned_by = ? WHERE id = ?")
         .bind(&now)
-        .bind(&claims.sub)
-        .bind(&message_id)
-        .execute(pool.get_ref())
+        .bind(pinned_by)
+        .bind(message_id)
+        .execute(&mut *tx)
         .await;
 
-    match result {
-        Ok(_) => {
-            let event = serde_json::json!({
-                "type": "message_pinned",
-                "id": message_id,
-                "room_id": room_id,
-                "pinned_at": now,
-                "pinned_by": claims.sub,
-            });
-            let _ = broadcaster.send(event.to_string());
-            HttpResponse::Ok().json(serde_json::json!({ "status": "pinned" }))
-        }
-        Err(_) => HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Failed to pin message" })),
+    if result.is_err() || outgoing_webhooks::enqueue(&mut tx, &event).await.is_err() || tx.commit().await.is_err() {
+        return Err(ErrorCode::PinFailed);
     }
+
+    events::broadcast(broadcaster, &event);
+    Ok(())
 }
 
 /// DELETE /api/messages/{id}/pin — Unpin message (admin only)
@@ -474,11 +860,14 @@ pub async fn unpin_message(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::PinsManage) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let message_id = path.into_inner();
@@ -490,26 +879,28 @@ pub async fn unpin_message(
         .unwrap_or(None);
 
     let Some(room_id) = msg_room else {
-        return HttpResponse::NotFound().json(serde_json::json!({ "error": "Message not found" }));
+        return ErrorCode::MessageNotFound.respond(&req);
     };
 
+    let event = ServerEvent::MessageUnpinned(MessageUnpinnedEvent {
+        id: message_id.clone(),
+        room_id,
+    });
+
+    let Ok(mut tx) = pool.begin().await else {
+        return ErrorCode::UnpinFailed.respond(&req);
+    };
     let result = sqlx::query("UPDATE messages SET pinned_at = NULL, pinned_by = NULL WHERE id = ?")
         .bind(&message_id)
-        .execute(pool.get_ref())
+        .execute(&mut *tx)
         .await;
 
-    match result {
-        Ok(_) => {
-            let event = serde_json::json!({
-                "type": "message_unpinned",
-                "id": message_id,
-                "room_id": room_id,
-            });
-            let _ = broadcaster.send(event.to_string());
-            HttpResponse::Ok().json(serde_json::json!({ "status": "unpinned" }))
-        }
-        Err(_) => HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Failed to unpin message" })),
+    if result.is_err() || outgoing_webhooks::enqueue(&mut tx, &event).await.is_err() || tx.commit().await.is_err() {
+        return ErrorCode::UnpinFailed.respond(&req);
     }
+
+    events::broadcast(broadcaster.get_ref(), &event);
+    HttpResponse::Ok().json(serde_json::json!({ "status": "unpinned" }))
 }
 
 /// DELETE /api/users/{id}/messages — Admin purge all messages from one user
@@ -518,39 +909,56 @@ pub async fn delete_user_messages(
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
     broadcaster: web::Data<crate::ws::Broadcaster>,
+    storage: web::Data<UploadStorage>,
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let target_user_id = path.into_inner();
 
-    let result = sqlx::query("DELETE FROM messages WHERE user_id = ?")
+    let rows = sqlx::query("SELECT id, image_url FROM messages WHERE user_id = ?")
         .bind(&target_user_id)
-        .execute(pool.get_ref())
+        .fetch_all(pool.get_ref())
         .await;
+    let Ok(rows) = rows else {
+        return ErrorCode::PurgeFailed.respond(&req);
+    };
 
-    match result {
-        Ok(res) => {
-            let event = serde_json::json!({
-                "type": "messages_purged",
-                "user_id": target_user_id,
-                "count": res.rows_affected()
-            });
-            let _ = broadcaster.send(event.to_string());
-
-            HttpResponse::Ok().json(serde_json::json!({
-                "status": "purged",
-                "count": res.rows_affected()
-            }))
+    // One at a time, so their files are released like for any deleted message
+    let mut count: u64 = 0;
+    for row in rows {
+        let id: String = row.get("id");
+        let image_url: Option<String> = row.try_get("image_url").unwrap_or(None);
+        if delete_stored_message(pool.get_ref(), storage.get_ref(), &id, image_url.as_deref(), None).await {
+            count += 1;
         }
-        Err(_) => HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Failed to purge messages" })),
     }
+
+    let event = ServerEvent::MessagesPurged(MessagesPurgedEvent {
+        user_id: target_user_id,
+        count,
+    });
+    // Announced once every message is gone, each having been deleted in its own write
+    if let Ok(mut conn) = pool.acquire().await {
+        if let Err(e) = outgoing_webhooks::enqueue(&mut conn, &event).await {
+            eprintln!("Webhook delivery enqueue error: {:?}", e);
+        }
+    }
+    events::broadcast(broadcaster.get_ref(), &event);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "purged",
+        "count": count
+    }))
 }
 
 /// GET /api/messages/search — Advanced message search
@@ -561,8 +969,11 @@ pub async fn search_messages(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
 
     if let Some(room_id) = &query.room_id {
         let room_role: Option<String> = sqlx::query_scalar("SELECT required_role FROM rooms WHERE id = ?")
@@ -572,21 +983,15 @@ pub async fn search_messages(
             .unwrap_or(None);
 
         let Some(required_role) = room_role else {
-            return HttpResponse::NotFound().json(serde_json::json!({ "error": "Room not found" }));
+            return ErrorCode::RoomNotFound.respond(&req);
         };
         if required_role != "user" && claims.role != "admin" && claims.role != required_role {
-            return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Access denied for this room" }));
+            return ErrorCode::RoomAccessDenied.respond(&req);
         }
     }
 
     let limit = query.limit.unwrap_or(80).clamp(1, 200);
-    let mut sql = String::from(
-        "SELECT m.id, m.room_id, m.user_id, m.username, m.content, m.reply_to_id, m.created_at, m.image_url, m.pinned_at, m.pinned_by, u.avatar_url \
-         FROM messages m \
-         LEFT JOIN users u ON m.user_id = u.id \
-         LEFT JOIN rooms r ON m.room_id = r.id \
-         WHERE 1=1"
-    );
+    let mut sql = format!("{} LEFT JOIN rooms r ON m.room_id = r.id WHERE 1=1", STORED_MESSAGE_COLUMNS);
 
     if claims.role != "admin" {
         sql.push_str(" AND (r.required_role = 'user' OR r.required_role = ?)");
@@ -645,7 +1050,136 @@ pub async fn search_messages(
         messages.push(message_from_row(&row));
     }
 
-    enrich_messages_with_reactions(pool.get_ref(), &mut messages).await;
+    enrich_messages(pool.get_ref(), &mut messages).await;
 
     HttpResponse::Ok().json(messages)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn message_pool() -> SqlitePool {
+        let pool = crate::db::test_pool().await;
+        for sql in [
+            "INSERT INTO users (id, username, password_hash) VALUES ('u1', 'alice', '')",
+            "INSERT INTO rooms (id, name, required_role) VALUES ('staff', 'staff', 'admin')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    fn send(room_id: &str, content: &str, nonce: Option<&str>) -> SendMessagePayload {
+        serde_json::from_value(serde_json::json!({
+            "room_id": room_id,
+            "user_id": "u1",
+            "username": "alice",
+            "content": content,
+            "nonce": nonce,
+        }))
+        .unwrap()
+    }
+
+    async fn message_count(pool: &SqlitePool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM messages").fetch_one(pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn retried_nonces_return_the_original_message() {
+        let pool = message_pool().await;
+        let cache = crate::ws::create_access_cache();
+        let Ok(StoredMessage::Created(first)) = create_message(&pool, &cache, send("general", "hi", Some("n1")), 10).await else {
+            panic!("expected a new message");
+        };
+        let Ok(StoredMessage::Duplicate(again)) = create_message(&pool, &cache, send("general", "hi", Some("n1")), 10).await else {
+            panic!("expected the original message");
+        };
+        assert_eq!(again.id, first.id);
+        assert_eq!(again.nonce.as_deref(), Some("n1"));
+        assert_eq!(message_count(&pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn messages_without_nonce_are_never_deduplicated() {
+        let pool = message_pool().await;
+        let cache = crate::ws::create_access_cache();
+        for _ in 0..2 {
+            assert!(matches!(
+                create_message(&pool, &cache, send("general", "hi", None), 10).await,
+                Ok(StoredMessage::Created(_))
+            ));
+        }
+        assert_eq!(message_count(&pool).await, 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_refused() {
+        let pool = message_pool().await;
+        let cache = crate::ws::create_access_cache();
+        let long_nonce = "n".repeat(MAX_NONCE_LEN + 1);
+        for (message, code) in [
+            (send("general", "hi", Some("")), ErrorCode::InvalidNonce),
+            (send("general", "hi", Some(&long_nonce)), ErrorCode::InvalidNonce),
+            (send("general", "   ", None), ErrorCode::EmptyMessage),
+            (send("general", "ééééééééééé", None), ErrorCode::MessageTooLong),
+            (send("staff", "hi", None), ErrorCode::RoomAccessDenied),
+            (send("missing", "hi", None), ErrorCode::RoomAccessDenied),
+        ] {
+            match create_message(&pool, &cache, message, 10).await {
+                Err(error) => assert_eq!(error, code),
+                Ok(_) => panic!("expected {:?}", code),
+            }
+        }
+        assert_eq!(message_count(&pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn legacy_images_still_referenced_are_kept() {
+        let pool = message_pool().await;
+        for sql in [
+            "INSERT INTO messages (id, room_id, user_id, username, content, image_url) VALUES \
+             ('m1', 'general', 'u1', 'alice', '', '/uploads/u1_a.png'), \
+             ('m2', 'general', 'u1', 'alice', '', '/uploads/u1_b.png'), \
+             ('m3', 'general', 'u1', 'alice', '', '/uploads/u1_b.png')",
+            "UPDATE users SET avatar_url = 'http://localhost:8080/uploads/u1_avatar.png' WHERE id = 'u1'",
+            "INSERT INTO upload_blobs (sha256, path, size, refs) VALUES ('abc', 'abc.png', 1, 1)",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        assert!(!legacy_image_in_use(&pool, "u1_a.png", "m1").await);
+        assert!(legacy_image_in_use(&pool, "u1_b.png", "m2").await);
+        assert!(legacy_image_in_use(&pool, "u1_avatar.png", "m1").await);
+        assert!(legacy_image_in_use(&pool, "abc.png", "m1").await);
+    }
+
+    #[tokio::test]
+    async fn retries_are_checked_like_first_attempts() {
+        let pool = message_pool().await;
+        let cache = crate::ws::create_access_cache();
+        // Sent while u1 could still post in staff
+        sqlx::query(
+            "INSERT INTO messages (id, room_id, user_id, username, content, nonce) \
+             VALUES ('old', 'staff', 'u1', 'alice', 'hi', 'n1')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        match create_message(&pool, &cache, send("staff", "hi", Some("n1")), 10).await {
+            Err(error) => assert_eq!(error, ErrorCode::RoomAccessDenied),
+            Ok(_) => panic!("expected the access check to run first"),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_stored_username_is_the_authors() {
+        let pool = message_pool().await;
+        let cache = crate::ws::create_access_cache();
+        let mut message = send("general", "hi", None);
+        message.username = "admin".to_string();
+        let Ok(StoredMessage::Created(stored)) = create_message(&pool, &cache, message, 10).await else {
+            panic!("expected a new message");
+        };
+        assert_eq!(stored.username, "alice");
+    }
+}
@@ -0,0 +1,352 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use std::collections::{HashMap, HashSet};
+
+use crate::auth::extract_claims;
+use crate::errors::ErrorCode;
+use crate::mentions::MentionReason;
+use crate::ws::{can_user_access_room_cached, AccessCache};
+
+const LEVELS: &[&str] = &["all", "mentions", "nothing"];
+const MAX_MUTE_MS: i64 = 366 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Serialize)]
+pub struct RoomNotificationSettings {
+    pub room_id: String,
+    /// `None` inherits the server-wide level.
+    pub level: Option<String>,
+    pub muted: bool,
+    /// Unix time in milliseconds; `None` while muted means until unmuted.
+    pub muted_until: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotificationSettings {
+    pub level: String,
+    pub suppress_everyone: bool,
+    pub rooms: Vec<RoomNotificationSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateNotificationSettings {
+    pub level: Option<String>,
+    pub suppress_everyone: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateRoomNotificationSettings {
+    /// `null` or omitted inherits the server-wide level.
+    pub level: Option<String>,
+    #[serde(default)]
+    pub muted: bool,
+    /// How long to mute for; omitted mutes until unmuted.
+    pub mute_duration_ms: Option<i64>,
+}
+
+fn check_level(level: Option<&str>) -> Result<Option<String>, ErrorCode> {
+    match level.map(|l| l.trim().to_lowercase()) {
+        Some(level) if LEVELS.contains(&level.as_str()) => Ok(Some(level)),
+        Some(_) => Err(ErrorCode::InvalidNotificationLevel),
+        None => Ok(None),
+    }
+}
+
+async fn load_settings(pool: &SqlitePool, user_id: &str) -> NotificationSettings {
+    let defaults = sqlx::query("SELECT level, suppress_everyone FROM notification_settings WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None);
+
+    let (level, suppress_everyone) = match defaults {
+        Some(row) => (
+            row.try_get("level").unwrap_or_else(|_| "all".to_string()),
+            row.try_get("suppress_everyone").unwrap_or(false),
+        ),
+        None => ("all".to_string(), false),
+    };
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let rows = sqlx::query(
+        "SELECT room_id, level, muted, muted_until FROM room_notification_settings WHERE user_id = ? ORDER BY room_id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    let rooms = rows
+        .iter()
+        .map(|row| {
+            let muted_until: Option<i64> = row.try_get("muted_until").unwrap_or(None);
+            // An expired mute reads as not muted
+            let muted = row.try_get("muted").unwrap_or(false) && muted_until.is_none_or(|until| until > now);
+            RoomNotificationSettings {
+                room_id: row.try_get("room_id").unwrap_or_default(),
+                level: row.try_get("level").unwrap_or(None),
+                muted,
+                muted_until: if muted { muted_until } else { None },
+            }
+        })
+        .collect();
+
+    NotificationSettings {
+        level,
+        suppress_everyone,
+        rooms,
+    }
+}
+
+/// Keep the mention recipients whose settings let this room notify them. A user is dropped when
+/// the room is muted, their level for it is `nothing`, or only `@everyone` reached them while
+/// they suppress it. Users who never changed a setting are always kept.
+pub(crate) async fn filter_mention_recipients(
+    pool: &SqlitePool,
+    room_id: &str,
+    recipients: HashMap<String, MentionReason>,
+) -> HashSet<String> {
+    let rows = sqlx::query(
+        "SELECT u.id, COALESCE(rns.level, ns.level, 'all') AS level, \
+                COALESCE(ns.suppress_everyone, 0) AS suppress_everyone, \
+                COALESCE(rns.muted = 1 AND (rns.muted_until IS NULL OR rns.muted_until > ?), 0) AS muted \
+         FROM users u \
+         LEFT JOIN notification_settings ns ON ns.user_id = u.id \
+         LEFT JOIN room_notification_settings rns ON rns.user_id = u.id AND rns.room_id = ? \
+         WHERE ns.user_id IS NOT NULL OR rns.user_id IS NOT NULL",
+    )
+    .bind(chrono::Utc::now().timestamp_millis())
+    .bind(room_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    let mut settings: HashMap<String, (String, bool, bool)> = HashMap::new();
+    for row in rows {
+        let user_id: String = row.try_get("id").unwrap_or_default();
+        let level: String = row.try_get("level").unwrap_or_else(|_| "all".to_string());
+        let suppress_everyone: bool = row.try_get("suppress_everyone").unwrap_or(false);
+        let muted: bool = row.try_get("muted").unwrap_or(false);
+        settings.insert(user_id, (level, suppress_everyone, muted));
+    }
+
+    recipients
+        .into_iter()
+        .filter(|(user_id, reason)| match settings.get(user_id) {
+            None => true,
+            Some((level, suppress_everyone, muted)) => {
+                let everyone_only = reason.everyone && !reason.direct && !reason.role;
+                !muted && level != "nothing" && !(*suppress_everyone && everyone_only)
+            }
+        })
+        .map(|(user_id, _)| user_id)
+        .collect()
+}
+
+/// GET /api/users/me/notification-settings
+pub async fn get_notification_settings(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
+
+    HttpResponse::Ok().json(load_settings(pool.get_ref(), &claims.sub).await)
+}
+
+/// PATCH /api/users/me/notification-settings — Server-wide defaults
+pub async fn update_notification_settings(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    body: web::Json<UpdateNotificationSettings>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
+
+    let level = match check_level(body.level.as_deref()) {
+        Ok(level) => level,
+        Err(code) => return code.respond(&req),
+    };
+
+    let result = sqlx::query(
+        "INSERT INTO notification_settings (user_id, level, suppress_everyone) VALUES (?, COALESCE(?, 'all'), COALESCE(?, 0)) \
+         ON CONFLICT(user_id) DO UPDATE SET \
+             level = COALESCE(?, level), \
+             suppress_everyone = COALESCE(?, suppress_everyone), \
+             updated_at = datetime('now')",
+    )
+    .bind(&claims.sub)
+    .bind(&level)
+    .bind(body.suppress_everyone)
+    .bind(&level)
+    .bind(body.suppress_everyone)
+    .execute(pool.get_ref())
+    .await;
+
+    if result.is_err() {
+        return ErrorCode::InternalError.respond(&req);
+    }
+
+    HttpResponse::Ok().json(load_settings(pool.get_ref(), &claims.sub).await)
+}
+
+/// PUT /api/users/me/notification-settings/rooms/{room_id} — Per-room level and mute
+pub async fn update_room_notification_settings(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: web::Json<UpdateRoomNotificationSettings>,
+    access_cache: web::Data<AccessCache>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_session() {
+        return err.respond(&req);
+    }
+
+    let room_id = path.into_inner();
+    if !can_user_access_room_cached(pool.get_ref(), access_cache.get_ref(), &claims.sub, &room_id).await {
+        return ErrorCode::RoomAccessDenied.respond(&req);
+    }
+
+    let level = match check_level(body.level.as_deref()) {
+        Ok(level) => level,
+        Err(code) => return code.respond(&req),
+    };
+
+    let muted_until = match (body.muted, body.mute_duration_ms) {
+        (true, Some(ms)) if ms <= 0 || ms > MAX_MUTE_MS => return ErrorCode::InvalidMuteDuration.respond(&req),
+        (true, Some(ms)) => Some(chrono::Utc::now().timestamp_millis() + ms),
+        _ => None,
+    };
+
+    // Nothing left to override: fall back to the defaults entirely
+    let result = if level.is_none() && !body.muted {
+        sqlx::query("DELETE FROM room_notification_settings WHERE user_id = ? AND room_id = ?")
+            .bind(&claims.sub)
+            .bind(&room_id)
+            .execute(pool.get_ref())
+            .await
+    } else {
+        sqlx::query(
+            "INSERT INTO room_notification_settings (user_id, room_id, level, muted, muted_until) VALUES (?, ?, ?, ?, ?) \
+             ON CONFLICT(user_id, room_id) DO UPDATE SET \
+                 level = excluded.level, \
+                 muted = excluded.muted, \
+                 muted_until = excluded.muted_until, \
+                 updated_at = datetime('now')",
+        )
+        .bind(&claims.sub)
+        .bind(&room_id)
+        .bind(&level)
+        .bind(body.muted)
+        .bind(muted_until)
+        .execute(pool.get_ref())
+        .await
+    };
+
+    if result.is_err() {
+        return ErrorCode::InternalError.respond(&req);
+    }
+
+    HttpResponse::Ok().json(load_settings(pool.get_ref(), &claims.sub).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason(direct: bool, everyone: bool) -> MentionReason {
+        MentionReason {
+            direct,
+            role: false,
+            everyone,
+        }
+    }
+
+    async fn settings_pool() -> SqlitePool {
+        let pool = crate::db::test_pool().await;
+        let past = chrono::Utc::now().timestamp_millis() - 1000;
+        for sql in [
+            "INSERT INTO users (id, username, password_hash) VALUES \
+             ('plain', 'plain', ''), ('silent', 'silent', ''), ('no_everyone', 'no_everyone', ''), \
+             ('muted', 'muted', ''), ('expired', 'expired', ''), ('override', 'override', '')".to_string(),
+            "INSERT INTO notification_settings (user_id, level, suppress_everyone) VALUES \
+             ('silent', 'nothing', 0), ('no_everyone', 'all', 1), ('override', 'nothing', 0)".to_string(),
+            format!(
+                "INSERT INTO room_notification_settings (user_id, room_id, level, muted, muted_until) VALUES \
+                 ('muted', 'general', NULL, 1, NULL), ('expired', 'general', NULL, 1, {}), \
+                 ('override', 'general', 'mentions', 0, NULL)",
+                past
+            ),
+        ] {
+            sqlx::query(&sql).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    fn sorted(recipients: HashSet<String>) -> Vec<String> {
+        let mut recipients: Vec<String> = recipients.into_iter().collect();
+        recipients.sort();
+        recipients
+    }
+
+    #[test]
+    fn levels_are_normalized() {
+        assert_eq!(check_level(Some(" Mentions ")), Ok(Some("mentions".to_string())));
+        assert_eq!(check_level(None), Ok(None));
+        assert_eq!(check_level(Some("loud")), Err(ErrorCode::InvalidNotificationLevel));
+    }
+
+    #[tokio::test]
+    async fn direct_mentions_respect_mutes_and_levels() {
+        let pool = settings_pool().await;
+        let users = ["plain", "silent", "no_everyone", "muted", "expired", "override"];
+        let recipients = users.iter().map(|u| (u.to_string(), reason(true, false))).collect();
+        assert_eq!(
+            sorted(filter_mention_recipients(&pool, "general", recipients).await),
+            ["expired", "no_everyone", "override", "plain"]
+        );
+
+        // Mutes and overrides are per room
+        let recipients = users.iter().map(|u| (u.to_string(), reason(true, false))).collect();
+        assert_eq!(
+            sorted(filter_mention_recipients(&pool, "other", recipients).await),
+            ["expired", "muted", "no_everyone", "plain"]
+        );
+    }
+
+    #[tokio::test]
+    async fn suppressed_everyone_only_drops_everyone_only_mentions() {
+        let pool = settings_pool().await;
+        let recipients = HashMap::from([
+            ("plain".to_string(), reason(false, true)),
+            ("no_everyone".to_string(), reason(false, true)),
+        ]);
+        assert_eq!(sorted(filter_mention_recipients(&pool, "general", recipients).await), ["plain"]);
+
+        let recipients = HashMap::from([("no_everyone".to_string(), reason(true, true))]);
+        assert_eq!(sorted(filter_mention_recipients(&pool, "general", recipients).await), ["no_everyone"]);
+    }
+
+    #[tokio::test]
+    async fn expired_mutes_read_as_unmuted() {
+        let pool = settings_pool().await;
+        let settings = load_settings(&pool, "expired").await;
+        assert_eq!(settings.level, "all");
+        assert!(!settings.rooms[0].muted);
+        assert_eq!(settings.rooms[0].muted_until, None);
+
+        let settings = load_settings(&pool, "muted").await;
+        assert!(settings.rooms[0].muted);
+    }
+}
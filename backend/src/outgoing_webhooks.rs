@@ -0,0 +1,812 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::sqlite::{SqliteConnection, SqliteRow};
+use sqlx::{Row, SqlitePool};
+use tokio::sync::{broadcast, Notify};
+use uuid::Uuid;
+
+use crate::bots::generate_token;
+use crate::codec::BroadcastFrame;
+use crate::errors::{ApiError, ErrorCode};
+use crate::events::ServerEvent;
+use crate::rate_limit::env_or;
+use crate::webhooks::require_admin;
+use crate::ws::Broadcaster;
+
+/// Events an outgoing webhook can subscribe to, named like their `type` on /ws,
+/// except `join` which is `member_joined`: sent once, when an account is created.
+pub const EVENT_TYPES: &[&str] = &[
+    "message",
+    "message_deleted",
+    "messages_purged",
+    "message_reaction_updated",
+    "message_pinned",
+    "message_unpinned",
+    "join",
+];
+
+const SECRET_PREFIX: &str = "vxs_";
+const MAX_NAME_CHARS: usize = 64;
+const DEFAULT_LOG_LIMIT: i64 = 50;
+const MAX_LOG_LIMIT: i64 = 200;
+const DELIVERY_BATCH: i64 = 20;
+const PRUNE_EVERY: Duration = Duration::from_secs(3600);
+
+fn event_type(event: &ServerEvent) -> Option<&'static str> {
+    match event {
+        ServerEvent::Message(_) => Some("message"),
+        ServerEvent::MessageDeleted(_) => Some("message_deleted"),
+        ServerEvent::MessagesPurged(_) => Some("messages_purged"),
+        ServerEvent::MessageReactionUpdated(_) => Some("message_reaction_updated"),
+        ServerEvent::MessagePinned(_) => Some("message_pinned"),
+        ServerEvent::MessageUnpinned(_) => Some("message_unpinned"),
+        ServerEvent::MemberJoined(_) => Some("join"),
+        _ => None,
+    }
+}
+
+/// Delivery settings, read once at startup.
+#[derive(Debug, Clone)]
+struct DeliveryConfig {
+    timeout: Duration,
+    max_attempts: i64,
+    retry_base_ms: i64,
+    retry_max_ms: i64,
+    poll_interval: Duration,
+    retention_days: i64,
+}
+
+impl DeliveryConfig {
+    /// Reads `OUTGOING_WEBHOOK_TIMEOUT_MS`, `OUTGOING_WEBHOOK_MAX_ATTEMPTS`,
+    /// `OUTGOING_WEBHOOK_RETRY_BASE_MS`, `OUTGOING_WEBHOOK_RETRY_MAX_MS`,
+    /// `OUTGOING_WEBHOOK_POLL_MS` and `OUTGOING_WEBHOOK_RETENTION_DAYS`.
+    fn from_env() -> DeliveryConfig {
+        DeliveryConfig {
+            timeout: Duration::from_millis(env_or("OUTGOING_WEBHOOK_TIMEOUT_MS", 10_000u64)),
+            max_attempts: env_or("OUTGOING_WEBHOOK_MAX_ATTEMPTS", 8i64),
+            retry_base_ms: env_or("OUTGOING_WEBHOOK_RETRY_BASE_MS", 10_000i64),
+            retry_max_ms: env_or("OUTGOING_WEBHOOK_RETRY_MAX_MS", 3_600_000i64),
+            poll_interval: Duration::from_millis(env_or("OUTGOING_WEBHOOK_POLL_MS", 5_000u64)),
+            retention_days: env_or("OUTGOING_WEBHOOK_RETENTION_DAYS", 7i64),
+        }
+    }
+
+    /// Wait before the next try after `attempts` failures: base, 2×base, 4×base… capped.
+    fn backoff_ms(&self, attempts: i64) -> i64 {
+        let shift = (attempts - 1).clamp(0, 30) as u32;
+        self.retry_base_ms.saturating_mul(1i64 << shift).min(self.retry_max_ms)
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// `sha256=<hex>` over `"{timestamp}.{body}"`, sent as `X-Voxium-Signature`.
+pub fn sign_payload(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+// ── Dispatcher ──────────────────────────────────────────
+
+/// Spawn the task that delivers queued events, woken when one is broadcast.
+/// Deliveries still pending from a previous run are picked up again.
+pub fn start_dispatcher(pool: SqlitePool, broadcaster: &Broadcaster) {
+    let config = DeliveryConfig::from_env();
+    let wake = Arc::new(Notify::new());
+    let rx = broadcaster.subscribe();
+
+    actix_web::rt::spawn(wake_on_events(rx, wake.clone()));
+    actix_web::rt::spawn(deliver_pending(pool, config, wake));
+}
+
+/// Deliveries are queued by the write that caused the event, so events this receiver
+/// skips when lagging are only delivered on the next poll, never lost.
+async fn wake_on_events(mut rx: broadcast::Receiver<Arc<BroadcastFrame>>, wake: Arc<Notify>) {
+    loop {
+        match rx.recv().await {
+            Ok(frame) if event_type(&frame.event).is_some() => wake.notify_one(),
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(_)) => wake.notify_one(),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Queue one delivery per enabled webhook subscribed to this event; returns how many.
+/// Run it on the transaction that stores what the event announces, so both commit together.
+pub(crate) async fn enqueue(conn: &mut SqliteConnection, event: &ServerEvent) -> Result<usize, sqlx::Error> {
+    let Some(kind) = event_type(event) else {
+        return Ok(0);
+    };
+    let rows = sqlx::query("SELECT id, event_types, room_id FROM outgoing_webhooks WHERE enabled = 1")
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let mut queued = 0;
+    for row in rows {
+        let event_types: String = row.get("event_types");
+        if !event_types.split_whitespace().any(|t| t == kind) {
+            continue;
+        }
+        // A room filter also drops events that are not tied to a room
+        let room_filter: Option<String> = row.try_get("room_id").unwrap_or(None);
+        if room_filter.is_some() && room_filter.as_deref() != event.room_id() {
+            continue;
+        }
+
+        let webhook_id: String = row.get("id");
+        let delivery_id = Uuid::new_v4().to_string();
+        let created_at = chrono::Utc::now().to_rfc3339();
+        let payload = serde_json::json!({
+            "id": delivery_id,
+            "webhook_id": webhook_id,
+            "created_at": created_at,
+            "event": event,
+        });
+
+        sqlx::query(
+            "INSERT INTO webhook_deliveries (id, webhook_id, event_type, payload, status, attempts, next_attempt_at, created_at) \
+             VALUES (?, ?, ?, ?, 'pending', 0, ?, ?)"
+        )
+        .bind(&delivery_id)
+        .bind(&webhook_id)
+        .bind(kind)
+        .bind(payload.to_string())
+        .bind(now_ms())
+        .bind(&created_at)
+        .execute(&mut *conn)
+        .await?;
+        queued += 1;
+    }
+    Ok(queued)
+}
+
+struct DueDelivery {
+    id: String,
+    event_type: String,
+    payload: String,
+    attempts: i64,
+    url: String,
+    secret: String,
+}
+
+async fn deliver_pending(pool: SqlitePool, config: DeliveryConfig, wake: Arc<Notify>) {
+    let client = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .build()
+        .unwrap_or_default();
+    let mut last_prune: Option<Instant> = None;
+
+    loop {
+        if last_prune.is_none_or(|at| at.elapsed() >= PRUNE_EVERY) {
+            prune_deliveries(&pool, config.retention_days).await;
+            last_prune = Some(Instant::now());
+        }
+
+        let rows = sqlx::query(
+            "SELECT d.id, d.event_type, d.payload, d.attempts, w.url, w.secret \
+             FROM webhook_deliveries d JOIN outgoing_webhooks w ON d.webhook_id = w.id \
+             WHERE d.status = 'pending' AND d.next_attempt_at <= ? AND w.enabled = 1 \
+             ORDER BY d.next_attempt_at ASC LIMIT ?"
+        )
+        .bind(now_ms())
+        .bind(DELIVERY_BATCH)
+        .fetch_all(&pool)
+        .await
+        .unwrap_or_default();
+
+        if rows.is_empty() {
+            // New events wake us up early; the poll catches retries coming due
+            tokio::select! {
+                _ = wake.notified() => {}
+                _ = tokio::time::sleep(config.poll_interval) => {}
+            }
+            continue;
+        }
+
+        let due = rows.iter().map(|row| DueDelivery {
+            id: row.get("id"),
+            event_type: row.get("event_type"),
+            payload: row.get("payload"),
+            attempts: row.get("attempts"),
+            url: row.get("url"),
+            secret: row.get("secret"),
+        });
+        futures_util::future::join_all(due.map(|delivery| attempt_delivery(&client, &pool, &config, delivery))).await;
+    }
+}
+
+async fn attempt_delivery(client: &reqwest::Client, pool: &SqlitePool, config: &DeliveryConfig, delivery: DueDelivery) {
+    let timestamp = chrono::Utc::now().timestamp();
+    let result = client
+        .post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", "Voxium-Webhooks/1")
+        .header("X-Voxium-Event", &delivery.event_type)
+        .header("X-Voxium-Delivery", &delivery.id)
+        .header("X-Voxium-Timestamp", timestamp.to_string())
+        .header("X-Voxium-Signature", sign_payload(&delivery.secret, timestamp, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    let attempts = delivery.attempts + 1;
+    let (status_code, error) = match result {
+        Ok(resp) if resp.status().is_success() => {
+            let _ = sqlx::query(
+                "UPDATE webhook_deliveries SET status = 'succeeded', attempts = ?, last_status_code = ?, last_error = NULL, delivered_at = ? WHERE id = ?"
+            )
+            .bind(attempts)
+            .bind(resp.status().as_u16() as i64)
+            .bind(chrono::Utc::now().to_rfc3339())
+            .bind(&delivery.id)
+            .execute(pool)
+            .await;
+            return;
+        }
+        Ok(resp) => (Some(resp.status().as_u16() as i64), format!("HTTP {}", resp.status())),
+        Err(e) => (None, e.to_string()),
+    };
+
+    let (status, next_attempt_at) = if attempts >= config.max_attempts {
+        ("failed", now_ms())
+    } else {
+        ("pending", now_ms() + config.backoff_ms(attempts))
+    };
+    let _ = sqlx::query(
+        "UPDATE webhook_deliveries SET status = ?, attempts = ?, next_attempt_at = ?, last_status_code = ?, last_error = ? WHERE id = ?"
+    )
+    .bind(status)
+    .bind(attempts)
+    .bind(next_attempt_at)
+    .bind(status_code)
+    .bind(&error)
+    .bind(&delivery.id)
+    .execute(pool)
+    .await;
+}
+
+/// Finished deliveries are only kept as a log for `OUTGOING_WEBHOOK_RETENTION_DAYS`.
+async fn prune_deliveries(pool: &SqlitePool, retention_days: i64) {
+    let cutoff = (chrono::Utc::now() - chrono::Duration::days(retention_days)).to_rfc3339();
+    let _ = sqlx::query("DELETE FROM webhook_deliveries WHERE status != 'pending' AND created_at < ?")
+        .bind(cutoff)
+        .execute(pool)
+        .await;
+}
+
+// ── Admin endpoints ─────────────────────────────────────
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOutgoingWebhook {
+    pub name: String,
+    pub url: String,
+    pub event_types: Vec<String>,
+    /// Only send events from this room.
+    pub room_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateOutgoingWebhook {
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub event_types: Option<Vec<String>>,
+    /// An empty string removes the room filter.
+    pub room_id: Option<String>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuery {
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutgoingWebhook {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub room_id: Option<String>,
+    pub enabled: bool,
+    pub created_by: Option<String>,
+    pub created_at: String,
+    /// Only present on create and rotate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub event_type: String,
+    pub status: String,
+    pub attempts: i64,
+    /// When the next try is due, for pending deliveries.
+    pub next_attempt_at: Option<String>,
+    pub last_status_code: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+const OUTGOING_WEBHOOK_COLUMNS: &str =
+    "SELECT id, name, url, event_types, room_id, enabled, created_by, created_at FROM outgoing_webhooks";
+
+fn outgoing_webhook_from_row(row: &SqliteRow) -> OutgoingWebhook {
+    OutgoingWebhook {
+        id: row.get("id"),
+        name: row.get("name"),
+        url: row.get("url"),
+        event_types: row
+            .get::<String, _>("event_types")
+            .split_whitespace()
+            .map(|t| t.to_string())
+            .collect(),
+        room_id: row.try_get("room_id").unwrap_or(None),
+        enabled: row.try_get("enabled").unwrap_or(true),
+        created_by: row.try_get("created_by").unwrap_or(None),
+        created_at: row.get("created_at"),
+        secret: None,
+    }
+}
+
+async fn fetch_outgoing_webhook(pool: &SqlitePool, id: &str) -> Option<OutgoingWebhook> {
+    let row = sqlx::query(&format!("{} WHERE id = ?", OUTGOING_WEBHOOK_COLUMNS))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)?;
+    Some(outgoing_webhook_from_row(&row))
+}
+
+fn clean_name(raw: &str) -> Option<String> {
+    let name = raw.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+fn clean_url(raw: &str) -> Option<String> {
+    let url = reqwest::Url::parse(raw.trim()).ok()?;
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+/// Deduplicated, space-separated event types as stored in the table.
+fn clean_event_types(raw: &[String]) -> Result<String, String> {
+    let mut types: Vec<&str> = Vec::new();
+    for value in raw {
+        let Some(kind) = EVENT_TYPES.iter().copied().find(|t| *t == value.trim()) else {
+            return Err(format!("Unknown event type '{}', expected one of {}", value, EVENT_TYPES.join(", ")));
+        };
+        if !types.contains(&kind) {
+            types.push(kind);
+        }
+    }
+    if types.is_empty() {
+        return Err("At least one event type is required".to_string());
+    }
+    Ok(types.join(" "))
+}
+
+async fn room_exists(pool: &SqlitePool, room_id: &str) -> bool {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rooms WHERE id = ?")
+        .bind(room_id)
+        .fetch_one(pool)
+        .await
+        .unwrap_or(0);
+    count > 0
+}
+
+/// GET /api/outgoing-webhooks — List outgoing webhooks (Admin only)
+pub async fn list_outgoing_webhooks(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let rows = sqlx::query(&format!("{} ORDER BY created_at ASC", OUTGOING_WEBHOOK_COLUMNS))
+        .fetch_all(pool.get_ref())
+        .await
+        .unwrap_or_default();
+
+    let webhooks: Vec<OutgoingWebhook> = rows.iter().map(outgoing_webhook_from_row).collect();
+    HttpResponse::Ok().json(webhooks)
+}
+
+/// POST /api/outgoing-webhooks — Subscribe a URL to events; the signing secret is only returned here and on rotate (Admin only)
+pub async fn create_outgoing_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    body: web::Json<CreateOutgoingWebhook>,
+) -> HttpResponse {
+    let claims = match require_admin(&req) {
+        Ok(c) => c,
+        Err(err) => return err.respond(&req),
+    };
+
+    let Some(name) = clean_name(&body.name) else {
+        return ErrorCode::InvalidWebhookName.respond(&req);
+    };
+    let Some(url) = clean_url(&body.url) else {
+        return ErrorCode::InvalidWebhookUrl.respond(&req);
+    };
+    let event_types = match clean_event_types(&body.event_types) {
+        Ok(types) => types,
+        Err(details) => return ApiError::with_details(ErrorCode::InvalidEventType, details).respond(&req),
+    };
+    let room_id = body.room_id.as_deref().map(str::trim).filter(|id| !id.is_empty());
+    if let Some(room_id) = room_id {
+        if !room_exists(pool.get_ref(), room_id).await {
+            return ErrorCode::RoomNotFound.respond(&req);
+        }
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let secret = generate_token(SECRET_PREFIX);
+    let result = sqlx::query(
+        "INSERT INTO outgoing_webhooks (id, name, url, secret, event_types, room_id, enabled, created_by, created_at) \
+         VALUES (?, ?, ?, ?, ?, ?, 1, ?, ?)"
+    )
+    .bind(&id)
+    .bind(&name)
+    .bind(&url)
+    .bind(&secret)
+    .bind(&event_types)
+    .bind(room_id)
+    .bind(&claims.sub)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .execute(pool.get_ref())
+    .await;
+
+    if result.is_err() {
+        return ErrorCode::InternalError.respond(&req);
+    }
+
+    match fetch_outgoing_webhook(pool.get_ref(), &id).await {
+        Some(mut webhook) => {
+            webhook.secret = Some(secret);
+            HttpResponse::Created().json(webhook)
+        }
+        None => ErrorCode::InternalError.respond(&req),
+    }
+}
+
+/// PATCH /api/outgoing-webhooks/{id} — Change the URL, filters or enable flag (Admin only)
+pub async fn update_outgoing_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: web::Json<UpdateOutgoingWebhook>,
+) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let id = path.into_inner();
+    let Some(mut webhook) = fetch_outgoing_webhook(pool.get_ref(), &id).await else {
+        return ErrorCode::OutgoingWebhookNotFound.respond(&req);
+    };
+
+    if let Some(name) = &body.name {
+        let Some(name) = clean_name(name) else {
+            return ErrorCode::InvalidWebhookName.respond(&req);
+        };
+        webhook.name = name;
+    }
+    if let Some(url) = &body.url {
+        let Some(url) = clean_url(url) else {
+            return ErrorCode::InvalidWebhookUrl.respond(&req);
+        };
+        webhook.url = url;
+    }
+    let event_types = match &body.event_types {
+        Some(types) => match clean_event_types(types) {
+            Ok(types) => types,
+            Err(details) => {
+                return ApiError::with_details(ErrorCode::InvalidEventType, details).respond(&req)
+            }
+        },
+        None => webhook.event_types.join(" "),
+    };
+    if let Some(room_id) = &body.room_id {
+        let room_id = room_id.trim();
+        if room_id.is_empty() {
+            webhook.room_id = None;
+        } else if room_exists(pool.get_ref(), room_id).await {
+            webhook.room_id = Some(room_id.to_string());
+        } else {
+            return ErrorCode::RoomNotFound.respond(&req);
+        }
+    }
+    if let Some(enabled) = body.enabled {
+        webhook.enabled = enabled;
+    }
+
+    let result = sqlx::query(
+        "UPDATE outgoing_webhooks SET name = ?, url = ?, event_types = ?, room_id = ?, enabled = ? WHERE id = ?"
+    )
+    .bind(&webhook.name)
+    .bind(&webhook.url)
+    .bind(&event_types)
+    .bind(&webhook.room_id)
+    .bind(webhook.enabled)
+    .bind(&id)
+    .execute(pool.get_ref())
+    .await;
+
+    if result.is_err() {
+        return ErrorCode::InternalError.respond(&req);
+    }
+
+    match fetch_outgoing_webhook(pool.get_ref(), &id).await {
+        Some(webhook) => HttpResponse::Ok().json(webhook),
+        None => ErrorCode::OutgoingWebhookNotFound.respond(&req),
+    }
+}
+
+/// POST /api/outgoing-webhooks/{id}/rotate — Replace the signing secret (Admin only)
+pub async fn rotate_outgoing_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let id = path.into_inner();
+    let secret = generate_token(SECRET_PREFIX);
+    let result = sqlx::query("UPDATE outgoing_webhooks SET secret = ? WHERE id = ?")
+        .bind(&secret)
+        .bind(&id)
+        .execute(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(res) if res.rows_affected() > 0 => {}
+        Ok(_) => return ErrorCode::OutgoingWebhookNotFound.respond(&req),
+        Err(_) => return ErrorCode::InternalError.respond(&req),
+    }
+
+    match fetch_outgoing_webhook(pool.get_ref(), &id).await {
+        Some(mut webhook) => {
+            webhook.secret = Some(secret);
+            HttpResponse::Ok().json(webhook)
+        }
+        None => ErrorCode::OutgoingWebhookNotFound.respond(&req),
+    }
+}
+
+/// DELETE /api/outgoing-webhooks/{id} — Delete an outgoing webhook and its delivery log (Admin only)
+pub async fn delete_outgoing_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let result = sqlx::query("DELETE FROM outgoing_webhooks WHERE id = ?")
+        .bind(path.into_inner())
+        .execute(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(res) if res.rows_affected() > 0 => HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" })),
+        Ok(_) => ErrorCode::OutgoingWebhookNotFound.respond(&req),
+        Err(_) => ErrorCode::InternalError.respond(&req),
+    }
+}
+
+/// GET /api/outgoing-webhooks/{id}/deliveries — Recent deliveries, newest first (Admin only)
+pub async fn list_deliveries(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    query: web::Query<DeliveryQuery>,
+) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let id = path.into_inner();
+    if fetch_outgoing_webhook(pool.get_ref(), &id).await.is_none() {
+        return ErrorCode::OutgoingWebhookNotFound.respond(&req);
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LOG_LIMIT).clamp(1, MAX_LOG_LIMIT);
+    let status = query.status.as_deref().filter(|s| !s.is_empty());
+    let rows = sqlx::query(
+        "SELECT id, event_type, payload, status, attempts, next_attempt_at, last_status_code, last_error, created_at, delivered_at \
+         FROM webhook_deliveries WHERE webhook_id = ? AND (? IS NULL OR status = ?) ORDER BY created_at DESC LIMIT ?"
+    )
+    .bind(&id)
+    .bind(status)
+    .bind(status)
+    .bind(limit)
+    .fetch_all(pool.get_ref())
+    .await
+    .unwrap_or_default();
+
+    let deliveries: Vec<WebhookDelivery> = rows
+        .iter()
+        .map(|row| {
+            let status: String = row.get("status");
+            let next_attempt_at = (status == "pending")
+                .then(|| chrono::DateTime::from_timestamp_millis(row.get("next_attempt_at")))
+                .flatten()
+                .map(|at| at.to_rfc3339());
+            WebhookDelivery {
+                id: row.get("id"),
+                event_type: row.get("event_type"),
+                status,
+                attempts: row.get("attempts"),
+                next_attempt_at,
+                last_status_code: row.try_get("last_status_code").unwrap_or(None),
+                last_error: row.try_get("last_error").unwrap_or(None),
+                created_at: row.get("created_at"),
+                delivered_at: row.try_get("delivered_at").unwrap_or(None),
+                payload: serde_json::from_str(row.get("payload")).unwrap_or_default(),
+            }
+        })
+        .collect();
+
+    HttpResponse::Ok().json(deliveries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(retry_base_ms: i64, retry_max_ms: i64) -> DeliveryConfig {
+        DeliveryConfig {
+            timeout: Duration::from_secs(10),
+            max_attempts: 8,
+            retry_base_ms,
+            retry_max_ms,
+            poll_interval: Duration::from_secs(5),
+            retention_days: 7,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let config = config(10_000, 3_600_000);
+        assert_eq!(config.backoff_ms(0), 10_000);
+        assert_eq!(config.backoff_ms(1), 10_000);
+        assert_eq!(config.backoff_ms(2), 20_000);
+        assert_eq!(config.backoff_ms(3), 40_000);
+        assert_eq!(config.backoff_ms(9), 2_560_000);
+        assert_eq!(config.backoff_ms(10), 3_600_000);
+        assert_eq!(config.backoff_ms(1_000), 3_600_000);
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let config = config(i64::MAX / 2, i64::MAX);
+        assert_eq!(config.backoff_ms(64), i64::MAX);
+        assert_eq!(config.backoff_ms(i64::MAX), i64::MAX);
+    }
+
+    #[test]
+    fn payload_signature_matches_hmac_sha256() {
+        let body = r#"{"event":"message_create"}"#;
+        assert_eq!(
+            sign_payload("whsec_test", 1_700_000_000, body),
+            "sha256=b4f3344757508468f3ccbf2e98c5d0036c811abcf0cff90d5790f9b318c4d4ec"
+        );
+        // The timestamp and the secret are both covered
+        assert_eq!(
+            sign_payload("whsec_test", 1_700_000_001, body),
+            "sha256=d9119f14dbcc6ce416a1731ddac3adf94bc7dda7a853b6f82d5234b01ffa2612"
+        );
+        assert_eq!(
+            sign_payload("whsec_other", 1_700_000_000, body),
+            "sha256=58b857b5f8def4307855a0ea00b68a60a1d22bb1c1cd7d18d07006800a1178e5"
+        );
+    }
+
+    #[test]
+    fn only_http_urls_are_accepted() {
+        assert_eq!(clean_url(" https://example.com/hook ").as_deref(), Some("https://example.com/hook"));
+        assert!(clean_url("http://127.0.0.1:8080").is_some());
+        assert!(clean_url("ftp://example.com/hook").is_none());
+        assert!(clean_url("file:///etc/passwd").is_none());
+        assert!(clean_url("example.com/hook").is_none());
+    }
+
+    #[test]
+    fn event_types_are_validated_and_deduplicated() {
+        let raw = ["message".to_string(), " join ".to_string(), "message".to_string()];
+        assert_eq!(clean_event_types(&raw).unwrap(), "message join");
+        assert!(clean_event_types(&["typing".to_string()]).is_err());
+        assert!(clean_event_types(&[]).is_err());
+    }
+
+    async fn subscribed_pool(event_types: &str) -> SqlitePool {
+        let pool = crate::db::test_pool().await;
+        sqlx::query("INSERT INTO users (id, username, password_hash) VALUES ('u1', 'alice', '')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO outgoing_webhooks (id, name, url, secret, event_types) VALUES ('w1', 'hook', 'http://localhost', 's', ?)")
+            .bind(event_types)
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool
+    }
+
+    async fn queued(pool: &SqlitePool) -> Vec<String> {
+        sqlx::query_scalar("SELECT event_type FROM webhook_deliveries ORDER BY created_at")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn join_is_the_member_joined_event() {
+        let joined = ServerEvent::MemberJoined(crate::events::MemberJoinedEvent {
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            avatar_color: 0,
+            avatar_url: None,
+            role: "user".to_string(),
+        });
+        assert_eq!(event_type(&joined), Some("join"));
+        let presence: ServerEvent = serde_json::from_value(serde_json::json!({
+            "type": "join", "user_id": "u1", "username": "alice", "avatar_color": 0,
+            "avatar_url": null, "banner_url": null, "status": null, "role": null, "about": null,
+        }))
+        .unwrap();
+        assert_eq!(event_type(&presence), None);
+    }
+
+    #[tokio::test]
+    async fn stored_messages_queue_their_deliveries() {
+        let pool = subscribed_pool("message join").await;
+        let cache = crate::ws::create_access_cache();
+        let message: crate::events::SendMessagePayload = serde_json::from_value(serde_json::json!({
+            "room_id": "general", "user_id": "u1", "username": "alice", "content": "hi",
+        }))
+        .unwrap();
+        crate::messages::create_message(&pool, &cache, message, 10).await.unwrap();
+        assert_eq!(queued(&pool).await, ["message"]);
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_rolled_back_with_their_write() {
+        let pool = subscribed_pool("message_pinned").await;
+        let event = ServerEvent::MessagePinned(crate::events::MessagePinnedEvent {
+            id: "m1".to_string(),
+            room_id: "general".to_string(),
+            pinned_at: "2026-01-01T00:00:00Z".to_string(),
+            pinned_by: "u1".to_string(),
+        });
+
+        let mut tx = pool.begin().await.unwrap();
+        assert_eq!(enqueue(&mut tx, &event).await.unwrap(), 1);
+        tx.rollback().await.unwrap();
+        assert!(queued(&pool).await.is_empty());
+
+        let mut tx = pool.begin().await.unwrap();
+        enqueue(&mut tx, &event).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(queued(&pool).await, ["message_pinned"]);
+    }
+}
@@ -0,0 +1,457 @@
+//! The `permessage-deflate` WebSocket extension (RFC 7692) for `/ws`.
+//!
+//! actix-ws refuses frames with RSV bits set, so a negotiated connection is wrapped on
+//! both sides: client frames are inflated before actix-ws parses them, and the frames
+//! it writes are compressed on their way to the socket.
+
+use std::collections::HashSet;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{dev, web, FromRequest, HttpRequest, HttpResponse};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures_util::Stream;
+
+const EXTENSION: &str = "permessage-deflate";
+
+/// Negotiated as a response to any acceptable offer; every message is compressed on
+/// its own, so neither side keeps a window between messages.
+const RESPONSE: &str = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// What a sync flush ends with; stripped from sent messages and restored before inflating.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const OPCODE: u8 = 0x0f;
+const MASKED: u8 = 0x80;
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+
+/// Whether the upgrade request offers `permessage-deflate` with parameters we can honour.
+/// Offers are tried in the client's order; the deflate backend cannot shrink its window,
+/// so an offer limiting `server_max_window_bits` below 15 is declined.
+pub fn negotiate(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(HeaderName::from_static("sec-websocket-extensions"))
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(acceptable_offer)
+}
+
+fn acceptable_offer(offer: &str) -> bool {
+    let mut parts = offer.split(';').map(str::trim);
+    if parts.next() != Some(EXTENSION) {
+        return false;
+    }
+    let mut seen = HashSet::new();
+    parts.all(|param| {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        // A parameter given twice declines the offer (RFC 7692 section 7)
+        if !seen.insert(name) {
+            return false;
+        }
+        match (name, value) {
+            ("server_no_context_takeover" | "client_no_context_takeover", None) => true,
+            ("server_max_window_bits", Some(bits)) => bits == "15",
+            ("client_max_window_bits", None) => true,
+            ("client_max_window_bits", Some(bits)) => bits.parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits)),
+            _ => false,
+        }
+    })
+}
+
+/// The upgrade payload with compressed client messages inflated, for `actix_ws::handle`.
+/// Inflated messages longer than `max_frame_bytes` are cut just past the limit, so
+/// actix-ws still rejects them as too large.
+pub async fn inflating(req: &HttpRequest, payload: web::Payload, max_frame_bytes: usize) -> Result<web::Payload, actix_web::Error> {
+    let mut payload: dev::Payload = dev::Payload::Stream {
+        payload: Box::pin(Inflating {
+            inner: payload.into_inner(),
+            buf: BytesMut::new(),
+            message: None,
+            max_frame_bytes,
+        }),
+    };
+    web::Payload::from_request(req, &mut payload).await
+}
+
+/// The upgrade response with the extension accepted and every whole data frame compressed.
+pub fn deflating(response: HttpResponse) -> HttpResponse {
+    let mut response = response.map_body(|_, body| {
+        BoxBody::new(Deflating {
+            inner: body,
+            buf: BytesMut::new(),
+        })
+    });
+    response.headers_mut().insert(
+        HeaderName::from_static("sec-websocket-extensions"),
+        HeaderValue::from_static(RESPONSE),
+    );
+    response
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+/// The header of the frame at the start of `buf`, once the whole frame has arrived.
+fn complete_frame(buf: &[u8]) -> Option<Header> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let mut header_len = 2;
+    let payload_len = match buf[1] & 0x7f {
+        126 => {
+            header_len += 2;
+            u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as usize
+        }
+        127 => {
+            header_len += 8;
+            usize::try_from(u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?)).ok()?
+        }
+        len => len as usize,
+    };
+    let mask = if buf[1] & MASKED != 0 {
+        let mask: [u8; 4] = buf.get(header_len..header_len + 4)?.try_into().ok()?;
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    if buf.len() < header_len.checked_add(payload_len)? {
+        return None;
+    }
+    Some(Header {
+        fin: buf[0] & FIN != 0,
+        rsv1: buf[0] & RSV1 != 0,
+        opcode: buf[0] & OPCODE,
+        mask,
+        header_len,
+        payload_len,
+    })
+}
+
+fn write_frame(out: &mut BytesMut, first: u8, mask: Option<[u8; 4]>, payload: &[u8]) {
+    out.put_u8(first);
+    let masked = if mask.is_some() { MASKED } else { 0 };
+    match payload.len() {
+        len if len < 126 => out.put_u8(masked | len as u8),
+        len if len <= u16::MAX as usize => {
+            out.put_u8(masked | 126);
+            out.put_u16(len as u16);
+        }
+        len => {
+            out.put_u8(masked | 127);
+            out.put_u64(len as u64);
+        }
+    }
+    if let Some(mask) = mask {
+        out.put_slice(&mask);
+    }
+    out.put_slice(payload);
+}
+
+/// Compresses one message as RFC 7692 sends it: raw deflate, sync-flushed, without the tail.
+pub fn deflate(payload: &[u8]) -> Vec<u8> {
+    let mut deflater = Compress::new(Compression::default(), false);
+    let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+    loop {
+        let consumed = deflater.total_in() as usize;
+        deflater
+            .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+            .expect("compressing into a Vec cannot fail");
+        if deflater.total_in() as usize == payload.len() && out.len() < out.capacity() {
+            break;
+        }
+        out.reserve(out.capacity().max(64));
+    }
+    if out.ends_with(&TAIL) {
+        out.truncate(out.len() - TAIL.len());
+    }
+    out
+}
+
+/// Inflates one message, stopping once it is longer than `limit`.
+pub fn inflate(payload: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut input = Vec::with_capacity(payload.len() + TAIL.len());
+    input.extend_from_slice(payload);
+    input.extend_from_slice(&TAIL);
+
+    let mut inflater = Decompress::new(false);
+    let mut out = Vec::with_capacity((payload.len() * 4).max(64).min(limit.saturating_add(1)));
+    loop {
+        if out.len() == out.capacity() {
+            if out.len() > limit {
+                break;
+            }
+            out.reserve_exact((limit + 1 - out.len()).min(out.len()));
+        }
+        let (read, written) = (inflater.total_in(), inflater.total_out());
+        let status = inflater
+            .decompress_vec(&input[read as usize..], &mut out, FlushDecompress::Sync)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if status == Status::StreamEnd || (inflater.total_in() as usize == input.len() && out.len() < out.capacity()) {
+            break;
+        }
+        if inflater.total_in() == read && inflater.total_out() == written && out.len() < out.capacity() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated deflate stream"));
+        }
+    }
+    Ok(out)
+}
+
+/// Client frames on their way to actix-ws. Compressed messages, possibly fragmented, come
+/// out as one plain frame, masked with a zero key since actix-ws requires client frames
+/// to be masked; everything else is passed through untouched.
+struct Inflating {
+    inner: dev::Payload,
+    buf: BytesMut,
+    /// Opcode and compressed bytes of a fragmented message still being received.
+    message: Option<(u8, BytesMut)>,
+    max_frame_bytes: usize,
+}
+
+impl Inflating {
+    fn drain(&mut self) -> Result<BytesMut, PayloadError> {
+        let mut out = BytesMut::new();
+        while let Some(header) = complete_frame(&self.buf) {
+            let frame = self.buf.split_to(header.header_len + header.payload_len);
+            let starts_message = header.rsv1 && matches!(header.opcode, TEXT | BINARY);
+            let continues_message = header.opcode == CONTINUATION && self.message.is_some();
+            let Some(mask) = header.mask.filter(|_| starts_message || continues_message) else {
+                // Unmasked or reserved-bit frames are left for actix-ws to refuse
+                out.extend_from_slice(&frame);
+                continue;
+            };
+            if continues_message && header.rsv1 {
+                return Err(invalid("RSV1 set on a continuation frame"));
+            }
+            if starts_message && self.message.is_some() {
+                return Err(invalid("new message before the last one finished"));
+            }
+
+            let (opcode, mut compressed) = match self.message.take() {
+                Some(message) if continues_message => message,
+                _ => (header.opcode, BytesMut::new()),
+            };
+            compressed.extend(
+                frame[header.header_len..]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, byte)| byte ^ mask[i % 4]),
+            );
+
+            if compressed.len() > self.max_frame_bytes {
+                // Already too large to accept, whatever it inflates to
+                write_frame(&mut out, FIN | opcode, Some([0; 4]), &compressed);
+            } else if header.fin {
+                let inflated = inflate(&compressed, self.max_frame_bytes).map_err(PayloadError::Io)?;
+                write_frame(&mut out, FIN | opcode, Some([0; 4]), &inflated);
+            } else {
+                self.message = Some((opcode, compressed));
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn invalid(reason: &'static str) -> PayloadError {
+    PayloadError::Io(io::Error::new(io::ErrorKind::InvalidData, reason))
+}
+
+impl Stream for Inflating {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.buf.extend_from_slice(&chunk);
+                    match this.drain() {
+                        Ok(out) if out.is_empty() => continue,
+                        Ok(out) => return Poll::Ready(Some(Ok(out.freeze()))),
+                        Err(err) => return Poll::Ready(Some(Err(err))),
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+/// Server frames on their way to the socket. actix-ws writes each message as one frame,
+/// which is compressed with RSV1 set; fragments and control frames go out as they are.
+struct Deflating {
+    inner: BoxBody,
+    buf: BytesMut,
+}
+
+impl Deflating {
+    fn drain(&mut self) -> BytesMut {
+        let mut out = BytesMut::new();
+        while let Some(header) = complete_frame(&self.buf) {
+            let frame = self.buf.split_to(header.header_len + header.payload_len);
+            if header.fin && !header.rsv1 && matches!(header.opcode, TEXT | BINARY) {
+                let compressed = deflate(&frame[header.header_len..]);
+                write_frame(&mut out, FIN | RSV1 | header.opcode, None, &compressed);
+            } else {
+                out.extend_from_slice(&frame);
+            }
+        }
+        out
+    }
+}
+
+impl MessageBody for Deflating {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.buf.extend_from_slice(&chunk);
+                    let out = this.drain();
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(out.freeze())));
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) if this.buf.has_remaining() => {
+                    return Poll::Ready(Some(Ok(this.buf.split().freeze())));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn offering(extensions: &str) -> bool {
+        negotiate(&TestRequest::default().insert_header(("Sec-WebSocket-Extensions", extensions)).to_http_request())
+    }
+
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let masked: Vec<u8> = payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+        let mut out = BytesMut::new();
+        write_frame(&mut out, first, Some(mask), &masked);
+        out.to_vec()
+    }
+
+    fn inflating(max_frame_bytes: usize) -> Inflating {
+        Inflating {
+            inner: dev::Payload::None,
+            buf: BytesMut::new(),
+            message: None,
+            max_frame_bytes,
+        }
+    }
+
+    #[test]
+    fn offers_are_accepted_only_with_parameters_we_honour() {
+        assert!(!negotiate(&TestRequest::default().to_http_request()));
+        assert!(offering("permessage-deflate"));
+        assert!(offering("permessage-deflate; client_max_window_bits"));
+        assert!(offering("x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover"));
+        assert!(offering("permessage-deflate; server_max_window_bits=10, permessage-deflate"));
+        assert!(offering("permessage-deflate; client_max_window_bits=\"12\""));
+
+        assert!(!offering("x-webkit-deflate-frame"));
+        assert!(!offering("permessage-deflate; server_max_window_bits=10"));
+        assert!(!offering("permessage-deflate; client_max_window_bits=16"));
+        assert!(!offering("permessage-deflate; server_no_context_takeover; server_no_context_takeover"));
+        assert!(!offering("permessage-deflate; unknown_parameter"));
+    }
+
+    #[test]
+    fn messages_round_trip_without_the_flush_tail() {
+        let payload = br#"{"type":"message","data":{"content":"hello hello hello hello"}}"#;
+        let compressed = deflate(payload);
+        assert!(!compressed.ends_with(&TAIL));
+        assert!(compressed.len() < payload.len());
+        assert_eq!(inflate(&compressed, 1024).unwrap(), payload);
+        assert_eq!(inflate(&deflate(b""), 1024).unwrap(), b"");
+    }
+
+    #[test]
+    fn inflating_stops_past_the_limit() {
+        let bomb = deflate(&vec![b'a'; 1 << 20]);
+        assert_eq!(inflate(&bomb, 1000).unwrap().len(), 1001);
+        assert!(inflate(b"\xff\xff\xff", 1000).is_err());
+    }
+
+    #[test]
+    fn compressed_client_messages_reach_actix_as_plain_frames() {
+        let mut payload = inflating(1024);
+        let compressed = deflate(b"hello world");
+        let (head, tail) = compressed.split_at(4);
+        payload.buf.extend_from_slice(&client_frame(RSV1 | TEXT, head));
+        payload.buf.extend_from_slice(&client_frame(FIN | 0x9, b"ping"));
+        payload.buf.extend_from_slice(&client_frame(FIN | CONTINUATION, tail));
+
+        let out = payload.drain().unwrap();
+        let ping = complete_frame(&out).unwrap();
+        assert_eq!((ping.opcode, ping.rsv1), (0x9, false));
+        let rest = &out[ping.header_len + ping.payload_len..];
+        let text = complete_frame(rest).unwrap();
+        assert_eq!((text.fin, text.rsv1, text.opcode, text.mask), (true, false, TEXT, Some([0; 4])));
+        assert_eq!(&rest[text.header_len..], b"hello world");
+        assert!(payload.buf.is_empty() && payload.message.is_none());
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let mut payload = inflating(1024);
+        let frame = client_frame(FIN | RSV1 | BINARY, &deflate(b"abc"));
+        payload.buf.extend_from_slice(&frame[..3]);
+        assert!(payload.drain().unwrap().is_empty());
+        payload.buf.extend_from_slice(&frame[3..]);
+        let out = payload.drain().unwrap();
+        assert_eq!(&out[complete_frame(&out).unwrap().header_len..], b"abc");
+    }
+
+    #[test]
+    fn server_messages_go_out_compressed_with_rsv1() {
+        let mut body = Deflating {
+            inner: BoxBody::new(()),
+            buf: BytesMut::new(),
+        };
+        write_frame(&mut body.buf, FIN | TEXT, None, b"hello hello hello");
+        write_frame(&mut body.buf, FIN | 0x9, None, b"");
+
+        let out = body.drain();
+        let text = complete_frame(&out).unwrap();
+        assert_eq!((text.fin, text.rsv1, text.opcode, text.mask), (true, true, TEXT, None));
+        let payload = &out[text.header_len..text.header_len + text.payload_len];
+        assert_eq!(inflate(payload, 1024).unwrap(), b"hello hello hello");
+        let ping = complete_frame(&out[text.header_len + text.payload_len..]).unwrap();
+        assert_eq!((ping.rsv1, ping.opcode, ping.payload_len), (false, 0x9, 0));
+    }
+}
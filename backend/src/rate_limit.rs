@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::events::ClientEvent;
+
+/// Token bucket settings for one class of client events.
+#[derive(Debug, Clone, Copy)]
+pub struct BucketConfig {
+    /// Frames that may be sent in a burst.
+    pub burst: f64,
+    /// Tokens regained per second.
+    pub per_second: f64,
+}
+
+/// Limits applied to every WebSocket connection, read once at startup.
+#[derive(Debug, Clone)]
+pub struct WsLimits {
+    pub max_frame_bytes: usize,
+    pub max_message_chars: usize,
+    pub buckets: HashMap<&'static str, BucketConfig>,
+    /// Violations inside this window count towards escalation.
+    pub strike_window: Duration,
+    /// From this many violations on, dropped frames are answered with `rate_limited`.
+    pub warn_after: u32,
+    /// At this many violations the connection is closed.
+    pub disconnect_after: u32,
+}
+
+/// Event classes that share a bucket, with their default burst and refill rate.
+const DEFAULT_BUCKETS: &[(&str, f64, f64)] = &[
+    ("message", 5.0, 1.0),
+    ("typing", 3.0, 0.5),
+    ("presence", 5.0, 0.2),
+    // ICE candidates arrive in bursts while a call is set up
+    ("voice", 60.0, 20.0),
+    ("other", 10.0, 2.0),
+];
+
+pub(crate) fn env_or<T: std::str::FromStr + PartialOrd + Default>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<T>().ok())
+        .filter(|v| *v > T::default())
+        .unwrap_or(default)
+}
+
+impl WsLimits {
+    /// Reads `WS_MAX_FRAME_BYTES`, `MAX_MESSAGE_CHARS`, `WS_RATE_<CLASS>_BURST`,
+    /// `WS_RATE_<CLASS>_PER_SEC`, `WS_RATE_STRIKE_WINDOW_MS`, `WS_RATE_WARN_AFTER`
+    /// and `WS_RATE_DISCONNECT_AFTER`.
+    pub fn from_env() -> WsLimits {
+        let buckets = DEFAULT_BUCKETS
+            .iter()
+            .map(|(class, burst, per_second)| {
+                let prefix = format!("WS_RATE_{}", class.to_uppercase());
+                let config = BucketConfig {
+                    burst: env_or(&format!("{}_BURST", prefix), *burst),
+                    per_second: env_or(&format!("{}_PER_SEC", prefix), *per_second),
+                };
+                (*class, config)
+            })
+            .collect();
+
+        let warn_after = env_or("WS_RATE_WARN_AFTER", 3u32);
+        WsLimits {
+            max_frame_bytes: env_or("WS_MAX_FRAME_BYTES", 64 * 1024usize),
+            max_message_chars: env_or("MAX_MESSAGE_CHARS", 4000usize),
+            buckets,
+            strike_window: Duration::from_millis(env_or("WS_RATE_STRIKE_WINDOW_MS", 10_000u64)),
+            warn_after,
+            disconnect_after: env_or("WS_RATE_DISCONNECT_AFTER", 20u32).max(warn_after),
+        }
+    }
+}
+
+/// Bucket class for a parsed event; frames that fail to parse use `other`.
+pub fn event_class(event: &ClientEvent) -> &'static str {
+    match event {
+        ClientEvent::Message(_) => "message",
+        ClientEvent::Typing(_) => "typing",
+        ClientEvent::Presence(_) => "presence",
+        ClientEvent::VoiceJoin(_)
+        | ClientEvent::VoiceLeave(_)
+        | ClientEvent::VoiceState(_)
+        | ClientEvent::VoiceSignal(_) => "voice",
+        ClientEvent::Join(_) | ClientEvent::Leave(_) | ClientEvent::Heartbeat(_) => "other",
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What to do with a frame after it went through the limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit: ignore the frame, telling only a `message` that waits for its ack.
+    Drop { retry_after_ms: u64 },
+    /// Over the limit repeatedly: ignore the frame and tell the client.
+    Warn { retry_after_ms: u64 },
+    /// Kept going after warnings: close the connection.
+    Disconnect,
+}
+
+/// Per-connection limiter state.
+pub struct ConnectionLimiter {
+    limits: WsLimits,
+    buckets: HashMap<&'static str, TokenBucket>,
+    strikes: u32,
+    first_strike: Option<Instant>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: WsLimits) -> ConnectionLimiter {
+        ConnectionLimiter {
+            limits,
+            buckets: HashMap::new(),
+            strikes: 0,
+            first_strike: None,
+        }
+    }
+
+    pub fn check(&mut self, class: &'static str) -> Verdict {
+        let Some(config) = self.limits.buckets.get(class).copied() else {
+            return Verdict::Allow;
+        };
+
+        let now = Instant::now();
+        let bucket = self.buckets.entry(class).or_insert(TokenBucket {
+            tokens: config.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * config.per_second).min(config.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Verdict::Allow;
+        }
+        let retry_after_ms = (((1.0 - bucket.tokens) / config.per_second) * 1000.0).ceil() as u64;
+
+        if self
+            .first_strike
+            .is_none_or(|first| now.duration_since(first) > self.limits.strike_window)
+        {
+            self.first_strike = Some(now);
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+
+        if self.strikes >= self.limits.disconnect_after {
+            Verdict::Disconnect
+        } else if self.strikes >= self.limits.warn_after {
+            Verdict::Warn { retry_after_ms }
+        } else {
+            Verdict::Drop { retry_after_ms }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(burst: f64, per_second: f64) -> WsLimits {
+        WsLimits {
+            max_frame_bytes: 64 * 1024,
+            max_message_chars: 4000,
+            buckets: HashMap::from([
+                ("message", BucketConfig { burst, per_second }),
+                ("typing", BucketConfig { burst, per_second }),
+            ]),
+            strike_window: Duration::from_secs(60),
+            warn_after: 3,
+            disconnect_after: 5,
+        }
+    }
+
+    #[test]
+    fn burst_is_allowed_then_dropped() {
+        let mut limiter = ConnectionLimiter::new(limits(3.0, 1.0));
+        for _ in 0..3 {
+            assert_eq!(limiter.check("message"), Verdict::Allow);
+        }
+        assert!(matches!(limiter.check("message"), Verdict::Drop { retry_after_ms } if retry_after_ms > 0));
+    }
+
+    #[test]
+    fn repeated_violations_escalate() {
+        let mut limiter = ConnectionLimiter::new(limits(1.0, 0.001));
+        assert_eq!(limiter.check("message"), Verdict::Allow);
+        assert!(matches!(limiter.check("message"), Verdict::Drop { .. }));
+        assert!(matches!(limiter.check("message"), Verdict::Drop { .. }));
+        match limiter.check("message") {
+            Verdict::Warn { retry_after_ms } => assert!(retry_after_ms > 1000),
+            verdict => panic!("expected a warning, got {:?}", verdict),
+        }
+        assert!(matches!(limiter.check("message"), Verdict::Warn { .. }));
+        assert_eq!(limiter.check("message"), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn strikes_are_shared_across_classes() {
+        let mut limiter = ConnectionLimiter::new(limits(1.0, 0.001));
+        assert_eq!(limiter.check("message"), Verdict::Allow);
+        assert_eq!(limiter.check("typing"), Verdict::Allow);
+        assert!(matches!(limiter.check("message"), Verdict::Drop { .. }));
+        assert!(matches!(limiter.check("typing"), Verdict::Drop { .. }));
+        assert!(matches!(limiter.check("typing"), Verdict::Warn { .. }));
+    }
+
+    #[test]
+    fn strikes_expire_after_the_window() {
+        let mut config = limits(1.0, 0.001);
+        config.strike_window = Duration::from_millis(20);
+        let mut limiter = ConnectionLimiter::new(config);
+        assert_eq!(limiter.check("message"), Verdict::Allow);
+        assert!(matches!(limiter.check("message"), Verdict::Drop { .. }));
+        assert!(matches!(limiter.check("message"), Verdict::Drop { .. }));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(matches!(limiter.check("message"), Verdict::Drop { .. }));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut limiter = ConnectionLimiter::new(limits(1.0, 1000.0));
+        assert_eq!(limiter.check("message"), Verdict::Allow);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(limiter.check("message"), Verdict::Allow);
+    }
+
+    #[test]
+    fn unknown_classes_are_not_limited() {
+        let mut limiter = ConnectionLimiter::new(limits(1.0, 0.001));
+        for _ in 0..100 {
+            assert_eq!(limiter.check("voice"), Verdict::Allow);
+        }
+    }
+}
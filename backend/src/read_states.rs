@@ -0,0 +1,230 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{Row, SqlitePool};
+use std::collections::HashSet;
+
+use crate::auth::extract_claims;
+use crate::bots::Scope;
+use crate::errors::ErrorCode;
+use crate::events::{self, ReadStateUpdatedEvent, ServerEvent};
+use crate::mentions::MENTIONING_MESSAGES_SQL;
+use crate::ws::{can_user_access_room_cached, AccessCache, Broadcaster};
+
+#[derive(Debug, Deserialize)]
+pub struct AckInput {
+    /// Defaults to the latest message in the room.
+    pub message_id: Option<String>,
+}
+
+/// Read state and unread counts of every room `user_id` can access, or just `room_id`.
+/// Messages of the user themselves never count as unread.
+pub(crate) async fn fetch_read_states(
+    pool: &SqlitePool,
+    user_id: &str,
+    role: &str,
+    room_id: Option<&str>,
+) -> Vec<ReadStateUpdatedEvent> {
+    let sql = format!(
+        "SELECT r.id AS room_id, rs.last_read_message_id, rs.last_read_at, \
+             (SELECT COUNT(*) FROM messages m WHERE m.room_id = r.id AND m.user_id != ? \
+                 AND (rs.last_read_at IS NULL OR m.created_at > rs.last_read_at)) AS unread_count, \
+             (SELECT COUNT(*) FROM messages m WHERE m.room_id = r.id AND m.user_id != ? \
+                 AND (rs.last_read_at IS NULL OR m.created_at > rs.last_read_at) \
+                 AND m.id IN ({})) AS mention_count \
+         FROM rooms r LEFT JOIN read_states rs ON rs.room_id = r.id AND rs.user_id = ? \
+         WHERE (? = 'admin' OR r.required_role = 'user' OR r.required_role = ?) \
+         AND (? IS NULL OR r.id = ?) \
+         ORDER BY r.created_at",
+        MENTIONING_MESSAGES_SQL
+    );
+
+    let rows = sqlx::query(&sql)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(role)
+        .bind(user_id)
+        .bind(role)
+        .bind(role)
+        .bind(room_id)
+        .bind(room_id)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+
+    rows.iter()
+        .map(|row| ReadStateUpdatedEvent {
+            room_id: row.try_get("room_id").unwrap_or_default(),
+            last_read_message_id: row.try_get("last_read_message_id").unwrap_or(None),
+            last_read_at: row.try_get("last_read_at").unwrap_or(None),
+            unread_count: row.try_get("unread_count").unwrap_or(0),
+            mention_count: row.try_get("mention_count").unwrap_or(0),
+        })
+        .collect()
+}
+
+/// GET /api/users/me/read-states — Unread and mention counts for every accessible room
+pub async fn get_my_read_states(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
+
+    let states = fetch_read_states(pool.get_ref(), &claims.sub, &claims.role, None).await;
+    HttpResponse::Ok().json(states)
+}
+
+/// POST /api/rooms/{id}/ack — Mark a room read up to a message; markers only move forward
+pub async fn ack_room(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: Option<web::Json<AckInput>>,
+    broadcaster: web::Data<Broadcaster>,
+    access_cache: web::Data<AccessCache>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
+    }
+
+    let room_id = path.into_inner();
+    if !can_user_access_room_cached(pool.get_ref(), access_cache.get_ref(), &claims.sub, &room_id).await {
+        return ErrorCode::RoomAccessDenied.respond(&req);
+    }
+
+    let message_id = body
+        .and_then(|b| b.into_inner().message_id)
+        .filter(|id| !id.is_empty());
+
+    let target: Option<(String, String)> = match &message_id {
+        Some(id) => sqlx::query_as("SELECT id, created_at FROM messages WHERE id = ? AND room_id = ?")
+            .bind(id)
+            .bind(&room_id)
+            .fetch_optional(pool.get_ref())
+            .await
+            .unwrap_or(None),
+        None => sqlx::query_as("SELECT id, created_at FROM messages WHERE room_id = ? ORDER BY created_at DESC LIMIT 1")
+            .bind(&room_id)
+            .fetch_optional(pool.get_ref())
+            .await
+            .unwrap_or(None),
+    };
+
+    if message_id.is_some() && target.is_none() {
+        return ErrorCode::MessageNotFound.respond(&req);
+    }
+
+    if let Some((target_id, target_at)) = target {
+        let result = sqlx::query(
+            "INSERT INTO read_states (user_id, room_id, last_read_message_id, last_read_at, updated_at) \
+             VALUES (?, ?, ?, ?, datetime('now')) \
+             ON CONFLICT(user_id, room_id) DO UPDATE SET \
+                 last_read_message_id = excluded.last_read_message_id, \
+                 last_read_at = excluded.last_read_at, \
+                 updated_at = excluded.updated_at \
+             WHERE excluded.last_read_at >= read_states.last_read_at",
+        )
+        .bind(&claims.sub)
+        .bind(&room_id)
+        .bind(&target_id)
+        .bind(&target_at)
+        .execute(pool.get_ref())
+        .await;
+
+        let moved = match result {
+            Ok(res) => res.rows_affected() > 0,
+            Err(_) => return ErrorCode::InternalError.respond(&req),
+        };
+
+        if moved {
+            if let Some(state) = fetch_read_states(pool.get_ref(), &claims.sub, &claims.role, Some(&room_id)).await.pop() {
+                // Keeps the user's other devices in sync
+                let event = ServerEvent::ReadStateUpdated(state.clone());
+                events::broadcast_to(broadcaster.get_ref(), &event, HashSet::from([claims.sub.clone()]));
+                return HttpResponse::Ok().json(state);
+            }
+        }
+    }
+
+    match fetch_read_states(pool.get_ref(), &claims.sub, &claims.role, Some(&room_id)).await.pop() {
+        Some(state) => HttpResponse::Ok().json(state),
+        None => ErrorCode::RoomNotFound.respond(&req),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_state_pool() -> SqlitePool {
+        let pool = crate::db::test_pool().await;
+        for sql in [
+            "INSERT INTO users (id, username, password_hash) VALUES ('alice', 'alice', ''), ('bob', 'bob', '')",
+            "INSERT INTO rooms (id, name, required_role) VALUES ('staff', 'staff', 'admin')",
+            "INSERT INTO messages (id, room_id, user_id, username, content, created_at) VALUES \
+             ('m1', 'general', 'bob', 'bob', 'hi', '2026-01-01T10:00:00Z'), \
+             ('m2', 'general', 'alice', 'alice', 'hello', '2026-01-01T10:01:00Z'), \
+             ('m3', 'general', 'bob', 'bob', '<@alice>', '2026-01-01T10:02:00Z'), \
+             ('m4', 'general', 'bob', 'bob', '@everyone', '2026-01-01T10:03:00Z'), \
+             ('s1', 'staff', 'bob', 'bob', 'secret', '2026-01-01T10:00:00Z')",
+            "INSERT INTO message_mentions (message_id, kind, target) VALUES ('m3', 'user', 'alice'), ('m4', 'everyone', '')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    fn counts(states: &[ReadStateUpdatedEvent], room_id: &str) -> Option<(i64, i64)> {
+        states
+            .iter()
+            .find(|s| s.room_id == room_id)
+            .map(|s| (s.unread_count, s.mention_count))
+    }
+
+    #[tokio::test]
+    async fn everything_from_others_is_unread_without_a_marker() {
+        let pool = read_state_pool().await;
+        let states = fetch_read_states(&pool, "alice", "user", None).await;
+        // Alice's own message does not count
+        assert_eq!(counts(&states, "general"), Some((3, 2)));
+        assert!(states[0].last_read_message_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_messages_after_the_marker_are_unread() {
+        let pool = read_state_pool().await;
+        sqlx::query(
+            "INSERT INTO read_states (user_id, room_id, last_read_message_id, last_read_at) \
+             VALUES ('alice', 'general', 'm3', '2026-01-01T10:02:00Z')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let states = fetch_read_states(&pool, "alice", "user", Some("general")).await;
+        assert_eq!(states.len(), 1);
+        assert_eq!(counts(&states, "general"), Some((1, 1)));
+        assert_eq!(states[0].last_read_message_id.as_deref(), Some("m3"));
+
+        // Bob has not read anything; his own messages and the mentions of alice do not count
+        let states = fetch_read_states(&pool, "bob", "user", Some("general")).await;
+        assert_eq!(counts(&states, "general"), Some((1, 0)));
+    }
+
+    #[tokio::test]
+    async fn restricted_rooms_are_only_listed_for_their_role() {
+        let pool = read_state_pool().await;
+        let states = fetch_read_states(&pool, "alice", "user", None).await;
+        assert_eq!(counts(&states, "staff"), None);
+
+        let states = fetch_read_states(&pool, "alice", "admin", None).await;
+        assert_eq!(counts(&states, "staff"), Some((1, 0)));
+    }
+}
@@ -1,4 +1,4 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use base64::{engine::general_purpose, Engine};
 use futures_util::{SinkExt, StreamExt};
 use rsa::{pkcs8::EncodePublicKey, rand_core::OsRng, Oaep, RsaPrivateKey, RsaPublicKey};
@@ -10,6 +10,9 @@ use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
 use tokio_tungstenite::tungstenite::Message;
 
+use crate::errors::ErrorCode;
+use crate::ws::Broadcaster;
+
 const DISCORD_REMOTE_AUTH_GATEWAY: &str = "wss://remote-auth-gateway.discord.gg/?v=2";
 const DISCORD_REMOTE_AUTH_LOGIN_API: &str =
     "https://discord.com/api/v9/users/@me/remote-auth/login";
@@ -66,6 +69,7 @@ pub struct CancelPayload {
 pub async fn start_qr_session(
     pool: web::Data<SqlitePool>,
     sessions: web::Data<QrAuthSessions>,
+    broadcaster: web::Data<Broadcaster>,
 ) -> HttpResponse {
     let session_id = uuid::Uuid::new_v4().to_string();
     let (cancel_tx, cancel_rx) = mpsc::channel(1);
@@ -94,15 +98,17 @@ pub async fn start_qr_session(
 
     let sessions_clone = sessions.get_ref().clone();
     let pool_clone = pool.get_ref().clone();
+    let broadcaster_clone = broadcaster.get_ref().clone();
     let sid = session_id.clone();
     tokio::spawn(async move {
-        run_remote_auth_flow(sid, sessions_clone, pool_clone, cancel_rx).await;
+        run_remote_auth_flow(sid, sessions_clone, pool_clone, broadcaster_clone, cancel_rx).await;
     });
 
     HttpResponse::Ok().json(serde_json::json!({ "session_id": session_id }))
 }
 
 pub async fn get_qr_status(
+    req: HttpRequest,
     sessions: web::Data<QrAuthSessions>,
     query: web::Query<SessionQuery>,
 ) -> HttpResponse {
@@ -110,11 +116,12 @@ pub async fn get_qr_status(
     if let Some(session) = map.get(&query.session_id) {
         HttpResponse::Ok().json(&session.status)
     } else {
-        HttpResponse::NotFound().json(serde_json::json!({ "error": "Session introuvable" }))
+        ErrorCode::QrSessionNotFound.respond(&req)
     }
 }
 
 pub async fn cancel_qr_session(
+    req: HttpRequest,
     sessions: web::Data<QrAuthSessions>,
     body: web::Json<CancelPayload>,
 ) -> HttpResponse {
@@ -126,7 +133,7 @@ pub async fn cancel_qr_session(
         session.status = QrStatus::Cancelled;
         HttpResponse::Ok().json(serde_json::json!({ "ok": true }))
     } else {
-        HttpResponse::NotFound().json(serde_json::json!({ "error": "Session introuvable" }))
+        ErrorCode::QrSessionNotFound.respond(&req)
     }
 }
 
@@ -159,6 +166,7 @@ async fn run_remote_auth_flow(
     session_id: String,
     sessions: QrAuthSessions,
     pool: SqlitePool,
+    broadcaster: Broadcaster,
     mut cancel_rx: mpsc::Receiver<()>,
 ) {
     // Generate RSA-OAEP 2048 key pair
@@ -444,6 +452,7 @@ async fn run_remote_auth_flow(
                                     &ticket,
                                     &private_key,
                                     &pool,
+                                    &broadcaster,
                                 )
                                 .await
                                 {
@@ -481,6 +490,7 @@ async fn run_remote_auth_flow(
                                         enc_token,
                                         &private_key,
                                         &pool,
+                                        &broadcaster,
                                     )
                                     .await
                                     {
@@ -571,6 +581,7 @@ async fn decrypt_and_login(
     encrypted_token_b64: &str,
     private_key: &RsaPrivateKey,
     pool: &SqlitePool,
+    broadcaster: &Broadcaster,
 ) -> Result<serde_json::Value, String> {
     let encrypted = general_purpose::STANDARD
         .decode(encrypted_token_b64)
@@ -591,7 +602,7 @@ async fn decrypt_and_login(
         return Err("Empty token after decryption".into());
     }
 
-    let auth = crate::auth::do_discord_token_login(pool, &discord_token)
+    let auth = crate::auth::do_discord_token_login(pool, broadcaster, &discord_token)
         .await
         .map_err(|e| format!("Login failed: {e}"))?;
 
@@ -602,6 +613,7 @@ async fn finalize_with_ticket(
     ticket: &str,
     private_key: &RsaPrivateKey,
     pool: &SqlitePool,
+    broadcaster: &Broadcaster,
 ) -> Result<serde_json::Value, String> {
     let client = reqwest::Client::new();
     let resp = client
@@ -625,13 +637,13 @@ async fn finalize_with_ticket(
         .map_err(|e| format!("Bad Discord response: {e}"))?;
 
     if let Some(enc) = body.get("encrypted_token").and_then(|v| v.as_str()) {
-        return decrypt_and_login(enc, private_key, pool).await;
+        return decrypt_and_login(enc, private_key, pool, broadcaster).await;
     }
 
     if let Some(tok) = body.get("token").and_then(|v| v.as_str()) {
         let t = tok.trim();
         if !t.is_empty() {
-            let auth = crate::auth::do_discord_token_login(pool, t)
+            let auth = crate::auth::do_discord_token_login(pool, broadcaster, t)
                 .await
                 .map_err(|e| format!("Login failed: {e}"))?;
             return Ok(serde_json::to_value(auth).unwrap_or_default());
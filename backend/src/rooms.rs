@@ -3,7 +3,10 @@ use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use uuid::Uuid;
 use crate::auth::extract_claims;
-use crate::ws::{cache_remove_room, cache_set_room_required_role, AccessCache, Broadcaster};
+use crate::bots::Scope;
+use crate::errors::ErrorCode;
+use crate::events::{self, RoomDeletedEvent, RoomUpdatedEvent, ServerEvent, TypingEvent};
+use crate::ws::{cache_remove_room, cache_set_room_required_role, can_user_access_room_cached, AccessCache, Broadcaster};
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Room {
@@ -11,6 +14,7 @@ pub struct Room {
     pub name: String,
     pub kind: String,
     pub required_role: String,
+    pub topic: Option<String>,
     pub created_at: String,
 }
 
@@ -26,23 +30,73 @@ pub struct UpdateRoomSettings {
     pub name: String,
     pub kind: String,
     pub required_role: String,
+    /// Left unchanged when omitted, cleared with an empty string.
+    pub topic: Option<String>,
+}
+
+pub(crate) const MAX_TOPIC_CHARS: usize = 256;
+
+/// Trim a topic; empty means no topic.
+pub(crate) fn clean_topic(raw: &str) -> Result<Option<String>, ErrorCode> {
+    let topic = raw.trim();
+    if topic.chars().count() > MAX_TOPIC_CHARS {
+        return Err(ErrorCode::InvalidTopic);
+    }
+    Ok(Some(topic.to_string()).filter(|t| !t.is_empty()))
+}
+
+/// Set a room's topic and broadcast the updated room; used by `/topic`.
+pub(crate) async fn set_room_topic(
+    pool: &SqlitePool,
+    broadcaster: &Broadcaster,
+    room_id: &str,
+    topic: Option<&str>,
+) -> Result<(), ErrorCode> {
+    let result = sqlx::query("UPDATE rooms SET topic = ? WHERE id = ?")
+        .bind(topic)
+        .bind(room_id)
+        .execute(pool)
+        .await;
+    if !matches!(result, Ok(ref res) if res.rows_affected() > 0) {
+        return Err(ErrorCode::RoomNotFound);
+    }
+
+    let room = sqlx::query_as::<_, Room>("SELECT id, name, kind, required_role, topic, created_at FROM rooms WHERE id = ?")
+        .bind(room_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)
+        .ok_or(ErrorCode::RoomNotFound)?;
+
+    let event = ServerEvent::RoomUpdated(RoomUpdatedEvent {
+        room_id: room.id,
+        name: room.name,
+        kind: room.kind,
+        required_role: room.required_role,
+        topic: room.topic,
+    });
+    events::broadcast(broadcaster, &event);
+    Ok(())
 }
 
 /// GET /api/rooms — List all rooms
 pub async fn list_rooms(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
 
     let rooms = if claims.role == "admin" {
-        sqlx::query_as::<_, Room>("SELECT id, name, kind, required_role, created_at FROM rooms ORDER BY created_at")
+        sqlx::query_as::<_, Room>("SELECT id, name, kind, required_role, topic, created_at FROM rooms ORDER BY created_at")
             .fetch_all(pool.get_ref())
             .await
             .unwrap_or_default()
     } else {
         sqlx::query_as::<_, Room>(
-            "SELECT id, name, kind, required_role, created_at FROM rooms WHERE required_role = 'user' OR required_role = ? ORDER BY created_at"
+            "SELECT id, name, kind, required_role, topic, created_at FROM rooms WHERE required_role = 'user' OR required_role = ? ORDER BY created_at"
         )
         .bind(&claims.role)
         .fetch_all(pool.get_ref())
@@ -62,17 +116,20 @@ pub async fn create_room(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     let name = body.name.trim();
     if name.is_empty() {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Room name is required" }));
+        return ErrorCode::RoomNameRequired.respond(&req);
     }
 
     let kind = body.kind.as_deref().unwrap_or("text").trim().to_lowercase();
     if kind != "text" && kind != "voice" {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Room kind must be text or voice" }));
+        return ErrorCode::InvalidRoomKind.respond(&req);
     }
 
     let required_role = body.required_role.as_deref().unwrap_or("user").trim().to_lowercase();
@@ -84,11 +141,11 @@ pub async fn create_room(
         .unwrap_or(0);
 
     if role_exists <= 0 {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid required role" }));
+        return ErrorCode::InvalidRole.respond(&req);
     }
 
     if required_role != "user" && claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Only admins can create restricted rooms" }));
+        return ErrorCode::RestrictedRoomAdminOnly.respond(&req);
     }
 
     let id = Uuid::new_v4().to_string();
@@ -106,7 +163,7 @@ pub async fn create_room(
             cache_set_room_required_role(access_cache.get_ref(), &id, &required_role);
             HttpResponse::Ok().json(serde_json::json!({ "id": id, "name": name, "kind": kind, "required_role": required_role }))
         }
-        Err(_) => HttpResponse::Conflict().json(serde_json::json!({ "error": "Room name already exists" })),
+        Err(_) => ErrorCode::RoomNameTaken.respond(&req),
     }
 }
 
@@ -121,22 +178,25 @@ pub async fn update_room(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let room_id = path.into_inner();
     let room_name = body.name.trim();
     if room_name.is_empty() {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Room name is required" }));
+        return ErrorCode::RoomNameRequired.respond(&req);
     }
 
     let kind = body.kind.trim().to_lowercase();
     if kind != "text" && kind != "voice" {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Room kind must be text or voice" }));
+        return ErrorCode::InvalidRoomKind.respond(&req);
     }
 
     let required_role = body.required_role.trim().to_lowercase();
@@ -148,37 +208,53 @@ pub async fn update_room(
         .unwrap_or(0);
 
     if role_exists <= 0 {
-        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid required role" }));
+        return ErrorCode::InvalidRole.respond(&req);
     }
 
-    let result = sqlx::query("UPDATE rooms SET name = ?, kind = ?, required_role = ? WHERE id = ?")
-        .bind(room_name)
-        .bind(&kind)
-        .bind(&required_role)
-        .bind(&room_id)
-        .execute(pool.get_ref())
-        .await;
+    let topic = match body.topic.as_deref().map(clean_topic).transpose() {
+        Ok(topic) => topic,
+        Err(code) => return code.respond(&req),
+    };
+
+    let result = sqlx::query(
+        "UPDATE rooms SET name = ?, kind = ?, required_role = ?, topic = CASE WHEN ? THEN ? ELSE topic END WHERE id = ?",
+    )
+    .bind(room_name)
+    .bind(&kind)
+    .bind(&required_role)
+    .bind(topic.is_some())
+    .bind(topic.clone().flatten())
+    .bind(&room_id)
+    .execute(pool.get_ref())
+    .await;
 
     match result {
         Ok(res) => {
             if res.rows_affected() == 0 {
-                return HttpResponse::NotFound().json(serde_json::json!({ "error": "Room not found" }));
+                return ErrorCode::RoomNotFound.respond(&req);
             }
 
             cache_set_room_required_role(access_cache.get_ref(), &room_id, &required_role);
 
-            let event = serde_json::json!({
-                "type": "room_updated",
-                "room_id": room_id,
-                "name": room_name,
-                "kind": kind,
-                "required_role": required_role,
+            let topic: Option<String> = sqlx::query_scalar("SELECT topic FROM rooms WHERE id = ?")
+                .bind(&room_id)
+                .fetch_optional(pool.get_ref())
+                .await
+                .unwrap_or(None)
+                .flatten();
+
+            let event = ServerEvent::RoomUpdated(RoomUpdatedEvent {
+                room_id,
+                name: room_name.to_string(),
+                kind,
+                required_role,
+                topic,
             });
-            let _ = broadcaster.send(event.to_string());
+            events::broadcast(broadcaster.get_ref(), &event);
 
             HttpResponse::Ok().json(serde_json::json!({ "status": "updated" }))
         }
-        Err(_) => HttpResponse::Conflict().json(serde_json::json!({ "error": "Room name already exists" })),
+        Err(_) => ErrorCode::RoomNameTaken.respond(&req),
     }
 }
 
@@ -192,11 +268,14 @@ pub async fn delete_room(
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
 
     if claims.role != "admin" {
-        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin only" }));
+        return ErrorCode::AdminOnly.respond(&req);
     }
 
     let room_id = path.into_inner();
@@ -218,16 +297,44 @@ pub async fn delete_room(
                 cache_remove_room(access_cache.get_ref(), &room_id);
 
                 // Broadcast room_deleted event
-                let msg = serde_json::json!({
-                    "type": "room_deleted",
-                    "room_id": room_id
-                });
-                let _ = broadcaster.send(msg.to_string());
+                let event = ServerEvent::RoomDeleted(RoomDeletedEvent { room_id });
+                events::broadcast(broadcaster.get_ref(), &event);
                 HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
             } else {
-                HttpResponse::NotFound().json(serde_json::json!({ "error": "Room not found" }))
+                ErrorCode::RoomNotFound.respond(&req)
             }
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(_) => ErrorCode::InternalError.respond(&req),
     }
 }
+
+/// POST /api/rooms/{id}/typing — Typing indicator, same as a `typing` frame on /ws
+pub async fn send_typing(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    broadcaster: web::Data<Broadcaster>,
+    access_cache: web::Data<AccessCache>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
+    }
+
+    let room_id = path.into_inner();
+    if !can_user_access_room_cached(pool.get_ref(), access_cache.get_ref(), &claims.sub, &room_id).await {
+        return ErrorCode::RoomAccessDenied.respond(&req);
+    }
+
+    let event = ServerEvent::Typing(TypingEvent {
+        room_id,
+        user_id: Some(claims.sub),
+        username: Some(claims.username),
+    });
+    events::broadcast(&broadcaster, &event);
+
+    HttpResponse::NoContent().finish()
+}
@@ -0,0 +1,144 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+
+use crate::auth::extract_claims;
+use crate::bots::Scope;
+use crate::errors::{ApiError, ErrorCode};
+
+const KEEP_UPLOAD_METADATA_ROLES: &str = "keep_upload_metadata_roles";
+const ROLE_UPLOAD_QUOTAS: &str = "role_upload_quotas";
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ServerSettings {
+    /// Roles whose photo uploads are stored as sent, metadata included.
+    pub keep_upload_metadata_roles: Vec<String>,
+    /// Storage limit in bytes of each member of a role; roles not listed have none.
+    pub role_upload_quotas: HashMap<String, i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateServerSettings {
+    pub keep_upload_metadata_roles: Option<Vec<String>>,
+    /// Replaces every role quota.
+    pub role_upload_quotas: Option<HashMap<String, i64>>,
+}
+
+fn setting<T: DeserializeOwned + Default>(values: &HashMap<String, String>, key: &str) -> T {
+    values
+        .get(key)
+        .and_then(|value| serde_json::from_str(value).ok())
+        .unwrap_or_default()
+}
+
+/// Current settings; anything never set, or unreadable, takes its default.
+pub(crate) async fn load_server_settings(pool: &SqlitePool) -> ServerSettings {
+    let values: HashMap<String, String> = sqlx::query("SELECT key, value FROM server_settings")
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default()
+        .iter()
+        .map(|row| (row.get("key"), row.get("value")))
+        .collect();
+
+    ServerSettings {
+        keep_upload_metadata_roles: setting(&values, KEEP_UPLOAD_METADATA_ROLES),
+        role_upload_quotas: setting(&values, ROLE_UPLOAD_QUOTAS),
+    }
+}
+
+async fn store_setting<T: Serialize>(pool: &SqlitePool, key: &str, value: &T) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO server_settings (key, value) VALUES (?, ?) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = datetime('now')",
+    )
+    .bind(key)
+    .bind(serde_json::to_string(value).unwrap_or_default())
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+async fn role_exists(pool: &SqlitePool, name: &str) -> bool {
+    sqlx::query("SELECT 1 FROM roles WHERE name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)
+        .is_some()
+}
+
+/// GET /api/server/settings — Server-wide settings (Admin only)
+pub async fn get_server_settings(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
+
+    if claims.role != "admin" {
+        return ErrorCode::AdminOnly.respond(&req);
+    }
+
+    HttpResponse::Ok().json(load_server_settings(pool.get_ref()).await)
+}
+
+/// PATCH /api/server/settings — Change server-wide settings (Admin only)
+pub async fn update_server_settings(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    body: web::Json<UpdateServerSettings>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
+
+    if claims.role != "admin" {
+        return ErrorCode::AdminOnly.respond(&req);
+    }
+
+    if let Some(quotas) = &body.role_upload_quotas {
+        if quotas.values().any(|quota| *quota < 0) {
+            return ApiError::with_details(ErrorCode::InvalidPayload, "quotas must not be negative").respond(&req);
+        }
+    }
+
+    let mut role_quotas = HashMap::new();
+    for (role, quota) in body.role_upload_quotas.iter().flatten() {
+        role_quotas.insert(role.trim().to_lowercase(), *quota);
+    }
+    let mut keep_metadata_roles: Vec<String> = Vec::new();
+    for role in body.keep_upload_metadata_roles.iter().flatten() {
+        let name = role.trim().to_lowercase();
+        if !keep_metadata_roles.contains(&name) {
+            keep_metadata_roles.push(name);
+        }
+    }
+
+    for name in keep_metadata_roles.iter().chain(role_quotas.keys()) {
+        if !role_exists(pool.get_ref(), name).await {
+            return ErrorCode::InvalidRole.respond(&req);
+        }
+    }
+
+    if body.keep_upload_metadata_roles.is_some()
+        && store_setting(pool.get_ref(), KEEP_UPLOAD_METADATA_ROLES, &keep_metadata_roles).await.is_err()
+    {
+        return ErrorCode::InternalError.respond(&req);
+    }
+    if body.role_upload_quotas.is_some()
+        && store_setting(pool.get_ref(), ROLE_UPLOAD_QUOTAS, &role_quotas).await.is_err()
+    {
+        return ErrorCode::InternalError.respond(&req);
+    }
+
+    HttpResponse::Ok().json(load_server_settings(pool.get_ref()).await)
+}
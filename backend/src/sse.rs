@@ -0,0 +1,275 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::auth::{claims_for_bearer, extract_claims};
+use crate::codec::{BroadcastFrame, OutFrame, WireFormat};
+use crate::digest;
+use crate::bots::Scope;
+use crate::errors::ErrorCode;
+use crate::events::{self, ServerEvent};
+use crate::ws::{
+    apply_presence, fetch_accessible_rooms, heartbeat_interval_ms, hello_event, normalize_status, presence_snapshot,
+    register_connection, unregister_connection, verified_join, Broadcaster, OnlineUsers, PresenceRejected,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    /// `EventSource` cannot send headers, so the JWT may come in the query string instead.
+    pub token: Option<String>,
+    /// Initial status when this is the user's first connection.
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PresenceInput {
+    pub status: String,
+    #[serde(default)]
+    pub auto: bool,
+}
+
+/// Keeps the user online for as long as the stream is open.
+struct PresenceGuard {
+    pool: SqlitePool,
+    users: OnlineUsers,
+    broadcaster: Broadcaster,
+    user_id: String,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        if let Some(leave) = unregister_connection(&self.users, &self.user_id) {
+            events::broadcast(&self.broadcaster, &leave);
+        }
+        let pool = self.pool.clone();
+        let user_id = self.user_id.clone();
+        actix_web::rt::spawn(async move { digest::record_last_seen(&pool, &user_id).await });
+    }
+}
+
+struct EventStream {
+    rx: broadcast::Receiver<Arc<BroadcastFrame>>,
+    pending: VecDeque<ServerEvent>,
+    keepalive: tokio::time::Interval,
+    allowed_rooms: HashSet<String>,
+    is_admin: bool,
+    presence: PresenceGuard,
+}
+
+impl EventStream {
+    fn can_see(&self, frame: &BroadcastFrame) -> bool {
+        if !frame.is_for(&self.presence.user_id) {
+            return false;
+        }
+        match frame.event.room_id() {
+            Some(room_id) => self.is_admin || self.allowed_rooms.contains(room_id),
+            None => true,
+        }
+    }
+}
+
+fn sse_data(json: &str) -> Bytes {
+    Bytes::from(format!("data: {}\n\n", json))
+}
+
+/// GET /api/events — Server-sent events fallback for clients that cannot open /ws
+pub async fn events_stream(
+    req: HttpRequest,
+    query: web::Query<EventsQuery>,
+    pool: web::Data<SqlitePool>,
+    broadcaster: web::Data<Broadcaster>,
+    online_users: web::Data<OnlineUsers>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req).or_else(|| query.token.as_deref().and_then(|t| claims_for_bearer(&req, t))) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::RoomsRead) {
+        return err.respond(&req);
+    }
+
+    let Some((join, is_bot)) = verified_join(pool.get_ref(), &claims, query.status.clone(), None).await else {
+        return ErrorCode::UserNotFound.respond(&req);
+    };
+    let role = join.role.clone().unwrap_or_else(|| "user".to_string());
+
+    let tx = broadcaster.get_ref().clone();
+    let users = online_users.get_ref().clone();
+    let allowed_rooms = fetch_accessible_rooms(pool.get_ref(), &role).await;
+
+    // Same opening sequence as /ws: hello, then who is online, then our own join
+    let rx = tx.subscribe();
+    let announce = register_connection(&users, &join, is_bot);
+    let pending = VecDeque::from([hello_event(WireFormat::default()), presence_snapshot(&users, &claims.sub)]);
+    if let Some(join_event) = announce {
+        events::broadcast(&tx, &join_event);
+    }
+
+    let keepalive = Duration::from_millis(heartbeat_interval_ms());
+    let state = EventStream {
+        rx,
+        pending,
+        keepalive: tokio::time::interval_at(tokio::time::Instant::now() + keepalive, keepalive),
+        allowed_rooms,
+        is_admin: role == "admin",
+        presence: PresenceGuard {
+            pool: pool.get_ref().clone(),
+            users,
+            broadcaster: tx,
+            user_id: claims.sub,
+        },
+    };
+
+    let stream = futures_util::stream::unfold(state, |mut state| async move {
+        if let Some(event) = state.pending.pop_front() {
+            return Some((Ok::<_, actix_web::Error>(sse_data(&event.to_json())), state));
+        }
+        loop {
+            tokio::select! {
+                received = state.rx.recv() => {
+                    // Like /ws, a lagging or closed channel ends the stream; the client reconnects
+                    let frame = received.ok()?;
+                    if !state.can_see(&frame) {
+                        continue;
+                    }
+                    // Shares the JSON encoding cached for WebSocket connections
+                    let OutFrame::Text(json) = frame.encoded(WireFormat::default()) else {
+                        continue;
+                    };
+                    return Some((Ok(sse_data(&json)), state));
+                }
+                // Comment lines keep proxies from timing out an idle stream
+                _ = state.keepalive.tick() => return Some((Ok(Bytes::from_static(b": keepalive\n\n")), state)),
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream)
+}
+
+/// PUT /api/users/me/presence — Change status, same as a `presence` frame on /ws
+pub async fn set_presence(
+    req: HttpRequest,
+    body: web::Json<PresenceInput>,
+    broadcaster: web::Data<Broadcaster>,
+    online_users: web::Data<OnlineUsers>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
+    }
+
+    let Some(status) = normalize_status(Some(&body.status)) else {
+        return ErrorCode::InvalidStatus.respond(&req);
+    };
+
+    match apply_presence(online_users.get_ref(), &claims.sub, &status, body.auto) {
+        Err(PresenceRejected::NotConnected) => ErrorCode::NotConnected.respond(&req),
+        Err(PresenceRejected::Ignored) => HttpResponse::NoContent().finish(),
+        Ok(event) => {
+            if let Some(event) = event {
+                events::broadcast(&broadcaster, &event);
+            }
+            HttpResponse::NoContent().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{JoinPayload, TypingEvent};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn join(user_id: &str) -> JoinPayload {
+        JoinPayload {
+            user_id: user_id.to_string(),
+            username: user_id.to_string(),
+            avatar_color: 0,
+            protocol_version: None,
+            avatar_url: None,
+            banner_url: None,
+            status: None,
+            role: None,
+            about: None,
+        }
+    }
+
+    fn typing(room_id: &str) -> BroadcastFrame {
+        BroadcastFrame::new(ServerEvent::Typing(TypingEvent {
+            room_id: room_id.to_string(),
+            user_id: Some("u2".to_string()),
+            username: None,
+        }))
+    }
+
+    fn stream(pool: &SqlitePool, users: &OnlineUsers, tx: &Broadcaster, is_admin: bool) -> EventStream {
+        EventStream {
+            rx: tx.subscribe(),
+            pending: VecDeque::new(),
+            keepalive: tokio::time::interval(Duration::from_secs(30)),
+            allowed_rooms: HashSet::from(["general".to_string()]),
+            is_admin,
+            presence: PresenceGuard {
+                pool: pool.clone(),
+                users: users.clone(),
+                broadcaster: tx.clone(),
+                user_id: "u1".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn events_are_framed_as_sse_data() {
+        assert_eq!(sse_data(r#"{"type":"leave"}"#), Bytes::from_static(b"data: {\"type\":\"leave\"}\n\n"));
+    }
+
+    #[actix_web::test]
+    async fn events_are_filtered_by_access_and_recipients() {
+        let pool = crate::db::test_pool().await;
+        let users: OnlineUsers = Arc::new(Mutex::new(HashMap::new()));
+        let tx: Broadcaster = Arc::new(broadcast::channel(8).0);
+
+        let member = stream(&pool, &users, &tx, false);
+        assert!(member.can_see(&typing("general")));
+        assert!(!member.can_see(&typing("staff")));
+        assert!(member.can_see(&BroadcastFrame::new(presence_snapshot(&users, "u1"))));
+        assert!(member.can_see(&typing("general").with_recipients(HashSet::from(["u1".to_string()]))));
+        assert!(!member.can_see(&typing("general").with_recipients(HashSet::from(["u2".to_string()]))));
+
+        let admin = stream(&pool, &users, &tx, true);
+        assert!(admin.can_see(&typing("staff")));
+    }
+
+    #[actix_web::test]
+    async fn closing_the_last_stream_announces_the_leave() {
+        let pool = crate::db::test_pool().await;
+        let users: OnlineUsers = Arc::new(Mutex::new(HashMap::new()));
+        let tx: Broadcaster = Arc::new(broadcast::channel(8).0);
+        let mut rx = tx.subscribe();
+
+        register_connection(&users, &join("u1"), false);
+        register_connection(&users, &join("u1"), false);
+        drop(stream(&pool, &users, &tx, false));
+        assert!(rx.try_recv().is_err());
+
+        drop(stream(&pool, &users, &tx, false));
+        let frame = rx.try_recv().unwrap();
+        assert!(matches!(&frame.event, ServerEvent::Leave(leave) if leave.user_id == "u1"));
+        assert!(users.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use sqlx::{Row, SqlitePool};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use crate::auth::extract_claims;
+use crate::bots::Scope;
+use crate::errors::ErrorCode;
+use crate::rate_limit::env_or;
+use crate::storage::{StoredObject, UploadStorage};
+use crate::uploads::release_blobs;
+
+/// Sweeper settings, read once at startup.
+#[derive(Debug, Clone, Copy)]
+pub struct SweepConfig {
+    /// How long an unreferenced upload is kept, so it can still be attached or set as an avatar.
+    grace: Duration,
+    interval: Duration,
+}
+
+impl SweepConfig {
+    /// Reads `UPLOAD_ORPHAN_GRACE_SECS` and `UPLOAD_SWEEP_INTERVAL_SECS`.
+    pub fn from_env() -> SweepConfig {
+        SweepConfig {
+            grace: Duration::from_secs(env_or("UPLOAD_ORPHAN_GRACE_SECS", 24 * 60 * 60u64)),
+            interval: Duration::from_secs(env_or("UPLOAD_SWEEP_INTERVAL_SECS", 60 * 60u64)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrphanUpload {
+    pub id: String,
+    pub user_id: String,
+    pub url: String,
+    pub size: i64,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SweepReport {
+    pub dry_run: bool,
+    pub grace_secs: u64,
+    /// Uploads no message, profile or webhook refers to.
+    pub uploads: Vec<OrphanUpload>,
+    /// Keys of stored files named like uploads that no upload or reference knows of.
+    pub files: Vec<String>,
+    /// Storage space freed, or that a sweep would free.
+    pub bytes: i64,
+}
+
+/// Spawn the task sweeping orphaned uploads every `UPLOAD_SWEEP_INTERVAL_SECS`.
+pub fn start_sweeper(pool: SqlitePool, storage: UploadStorage) {
+    let config = SweepConfig::from_env();
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::time::sleep(config.interval).await;
+            let report = sweep(&pool, &storage, config.grace, false).await;
+            if !report.uploads.is_empty() || !report.files.is_empty() {
+                println!(
+                    "🧹 Swept {} orphaned uploads and {} stray files ({} bytes)",
+                    report.uploads.len(),
+                    report.files.len(),
+                    report.bytes
+                );
+            }
+        }
+    });
+}
+
+/// The file name an uploads url points to, also inside absolute urls and JSON.
+fn stored_name(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("/uploads/")?;
+    let end = rest
+        .find(|c: char| c == '"' || c == '?' || c == '#' || c == ')' || c.is_whitespace())
+        .unwrap_or(rest.len());
+    Some(&rest[..end]).filter(|name| !name.is_empty())
+}
+
+/// Names of the stored files referred to by url: profiles, webhooks and messages.
+/// Attachments refer to uploads by id and are checked separately.
+async fn referenced_names(pool: &SqlitePool) -> HashSet<String> {
+    let urls: Vec<String> = sqlx::query_scalar(
+        "SELECT avatar_url FROM users WHERE avatar_url LIKE '%/uploads/%' \
+         UNION SELECT banner_url FROM users WHERE banner_url LIKE '%/uploads/%' \
+         UNION SELECT avatar_url FROM webhooks WHERE avatar_url LIKE '%/uploads/%' \
+         UNION SELECT image_url FROM messages WHERE image_url LIKE '%/uploads/%' \
+         UNION SELECT author_avatar_url FROM messages WHERE author_avatar_url LIKE '%/uploads/%'",
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+    let mut names: HashSet<String> = urls.iter().filter_map(|url| stored_name(url)).map(str::to_string).collect();
+
+    // Embeds are JSON and may hold several urls
+    let embeds: Vec<String> = sqlx::query_scalar("SELECT embeds FROM messages WHERE embeds LIKE '%/uploads/%'")
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    for embed in &embeds {
+        let mut rest = embed.as_str();
+        while let Some(name) = stored_name(rest) {
+            names.insert(name.to_string());
+            rest = rest.split_once("/uploads/").map(|(_, after)| after).unwrap_or("");
+        }
+    }
+    names
+}
+
+/// Whether `key` is named the way uploads store files: `{sha256}.{ext}`, or
+/// `thumbs/{sha256}_{size}.{ext}` for thumbnails, or `{user_id}_{uuid}.{ext}` for images
+/// uploaded before uploads were recorded. Nothing else is ours to delete.
+fn is_upload_key(key: &str) -> bool {
+    let (name, thumbnail) = match key.strip_prefix("thumbs/") {
+        Some(name) => (name, true),
+        None => (key, false),
+    };
+    let Some((stem, ext)) = name.rsplit_once('.') else {
+        return false;
+    };
+    if !(1..=5).contains(&ext.len()) || !ext.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()) {
+        return false;
+    }
+    let sha256 = match stem.split_once('_') {
+        Some((sha256, size)) if thumbnail && !size.is_empty() && size.bytes().all(|b| b.is_ascii_digit()) => sha256,
+        Some((user_id, id)) if !thumbnail => return is_uuid(user_id) && is_uuid(id),
+        None if !thumbnail => stem,
+        _ => return false,
+    };
+    sha256.len() == 64 && sha256.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// A lowercase hyphenated uuid, as user ids and legacy file names use.
+fn is_uuid(value: &str) -> bool {
+    value.len() == 36 && value == value.to_ascii_lowercase() && uuid::Uuid::try_parse(value).is_ok()
+}
+
+/// Whether a stored file was last modified more than `grace` ago.
+fn older_than(object: &StoredObject, grace: Duration) -> bool {
+    object
+        .modified
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age >= grace)
+}
+
+/// Delete an upload listed as orphaned, unless it was attached or set as an image since.
+/// Returns its `sha256` column if it was deleted.
+async fn delete_orphan(pool: &SqlitePool, upload: &OrphanUpload) -> Option<Option<String>> {
+    sqlx::query_scalar(
+        "DELETE FROM uploads WHERE id = ?1 \
+         AND NOT EXISTS (SELECT 1 FROM attachments WHERE upload_id = uploads.id) \
+         AND NOT EXISTS (SELECT 1 FROM users WHERE avatar_url LIKE '%/uploads/' || ?2 OR banner_url LIKE '%/uploads/' || ?2) \
+         AND NOT EXISTS (SELECT 1 FROM webhooks WHERE avatar_url LIKE '%/uploads/' || ?2) \
+         AND NOT EXISTS (SELECT 1 FROM messages WHERE image_url LIKE '%/uploads/' || ?2 \
+             OR author_avatar_url LIKE '%/uploads/' || ?2 OR embeds LIKE '%/uploads/' || ?2 || '%') \
+         RETURNING sha256",
+    )
+    .bind(&upload.id)
+    .bind(stored_name(&upload.url).unwrap_or(&upload.url))
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None)
+}
+
+/// Find uploads and files nothing refers to for longer than `grace`, and delete them
+/// unless `dry_run`.
+pub(crate) async fn sweep(pool: &SqlitePool, storage: &UploadStorage, grace: Duration, dry_run: bool) -> SweepReport {
+    let referenced = referenced_names(pool).await;
+
+    let rows = sqlx::query(
+        "SELECT id, user_id, url, size, sha256, created_at FROM uploads \
+         WHERE NOT EXISTS (SELECT 1 FROM attachments WHERE upload_id = uploads.id) \
+         AND datetime(created_at) <= datetime('now', ?) ORDER BY created_at",
+    )
+    .bind(format!("-{} seconds", grace.as_secs()))
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    let mut uploads = Vec::new();
+    let mut orphaned_hashes: Vec<String> = Vec::new();
+    let mut bytes = 0;
+    for row in rows {
+        let url: String = row.try_get("url").unwrap_or_default();
+        if stored_name(&url).is_some_and(|name| referenced.contains(name)) {
+            continue;
+        }
+        let sha256: Option<String> = row.try_get("sha256").unwrap_or(None);
+        let upload = OrphanUpload {
+            id: row.try_get("id").unwrap_or_default(),
+            user_id: row.try_get("user_id").unwrap_or_default(),
+            url,
+            size: row.try_get("size").unwrap_or(0),
+            created_at: row.try_get("created_at").unwrap_or_default(),
+        };
+        match sha256 {
+            Some(sha256) => orphaned_hashes.push(sha256),
+            None => bytes += upload.size,
+        }
+        uploads.push(upload);
+    }
+
+    // A shared file is only freed once every upload using it is
+    let mut freed_hashes = HashSet::new();
+    for sha256 in &orphaned_hashes {
+        if !freed_hashes.insert(sha256.clone()) {
+            continue;
+        }
+        let blob = sqlx::query("SELECT refs, size FROM upload_blobs WHERE sha256 = ?")
+            .bind(sha256)
+            .fetch_optional(pool)
+            .await
+            .unwrap_or(None);
+        if let Some(blob) = blob {
+            let refs: i64 = blob.try_get("refs").unwrap_or(0);
+            let orphaned = orphaned_hashes.iter().filter(|hash| *hash == sha256).count() as i64;
+            if orphaned >= refs {
+                bytes += blob.try_get::<i64, _>("size").unwrap_or(0);
+            }
+        }
+    }
+
+    if !dry_run {
+        for upload in &uploads {
+            let thumbnails: Vec<String> = sqlx::query_scalar(
+                "SELECT t.path FROM upload_thumbnails t JOIN uploads u ON u.id = t.upload_id \
+                 WHERE t.upload_id = ? AND u.sha256 IS NULL",
+            )
+            .bind(&upload.id)
+            .fetch_all(pool)
+            .await
+            .unwrap_or_default();
+            match delete_orphan(pool, upload).await {
+                Some(Some(sha256)) => release_blobs(pool, storage, &[sha256]).await,
+                Some(None) => {
+                    if let Some(name) = stored_name(&upload.url) {
+                        storage.delete(name).await.ok();
+                    }
+                    for path in thumbnails {
+                        storage.delete(&path).await.ok();
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Files left behind by uploads that failed halfway; whatever else shares the storage stays
+    let mut known: HashSet<String> = referenced;
+    let stored: Vec<String> = sqlx::query_scalar(
+        "SELECT url FROM uploads UNION SELECT '/uploads/' || path FROM upload_blobs \
+         UNION SELECT '/uploads/' || path FROM upload_thumbnails",
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+    known.extend(stored.iter().filter_map(|url| stored_name(url)).map(str::to_string));
+
+    let mut files = Vec::new();
+    for object in storage.list("").await.unwrap_or_default() {
+        if !is_upload_key(&object.key) || known.contains(&object.key) || !older_than(&object, grace) {
+            continue;
+        }
+        bytes += object.size as i64;
+        if !dry_run {
+            storage.delete(&object.key).await.ok();
+        }
+        files.push(object.key);
+    }
+    files.sort();
+
+    SweepReport {
+        dry_run,
+        grace_secs: grace.as_secs(),
+        uploads,
+        files,
+        bytes,
+    }
+}
+
+/// GET /api/server/uploads/orphans — What a sweep would delete now (Admin only)
+pub async fn list_orphaned_uploads(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<UploadStorage>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
+
+    if claims.role != "admin" {
+        return ErrorCode::AdminOnly.respond(&req);
+    }
+
+    HttpResponse::Ok().json(sweep(pool.get_ref(), &storage, SweepConfig::from_env().grace, true).await)
+}
+
+/// POST /api/server/uploads/sweep — Delete orphaned uploads now (Admin only)
+pub async fn sweep_orphaned_uploads(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<UploadStorage>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
+
+    if claims.role != "admin" {
+        return ErrorCode::AdminOnly.respond(&req);
+    }
+
+    HttpResponse::Ok().json(sweep(pool.get_ref(), &storage, SweepConfig::from_env().grace, false).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "92ec19db80a39dae9f94fd1533f52e7f293a8b27e8c039c7b412d6db471e26b5";
+    const LEGACY_USER: &str = "0b6f1f8e-3c57-4a8e-9f3e-2d1c5b7a9e10";
+    const LEGACY_ID: &str = "6f1c2d9e-5b1a-4c1e-9d2a-1f0e2b3c4d5e";
+
+    #[test]
+    fn upload_keys_are_recognized() {
+        assert!(is_upload_key(&format!("{}.png", SHA256)));
+        assert!(is_upload_key(&format!("{}.webp", SHA256)));
+        assert!(is_upload_key(&format!("thumbs/{}_320.jpg", SHA256)));
+        assert!(is_upload_key(&format!("{}_{}.png", LEGACY_USER, LEGACY_ID)));
+    }
+
+    #[test]
+    fn other_keys_are_left_alone() {
+        assert!(!is_upload_key("backup.tar.gz"));
+        assert!(!is_upload_key("6f1c2d9e-5b1a-4c1e-9d2a-1f0e2b3c4d5e.png"));
+        assert!(!is_upload_key(SHA256));
+        assert!(!is_upload_key(&format!("{}.PNG", SHA256)));
+        assert!(!is_upload_key(&format!("{}.png", SHA256.to_uppercase())));
+        assert!(!is_upload_key(&format!("{}_320.jpg", SHA256)));
+        assert!(!is_upload_key(&format!("thumbs/{}.jpg", SHA256)));
+        assert!(!is_upload_key(&format!("thumbs/{}_big.jpg", SHA256)));
+        assert!(!is_upload_key(&format!("other/{}.png", SHA256)));
+        assert!(!is_upload_key(&format!("{}_{}", LEGACY_USER, LEGACY_ID)));
+        assert!(!is_upload_key(&format!("{}_{}.png", LEGACY_USER, LEGACY_ID.to_uppercase())));
+        assert!(!is_upload_key(&format!("notes_{}.png", LEGACY_ID)));
+        assert!(!is_upload_key(&format!("thumbs/{}_{}.png", LEGACY_USER, LEGACY_ID)));
+    }
+
+    #[tokio::test]
+    async fn uploads_set_as_images_since_listing_are_kept() {
+        let pool = crate::db::test_pool().await;
+        for sql in [
+            "INSERT INTO users (id, username, password_hash) VALUES ('u1', 'alice', '')",
+            "INSERT INTO uploads (id, user_id, url, filename, mime_type, size) VALUES \
+             ('a', 'u1', '/uploads/a.png', 'a.png', 'image/png', 1), \
+             ('b', 'u1', '/uploads/b.png', 'b.png', 'image/png', 1), \
+             ('c', 'u1', '/uploads/c.png', 'c.png', 'image/png', 1)",
+            "UPDATE users SET avatar_url = 'http://localhost:8080/uploads/a.png', banner_url = '/uploads/b.png'",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        let orphan = |id: &str| OrphanUpload {
+            id: id.to_string(),
+            user_id: "u1".to_string(),
+            url: format!("/uploads/{}.png", id),
+            size: 1,
+            created_at: String::new(),
+        };
+        assert_eq!(delete_orphan(&pool, &orphan("a")).await, None);
+        assert_eq!(delete_orphan(&pool, &orphan("b")).await, None);
+        assert_eq!(delete_orphan(&pool, &orphan("c")).await, Some(None));
+    }
+}
@@ -1,25 +1,436 @@
+use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType, HeaderValue};
+use actix_web::{mime, web, HttpRequest, HttpResponse};
 use futures_util::StreamExt;
-use std::io::Write;
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, RgbaImage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteConnection;
+use sqlx::{Acquire, Executor, Row, Sqlite, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
 use uuid::Uuid;
 
 use crate::auth::extract_claims;
+use crate::bots::Scope;
+use crate::errors::{ApiError, ErrorCode};
+use crate::events::{Attachment, AttachmentInput};
+use crate::messages::Message;
+use crate::server_settings::load_server_settings;
+use crate::storage::UploadStorage;
+
+pub(crate) const MAX_ATTACHMENTS: usize = 10;
+const MAX_ALT_TEXT_CHARS: usize = 1024;
+
+/// Formats accepted by `POST /api/upload`. SVG is not one: it can carry scripts.
+const ALLOWED_FORMATS: &[ImageFormat] =
+    &[ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif, ImageFormat::WebP, ImageFormat::Bmp];
+
+/// Largest width or height of an uploaded image, against decompression bombs.
+const MAX_IMAGE_SIDE: u32 = 16_384;
+
+/// Longest side of the thumbnails made for each image, in pixels.
+const THUMBNAIL_SIZES: &[u32] = &[160, 320, 640];
+const DEFAULT_THUMBNAIL_SIZE: u32 = 320;
+
+struct Thumbnail {
+    size: u32,
+    format: ImageFormat,
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+/// Quality of JPEG files re-encoded after applying their orientation.
+const JPEG_QUALITY: u8 = 90;
+
+/// An upload whose content was checked to be an image, with what is derived from it.
+struct ProcessedImage {
+    format: ImageFormat,
+    /// What to store: the upload itself, or a copy without its metadata.
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+    thumbnails: Vec<Thumbnail>,
+    blurhash: Option<String>,
+    /// Average color as `0xRRGGBB`.
+    dominant_color: Option<u32>,
+}
+
+/// Detect the format from the magic bytes, then decode the whole image to make sure it is one.
+/// Also returns the orientation its metadata asks for.
+fn detect_image(bytes: &[u8]) -> Option<(ImageFormat, DynamicImage, Orientation)> {
+    let format = image::guess_format(bytes).ok()?;
+    if !ALLOWED_FORMATS.contains(&format) {
+        return None;
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().ok()?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    Some((format, DynamicImage::from_decoder(decoder).ok()?, orientation))
+}
+
+/// The WebP encoder only takes 8-bit RGB(A).
+fn to_8bit(image: &DynamicImage) -> DynamicImage {
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    }
+}
+
+fn encode_image(image: &DynamicImage, format: ImageFormat) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    if format == ImageFormat::Jpeg {
+        JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY)
+            .encode_image(&image.to_rgb8())
+            .ok()?;
+    } else {
+        to_8bit(image).write_to(&mut Cursor::new(&mut bytes), format).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Copy of a JPEG file without its EXIF, XMP, IPTC and comment segments.
+/// JFIF, ICC color profiles and Adobe color transforms are kept.
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = vec![0xFF, 0xD8];
+    let mut i = 2;
+    loop {
+        if *bytes.get(i)? != 0xFF {
+            return None;
+        }
+        let marker = *bytes.get(i + 1)?;
+        match marker {
+            // Fill byte before a marker
+            0xFF => {
+                i += 1;
+                continue;
+            }
+            // Start of scan: image data follows up to the end of image; anything
+            // after it, such as embedded previews, is dropped
+            0xDA => {
+                let end = bytes[i..].windows(2).position(|w| w == [0xFF, 0xD9]).map(|p| i + p + 2)?;
+                out.extend_from_slice(&bytes[i..end]);
+                return Some(out);
+            }
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&bytes[i..i + 2]);
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let len = u16::from_be_bytes([*bytes.get(i + 2)?, *bytes.get(i + 3)?]) as usize;
+        let end = i + 2 + len;
+        if len < 2 || end > bytes.len() {
+            return None;
+        }
+        let payload = &bytes[i + 4..end];
+        let keep = match marker {
+            0xE0 => true,
+            0xE2 => payload.starts_with(b"ICC_PROFILE\0"),
+            0xEE => payload.starts_with(b"Adobe"),
+            0xE1..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(&bytes[i..end]);
+        }
+        i = end;
+    }
+}
+
+/// Copy of a PNG file without its text, time and EXIF chunks.
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !bytes.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut out = SIGNATURE.to_vec();
+    let mut i = SIGNATURE.len();
+    while i < bytes.len() {
+        let len = u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().ok()?) as usize;
+        let end = i.checked_add(12)?.checked_add(len)?;
+        let kind = bytes.get(i + 4..i + 8)?;
+        if end > bytes.len() {
+            return None;
+        }
+        if !matches!(kind, b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME") {
+            out.extend_from_slice(&bytes[i..end]);
+        }
+        if kind == b"IEND" {
+            return Some(out);
+        }
+        i = end;
+    }
+    None
+}
+
+/// Copy of a WebP file without its EXIF and XMP chunks.
+fn strip_webp(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return None;
+    }
+    let mut out = bytes[0..12].to_vec();
+    let mut i = 12;
+    while i + 8 <= bytes.len() {
+        let kind = &bytes[i..i + 4];
+        let len = u32::from_le_bytes(bytes[i + 4..i + 8].try_into().ok()?) as usize;
+        // Chunks are padded to an even size
+        let end = i.checked_add(8)?.checked_add(len + len % 2)?.min(bytes.len());
+        match kind {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = out.len();
+                out.extend_from_slice(&bytes[i..end]);
+                // Clear the EXIF and XMP flags
+                if let Some(flags) = out.get_mut(start + 8) {
+                    *flags &= !0x0C;
+                }
+            }
+            _ => out.extend_from_slice(&bytes[i..end]),
+        }
+        i = end;
+    }
+    let riff_size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+/// Bytes to store for a JPEG, PNG or WebP upload: without metadata, and re-encoded
+/// with the orientation applied when the metadata asked for one. Other formats are
+/// stored as they are.
+fn without_metadata(bytes: Vec<u8>, format: ImageFormat, image: &DynamicImage, reoriented: bool) -> Option<Vec<u8>> {
+    let stripped = match format {
+        _ if reoriented => None,
+        ImageFormat::Jpeg => strip_jpeg(&bytes),
+        ImageFormat::Png => strip_png(&bytes),
+        ImageFormat::WebP => strip_webp(&bytes),
+        _ => return Some(bytes),
+    };
+    stripped.or_else(|| encode_image(image, format))
+}
+
+/// Downscale to `size` pixels on the longest side. WebP is preferred; PNG is the fallback.
+fn encode_thumbnail(image: &DynamicImage, size: u32) -> Option<Thumbnail> {
+    let resized = to_8bit(&image.thumbnail(size, size));
+
+    [ImageFormat::WebP, ImageFormat::Png].into_iter().find_map(|format| {
+        let mut bytes = Vec::new();
+        resized.write_to(&mut Cursor::new(&mut bytes), format).ok()?;
+        Some(Thumbnail {
+            size,
+            format,
+            bytes,
+            width: resized.width(),
+            height: resized.height(),
+        })
+    })
+}
+
+fn average_color(image: &RgbaImage) -> Option<u32> {
+    let (mut r, mut g, mut b, mut count) = (0u64, 0u64, 0u64, 0u64);
+    for pixel in image.pixels().filter(|pixel| pixel[3] > 0) {
+        r += pixel[0] as u64;
+        g += pixel[1] as u64;
+        b += pixel[2] as u64;
+        count += 1;
+    }
+    (count > 0).then(|| (((r / count) << 16) | ((g / count) << 8) | (b / count)) as u32)
+}
+
+/// Check an upload and derive everything stored with it. Unless `keep_metadata`,
+/// the stored copy has its orientation applied and its metadata removed.
+fn process_image(bytes: Vec<u8>, keep_metadata: bool) -> Option<ProcessedImage> {
+    let (format, mut image, orientation) = detect_image(&bytes)?;
+    let reoriented = orientation != Orientation::NoTransforms;
+    image.apply_orientation(orientation);
+    let bytes = if keep_metadata {
+        bytes
+    } else {
+        without_metadata(bytes, format, &image, reoriented)?
+    };
+
+    let longest_side = image.width().max(image.height());
+    let thumbnails = THUMBNAIL_SIZES
+        .iter()
+        .filter(|&&size| size < longest_side)
+        .filter_map(|&size| encode_thumbnail(&image, size))
+        .collect();
+
+    // Both placeholders only need a tiny version of the image
+    let tiny = image.thumbnail(32, 32).to_rgba8();
+    Some(ProcessedImage {
+        format,
+        bytes,
+        width: image.width(),
+        height: image.height(),
+        thumbnails,
+        blurhash: blurhash::encode(4, 3, tiny.width(), tiny.height(), tiny.as_raw()).ok(),
+        dominant_color: average_color(&tiny),
+    })
+}
+
+/// Extension used for stored files, from the detected format.
+fn extension_for(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("bin")
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageUsage {
+    /// Bytes of the files a user uploaded, each distinct file counted once.
+    pub used: i64,
+    /// `None` when unlimited.
+    pub quota: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUploadQuota {
+    /// `null` falls back to the quota of the user's role.
+    #[serde(default)]
+    pub upload_quota: Option<i64>,
+}
+
+/// Bytes stored for `user_id`, each file counted once.
+async fn storage_used<'e>(executor: impl Executor<'e, Database = Sqlite>, user_id: &str) -> i64 {
+    // Files stored before deduplication have no hash and count once per upload
+    sqlx::query_scalar(
+        "SELECT COALESCE(SUM(size), 0) FROM ( \
+             SELECT size FROM uploads WHERE user_id = ?1 AND sha256 IS NULL \
+             UNION ALL \
+             SELECT MAX(size) AS size FROM uploads WHERE user_id = ?1 AND sha256 IS NOT NULL GROUP BY sha256 \
+         )",
+    )
+    .bind(user_id)
+    .fetch_one(executor)
+    .await
+    .unwrap_or(0)
+}
+
+/// Whether `user_id` stays within `quota` with the upload `upload_id` counted; a file they
+/// already had is always allowed. Run it on the transaction that inserted the upload, so
+/// concurrent uploads cannot all pass the check.
+async fn within_quota(conn: &mut SqliteConnection, user_id: &str, upload_id: &str, sha256: &str, quota: Option<i64>) -> bool {
+    let Some(quota) = quota else {
+        return true;
+    };
+    let owned: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM uploads WHERE user_id = ? AND sha256 = ? AND id != ?")
+        .bind(user_id)
+        .bind(sha256)
+        .bind(upload_id)
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap_or(false);
+    owned || storage_used(&mut *conn, user_id).await <= quota
+}
+
+/// Storage used by `user_id` and their quota: their own, else the one of `role`.
+pub(crate) async fn storage_usage(pool: &SqlitePool, user_id: &str, role: &str) -> StorageUsage {
+    let used = storage_used(pool, user_id).await;
+
+    let own_quota: Option<i64> = sqlx::query_scalar("SELECT upload_quota FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)
+        .flatten();
+    let quota = match own_quota {
+        Some(quota) => Some(quota),
+        None => load_server_settings(pool).await.role_upload_quotas.get(role).copied(),
+    };
+
+    StorageUsage { used, quota }
+}
+
+/// Drop one reference to each stored file; files nothing refers to anymore are
+/// deleted with their thumbnails.
+pub(crate) async fn release_blobs(pool: &SqlitePool, storage: &UploadStorage, hashes: &[String]) {
+    for sha256 in hashes {
+        let _ = sqlx::query("UPDATE upload_blobs SET refs = refs - 1 WHERE sha256 = ?")
+            .bind(sha256)
+            .execute(pool)
+            .await;
+        let unused: Option<String> =
+            sqlx::query_scalar("DELETE FROM upload_blobs WHERE sha256 = ? AND refs <= 0 RETURNING path")
+                .bind(sha256)
+                .fetch_optional(pool)
+                .await
+                .unwrap_or(None);
+        let Some(path) = unused else {
+            continue;
+        };
+
+        storage.delete(&path).await.ok();
+        let thumbnails = storage.list(&format!("thumbs/{}_", sha256)).await.unwrap_or_default();
+        for thumbnail in thumbnails {
+            storage.delete(&thumbnail.key).await.ok();
+        }
+    }
+}
+
+/// PATCH /api/users/{id}/upload-quota — Set or clear a user's storage quota (Admin only)
+pub async fn update_upload_quota(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: web::Json<UpdateUploadQuota>,
+) -> HttpResponse {
+    let claims = match extract_claims(&req) {
+        Some(c) => c,
+        None => return ErrorCode::NotAuthenticated.respond(&req),
+    };
+    if let Err(err) = claims.require_scope(Scope::Moderation) {
+        return err.respond(&req);
+    }
+
+    if claims.role != "admin" {
+        return ErrorCode::AdminOnly.respond(&req);
+    }
+
+    if body.upload_quota.is_some_and(|quota| quota < 0) {
+        return ApiError::with_details(ErrorCode::InvalidPayload, "upload_quota must not be negative").respond(&req);
+    }
+
+    let user_id = path.into_inner();
+    let role: Option<String> = sqlx::query_scalar("UPDATE users SET upload_quota = ? WHERE id = ? RETURNING role")
+        .bind(body.upload_quota)
+        .bind(&user_id)
+        .fetch_optional(pool.get_ref())
+        .await
+        .unwrap_or(None);
+    match role {
+        Some(role) => HttpResponse::Ok().json(storage_usage(pool.get_ref(), &user_id, &role).await),
+        None => ErrorCode::UserNotFound.respond(&req),
+    }
+}
 
 /// POST /api/upload — Upload an image file (authenticated)
 pub async fn upload_image(
     req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<UploadStorage>,
     mut payload: Multipart,
 ) -> HttpResponse {
     let claims = match extract_claims(&req) {
         Some(c) => c,
-        None => return HttpResponse::Unauthorized().finish(),
+        None => return ErrorCode::NotAuthenticated.respond(&req),
     };
-
-    // Ensure uploads directory exists
-    let upload_dir = std::path::Path::new("uploads");
-    if !upload_dir.exists() {
-        std::fs::create_dir_all(upload_dir).ok();
+    if let Err(err) = claims.require_scope(Scope::MessagesWrite) {
+        return err.respond(&req);
     }
 
     while let Some(Ok(mut field)) = payload.next().await {
@@ -33,64 +444,839 @@ pub async fn upload_image(
             .unwrap_or("file")
             .to_string();
 
-        // Extract extension
-        let extension = original_filename
-            .rsplit('.')
-            .next()
-            .unwrap_or("png")
-            .to_lowercase();
+        let mut bytes: Vec<u8> = Vec::new();
+        let max_size: usize = 8 * 1024 * 1024; // 8MB limit
+
+        while let Some(Ok(chunk)) = field.next().await {
+            if bytes.len() + chunk.len() > max_size {
+                return ErrorCode::FileTooLarge.respond(&req);
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        // Photos may carry GPS coordinates and camera serials; only trusted roles keep them
+        let keep_metadata = load_server_settings(pool.get_ref())
+            .await
+            .keep_upload_metadata_roles
+            .contains(&claims.role);
+
+        // The type comes from the content; the client's filename is only kept for display.
+        // Decoding and resizing are CPU-bound, so they run off the async runtime
+        let mut image = match web::block(move || process_image(bytes, keep_metadata)).await {
+            Ok(Some(image)) => image,
+            Ok(None) => return ErrorCode::UnsupportedFileType.respond(&req),
+            Err(_) => return ErrorCode::UploadFailed.respond(&req),
+        };
+        let bytes = &image.bytes;
+        let size = bytes.len() as i64;
 
-        // Only allow image types
-        let allowed = ["png", "jpg", "jpeg", "gif", "webp", "svg", "bmp"];
-        if !allowed.contains(&extension.as_str()) {
-            return HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Only image files are allowed (png, jpg, jpeg, gif, webp, svg, bmp)"
-            }));
+        // Files are named by their content, so identical uploads share one
+        let sha256 = format!("{:x}", Sha256::digest(bytes));
+        let owned: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM uploads WHERE user_id = ? AND sha256 = ?")
+            .bind(&claims.sub)
+            .bind(&sha256)
+            .fetch_one(pool.get_ref())
+            .await
+            .unwrap_or(false);
+        // Checked again once the upload is inserted; this only saves storing the file
+        let usage = storage_usage(pool.get_ref(), &claims.sub, &claims.role).await;
+        if !owned && usage.quota.is_some_and(|quota| usage.used + size > quota) {
+            return ErrorCode::StorageQuotaExceeded.respond(&req);
         }
 
-        // Generate unique filename
-        let filename = format!("{}_{}.{}", claims.sub, Uuid::new_v4(), extension);
-        let filepath = upload_dir.join(&filename);
+        let filename = format!("{}.{}", sha256, extension_for(image.format));
+        let stored: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM upload_blobs WHERE sha256 = ?")
+            .bind(&sha256)
+            .fetch_one(pool.get_ref())
+            .await
+            .unwrap_or(false);
+        let mime_type = image.format.to_mime_type();
+        if !stored && storage.put(&filename, std::mem::take(&mut image.bytes), mime_type).await.is_err() {
+            return ErrorCode::UploadFailed.respond(&req);
+        }
+        let referenced = sqlx::query(
+            "INSERT INTO upload_blobs (sha256, path, size, refs) VALUES (?, ?, ?, 1) \
+             ON CONFLICT(sha256) DO UPDATE SET refs = refs + 1",
+        )
+        .bind(&sha256)
+        .bind(&filename)
+        .bind(size)
+        .execute(pool.get_ref())
+        .await;
+        if referenced.is_err() {
+            if !stored {
+                storage.delete(&filename).await.ok();
+            }
+            return ErrorCode::UploadFailed.respond(&req);
+        }
 
-        // Write file
-        let mut file = match std::fs::File::create(&filepath) {
-            Ok(f) => f,
+        let upload_id = Uuid::new_v4().to_string();
+        let url = format!("/uploads/{}", filename);
+
+        let Ok(mut tx) = pool.begin().await else {
+            release_blobs(pool.get_ref(), &storage, &[sha256]).await;
+            return ErrorCode::UploadFailed.respond(&req);
+        };
+        let result = sqlx::query(
+            "INSERT INTO uploads (id, user_id, url, filename, mime_type, size, width, height, blurhash, dominant_color, sha256) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&upload_id)
+        .bind(&claims.sub)
+        .bind(&url)
+        .bind(&original_filename)
+        .bind(mime_type)
+        .bind(size)
+        .bind(image.width as i64)
+        .bind(image.height as i64)
+        .bind(&image.blurhash)
+        .bind(image.dominant_color.map(|color| color as i64))
+        .bind(&sha256)
+        .execute(&mut *tx)
+        .await;
+
+        // The transaction ends before the blob reference is released on another connection
+        let error = match result {
+            Ok(_) if within_quota(&mut tx, &claims.sub, &upload_id, &sha256, usage.quota).await => {
+                tx.commit().await.err().map(|_| ErrorCode::UploadFailed)
+            }
+            Ok(_) => {
+                let _ = tx.rollback().await;
+                Some(ErrorCode::StorageQuotaExceeded)
+            }
             Err(_) => {
-                return HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to save file"
-                }));
+                let _ = tx.rollback().await;
+                Some(ErrorCode::UploadFailed)
             }
         };
+        if let Some(code) = error {
+            release_blobs(pool.get_ref(), &storage, &[sha256]).await;
+            return code.respond(&req);
+        }
 
-        let mut total_size: usize = 0;
-        let max_size: usize = 8 * 1024 * 1024; // 8MB limit
-
-        while let Some(Ok(chunk)) = field.next().await {
-            total_size += chunk.len();
-            if total_size > max_size {
-                // Clean up partial file
-                drop(file);
-                std::fs::remove_file(&filepath).ok();
-                return HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "File too large (max 8MB)"
-                }));
+        // A missing thumbnail only means clients get the next size, or the original.
+        // Thumbnails are shared along with the file they were made from
+        let mut thumbnails = Vec::with_capacity(image.thumbnails.len());
+        for thumbnail in image.thumbnails {
+            let path = format!("thumbs/{}_{}.{}", sha256, thumbnail.size, extension_for(thumbnail.format));
+            let written = if stored {
+                storage.exists(&path).await.unwrap_or(false)
+            } else {
+                storage.put(&path, thumbnail.bytes, thumbnail.format.to_mime_type()).await.is_ok()
+            };
+            if !written {
+                continue;
             }
-            if file.write_all(&chunk).is_err() {
-                return HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to write file"
+            let row = sqlx::query(
+                "INSERT INTO upload_thumbnails (upload_id, size, path, mime_type, width, height) VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&upload_id)
+            .bind(thumbnail.size as i64)
+            .bind(&path)
+            .bind(thumbnail.format.to_mime_type())
+            .bind(thumbnail.width as i64)
+            .bind(thumbnail.height as i64)
+            .execute(pool.get_ref())
+            .await;
+            if row.is_ok() {
+                thumbnails.push(serde_json::json!({
+                    "size": thumbnail.size,
+                    "width": thumbnail.width,
+                    "height": thumbnail.height,
                 }));
             }
         }
 
-        // Return the URL to the uploaded file
-        let url = format!("/uploads/{}", filename);
+        // `id` goes in a message's `attachments`; `url` also works for avatars and banners
         return HttpResponse::Ok().json(serde_json::json!({
+            "id": upload_id,
             "url": url,
-            "filename": original_filename
+            "filename": original_filename,
+            "mime_type": mime_type,
+            "size": size,
+            "width": image.width,
+            "height": image.height,
+            "blurhash": image.blurhash,
+            "dominant_color": image.dominant_color,
+            "thumbnails": thumbnails,
         }));
     }
 
-    HttpResponse::BadRequest().json(serde_json::json!({
-        "error": "No file provided"
-    }))
+    ErrorCode::NoFileProvided.respond(&req)
+}
+
+/// Send a stored file with headers that keep browsers from running it, through the
+/// server or by redirecting to the storage backend. Only formats accepted at upload time
+/// are shown inline, with their own type; anything else, such as SVG files stored before
+/// uploads were checked, is downloaded instead.
+async fn safe_file_response(
+    req: &HttpRequest,
+    storage: &UploadStorage,
+    key: &str,
+    download_name: String,
+) -> HttpResponse {
+    let extension = key.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+    let format = ImageFormat::from_extension(&extension).filter(|format| ALLOWED_FORMATS.contains(format));
+
+    let disposition = ContentDisposition {
+        disposition: if format.is_some() { DispositionType::Inline } else { DispositionType::Attachment },
+        parameters: vec![DispositionParam::Filename(download_name)],
+    };
+    let content_type: mime::Mime = format
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream")
+        .parse()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    // The backend answers with the same type and disposition; it should be on another
+    // origin, since it cannot send the sandboxing headers below
+    if let Some(url) = storage.redirect_url(key, content_type.as_ref(), &disposition.to_string()) {
+        return HttpResponse::Found()
+            .insert_header((header::LOCATION, url))
+            .insert_header((header::CACHE_CONTROL, "private, max-age=300"))
+            .finish();
+    }
+
+    let mut response = if let Some(path) = storage.local_path(key) {
+        match NamedFile::open_async(path).await {
+            Ok(file) if file.metadata().is_file() => file
+                .set_content_type(content_type)
+                .set_content_disposition(disposition)
+                .into_response(req),
+            _ => return HttpResponse::NotFound().finish(),
+        }
+    } else {
+        match storage.get(key).await {
+            Ok(Some(bytes)) => HttpResponse::Ok()
+                .content_type(content_type)
+                .insert_header((header::CONTENT_DISPOSITION, disposition))
+                .body(bytes),
+            Ok(None) => return HttpResponse::NotFound().finish(),
+            Err(_) => return ErrorCode::InternalError.respond(req),
+        }
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("default-src 'none'; sandbox"),
+    );
+    // Stored files are never rewritten under the same name
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=31536000, immutable"));
+    response
+}
+
+/// GET /uploads/{filename} — Serve a stored file
+pub async fn serve_upload(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<UploadStorage>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let filename = path.into_inner();
+    if filename.starts_with('.') || filename.contains(['/', '\\']) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    // A file shared by several uploads keeps its stored name rather than one uploader's
+    let original_name: Option<String> =
+        sqlx::query_scalar("SELECT MAX(filename) FROM uploads WHERE url = ? HAVING COUNT(*) = 1")
+            .bind(format!("/uploads/{}", filename))
+            .fetch_optional(pool.get_ref())
+            .await
+            .unwrap_or(None);
+    safe_file_response(&req, &storage, &filename, original_name.unwrap_or_else(|| filename.clone())).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailQuery {
+    /// Wanted longest side in pixels.
+    pub size: Option<u32>,
+}
+
+/// GET /uploads/{id}/thumb?size= — The smallest thumbnail at least `size` pixels wide and high,
+/// else the largest one; images smaller than every thumbnail are served as they are
+pub async fn serve_thumbnail(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<UploadStorage>,
+    path: web::Path<String>,
+    query: web::Query<ThumbnailQuery>,
+) -> HttpResponse {
+    let upload_id = path.into_inner();
+    let size = query.size.unwrap_or(DEFAULT_THUMBNAIL_SIZE) as i64;
+
+    let thumbnail: Option<String> = sqlx::query_scalar(
+        "SELECT path FROM upload_thumbnails WHERE upload_id = ? \
+         ORDER BY size < ?, CASE WHEN size >= ? THEN size ELSE -size END LIMIT 1",
+    )
+    .bind(&upload_id)
+    .bind(size)
+    .bind(size)
+    .fetch_optional(pool.get_ref())
+    .await
+    .unwrap_or(None);
+
+    if let Some(thumbnail) = thumbnail {
+        let name = thumbnail.rsplit('/').next().unwrap_or(&thumbnail).to_string();
+        return safe_file_response(&req, &storage, &thumbnail, name).await;
+    }
+
+    let original = sqlx::query("SELECT url, filename FROM uploads WHERE id = ?")
+        .bind(&upload_id)
+        .fetch_optional(pool.get_ref())
+        .await
+        .unwrap_or(None);
+    match original {
+        Some(row) => {
+            let url: String = row.try_get("url").unwrap_or_default();
+            let filename: String = row.try_get("filename").unwrap_or_default();
+            safe_file_response(&req, &storage, url.trim_start_matches("/uploads/"), filename).await
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Check the attachments of a new message: uploads of its author, not attached to any
+/// message yet, each at most once. A legacy `image_url` must be the url of such an
+/// upload and comes first.
+pub(crate) async fn check_attachments(
+    pool: &SqlitePool,
+    user_id: &str,
+    image_url: Option<&str>,
+    inputs: &[AttachmentInput],
+) -> Result<Vec<AttachmentInput>, ErrorCode> {
+    let mut attachments = Vec::with_capacity(inputs.len() + 1);
+    if let Some(url) = image_url.filter(|url| !url.is_empty()) {
+        // The same file uploaded twice has one url for both uploads
+        let upload_id: Option<String> = sqlx::query_scalar(
+            "SELECT id FROM uploads WHERE url = ? AND user_id = ? \
+             AND NOT EXISTS (SELECT 1 FROM attachments WHERE upload_id = uploads.id) LIMIT 1",
+        )
+        .bind(url)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None);
+        let Some(upload_id) = upload_id else {
+            return Err(ErrorCode::InvalidAttachment);
+        };
+        attachments.push(AttachmentInput {
+            upload_id,
+            alt_text: None,
+            spoiler: false,
+        });
+    }
+    attachments.extend(inputs.iter().cloned());
+
+    if attachments.len() > MAX_ATTACHMENTS {
+        return Err(ErrorCode::TooManyAttachments);
+    }
+
+    let mut seen = HashSet::new();
+    for attachment in attachments.iter_mut() {
+        if !seen.insert(attachment.upload_id.clone()) {
+            return Err(ErrorCode::InvalidAttachment);
+        }
+        attachment.alt_text = attachment
+            .alt_text
+            .as_deref()
+            .map(str::trim)
+            .filter(|alt| !alt.is_empty())
+            .map(str::to_string);
+        if attachment.alt_text.as_ref().is_some_and(|alt| alt.chars().count() > MAX_ALT_TEXT_CHARS) {
+            return Err(ErrorCode::InvalidAttachment);
+        }
+
+        let available: Option<String> = sqlx::query_scalar(
+            "SELECT id FROM uploads WHERE id = ? AND user_id = ? \
+             AND NOT EXISTS (SELECT 1 FROM attachments WHERE upload_id = uploads.id)",
+        )
+        .bind(&attachment.upload_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None);
+        if available.is_none() {
+            return Err(ErrorCode::InvalidAttachment);
+        }
+    }
+
+    Ok(attachments)
+}
+
+/// Link checked attachments to a stored message, in order.
+pub(crate) async fn insert_attachments(
+    conn: impl Acquire<'_, Database = Sqlite>,
+    message_id: &str,
+    attachments: &[AttachmentInput],
+) -> Result<(), sqlx::Error> {
+    let mut conn = conn.acquire().await?;
+    for (position, attachment) in attachments.iter().enumerate() {
+        sqlx::query("INSERT INTO attachments (message_id, upload_id, position, alt_text, spoiler) VALUES (?, ?, ?, ?, ?)")
+            .bind(message_id)
+            .bind(&attachment.upload_id)
+            .bind(position as i64)
+            .bind(&attachment.alt_text)
+            .bind(attachment.spoiler)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Delete the uploads attached to a message, and their files unless other uploads share them.
+pub(crate) async fn delete_message_attachments(pool: &SqlitePool, storage: &UploadStorage, message_id: &str) {
+    let hashes: Vec<String> = sqlx::query_scalar(
+        "SELECT u.sha256 FROM attachments a JOIN uploads u ON u.id = a.upload_id \
+         WHERE a.message_id = ? AND u.sha256 IS NOT NULL",
+    )
+    .bind(message_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+    // Files stored before deduplication belong to their upload alone
+    let urls: Vec<String> = sqlx::query_scalar(
+        "SELECT u.url FROM attachments a JOIN uploads u ON u.id = a.upload_id \
+         WHERE a.message_id = ? AND u.sha256 IS NULL",
+    )
+    .bind(message_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+    let thumbnails: Vec<String> = sqlx::query_scalar(
+        "SELECT t.path FROM attachments a JOIN uploads u ON u.id = a.upload_id \
+         JOIN upload_thumbnails t ON t.upload_id = a.upload_id WHERE a.message_id = ? AND u.sha256 IS NULL",
+    )
+    .bind(message_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    let _ = sqlx::query("DELETE FROM uploads WHERE id IN (SELECT upload_id FROM attachments WHERE message_id = ?)")
+        .bind(message_id)
+        .execute(pool)
+        .await;
+
+    for url in urls {
+        if let Some(key) = url.strip_prefix("/uploads/") {
+            storage.delete(key).await.ok();
+        }
+    }
+    for path in thumbnails {
+        storage.delete(&path).await.ok();
+    }
+    release_blobs(pool, storage, &hashes).await;
+}
+
+pub(crate) async fn enrich_messages_with_attachments<'e>(executor: impl Executor<'e, Database = Sqlite>, messages: &mut [Message]) {
+    if messages.is_empty() {
+        return;
+    }
+
+    let mut query = String::from(
+        "SELECT a.message_id, a.alt_text, a.spoiler, u.id, u.url, u.filename, u.mime_type, u.size, u.width, u.height, \
+                u.blurhash, u.dominant_color \
+         FROM attachments a JOIN uploads u ON u.id = a.upload_id WHERE a.message_id IN (",
+    );
+    for idx in 0..messages.len() {
+        if idx > 0 {
+            query.push(',');
+        }
+        query.push('?');
+    }
+    query.push_str(") ORDER BY a.position");
+
+    let mut qx = sqlx::query(&query);
+    for message in messages.iter() {
+        qx = qx.bind(&message.id);
+    }
+
+    let rows = qx.fetch_all(executor).await.unwrap_or_default();
+    let mut per_message: HashMap<String, Vec<Attachment>> = HashMap::new();
+    for row in rows {
+        let message_id: String = row.try_get("message_id").unwrap_or_default();
+        per_message.entry(message_id).or_default().push(Attachment {
+            id: row.try_get("id").unwrap_or_default(),
+            url: row.try_get("url").unwrap_or_default(),
+            filename: row.try_get("filename").unwrap_or_default(),
+            mime_type: row.try_get("mime_type").unwrap_or_default(),
+            size: row.try_get("size").unwrap_or(0),
+            width: row.try_get("width").unwrap_or(None),
+            height: row.try_get("height").unwrap_or(None),
+            blurhash: row.try_get("blurhash").unwrap_or(None),
+            dominant_color: row.try_get::<Option<i64>, _>("dominant_color").unwrap_or(None).map(|color| color as u32),
+            alt_text: row.try_get("alt_text").unwrap_or(None),
+            spoiler: row.try_get("spoiler").unwrap_or(false),
+        });
+    }
+
+    for message in messages.iter_mut() {
+        message.attachments = per_message.remove(&message.id).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn upload_pool() -> SqlitePool {
+        let pool = crate::db::test_pool().await;
+        for sql in [
+            "INSERT INTO users (id, username, password_hash) VALUES ('alice', 'alice', ''), ('bob', 'bob', '')",
+            "INSERT INTO uploads (id, user_id, url, filename, mime_type, size) VALUES \
+             ('up1', 'alice', '/uploads/up1.png', 'a.png', 'image/png', 10), \
+             ('up2', 'alice', '/uploads/up2.png', 'b.png', 'image/png', 10), \
+             ('used', 'alice', '/uploads/used.png', 'c.png', 'image/png', 10), \
+             ('bobs', 'bob', '/uploads/bobs.png', 'd.png', 'image/png', 10)",
+            "INSERT INTO messages (id, room_id, user_id, username, content) VALUES ('m1', 'general', 'alice', 'alice', '')",
+            "INSERT INTO attachments (message_id, upload_id, position) VALUES ('m1', 'used', 0)",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    fn input(upload_id: &str, alt_text: Option<&str>) -> AttachmentInput {
+        AttachmentInput {
+            upload_id: upload_id.to_string(),
+            alt_text: alt_text.map(str::to_string),
+            spoiler: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_image_url_comes_first() {
+        let pool = upload_pool().await;
+        let checked = check_attachments(&pool, "alice", Some("/uploads/up1.png"), &[input("up2", Some("  a cat "))])
+            .await
+            .unwrap();
+        let ids: Vec<&str> = checked.iter().map(|a| a.upload_id.as_str()).collect();
+        assert_eq!(ids, ["up1", "up2"]);
+        assert_eq!(checked[1].alt_text.as_deref(), Some("a cat"));
+
+        let checked = check_attachments(&pool, "alice", Some(""), &[input("up1", Some("   "))]).await.unwrap();
+        assert_eq!(checked.len(), 1);
+        assert_eq!(checked[0].alt_text, None);
+    }
+
+    #[tokio::test]
+    async fn only_unused_uploads_of_the_author_are_accepted() {
+        let pool = upload_pool().await;
+        for (image_url, inputs) in [
+            (Some("/uploads/bobs.png"), vec![]),
+            (Some("https://example.com/cat.png"), vec![]),
+            (None, vec![input("bobs", None)]),
+            (None, vec![input("used", None)]),
+            (None, vec![input("missing", None)]),
+            (None, vec![input("up1", None), input("up1", None)]),
+            (Some("/uploads/up1.png"), vec![input("up1", None)]),
+            (None, vec![input("up1", Some(&"x".repeat(MAX_ALT_TEXT_CHARS + 1)))]),
+        ] {
+            assert_eq!(
+                check_attachments(&pool, "alice", image_url, &inputs).await.unwrap_err(),
+                ErrorCode::InvalidAttachment
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn attachments_are_capped() {
+        let pool = upload_pool().await;
+        let inputs: Vec<AttachmentInput> = (0..=MAX_ATTACHMENTS).map(|i| input(&format!("up{}", i), None)).collect();
+        assert_eq!(
+            check_attachments(&pool, "alice", None, &inputs).await.unwrap_err(),
+            ErrorCode::TooManyAttachments
+        );
+    }
+
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(4, 3).write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn png() -> Vec<u8> {
+        encoded(ImageFormat::Png)
+    }
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut crc = flate2::Crc::new();
+        crc.update(kind);
+        crc.update(data);
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&crc.sum().to_be_bytes());
+        chunk
+    }
+
+    #[test]
+    fn real_images_are_detected() {
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif, ImageFormat::Bmp] {
+            let (detected, image, orientation) = detect_image(&encoded(format)).unwrap();
+            assert_eq!(detected, format);
+            assert_eq!((image.width(), image.height()), (4, 3));
+            assert_eq!(orientation, Orientation::NoTransforms);
+        }
+        assert_eq!(extension_for(ImageFormat::Jpeg), "jpg");
+        assert_eq!(extension_for(ImageFormat::Png), "png");
+    }
+
+    #[test]
+    fn non_images_are_rejected() {
+        assert!(detect_image(b"").is_none());
+        assert!(detect_image(b"hello, world").is_none());
+        assert!(detect_image(b"<svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>").is_none());
+        assert!(detect_image(b"<?xml version=\"1.0\"?><svg></svg>").is_none());
+        // The right magic bytes are not enough
+        assert!(detect_image(b"\x89PNG\r\n\x1a\n<script>alert(1)</script>").is_none());
+        let mut truncated = png();
+        truncated.truncate(40);
+        assert!(detect_image(&truncated).is_none());
+        // A known format that is not allowed
+        assert!(detect_image(b"II*\x00\x08\x00\x00\x00").is_none());
+    }
+
+    #[test]
+    fn oversized_images_are_rejected() {
+        let mut bytes = png();
+        // Claim a width beyond MAX_IMAGE_SIDE in the IHDR chunk
+        bytes[16..20].copy_from_slice(&(MAX_IMAGE_SIDE + 1).to_be_bytes());
+        let crc = png_chunk(b"IHDR", &bytes[16..29]);
+        bytes[29..33].copy_from_slice(&crc[crc.len() - 4..]);
+        assert!(detect_image(&bytes).is_none());
+    }
+
+    fn solid(width: u32, height: u32, color: [u8; 3]) -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(width, height, image::Rgb(color));
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image).write_to(&mut out, ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn thumbnails_are_only_made_for_larger_images() {
+        let processed = process_image(solid(400, 200, [0, 0, 0]), false).unwrap();
+        assert_eq!((processed.width, processed.height), (400, 200));
+        let sizes: Vec<u32> = processed.thumbnails.iter().map(|t| t.size).collect();
+        assert_eq!(sizes, [160, 320]);
+        let thumbnail = &processed.thumbnails[0];
+        assert_eq!((thumbnail.width, thumbnail.height), (160, 80));
+        let (format, decoded, _) = detect_image(&thumbnail.bytes).unwrap();
+        assert_eq!(format, thumbnail.format);
+        assert_eq!((decoded.width(), decoded.height()), (160, 80));
+
+        assert!(process_image(png(), false).unwrap().thumbnails.is_empty());
+    }
+
+    #[test]
+    fn placeholders_follow_the_image_colors() {
+        let processed = process_image(solid(64, 48, [0x33, 0x66, 0x99]), false).unwrap();
+        assert_eq!(processed.dominant_color, Some(0x336699));
+        let blurhash = processed.blurhash.unwrap();
+        // 4x3 components: size flag, max AC, DC, then 11 AC values of 2 characters
+        assert_eq!(blurhash.len(), 1 + 1 + 4 + 2 * 11);
+
+        let transparent = image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 0]));
+        assert_eq!(average_color(&transparent), None);
+    }
+
+    fn jpeg() -> Vec<u8> {
+        encoded(ImageFormat::Jpeg)
+    }
+
+    /// PNG with `chunk` inserted right after IHDR.
+    fn png_with(chunk: &[u8]) -> Vec<u8> {
+        let bytes = png();
+        let ihdr_end = 8 + 12 + 13;
+        [&bytes[..ihdr_end], chunk, &bytes[ihdr_end..]].concat()
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /// EXIF payload with only an orientation tag.
+    fn exif_orientation(value: u16) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2A\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1]);
+        exif.extend_from_slice(&value.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        exif
+    }
+
+    /// JPEG with `segments` inserted right after SOI.
+    fn jpeg_with(segments: &[Vec<u8>]) -> Vec<u8> {
+        let bytes = jpeg();
+        [&bytes[..2], &segments.concat(), &bytes[2..]].concat()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn jpeg_orientation_is_reported() {
+        let bytes = jpeg_with(&[jpeg_segment(0xE1, &exif_orientation(6))]);
+        let (_, _, orientation) = detect_image(&bytes).unwrap();
+        assert_eq!(orientation, Orientation::Rotate90);
+    }
+
+    #[test]
+    fn png_metadata_is_stripped() {
+        for kind in [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"] {
+            let bytes = png_with(&png_chunk(kind, b"Author\0someone"));
+            let stripped = strip_png(&bytes).unwrap();
+            assert!(!contains(&stripped, kind));
+            assert_eq!(stripped, png());
+            assert!(detect_image(&stripped).is_some());
+        }
+    }
+
+    #[test]
+    fn png_without_metadata_is_unchanged() {
+        let bytes = png_with(&png_chunk(b"gAMA", &45455u32.to_be_bytes()));
+        assert_eq!(strip_png(&bytes).unwrap(), bytes);
+    }
+
+    #[test]
+    fn malformed_png_is_not_stripped() {
+        assert!(strip_png(&jpeg()).is_none());
+        let bytes = png();
+        // No IEND chunk
+        assert!(strip_png(&bytes[..bytes.len() - 12]).is_none());
+        // A chunk longer than the file
+        let mut bytes = png_with(&png_chunk(b"tEXt", b"a"));
+        bytes[33..37].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(strip_png(&bytes).is_none());
+    }
+
+    #[test]
+    fn jpeg_metadata_is_stripped() {
+        let icc = [b"ICC_PROFILE\0".as_slice(), &[1, 1, 0, 0]].concat();
+        let bytes = jpeg_with(&[
+            jpeg_segment(0xE1, &exif_orientation(6)),
+            jpeg_segment(0xE1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"),
+            jpeg_segment(0xED, b"Photoshop 3.0\0"),
+            jpeg_segment(0xFE, b"a comment"),
+            jpeg_segment(0xE2, &icc),
+        ]);
+        let stripped = strip_jpeg(&bytes).unwrap();
+        assert!(!contains(&stripped, b"Exif"));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        assert!(!contains(&stripped, b"Photoshop"));
+        assert!(!contains(&stripped, b"a comment"));
+        assert!(contains(&stripped, b"ICC_PROFILE"));
+        assert_eq!(stripped, jpeg_with(&[jpeg_segment(0xE2, &icc)]));
+
+        let (_, _, orientation) = detect_image(&stripped).unwrap();
+        assert_eq!(orientation, Orientation::NoTransforms);
+    }
+
+    #[test]
+    fn jpeg_trailing_data_is_dropped() {
+        let bytes = [jpeg(), b"PK\x03\x04 hidden archive".to_vec()].concat();
+        assert_eq!(strip_jpeg(&bytes).unwrap(), jpeg());
+    }
+
+    #[test]
+    fn malformed_jpeg_is_not_stripped() {
+        assert!(strip_jpeg(&png()).is_none());
+        assert!(strip_jpeg(&[0xFF, 0xD8, 0x00]).is_none());
+        // A segment longer than the file
+        assert!(strip_jpeg(&[0xFF, 0xD8, 0xFF, 0xE1, 0xFF, 0xFF, 0x00]).is_none());
+        // No end of image after the scan
+        let bytes = jpeg();
+        assert!(strip_jpeg(&bytes[..bytes.len() - 2]).is_none());
+    }
+
+    #[test]
+    fn webp_metadata_is_stripped() {
+        let bytes = encode_image(&DynamicImage::new_rgb8(4, 3), ImageFormat::WebP).unwrap();
+        let mut vp8x = b"VP8X".to_vec();
+        vp8x.extend_from_slice(&10u32.to_le_bytes());
+        vp8x.extend_from_slice(&[0x0C, 0, 0, 0, 3, 0, 0, 2, 0, 0]);
+        let mut exif = b"EXIF".to_vec();
+        exif.extend_from_slice(&3u32.to_le_bytes());
+        exif.extend_from_slice(b"abc\0");
+        let mut with_exif = [&bytes[..12], &vp8x, &exif, &bytes[12..]].concat();
+        let riff_size = (with_exif.len() - 8) as u32;
+        with_exif[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+        let stripped = strip_webp(&with_exif).unwrap();
+        assert!(!contains(&stripped, b"EXIF"));
+        assert_eq!(stripped[20] & 0x0C, 0);
+        assert_eq!(u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize, stripped.len() - 8);
+        assert!(strip_webp(&png()).is_none());
+    }
+
+    #[test]
+    fn processed_images_lose_their_metadata() {
+        let bytes = png_with(&png_chunk(b"tEXt", b"Location\0lat 52.1 lon 4.3"));
+        let processed = process_image(bytes.clone(), false).unwrap();
+        assert!(!contains(&processed.bytes, b"lat 52.1"));
+        let kept = process_image(bytes.clone(), true).unwrap();
+        assert_eq!(kept.bytes, bytes);
+    }
+
+    async fn quota_pool() -> SqlitePool {
+        let pool = upload_pool().await;
+        for sql in [
+            "UPDATE uploads SET size = 100",
+            "INSERT INTO uploads (id, user_id, url, filename, mime_type, size, sha256) VALUES \
+             ('h1', 'alice', '/uploads/aaa.png', 'x.png', 'image/png', 1000, 'aaa'), \
+             ('h2', 'alice', '/uploads/aaa.png', 'y.png', 'image/png', 1000, 'aaa'), \
+             ('h3', 'bob', '/uploads/aaa.png', 'z.png', 'image/png', 1000, 'aaa')",
+            "INSERT INTO server_settings (key, value) VALUES ('role_upload_quotas', '{\"user\": 5000}')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    #[tokio::test]
+    async fn shared_files_count_once_per_user() {
+        let pool = quota_pool().await;
+        // Three legacy files, then one deduplicated file uploaded twice
+        assert_eq!(storage_usage(&pool, "alice", "user").await.used, 3 * 100 + 1000);
+        assert_eq!(storage_usage(&pool, "bob", "user").await.used, 100 + 1000);
+        assert_eq!(storage_usage(&pool, "nobody", "user").await.used, 0);
+    }
+
+    #[tokio::test]
+    async fn user_quotas_override_role_quotas() {
+        let pool = quota_pool().await;
+        assert_eq!(storage_usage(&pool, "alice", "user").await.quota, Some(5000));
+        assert_eq!(storage_usage(&pool, "alice", "admin").await.quota, None);
+
+        sqlx::query("UPDATE users SET upload_quota = 0 WHERE id = 'alice'").execute(&pool).await.unwrap();
+        assert_eq!(storage_usage(&pool, "alice", "admin").await.quota, Some(0));
+        assert_eq!(storage_usage(&pool, "bob", "user").await.quota, Some(5000));
+    }
+
+    #[tokio::test]
+    async fn quotas_are_checked_with_the_new_upload_counted() {
+        let pool = quota_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            "INSERT INTO uploads (id, user_id, url, filename, mime_type, size, sha256) VALUES \
+             ('n1', 'alice', '/uploads/bbb.png', 'n.png', 'image/png', 2000, 'bbb'), \
+             ('n2', 'alice', '/uploads/aaa.png', 'again.png', 'image/png', 1000, 'aaa')",
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        assert!(within_quota(&mut tx, "alice", "n1", "bbb", Some(3300)).await);
+        assert!(!within_quota(&mut tx, "alice", "n1", "bbb", Some(3299)).await);
+        assert!(within_quota(&mut tx, "alice", "n1", "bbb", None).await);
+        // A file they already had costs nothing more
+        assert!(within_quota(&mut tx, "alice", "n2", "aaa", Some(0)).await);
+        tx.rollback().await.unwrap();
+
+        assert_eq!(storage_usage(&pool, "alice", "user").await.used, 3 * 100 + 1000);
+    }
 }
@@ -0,0 +1,411 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use uuid::Uuid;
+
+use crate::auth::{extract_claims, Claims};
+use crate::bots::{generate_token, hash_token, Scope};
+use crate::errors::{ApiError, ErrorCode};
+use crate::events::{Embed, ServerEvent};
+use crate::mentions::record_mentions;
+use crate::messages::{broadcast_message, fetch_stored_message};
+use crate::outgoing_webhooks;
+use crate::rate_limit::WsLimits;
+use crate::ws::Broadcaster;
+
+/// Bot user that authors every webhook message, created by migration 016.
+/// The display name and avatar are stored on each message instead.
+pub const WEBHOOK_USER_ID: &str = "webhook";
+
+const WEBHOOK_TOKEN_PREFIX: &str = "vxw_";
+const MAX_NAME_CHARS: usize = 32;
+const MAX_URL_LEN: usize = 2048;
+const MAX_EMBEDS: usize = 10;
+const MAX_EMBED_FIELDS: usize = 25;
+const MAX_EMBED_TITLE_CHARS: usize = 256;
+const MAX_EMBED_DESCRIPTION_CHARS: usize = 4096;
+const MAX_EMBED_FOOTER_CHARS: usize = 2048;
+const MAX_FIELD_NAME_CHARS: usize = 256;
+const MAX_FIELD_VALUE_CHARS: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhook {
+    pub name: String,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub room_id: String,
+    pub name: String,
+    pub avatar_url: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: String,
+}
+
+/// Returned on create and rotate, the only times the secret is visible.
+#[derive(Debug, Serialize)]
+pub struct WebhookWithToken {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub token: String,
+    pub url: String,
+}
+
+/// Body of `POST /api/webhooks/{id}/{token}`.
+#[derive(Debug, Deserialize)]
+pub struct ExecuteWebhook {
+    #[serde(default)]
+    pub content: String,
+    /// Overrides the webhook's name for this message only.
+    pub username: Option<String>,
+    /// Overrides the webhook's avatar for this message only.
+    pub avatar_url: Option<String>,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+}
+
+pub(crate) fn require_admin(req: &HttpRequest) -> Result<Claims, ApiError> {
+    let Some(claims) = extract_claims(req) else {
+        return Err(ApiError::new(ErrorCode::NotAuthenticated));
+    };
+    claims.require_scope(Scope::Moderation)?;
+    if claims.role != "admin" {
+        return Err(ApiError::new(ErrorCode::AdminOnly));
+    }
+    Ok(claims)
+}
+
+fn webhook_from_row(row: &sqlx::sqlite::SqliteRow) -> Webhook {
+    Webhook {
+        id: row.get("id"),
+        room_id: row.get("room_id"),
+        name: row.get("name"),
+        avatar_url: row.try_get("avatar_url").unwrap_or(None),
+        created_by: row.try_get("created_by").unwrap_or(None),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn with_token(webhook: Webhook, token: String) -> WebhookWithToken {
+    let url = format!("/api/webhooks/{}/{}", webhook.id, token);
+    WebhookWithToken { webhook, token, url }
+}
+
+/// Trimmed name, or `None` when it is empty or too long.
+fn clean_name(raw: &str) -> Option<String> {
+    let name = raw.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Media must be an http(s) URL or a file we serve, so clients can render it as-is.
+fn check_media_url(url: &Option<String>) -> Result<Option<String>, ApiError> {
+    let Some(url) = url.as_deref().map(str::trim).filter(|u| !u.is_empty()) else {
+        return Ok(None);
+    };
+    let allowed = url.starts_with("https://") || url.starts_with("http://") || url.starts_with("/uploads/");
+    if !allowed || url.len() > MAX_URL_LEN || url.chars().any(|c| c.is_whitespace() || c == '"' || c == '\'') {
+        return Err(ApiError::with_details(ErrorCode::InvalidMediaUrl, format!("Rejected URL '{}'", url)));
+    }
+    Ok(Some(url.to_string()))
+}
+
+fn check_length(value: Option<&str>, max: usize, what: &str) -> Result<(), ApiError> {
+    match value {
+        Some(text) if text.chars().count() > max => Err(ApiError::with_details(
+            ErrorCode::InvalidEmbed,
+            format!("{} is longer than {} characters", what, max),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_embeds(embeds: &mut [Embed]) -> Result<(), ApiError> {
+    if embeds.len() > MAX_EMBEDS {
+        return Err(ApiError::with_details(
+            ErrorCode::InvalidEmbed,
+            format!("At most {} embeds per message", MAX_EMBEDS),
+        ));
+    }
+    for embed in embeds.iter_mut() {
+        check_length(embed.title.as_deref(), MAX_EMBED_TITLE_CHARS, "Embed title")?;
+        check_length(embed.description.as_deref(), MAX_EMBED_DESCRIPTION_CHARS, "Embed description")?;
+        check_length(embed.footer.as_deref(), MAX_EMBED_FOOTER_CHARS, "Embed footer")?;
+        if embed.color.is_some_and(|color| color > 0xFF_FF_FF) {
+            return Err(ApiError::with_details(ErrorCode::InvalidEmbed, "Embed color must be an RGB value"));
+        }
+        if embed.fields.len() > MAX_EMBED_FIELDS {
+            return Err(ApiError::with_details(
+                ErrorCode::InvalidEmbed,
+                format!("At most {} fields per embed", MAX_EMBED_FIELDS),
+            ));
+        }
+        for field in &embed.fields {
+            if field.name.trim().is_empty() || field.value.trim().is_empty() {
+                return Err(ApiError::with_details(ErrorCode::InvalidEmbed, "Embed fields need a name and a value"));
+            }
+            check_length(Some(&field.name), MAX_FIELD_NAME_CHARS, "Embed field name")?;
+            check_length(Some(&field.value), MAX_FIELD_VALUE_CHARS, "Embed field value")?;
+        }
+        embed.url = check_media_url(&embed.url)?;
+        embed.image_url = check_media_url(&embed.image_url)?;
+
+        let is_empty = embed.title.is_none()
+            && embed.description.is_none()
+            && embed.fields.is_empty()
+            && embed.image_url.is_none();
+        if is_empty {
+            return Err(ApiError::with_details(ErrorCode::InvalidEmbed, "Embed has nothing to show"));
+        }
+    }
+    Ok(())
+}
+
+/// GET /api/rooms/{room_id}/webhooks — List a room's webhooks (Admin only)
+pub async fn list_webhooks(req: HttpRequest, pool: web::Data<SqlitePool>, path: web::Path<String>) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let rows = sqlx::query(
+        "SELECT id, room_id, name, avatar_url, created_by, created_at FROM webhooks WHERE room_id = ? ORDER BY created_at ASC"
+    )
+    .bind(path.into_inner())
+    .fetch_all(pool.get_ref())
+    .await
+    .unwrap_or_default();
+
+    let webhooks: Vec<Webhook> = rows.iter().map(webhook_from_row).collect();
+    HttpResponse::Ok().json(webhooks)
+}
+
+/// POST /api/rooms/{room_id}/webhooks — Create a webhook; the token is only returned here and on rotate (Admin only)
+pub async fn create_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: web::Json<CreateWebhook>,
+) -> HttpResponse {
+    let claims = match require_admin(&req) {
+        Ok(c) => c,
+        Err(err) => return err.respond(&req),
+    };
+
+    let room_id = path.into_inner();
+    let Some(name) = clean_name(&body.name) else {
+        return ErrorCode::InvalidWebhookName.respond(&req);
+    };
+    let avatar_url = match check_media_url(&body.avatar_url) {
+        Ok(url) => url,
+        Err(err) => return err.respond(&req),
+    };
+
+    let room_exists: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rooms WHERE id = ?")
+        .bind(&room_id)
+        .fetch_one(pool.get_ref())
+        .await
+        .unwrap_or(0);
+    if room_exists <= 0 {
+        return ErrorCode::RoomNotFound.respond(&req);
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let token = generate_token(WEBHOOK_TOKEN_PREFIX);
+    let created_at = chrono::Utc::now().to_rfc3339();
+
+    let result = sqlx::query(
+        "INSERT INTO webhooks (id, room_id, name, avatar_url, token_hash, created_by, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(&room_id)
+    .bind(&name)
+    .bind(&avatar_url)
+    .bind(hash_token(&token))
+    .bind(&claims.sub)
+    .bind(&created_at)
+    .execute(pool.get_ref())
+    .await;
+
+    if result.is_err() {
+        return ErrorCode::InternalError.respond(&req);
+    }
+
+    let webhook = Webhook {
+        id,
+        room_id,
+        name,
+        avatar_url,
+        created_by: Some(claims.sub),
+        created_at,
+    };
+    HttpResponse::Created().json(with_token(webhook, token))
+}
+
+/// POST /api/rooms/{room_id}/webhooks/{id}/rotate — Replace the token; the old URL stops working (Admin only)
+pub async fn rotate_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let (room_id, webhook_id) = path.into_inner();
+    let token = generate_token(WEBHOOK_TOKEN_PREFIX);
+
+    let result = sqlx::query("UPDATE webhooks SET token_hash = ? WHERE id = ? AND room_id = ?")
+        .bind(hash_token(&token))
+        .bind(&webhook_id)
+        .bind(&room_id)
+        .execute(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(res) if res.rows_affected() > 0 => {}
+        Ok(_) => return ErrorCode::WebhookNotFound.respond(&req),
+        Err(_) => return ErrorCode::InternalError.respond(&req),
+    }
+
+    let row = sqlx::query("SELECT id, room_id, name, avatar_url, created_by, created_at FROM webhooks WHERE id = ?")
+        .bind(&webhook_id)
+        .fetch_optional(pool.get_ref())
+        .await
+        .unwrap_or(None);
+
+    match row {
+        Some(row) => HttpResponse::Ok().json(with_token(webhook_from_row(&row), token)),
+        None => ErrorCode::WebhookNotFound.respond(&req),
+    }
+}
+
+/// DELETE /api/rooms/{room_id}/webhooks/{id} — Delete a webhook; its messages stay (Admin only)
+pub async fn delete_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    if let Err(err) = require_admin(&req) {
+        return err.respond(&req);
+    }
+
+    let (room_id, webhook_id) = path.into_inner();
+    let result = sqlx::query("DELETE FROM webhooks WHERE id = ? AND room_id = ?")
+        .bind(&webhook_id)
+        .bind(&room_id)
+        .execute(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(res) if res.rows_affected() > 0 => HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" })),
+        Ok(_) => ErrorCode::WebhookNotFound.respond(&req),
+        Err(_) => ErrorCode::InternalError.respond(&req),
+    }
+}
+
+/// POST /api/webhooks/{id}/{token} — Post a message into the webhook's room; the token is the only credential
+pub async fn execute_webhook(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<(String, String)>,
+    body: web::Json<ExecuteWebhook>,
+    broadcaster: web::Data<Broadcaster>,
+    limits: web::Data<WsLimits>,
+) -> HttpResponse {
+    let (webhook_id, token) = path.into_inner();
+
+    let row = sqlx::query("SELECT room_id, name, avatar_url, token_hash FROM webhooks WHERE id = ?")
+        .bind(&webhook_id)
+        .fetch_optional(pool.get_ref())
+        .await
+        .unwrap_or(None);
+    // A wrong token looks the same as an unknown webhook
+    let Some(row) = row.filter(|row| row.get::<String, _>("token_hash") == hash_token(&token)) else {
+        return ErrorCode::WebhookNotFound.respond(&req);
+    };
+
+    let mut input = body.into_inner();
+    let room_id: String = row.get("room_id");
+
+    let username = match input.username.as_deref().filter(|name| !name.trim().is_empty()) {
+        Some(name) => match clean_name(name) {
+            Some(name) => name,
+            None => return ErrorCode::InvalidWebhookName.respond(&req),
+        },
+        None => row.get("name"),
+    };
+    let avatar_url = match check_media_url(&input.avatar_url) {
+        Ok(Some(url)) => Some(url),
+        Ok(None) => row.try_get("avatar_url").unwrap_or(None),
+        Err(err) => return err.respond(&req),
+    };
+    let image_url = match check_media_url(&input.image_url) {
+        // Deleting the message deletes its image, so it must not be a file we store
+        Ok(Some(url)) if url.starts_with("/uploads/") => {
+            return ApiError::with_details(ErrorCode::InvalidMediaUrl, "image_url must be an http(s) URL").respond(&req)
+        }
+        Ok(url) => url,
+        Err(err) => return err.respond(&req),
+    };
+    if let Err(err) = check_embeds(&mut input.embeds) {
+        return err.respond(&req);
+    }
+
+    if input.content.trim().is_empty() && image_url.is_none() && input.embeds.is_empty() {
+        return ErrorCode::EmptyMessage.respond(&req);
+    }
+    if input.content.chars().count() > limits.max_message_chars {
+        return ErrorCode::MessageTooLong.respond(&req);
+    }
+
+    let embeds = if input.embeds.is_empty() {
+        None
+    } else {
+        serde_json::to_string(&input.embeds).ok()
+    };
+    let msg_id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+
+    let Ok(mut tx) = pool.begin().await else {
+        return ErrorCode::MessageNotStored.respond(&req);
+    };
+    let result = sqlx::query(
+        "INSERT INTO messages (id, room_id, user_id, username, content, created_at, image_url, webhook_id, author_avatar_url, embeds) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&msg_id)
+    .bind(&room_id)
+    .bind(WEBHOOK_USER_ID)
+    .bind(&username)
+    .bind(&input.content)
+    .bind(&now)
+    .bind(&image_url)
+    .bind(&webhook_id)
+    .bind(&avatar_url)
+    .bind(&embeds)
+    .execute(&mut *tx)
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("Webhook message insert error: {:?}", e);
+        return ErrorCode::MessageNotStored.respond(&req);
+    }
+    // Webhooks may ping users, never roles or everyone
+    record_mentions(&mut *tx, &msg_id, &input.content, false).await;
+
+    let Some(message) = fetch_stored_message(&mut *tx, &msg_id).await else {
+        return ErrorCode::MessageNotStored.respond(&req);
+    };
+    let queued = outgoing_webhooks::enqueue(&mut tx, &ServerEvent::Message(message.to_event())).await;
+    if queued.is_err() || tx.commit().await.is_err() {
+        return ErrorCode::MessageNotStored.respond(&req);
+    }
+    broadcast_message(pool.get_ref(), &broadcaster, &message).await;
+    HttpResponse::Created().json(message)
+}
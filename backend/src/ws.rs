@@ -1,46 +1,103 @@
 use actix_web::{web, HttpRequest, HttpResponse};
-use actix_ws::Message;
+use actix_ws::{CloseCode, CloseReason, Message, ProtocolError};
 use futures_util::StreamExt;
-use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use serde::Deserialize;
+use sqlx::{Row, SqlitePool};
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
-use uuid::Uuid;
-
-/// Represents a chat message sent/received over WebSocket.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct WsMessage {
-    #[serde(rename = "type")]
-    pub msg_type: String, // "message", "join", "leave", "room_deleted"
-    pub room_id: Option<String>,
-    pub user_id: Option<String>,
-    pub username: Option<String>,
-    pub content: Option<String>,
-    pub reply_to_id: Option<String>,
-    pub avatar_color: Option<i32>,
-    pub image_url: Option<String>,
+
+use crate::auth::{claims_for_bearer, extract_claims, Claims};
+use crate::bots::Scope;
+use crate::codec::{self, BroadcastFrame, WireFormat};
+use crate::commands::{self, CommandContext, CommandOutcome};
+use crate::digest;
+use crate::errors::{ApiError, ErrorCode, Lang};
+use crate::events::{
+    self, AckEvent, ClientEvent, ErrorEvent, HeartbeatAckEvent, HelloEvent, JoinPayload, LeaveEvent,
+    MemberEvent, PresenceEvent, PresenceSnapshotEvent, ServerEvent, TypingEvent, VoicePayload,
+};
+use crate::messages::{broadcast_message, create_message, Message as ChatMessage, StoredMessage};
+use crate::permessage_deflate;
+use crate::rate_limit::{self, ConnectionLimiter, Verdict, WsLimits};
+use crate::storage::UploadStorage;
+
+/// WebSocket protocol versions this server understands. Version 2 authenticates
+/// the upgrade request, which version 1 clients never did.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[2];
+
+/// Protocol version assumed for clients that don't declare one in `join`.
+pub const DEFAULT_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional behaviours clients can rely on, advertised in `hello`.
+pub const SERVER_FEATURES: &[&str] = &[
+    "presence_snapshot",
+    "invisible_presence",
+    "auto_idle",
+    "heartbeat",
+    "voice_signaling",
+    "slash_commands",
+];
+
+pub fn heartbeat_interval_ms() -> u64 {
+    std::env::var("WS_HEARTBEAT_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(30_000)
+}
+
+/// How long a socket may stay silent (no frame, no pong) before it is closed.
+pub fn client_timeout_ms() -> u64 {
+    std::env::var("WS_CLIENT_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or_else(|| heartbeat_interval_ms() * 2 + heartbeat_interval_ms() / 2)
+}
+
+/// Shared broadcast channel for all WebSocket connections.
+pub type Broadcaster = Arc<broadcast::Sender<Arc<BroadcastFrame>>>;
+
+/// Presence of one user, shared by all of their open connections.
+#[derive(Debug, Clone)]
+pub struct PresenceEntry {
+    pub connections: usize,
+    pub status: String,
+    /// Set when `idle` came from client idle detection rather than the user.
+    pub auto_idle: bool,
+    pub username: String,
+    pub avatar_color: i32,
     pub avatar_url: Option<String>,
     pub banner_url: Option<String>,
-    pub status: Option<String>,
     pub role: Option<String>,
     pub about: Option<String>,
-    pub target_user_id: Option<String>,
-    pub muted: Option<bool>,
-    pub deafened: Option<bool>,
-    pub sdp: Option<serde_json::Value>,
-    pub candidate: Option<serde_json::Value>,
-    #[serde(skip_deserializing, default)]
-    pub id: String,
-    #[serde(skip_deserializing, default)]
-    pub created_at: String,
+    pub is_bot: bool,
 }
 
-/// Shared broadcast channel for all WebSocket connections.
-pub type Broadcaster = Arc<broadcast::Sender<String>>;
+impl PresenceEntry {
+    fn is_visible(&self) -> bool {
+        self.status != "invisible"
+    }
+
+    fn to_member_event(&self, user_id: &str) -> MemberEvent {
+        MemberEvent {
+            user_id: user_id.to_string(),
+            username: self.username.clone(),
+            avatar_color: self.avatar_color,
+            avatar_url: self.avatar_url.clone(),
+            banner_url: self.banner_url.clone(),
+            status: Some(self.status.clone()),
+            role: self.role.clone(),
+            about: self.about.clone(),
+            is_bot: self.is_bot,
+        }
+    }
+}
 
-/// Shared state for online users: user_id -> username
-pub type OnlineUsers = Arc<Mutex<HashMap<String, i32>>>; // user_id -> avatar_color (simplified)
+/// Shared state for online users: user_id -> presence
+pub type OnlineUsers = Arc<Mutex<HashMap<String, PresenceEntry>>>;
 
 #[derive(Default)]
 pub struct AccessCacheState {
@@ -51,7 +108,7 @@ pub struct AccessCacheState {
 pub type AccessCache = Arc<Mutex<AccessCacheState>>;
 
 pub fn create_broadcaster() -> Broadcaster {
-    let (tx, _) = broadcast::channel::<String>(256);
+    let (tx, _) = broadcast::channel::<Arc<BroadcastFrame>>(256);
     Arc::new(tx)
 }
 
@@ -85,7 +142,7 @@ pub fn cache_remove_room(cache: &AccessCache, room_id: &str) {
     guard.room_required_roles.remove(room_id);
 }
 
-async fn get_user_role_cached(pool: &SqlitePool, cache: &AccessCache, user_id: &str) -> Option<String> {
+pub(crate) async fn get_user_role_cached(pool: &SqlitePool, cache: &AccessCache, user_id: &str) -> Option<String> {
     {
         let guard = cache.lock().unwrap();
         if let Some(role) = guard.user_roles.get(user_id) {
@@ -144,15 +201,181 @@ pub async fn can_user_access_room_cached(
     }
 }
 
-fn extract_room_id(payload: &str) -> Option<String> {
-    let value = serde_json::from_str::<serde_json::Value>(payload).ok()?;
-    value
-        .get("room_id")
-        .and_then(|v| v.as_str())
-        .map(|v| v.to_string())
+pub(crate) fn hello_event(wire: WireFormat) -> ServerEvent {
+    ServerEvent::Hello(HelloEvent {
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+        heartbeat_interval: heartbeat_interval_ms(),
+        features: SERVER_FEATURES.iter().map(|f| f.to_string()).collect(),
+        encoding: wire.encoding.as_str().to_string(),
+        compression: wire.compression.as_str().map(|c| c.to_string()),
+    })
+}
+
+/// The version a `join` asked for, or the `error` to send when it is not supported.
+fn check_protocol_version(requested: Option<u32>, lang: Lang) -> Result<u32, ErrorEvent> {
+    let version = requested.unwrap_or(DEFAULT_PROTOCOL_VERSION);
+    if SUPPORTED_PROTOCOL_VERSIONS.contains(&version) {
+        return Ok(version);
+    }
+    let code = ErrorCode::UnsupportedProtocolVersion;
+    Err(ErrorEvent {
+        code,
+        message: code.message(lang).to_string(),
+        details: Some(format!("Protocol version {} is not supported", version)),
+        nonce: None,
+        protocol_versions: Some(SUPPORTED_PROTOCOL_VERSIONS.to_vec()),
+    })
+}
+
+pub(crate) fn normalize_status(raw: Option<&str>) -> Option<String> {
+    let status = raw?.trim().to_lowercase();
+    match status.as_str() {
+        "online" | "idle" | "dnd" | "invisible" => Some(status),
+        _ => None,
+    }
+}
+
+/// Users currently online as seen by `viewer_id`; invisible users only see themselves.
+pub(crate) fn presence_snapshot(users: &OnlineUsers, viewer_id: &str) -> ServerEvent {
+    let guard = users.lock().unwrap();
+    let entries: Vec<MemberEvent> = guard
+        .iter()
+        .filter(|(uid, entry)| entry.is_visible() || uid.as_str() == viewer_id)
+        .map(|(uid, entry)| entry.to_member_event(uid))
+        .collect();
+
+    ServerEvent::PresenceSnapshot(PresenceSnapshotEvent { users: entries })
+}
+
+/// Register one more connection for a user. The first one decides the initial
+/// status, later ones pick up whatever the user already has server-side.
+/// Returns the `join` to broadcast when the user just came online.
+pub(crate) fn register_connection(users: &OnlineUsers, join: &JoinPayload, is_bot: bool) -> Option<ServerEvent> {
+    let mut guard = users.lock().unwrap();
+    let entry = guard.entry(join.user_id.clone()).or_insert_with(|| PresenceEntry {
+        connections: 0,
+        status: normalize_status(join.status.as_deref()).unwrap_or_else(|| "online".to_string()),
+        auto_idle: false,
+        username: join.username.clone(),
+        avatar_color: join.avatar_color,
+        avatar_url: None,
+        banner_url: None,
+        role: None,
+        about: None,
+        is_bot,
+    });
+    entry.connections += 1;
+    entry.username = join.username.clone();
+    entry.avatar_color = join.avatar_color;
+    entry.avatar_url = join.avatar_url.clone();
+    entry.banner_url = join.banner_url.clone();
+    entry.role = join.role.clone();
+    entry.about = join.about.clone();
+
+    if entry.connections == 1 && entry.is_visible() {
+        Some(ServerEvent::Join(entry.to_member_event(&join.user_id)))
+    } else {
+        None
+    }
+}
+
+/// Drop one connection of a user; returns the `leave` to broadcast when it was their last.
+pub(crate) fn unregister_connection(users: &OnlineUsers, uid: &str) -> Option<ServerEvent> {
+    let mut guard = users.lock().unwrap();
+    let went_offline = match guard.get_mut(uid) {
+        Some(entry) if entry.connections > 1 => {
+            entry.connections -= 1;
+            false
+        }
+        Some(_) => guard.remove(uid).is_some_and(|entry| entry.is_visible()),
+        None => false,
+    };
+    went_offline.then(|| ServerEvent::Leave(LeaveEvent { user_id: uid.to_string() }))
+}
+
+/// Why a status change was not applied.
+pub(crate) enum PresenceRejected {
+    /// The user has no open connection.
+    NotConnected,
+    /// An automatic change that does not apply to the current status.
+    Ignored,
+}
+
+/// Change a connected user's status. `status` must already be normalized.
+/// Returns the event to broadcast, if others should see the change.
+pub(crate) fn apply_presence(
+    users: &OnlineUsers,
+    uid: &str,
+    status: &str,
+    auto: bool,
+) -> Result<Option<ServerEvent>, PresenceRejected> {
+    let mut guard = users.lock().unwrap();
+    let Some(entry) = guard.get_mut(uid) else {
+        return Err(PresenceRejected::NotConnected);
+    };
+    // Automatic idle only toggles between online and idle,
+    // never over a status the user picked themselves
+    if auto {
+        let applies = match status {
+            "idle" => entry.status == "online",
+            "online" => entry.auto_idle,
+            _ => false,
+        };
+        if !applies {
+            return Err(PresenceRejected::Ignored);
+        }
+    }
+    entry.auto_idle = auto && status == "idle";
+
+    let was_visible = entry.is_visible();
+    entry.status = status.to_string();
+
+    Ok(match (was_visible, entry.is_visible()) {
+        (true, false) => Some(ServerEvent::Leave(LeaveEvent { user_id: uid.to_string() })),
+        (false, true) => Some(ServerEvent::Join(entry.to_member_event(uid))),
+        (true, true) => Some(ServerEvent::Presence(PresenceEvent {
+            user_id: uid.to_string(),
+            status: status.to_string(),
+        })),
+        (false, false) => None,
+    })
+}
+
+/// Voice signaling as relayed: whatever the client claims, it comes from the token's user.
+fn voice_from(claims: &Claims, mut voice: VoicePayload) -> VoicePayload {
+    voice.user_id = Some(claims.sub.clone());
+    voice.username = Some(claims.username.clone());
+    voice
+}
+
+fn ack_event(message: &ChatMessage, duplicate: bool) -> ServerEvent {
+    ServerEvent::Ack(AckEvent {
+        nonce: message.nonce.clone(),
+        id: message.id.clone(),
+        room_id: message.room_id.clone(),
+        created_at: message.created_at.clone(),
+        duplicate,
+    })
+}
+
+/// Broadcast a newly stored message and build the sender's `ack`; duplicates are only acked.
+async fn acknowledge_stored(pool: &SqlitePool, tx: &Broadcaster, stored: StoredMessage) -> ServerEvent {
+    match stored {
+        StoredMessage::Created(message) => {
+            let ack = ack_event(&message, false);
+            broadcast_message(pool, tx, &message).await;
+            ack
+        }
+        StoredMessage::Duplicate(message) => ack_event(&message, true),
+    }
+}
+
+async fn send_event(session: &mut actix_ws::Session, wire: WireFormat, event: &ServerEvent) -> bool {
+    codec::encode(event, wire).send(session).await
 }
 
-async fn fetch_accessible_rooms(pool: &SqlitePool, role: &str) -> HashSet<String> {
+pub(crate) async fn fetch_accessible_rooms(pool: &SqlitePool, role: &str) -> HashSet<String> {
     let rows = if role == "admin" {
         sqlx::query_scalar::<_, String>("SELECT id FROM rooms")
             .fetch_all(pool)
@@ -171,7 +394,54 @@ async fn fetch_accessible_rooms(pool: &SqlitePool, role: &str) -> HashSet<String
     rows.into_iter().collect()
 }
 
+/// The `join` of an authenticated user, with their profile and role as stored, and whether
+/// they are a bot; `None` when the account no longer exists.
+pub(crate) async fn verified_join(
+    pool: &SqlitePool,
+    claims: &Claims,
+    status: Option<String>,
+    protocol_version: Option<u32>,
+) -> Option<(JoinPayload, bool)> {
+    let row = sqlx::query("SELECT username, avatar_color, avatar_url, banner_url, role, about, is_bot FROM users WHERE id = ?")
+        .bind(&claims.sub)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)?;
+
+    let join = JoinPayload {
+        user_id: claims.sub.clone(),
+        username: row.try_get("username").unwrap_or_else(|_| claims.username.clone()),
+        avatar_color: row.try_get("avatar_color").unwrap_or(0),
+        protocol_version,
+        avatar_url: row.try_get("avatar_url").unwrap_or(None),
+        banner_url: row.try_get("banner_url").unwrap_or(None),
+        status,
+        role: Some(row.try_get("role").unwrap_or_else(|_| "user".to_string())),
+        about: row.try_get("about").unwrap_or(None),
+    };
+    Some((join, row.try_get("is_bot").unwrap_or(false)))
+}
+
+#[derive(Debug, Deserialize)]
+struct WsAuthQuery {
+    /// Browsers cannot set headers on the upgrade request, so the JWT may come in the query string instead.
+    token: Option<String>,
+}
+
+/// Who is opening a connection, from the upgrade request's header or `?token=`.
+fn upgrade_claims(req: &HttpRequest) -> Result<Claims, ApiError> {
+    let token = web::Query::<WsAuthQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().token);
+    let claims = extract_claims(req)
+        .or_else(|| token.as_deref().and_then(|t| claims_for_bearer(req, t)))
+        .ok_or_else(|| ApiError::new(ErrorCode::NotAuthenticated))?;
+    claims.require_scope(Scope::RoomsRead)?;
+    Ok(claims)
+}
+
 /// GET /ws — WebSocket upgrade
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
@@ -179,13 +449,40 @@ pub async fn ws_handler(
     broadcaster: web::Data<Broadcaster>,
     online_users: web::Data<OnlineUsers>,
     access_cache: web::Data<AccessCache>,
+    limits: web::Data<WsLimits>,
+    storage: web::Data<UploadStorage>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let (response, session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    let wire = match WireFormat::from_query(req.query_string()) {
+        Ok(wire) => wire,
+        Err(err) => return Ok(err.respond(&req)),
+    };
+    let claims = match upgrade_claims(&req) {
+        Ok(claims) => claims,
+        Err(err) => return Ok(err.respond(&req)),
+    };
+    let deflate = permessage_deflate::negotiate(&req);
+    let stream = if deflate {
+        permessage_deflate::inflating(&req, stream, limits.max_frame_bytes).await?
+    } else {
+        stream
+    };
+    let (response, mut session, msg_stream) = actix_ws::handle(&req, stream)?;
+    let response = if deflate { permessage_deflate::deflating(response) } else { response };
+    let mut msg_stream = msg_stream.max_frame_size(limits.max_frame_bytes);
+    let lang = Lang::from_request(&req);
+
+    // Greet first so the client can check compatibility before it joins
+    if !send_event(&mut session, wire, &hello_event(wire)).await {
+        return Ok(response);
+    }
 
     let pool = pool.get_ref().clone();
     let tx = broadcaster.get_ref().clone();
     let users = online_users.get_ref().clone();
     let access_cache = access_cache.get_ref().clone();
+    let storage = storage.get_ref().clone();
+    let max_message_chars = limits.max_message_chars;
+    let mut limiter = ConnectionLimiter::new(limits.get_ref().clone());
     let mut rx = tx.subscribe();
 
     // We'll wait for a "join" message to hydrate user context.
@@ -197,17 +494,22 @@ pub async fn ws_handler(
     let mut send_session = session.clone();
     let send_allowed_rooms = allowed_rooms.clone();
     let send_is_admin = is_admin.clone();
+    // Targeted events follow the token, never what a frame claims
+    let send_user_id = claims.sub.clone();
     actix_web::rt::spawn(async move {
-        while let Ok(text) = rx.recv().await {
-            let room_id = extract_room_id(&text);
-            if let Some(rid) = room_id {
+        while let Ok(frame) = rx.recv().await {
+            if !frame.is_for(&send_user_id) {
+                continue;
+            }
+
+            if let Some(rid) = frame.event.room_id() {
                 let allowed = {
                     let admin = *send_is_admin.lock().unwrap();
                     if admin {
                         true
                     } else {
                         let guard = send_allowed_rooms.lock().unwrap();
-                        guard.contains(&rid)
+                        guard.contains(rid)
                     }
                 };
 
@@ -216,7 +518,8 @@ pub async fn ws_handler(
                 }
             }
 
-            if send_session.text(text).await.is_err() {
+            // Encoded once per wire format and shared by every connection using it
+            if !frame.encoded(wire).send(&mut send_session).await {
                 break;
             }
         }
@@ -224,133 +527,455 @@ pub async fn ws_handler(
 
     // Spawn task: read messages from this client
     actix_web::rt::spawn(async move {
-        while let Some(Ok(msg)) = msg_stream.next().await {
-            match msg {
-                Message::Text(text) => {
-                    if let Ok(mut ws_msg) = serde_json::from_str::<WsMessage>(&text) {
-                        
-                        // Handle JOIN
-                        if ws_msg.msg_type == "join" {
-                            if let (Some(uid), Some(_uname), Some(color)) = (&ws_msg.user_id, &ws_msg.username, ws_msg.avatar_color) {
-                                my_user_id = Some(uid.clone());
-
-                                let role = get_user_role_cached(&pool, &access_cache, uid)
-                                    .await
-                                    .unwrap_or_else(|| "user".to_string());
-                                let rooms = fetch_accessible_rooms(&pool, &role).await;
-                                {
-                                    let mut guard = allowed_rooms.lock().unwrap();
-                                    *guard = rooms;
-                                }
-                                {
-                                    let mut admin_guard = is_admin.lock().unwrap();
-                                    *admin_guard = role == "admin";
-                                }
-
-                                {
-                                    let mut guard = users.lock().unwrap();
-                                    guard.insert(uid.clone(), color);
-                                }
-                                
-                                // Broadcast join with all details
-                                let join_msg = serde_json::json!({
-                                    "type": "join",
-                                    "user_id": uid,
-                                    "username": _uname,
-                                    "avatar_color": color,
-                                    "avatar_url": ws_msg.avatar_url,
-                                    "banner_url": ws_msg.banner_url,
-                                    "status": ws_msg.status,
-                                    "role": ws_msg.role,
-                                    "about": ws_msg.about
-                                });
-                                let _ = tx.send(join_msg.to_string());
-                            }
-                        }
-                        // Handle LEAVE (explicit)
-                        else if ws_msg.msg_type == "leave" {
-                             if let Some(uid) = &my_user_id {
-                                {
-                                    let mut guard = users.lock().unwrap();
-                                    guard.remove(uid);
-                                }
-                                let _ = tx.send(text.to_string()); // Broadcast leave
-                             }
-                             break;
-                        }
-                                // Handle MESSAGE
-                        else if ws_msg.msg_type == "message" {
-                             if let (Some(content), Some(rid), Some(uid), Some(uname)) = (&ws_msg.content, &ws_msg.room_id, &ws_msg.user_id, &ws_msg.username) {
-                                let allowed = can_user_access_room_cached(&pool, &access_cache, uid, rid).await;
-
-                                if !allowed {
-                                    continue;
-                                }
-
-                                let has_content = !content.trim().is_empty();
-                                let has_image = ws_msg.image_url.as_ref().map_or(false, |u| !u.is_empty());
-                                if has_content || has_image {
-                                    let msg_id = Uuid::new_v4().to_string();
-                                    let now = chrono::Utc::now().to_rfc3339();
-
-                                    let _ = sqlx::query(
-                                        "INSERT INTO messages (id, room_id, user_id, username, content, created_at, image_url, reply_to_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
-                                    )
-                                    .bind(&msg_id)
-                                    .bind(rid)
-                                    .bind(uid)
-                                    .bind(uname)
-                                    .bind(content)
-                                    .bind(&now)
-                                    .bind(&ws_msg.image_url)
-                                    .bind(&ws_msg.reply_to_id)
-                                    .execute(&pool)
-                                    .await;
-
-                                    ws_msg.id = msg_id;
-                                    ws_msg.created_at = now;
-
-                                    let _ = tx.send(serde_json::to_string(&ws_msg).unwrap());
-                                }
-                             }
-                        }
-                        // Handle TYPING relay
-                        else if ws_msg.msg_type == "typing" {
-                            let _ = tx.send(text.to_string());
-                        }
-                        // Handle PRESENCE relay
-                        else if ws_msg.msg_type == "presence" {
-                            let _ = tx.send(text.to_string());
+        let timeout = Duration::from_millis(client_timeout_ms());
+        let mut heartbeat = tokio::time::interval(Duration::from_millis(heartbeat_interval_ms()));
+        let mut last_seen = Instant::now();
+
+        loop {
+            let frame = tokio::select! {
+                frame = msg_stream.next() => frame,
+                _ = heartbeat.tick() => {
+                    // Half-open sockets never answer; reap them through the normal leave path
+                    if last_seen.elapsed() > timeout {
+                        let _ = session
+                            .close(Some(CloseReason {
+                                code: CloseCode::Away,
+                                description: Some("heartbeat timeout".to_string()),
+                            }))
+                            .await;
+                        break;
+                    }
+                    if session.ping(b"").await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let msg = match frame {
+                Some(Ok(msg)) => msg,
+                Some(Err(ProtocolError::Overflow)) => {
+                    let _ = session
+                        .close(Some(CloseReason {
+                            code: CloseCode::Size,
+                            description: Some("frame too large".to_string()),
+                        }))
+                        .await;
+                    break;
+                }
+                _ => break,
+            };
+            last_seen = Instant::now();
+
+            let parsed = match msg {
+                Message::Text(text) => ClientEvent::parse(&text),
+                Message::Binary(bytes) => wire.decode_binary(&bytes),
+                Message::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                Message::Close(_) => break,
+                _ => continue,
+            };
+
+            // Frames that fail to parse still count, against the shared bucket
+            let class = parsed.as_ref().map(rate_limit::event_class).unwrap_or("other");
+            let nonce = match &parsed {
+                Ok(ClientEvent::Message(message)) => message.nonce.clone(),
+                _ => None,
+            };
+            match limiter.check(class) {
+                Verdict::Allow => {}
+                // A message with a nonce waits for its ack, so it hears back even before warnings start
+                Verdict::Drop { .. } if nonce.is_none() => continue,
+                Verdict::Drop { retry_after_ms } | Verdict::Warn { retry_after_ms } => {
+                    let err = ApiError::with_details(
+                        ErrorCode::RateLimited,
+                        format!("Too many '{}' events, retry in {} ms", class, retry_after_ms),
+                    );
+                    let _ = send_event(&mut session, wire, &ServerEvent::error(&err, lang, nonce)).await;
+                    continue;
+                }
+                Verdict::Disconnect => {
+                    let _ = session
+                        .close(Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some("rate limit exceeded".to_string()),
+                        }))
+                        .await;
+                    break;
+                }
+            }
+
+            let event = match parsed {
+                Ok(event) => event,
+                Err(err) => {
+                    let error = ServerEvent::error(&ApiError::from(err), lang, None);
+                    let _ = send_event(&mut session, wire, &error).await;
+                    continue;
+                }
+            };
+
+            match event {
+                ClientEvent::Join(join) => {
+                    if let Err(error) = check_protocol_version(join.protocol_version, lang) {
+                        let _ = send_event(&mut session, wire, &ServerEvent::Error(error)).await;
+                        let _ = session
+                            .close(Some(CloseReason {
+                                code: CloseCode::Policy,
+                                description: Some("unsupported protocol version".to_string()),
+                            }))
+                            .await;
+                        break;
+                    }
+
+                    if my_user_id.is_some() {
+                        continue;
+                    }
+                    if join.user_id != claims.sub {
+                        let err = ApiError::with_details(ErrorCode::AccessDenied, "user_id does not match the authenticated user");
+                        let _ = send_event(&mut session, wire, &ServerEvent::error(&err, lang, None)).await;
+                        continue;
+                    }
+                    // Announce the profile as stored, not as the frame describes it
+                    let Some((join, is_bot)) = verified_join(&pool, &claims, join.status, join.protocol_version).await else {
+                        let _ = send_event(&mut session, wire, &ServerEvent::error(&ApiError::new(ErrorCode::UserNotFound), lang, None)).await;
+                        break;
+                    };
+                    let uid = join.user_id.clone();
+                    my_user_id = Some(uid.clone());
+
+                    let role = join.role.clone().unwrap_or_else(|| "user".to_string());
+                    let rooms = fetch_accessible_rooms(&pool, &role).await;
+                    {
+                        let mut guard = allowed_rooms.lock().unwrap();
+                        *guard = rooms;
+                    }
+                    {
+                        let mut admin_guard = is_admin.lock().unwrap();
+                        *admin_guard = role == "admin";
+                    }
+
+                    let announce = register_connection(&users, &join, is_bot);
+
+                    // Send who is already online to this connection only
+                    if !send_event(&mut session, wire, &presence_snapshot(&users, &uid)).await {
+                        break;
+                    }
+
+                    // Broadcast join with all details
+                    if let Some(join_event) = announce {
+                        events::broadcast(&tx, &join_event);
+                    }
+                }
+                // Explicit LEAVE — cleanup below broadcasts it if needed
+                ClientEvent::Leave(_) => break,
+                ClientEvent::Message(message) => {
+                    let nonce = message.nonce.clone();
+                    // Only the token's user may post from this connection
+                    let allowed = if message.user_id != claims.sub {
+                        Err(ApiError::with_details(ErrorCode::AccessDenied, "user_id does not match the authenticated user"))
+                    } else {
+                        claims.require_scope(Scope::MessagesWrite)
+                    };
+                    if let Err(err) = allowed {
+                        let _ = send_event(&mut session, wire, &ServerEvent::error(&err, lang, nonce)).await;
+                        continue;
+                    }
+                    let reply = if let Some((name, args)) = commands::parse_invocation(&message.content) {
+                        let ctx = CommandContext {
+                            pool: &pool,
+                            broadcaster: &tx,
+                            access_cache: &access_cache,
+                            storage: &storage,
+                            max_message_chars,
+                        };
+                        match commands::run(&ctx, &name, &args, message, Some(&claims)).await {
+                            // Only this connection sees the reply
+                            Ok(CommandOutcome::Ephemeral(event)) => ServerEvent::Ephemeral(event),
+                            Ok(CommandOutcome::Posted(stored)) => acknowledge_stored(&pool, &tx, *stored).await,
+                            Err(err) => ServerEvent::error(&err, lang, nonce),
                         }
-                        // Handle VOICE events relay
-                        else if ws_msg.msg_type == "voice_join"
-                            || ws_msg.msg_type == "voice_leave"
-                            || ws_msg.msg_type == "voice_state"
-                            || ws_msg.msg_type == "voice_signal"
-                        {
-                            let _ = tx.send(text.to_string());
+                    } else {
+                        match create_message(&pool, &access_cache, message, max_message_chars).await {
+                            Ok(stored) => acknowledge_stored(&pool, &tx, stored).await,
+                            Err(code) => ServerEvent::error(&ApiError::new(code), lang, nonce),
                         }
+                    };
+                    let _ = send_event(&mut session, wire, &reply).await;
+                }
+                // PRESENCE is stored server-side, invisible users appear offline
+                ClientEvent::Presence(presence) => {
+                    let Some(uid) = &my_user_id else {
+                        continue;
+                    };
+                    let Some(status) = normalize_status(Some(&presence.status)) else {
+                        continue;
+                    };
+
+                    let Ok(event) = apply_presence(&users, uid, &status, presence.auto) else {
+                        continue;
+                    };
+
+                    if status == "invisible" {
+                        // Still let this connection see its own status
+                        let own = ServerEvent::Presence(PresenceEvent {
+                            user_id: uid.clone(),
+                            status: status.clone(),
+                        });
+                        let _ = send_event(&mut session, wire, &own).await;
+                    }
+                    if let Some(event) = event {
+                        events::broadcast(&tx, &event);
                     }
                 }
-                Message::Close(_) => break,
-                _ => {}
+                ClientEvent::Heartbeat(_) => {
+                    let _ = send_event(&mut session, wire, &ServerEvent::HeartbeatAck(HeartbeatAckEvent {})).await;
+                }
+                ClientEvent::Typing(typing) => {
+                    let event = ServerEvent::Typing(TypingEvent {
+                        room_id: typing.room_id,
+                        user_id: Some(claims.sub.clone()),
+                        username: Some(claims.username.clone()),
+                    });
+                    events::broadcast(&tx, &event);
+                }
+                // VOICE signaling is relayed as the token's user
+                ClientEvent::VoiceJoin(voice) => events::broadcast(&tx, &ServerEvent::VoiceJoin(voice_from(&claims, voice))),
+                ClientEvent::VoiceLeave(voice) => events::broadcast(&tx, &ServerEvent::VoiceLeave(voice_from(&claims, voice))),
+                ClientEvent::VoiceState(voice) => events::broadcast(&tx, &ServerEvent::VoiceState(voice_from(&claims, voice))),
+                ClientEvent::VoiceSignal(voice) => events::broadcast(&tx, &ServerEvent::VoiceSignal(voice_from(&claims, voice))),
             }
         }
 
-        // Cleanup on disconnect
+        // Cleanup on disconnect: only the last connection of a user takes them offline
         if let Some(uid) = my_user_id {
-            {
-                let mut guard = users.lock().unwrap();
-                guard.remove(&uid);
+            if let Some(leave) = unregister_connection(&users, &uid) {
+                events::broadcast(&tx, &leave);
             }
-            // Broadcast offline
-            let offline_msg = serde_json::json!({
-                "type": "leave",
-                "user_id": uid
-            });
-            let _ = tx.send(offline_msg.to_string());
+            digest::record_last_seen(&pool, &uid).await;
         }
     });
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Compression, Encoding};
+
+    fn join(user_id: &str, status: Option<&str>) -> JoinPayload {
+        JoinPayload {
+            user_id: user_id.to_string(),
+            username: user_id.to_string(),
+            avatar_color: 3,
+            protocol_version: None,
+            avatar_url: None,
+            banner_url: None,
+            status: status.map(str::to_string),
+            role: None,
+            about: None,
+        }
+    }
+
+    fn snapshot_ids(users: &OnlineUsers, viewer_id: &str) -> Vec<String> {
+        let ServerEvent::PresenceSnapshot(snapshot) = presence_snapshot(users, viewer_id) else {
+            panic!("expected a presence snapshot");
+        };
+        let mut ids: Vec<String> = snapshot.users.into_iter().map(|user| user.user_id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn only_the_first_connection_announces_the_user() {
+        let users = create_online_users();
+        match register_connection(&users, &join("u1", None), false) {
+            Some(ServerEvent::Join(member)) => assert_eq!(member.status.as_deref(), Some("online")),
+            other => panic!("expected a join, got {:?}", other),
+        }
+        assert!(register_connection(&users, &join("u1", None), false).is_none());
+        assert_eq!(users.lock().unwrap()["u1"].connections, 2);
+    }
+
+    #[test]
+    fn only_the_last_connection_takes_the_user_offline() {
+        let users = create_online_users();
+        register_connection(&users, &join("u1", None), false);
+        register_connection(&users, &join("u1", None), false);
+        assert!(unregister_connection(&users, "u1").is_none());
+        assert_eq!(snapshot_ids(&users, "u2"), ["u1"]);
+        assert!(matches!(unregister_connection(&users, "u1"), Some(ServerEvent::Leave(_))));
+        assert!(snapshot_ids(&users, "u2").is_empty());
+        assert!(unregister_connection(&users, "u1").is_none());
+    }
+
+    #[test]
+    fn later_connections_keep_the_current_status() {
+        let users = create_online_users();
+        register_connection(&users, &join("u1", Some("dnd")), false);
+        register_connection(&users, &join("u1", Some("online")), false);
+        assert_eq!(users.lock().unwrap()["u1"].status, "dnd");
+    }
+
+    #[test]
+    fn invisible_users_are_hidden_from_others() {
+        let users = create_online_users();
+        assert!(register_connection(&users, &join("u1", Some("Invisible")), false).is_none());
+        register_connection(&users, &join("u2", None), false);
+        assert_eq!(snapshot_ids(&users, "u1"), ["u1", "u2"]);
+        assert_eq!(snapshot_ids(&users, "u2"), ["u2"]);
+        // Nobody saw them leave either
+        assert!(unregister_connection(&users, "u1").is_none());
+    }
+
+    #[test]
+    fn upgrades_need_a_token() {
+        let token = crate::auth::create_token("u1", "alice", "user");
+        let req = actix_web::test::TestRequest::get().uri(&format!("/ws?encoding=cbor&token={}", token)).to_http_request();
+        assert_eq!(upgrade_claims(&req).unwrap().sub, "u1");
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/ws")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_http_request();
+        assert_eq!(upgrade_claims(&req).unwrap().username, "alice");
+
+        for uri in ["/ws", "/ws?token=", "/ws?token=not-a-jwt"] {
+            let req = actix_web::test::TestRequest::get().uri(uri).to_http_request();
+            assert_eq!(upgrade_claims(&req).unwrap_err().code, ErrorCode::NotAuthenticated);
+        }
+    }
+
+    #[test]
+    fn voice_is_relayed_as_the_tokens_user() {
+        let claims = Claims {
+            sub: "u1".to_string(),
+            username: "alice".to_string(),
+            role: "user".to_string(),
+            exp: 0,
+            scopes: None,
+            is_bot: false,
+        };
+        let voice: VoicePayload = serde_json::from_value(serde_json::json!({
+            "room_id": "voice", "user_id": "u2", "username": "bob", "target_user_id": "u3",
+        }))
+        .unwrap();
+        let voice = voice_from(&claims, voice);
+        assert_eq!(voice.user_id.as_deref(), Some("u1"));
+        assert_eq!(voice.username.as_deref(), Some("alice"));
+        assert_eq!(voice.target_user_id.as_deref(), Some("u3"));
+    }
+
+    #[test]
+    fn unknown_statuses_are_refused() {
+        assert_eq!(normalize_status(Some(" IDLE ")).as_deref(), Some("idle"));
+        assert_eq!(normalize_status(Some("away")), None);
+        assert_eq!(normalize_status(None), None);
+    }
+
+    #[test]
+    fn hello_advertises_versions_and_features() {
+        let ServerEvent::Hello(hello) = hello_event(WireFormat::default()) else {
+            panic!("expected a hello");
+        };
+        assert_eq!(hello.server_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(hello.protocol_versions, SUPPORTED_PROTOCOL_VERSIONS);
+        assert_eq!(hello.features, SERVER_FEATURES);
+        assert!(hello.heartbeat_interval > 0);
+        assert_eq!(hello.encoding, "json");
+        assert_eq!(hello.compression, None);
+
+        let wire = WireFormat { encoding: Encoding::Cbor, compression: Compression::Zlib };
+        let ServerEvent::Hello(hello) = hello_event(wire) else {
+            panic!("expected a hello");
+        };
+        assert_eq!(hello.encoding, "cbor");
+        assert_eq!(hello.compression.as_deref(), Some("zlib"));
+    }
+
+    #[test]
+    fn protocol_version_is_negotiated() {
+        assert_eq!(check_protocol_version(Some(2), Lang::En).unwrap(), 2);
+        // Clients that declare nothing speak version 1, which is no longer served
+        assert_eq!(DEFAULT_PROTOCOL_VERSION, 1);
+        assert!(check_protocol_version(None, Lang::En).is_err());
+        assert!(check_protocol_version(Some(1), Lang::En).is_err());
+        let error = check_protocol_version(Some(99), Lang::Fr).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnsupportedProtocolVersion);
+        assert_eq!(error.message, ErrorCode::UnsupportedProtocolVersion.message(Lang::Fr));
+        assert_eq!(error.protocol_versions.as_deref(), Some(SUPPORTED_PROTOCOL_VERSIONS));
+    }
+
+    fn status(users: &OnlineUsers, uid: &str) -> String {
+        users.lock().unwrap()[uid].status.clone()
+    }
+
+    #[test]
+    fn auto_idle_toggles_between_online_and_idle() {
+        let users = create_online_users();
+        register_connection(&users, &join("u1", None), false);
+        let event = apply_presence(&users, "u1", "idle", true).ok().flatten();
+        assert!(matches!(event, Some(ServerEvent::Presence(p)) if p.status == "idle"));
+        assert!(users.lock().unwrap()["u1"].auto_idle);
+        assert!(apply_presence(&users, "u1", "online", true).is_ok());
+        assert_eq!(status(&users, "u1"), "online");
+        assert!(!users.lock().unwrap()["u1"].auto_idle);
+    }
+
+    #[test]
+    fn auto_idle_never_overrides_a_chosen_status() {
+        let users = create_online_users();
+        register_connection(&users, &join("u1", Some("dnd")), false);
+        assert!(matches!(apply_presence(&users, "u1", "idle", true), Err(PresenceRejected::Ignored)));
+        assert_eq!(status(&users, "u1"), "dnd");
+
+        // Idle picked by the user is not undone by activity either
+        apply_presence(&users, "u1", "idle", false).ok();
+        assert!(matches!(apply_presence(&users, "u1", "online", true), Err(PresenceRejected::Ignored)));
+        assert_eq!(status(&users, "u1"), "idle");
+        assert!(matches!(apply_presence(&users, "u1", "dnd", true), Err(PresenceRejected::Ignored)));
+    }
+
+    #[test]
+    fn invisibility_is_announced_as_leave_and_join() {
+        let users = create_online_users();
+        register_connection(&users, &join("u1", None), false);
+        assert!(matches!(apply_presence(&users, "u1", "invisible", false), Ok(Some(ServerEvent::Leave(_)))));
+        assert!(matches!(apply_presence(&users, "u1", "dnd", false), Ok(Some(ServerEvent::Join(_)))));
+        apply_presence(&users, "u1", "invisible", false).ok();
+        assert!(matches!(apply_presence(&users, "u1", "invisible", false), Ok(None)));
+    }
+
+    #[test]
+    fn presence_needs_a_connection() {
+        let users = create_online_users();
+        assert!(matches!(apply_presence(&users, "u1", "idle", false), Err(PresenceRejected::NotConnected)));
+    }
+
+    #[tokio::test]
+    async fn joins_carry_the_stored_profile() {
+        let pool = crate::db::test_pool().await;
+        sqlx::query(
+            "INSERT INTO users (id, username, password_hash, role, avatar_color, about, is_bot) \
+             VALUES ('u1', 'alice', '', 'admin', 7, 'hi', 1)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // The token may predate a rename or a role change
+        let claims = crate::auth::validate_token(&crate::auth::create_token("u1", "old-name", "user")).unwrap();
+        let (join, is_bot) = verified_join(&pool, &claims, Some("idle".to_string()), Some(2)).await.unwrap();
+        assert_eq!(join.username, "alice");
+        assert_eq!(join.role.as_deref(), Some("admin"));
+        assert_eq!((join.avatar_color, join.about.as_deref()), (7, Some("hi")));
+        assert_eq!((join.status.as_deref(), join.protocol_version), (Some("idle"), Some(2)));
+        assert!(is_bot);
+
+        let gone = crate::auth::validate_token(&crate::auth::create_token("u2", "bob", "user")).unwrap();
+        assert!(verified_join(&pool, &gone, None, None).await.is_none());
+    }
+}
@@ -56,6 +56,7 @@ let state = {
     currentRoomName: null,
     currentRoomKind: null,
     ws: null,
+    events: null,
     rooms: [],
     serverRoles: [],
     serverUsers: [],
@@ -653,7 +654,7 @@ function cycleOwnPresence() {
 }
 
 function broadcastPresence() {
-    if (!state.ws || state.ws.readyState !== WebSocket.OPEN) return;
+    if (!isRealtimeOpen()) return;
     wsSend({
         type: "presence",
         user_id: state.userId,
@@ -661,6 +662,34 @@ function broadcastPresence() {
     });
 }
 
+// ── Auto idle ──────────────────────────────────────────
+// The server only applies automatic idle over "online", and only lifts an
+// idle it set automatically, so a status picked by hand is never overridden.
+const AUTO_IDLE_AFTER_MS = 5 * 60 * 1000;
+let autoIdle = false;
+let lastActivityAt = Date.now();
+
+function setAutoIdle(idle) {
+    if (autoIdle === idle) return;
+    autoIdle = idle;
+    wsSend({ type: "presence", status: idle ? "idle" : "online", auto: true });
+}
+
+function noteUserActivity() {
+    lastActivityAt = Date.now();
+    if (autoIdle) setAutoIdle(false);
+}
+
+["mousemove", "mousedown", "keydown", "wheel", "touchstart"].forEach((evt) => {
+    window.addEventListener(evt, noteUserActivity, { passive: true });
+});
+
+setInterval(() => {
+    if (autoIdle || normalizePresence(state.presence) !== "online") return;
+    if (!isRealtimeOpen()) return;
+    if (Date.now() - lastActivityAt >= AUTO_IDLE_AFTER_MS) setAutoIdle(true);
+}, 30 * 1000);
+
 // ── Logout ─────────────────────────────────────────────
 function logout() {
     if (state.voice?.joinedRoomId) {
@@ -668,6 +697,7 @@ function logout() {
     }
     stopMicMeter();
     if (state.ws) state.ws.close();
+    closeEventStream();
     localStorage.removeItem("token");
     localStorage.removeItem("userId");
     localStorage.removeItem("username");
@@ -675,7 +705,7 @@ function logout() {
         token: null, userId: null, username: null, role: null,
         avatarColor: 0, avatarUrl: null, bannerUrl: null, presence: localStorage.getItem("presence") || "online", about: "",
         currentRoomId: null, currentRoomName: null, currentRoomKind: null,
-        ws: null, rooms: [], serverRoles: [], serverUsers: [], users: {}, unreadByRoom: {}, mentionByRoom: {}, messageMetaById: {}, replyingTo: null, pinnedMessageIds: new Set(), threadRootId: null, voice: createVoiceState()
+        ws: null, events: null, rooms: [], serverRoles: [], serverUsers: [], users: {}, unreadByRoom: {}, mentionByRoom: {}, messageMetaById: {}, replyingTo: null, pinnedMessageIds: new Set(), threadRootId: null, voice: createVoiceState()
     };
     updateGlobalMentionBadge();
     app.classList.add("hidden");
@@ -766,11 +796,48 @@ async function loadRooms() {
         }
 
         renderRooms();
+        loadReadStates();
     } catch (err) {
         console.error("Failed to load rooms:", err);
     }
 }
 
+// ── Read states ────────────────────────────────────────
+function applyReadState(readState) {
+    if (!readState?.room_id) return;
+    const isCurrent = readState.room_id === state.currentRoomId;
+    state.unreadByRoom[readState.room_id] = isCurrent ? 0 : Math.max(0, Number(readState.unread_count) || 0);
+    state.mentionByRoom[readState.room_id] = isCurrent ? 0 : Math.max(0, Number(readState.mention_count) || 0);
+}
+
+async function loadReadStates() {
+    try {
+        const res = await fetch(`${API}/api/users/me/read-states`, {
+            headers: { Authorization: `Bearer ${state.token}` }
+        });
+        if (!res.ok) return;
+        const readStates = await res.json();
+        if (Array.isArray(readStates)) readStates.forEach(applyReadState);
+        updateGlobalMentionBadge();
+        scheduleRoomsRender();
+    } catch (err) {
+        console.error("Failed to load read states:", err);
+    }
+}
+
+let roomAckTimer = null;
+function scheduleRoomAck(roomId) {
+    if (!roomId || discordState.mode) return;
+    if (roomAckTimer) clearTimeout(roomAckTimer);
+    roomAckTimer = setTimeout(() => {
+        roomAckTimer = null;
+        fetch(`${API}/api/rooms/${encodeURIComponent(roomId)}/ack`, {
+            method: "POST",
+            headers: { Authorization: `Bearer ${state.token}` }
+        }).catch(() => {});
+    }, 800);
+}
+
 function renderRooms() {
     roomsList.innerHTML = "";
     voiceRoomsList.innerHTML = "";
@@ -872,6 +939,7 @@ async function selectRoom(room) {
     state.unreadByRoom[room.id] = 0;
     state.mentionByRoom[room.id] = 0;
     updateGlobalMentionBadge();
+    if (room.kind !== "voice") scheduleRoomAck(room.id);
     state.currentRoomName = room.name;
     state.currentRoomKind = room.kind;
     currentRoomName.textContent = room.name;
@@ -993,19 +1061,42 @@ async function loadMessages(roomId) {
 }
 
 // ── WebSocket & Member List ────────────────────────────
+const WS_PROTOCOL_VERSION = 2;
+
+// After this many upgrades that never open, fall back to server-sent events + REST
+const WS_FAILURES_BEFORE_SSE = 2;
+let wsFailures = 0;
+
+function closeEventStream() {
+    if (state.events) {
+        state.events.close();
+        state.events = null;
+    }
+}
+
 function connectWebSocket() {
     if (state.ws) {
         state.ws.onmessage = null;
         state.ws.onclose = null;
         state.ws.close();
     }
+    closeEventStream();
 
-    state.ws = new WebSocket(WS_URL);
+    // Browsers cannot set headers on the upgrade request
+    const params = new URLSearchParams({ token: state.token || "" });
+    const ws = new WebSocket(`${WS_URL}?${params}`);
+    let opened = false;
+    state.ws = ws;
 
     state.ws.onopen = () => {
         console.log("✅ WebSocket connected");
+        opened = true;
+        wsFailures = 0;
+        // A fresh join restores the chosen status, dropping any automatic idle
+        autoIdle = false;
         state.ws.send(JSON.stringify({
             type: "join",
+            protocol_version: WS_PROTOCOL_VERSION,
             user_id: state.userId,
             username: state.username,
             avatar_color: state.avatarColor,
@@ -1029,228 +1120,340 @@ function connectWebSocket() {
         }
     };
 
-    state.ws.onmessage = (event) => {
-        try {
-            const msg = JSON.parse(event.data);
-
-            if (msg.type === "message" && msg.room_id === state.currentRoomId && !discordState.mode) {
-                const lastMsg = messagesContainer.querySelector(".message:last-child");
-                let isFirstInGroup = true;
-                if (lastMsg) {
-                    const lastUser = lastMsg.getAttribute("data-username");
-                    if (lastUser === msg.username) isFirstInGroup = false;
-                }
-                appendMessage(msg, isFirstInGroup);
-                scrollToBottom();
-                if (state.threadRootId && (msg.id === state.threadRootId || msg.reply_to_id === state.threadRootId)) {
-                    renderThreadPanel();
-                }
+    state.ws.onmessage = handleRealtimeMessage;
+
+    state.ws.onclose = () => {
+        resetVoiceConnections();
+        if (!opened && ++wsFailures >= WS_FAILURES_BEFORE_SSE) {
+            if (state.ws === ws) state.ws = null;
+            connectEventStream();
+            return;
+        }
+        setTimeout(connectWebSocket, 3000);
+    };
+}
+
+// Server-sent events deliver the same events as /ws; EventSource reconnects by itself.
+// Voice signaling still needs the WebSocket.
+function connectEventStream() {
+    closeEventStream();
+    console.warn("WebSocket unavailable, falling back to server-sent events");
+    autoIdle = false;
+    const params = new URLSearchParams({
+        token: state.token || "",
+        status: normalizePresence(state.presence),
+    });
+    state.events = new EventSource(`${API}/api/events?${params}`);
+    state.events.onmessage = handleRealtimeMessage;
+}
+
+function isRealtimeOpen() {
+    if (state.ws && state.ws.readyState === WebSocket.OPEN) return true;
+    return !!state.events && state.events.readyState !== EventSource.CLOSED;
+}
+
+// REST equivalents of the client frames, used while on server-sent events
+function sendViaRest(payload) {
+    const headers = { "Content-Type": "application/json", Authorization: `Bearer ${state.token}` };
+    let request = null;
+    if (payload.type === "message") {
+        request = fetch(`${API}/api/rooms/${encodeURIComponent(payload.room_id)}/messages`, {
+            method: "POST",
+            headers,
+            body: JSON.stringify({
+                content: payload.content || "",
+                reply_to_id: payload.reply_to_id || null,
+                attachments: payload.attachments || [],
+                nonce: payload.nonce || null,
+            }),
+        });
+    } else if (payload.type === "typing") {
+        request = fetch(`${API}/api/rooms/${encodeURIComponent(payload.room_id)}/typing`, { method: "POST", headers });
+    } else if (payload.type === "presence") {
+        request = fetch(`${API}/api/users/me/presence`, {
+            method: "PUT",
+            headers,
+            body: JSON.stringify({ status: payload.status, auto: !!payload.auto }),
+        });
+    } else {
+        console.warn(`'${payload.type}' needs a WebSocket connection`);
+        return;
+    }
+    request
+        .then(async (res) => {
+            if (!res.ok) {
+                const data = await res.json().catch(() => ({}));
+                console.warn("REST send failed:", data.code, data.error);
             }
-            else if (msg.type === "message" && msg.room_id && msg.username !== state.username) {
-                state.unreadByRoom[msg.room_id] = (state.unreadByRoom[msg.room_id] || 0) + 1;
-                if (messageMentionsCurrentUser(msg.content || "")) {
-                    state.mentionByRoom[msg.room_id] = (state.mentionByRoom[msg.room_id] || 0) + 1;
-                }
-                updateGlobalMentionBadge();
-                scheduleRoomsRender();
+        })
+        .catch((err) => console.error("REST send error:", err));
+}
+
+function handleRealtimeMessage(event) {
+    try {
+        const msg = JSON.parse(event.data);
+
+        if (msg.type === "hello") {
+            if (Array.isArray(msg.protocol_versions) && !msg.protocol_versions.includes(WS_PROTOCOL_VERSION)) {
+                console.warn(`Server ${msg.server_version} does not support protocol v${WS_PROTOCOL_VERSION}`, msg.protocol_versions);
             }
-            if (msg.type === "join") {
-                if (msg.user_id && msg.username) {
-                    const existing = state.users[msg.user_id];
-                    const nextStatus = normalizePresence(msg.status || "online");
-                    const changed = !existing
-                        || existing.username !== msg.username
-                        || (existing.avatar_color || 0) !== (msg.avatar_color || 0)
-                        || (existing.avatar_url || null) !== (msg.avatar_url || null)
-                        || (existing.banner_url || null) !== (msg.banner_url || null)
-                        || (existing.role || "user") !== (msg.role || "user")
-                        || (existing.about || null) !== (msg.about || null)
-                        || normalizePresence(existing.status || "online") !== nextStatus;
-
-                    state.users[msg.user_id] = {
-                        username: msg.username,
-                        avatar_color: msg.avatar_color || 0,
-                        avatar_url: msg.avatar_url || null,
-                        banner_url: msg.banner_url || null,
-                        status: nextStatus,
-                        role: msg.role || "user",
-                        about: msg.about || null,
-                    };
-                    if (changed) {
-                        scheduleMembersRender();
-                    }
+            return;
+        }
+        if (msg.type === "error") {
+            console.warn("WS error event:", msg.code, msg.message);
+            return;
+        }
+        if (msg.type === "read_state_updated") {
+            applyReadState(msg);
+            updateGlobalMentionBadge();
+            scheduleRoomsRender();
+            return;
+        }
+        if (msg.type === "ephemeral") {
+            if (msg.room_id === state.currentRoomId && !discordState.mode) {
+                const el = document.createElement("div");
+                el.className = "message-ephemeral";
+                el.textContent = String(msg.content || "");
+                messagesContainer.appendChild(el);
+                scrollToBottom();
+            }
+            return;
+        }
 
-                    // Update popout if open for this user
-                    if (currentPopoutUserId === msg.user_id) {
-                        renderUserPopoutContent(msg.user_id, state.users[msg.user_id]);
-                    }
-                }
+        if (msg.type === "message" && msg.room_id === state.currentRoomId && !discordState.mode) {
+            const lastMsg = messagesContainer.querySelector(".message:last-child");
+            let isFirstInGroup = true;
+            if (lastMsg) {
+                const lastUser = lastMsg.getAttribute("data-username");
+                if (lastUser === msg.username) isFirstInGroup = false;
             }
-            else if (msg.type === "presence") {
-                if (msg.user_id && state.users[msg.user_id]) {
-                    const nextStatus = normalizePresence(msg.status || "online");
-                    if (state.users[msg.user_id].status !== nextStatus) {
-                        state.users[msg.user_id].status = nextStatus;
-                        scheduleMembersRender();
-                    }
-                    if (currentPopoutUserId === msg.user_id) {
-                        renderUserPopoutContent(msg.user_id, state.users[msg.user_id]);
-                    }
-                }
+            appendMessage(msg, isFirstInGroup);
+            scrollToBottom();
+            scheduleRoomAck(msg.room_id);
+            if (state.threadRootId && (msg.id === state.threadRootId || msg.reply_to_id === state.threadRootId)) {
+                renderThreadPanel();
             }
-            else if (msg.type === "leave") {
-                if (msg.user_id) {
-                    delete state.users[msg.user_id];
-                    cleanupRemotePeer(msg.user_id);
-                    delete state.voice.members[msg.user_id];
-                    scheduleVoiceMembersRender();
-                    scheduleMembersRender();
-                }
+        }
+        else if (msg.type === "message" && msg.room_id && msg.username !== state.username) {
+            state.unreadByRoom[msg.room_id] = (state.unreadByRoom[msg.room_id] || 0) + 1;
+            if (messageMentionsCurrentUser(msg)) {
+                state.mentionByRoom[msg.room_id] = (state.mentionByRoom[msg.room_id] || 0) + 1;
             }
-            else if (msg.type === "room_deleted") {
-                if (msg.room_id) {
-                    delete state.unreadByRoom[msg.room_id];
-                    delete state.mentionByRoom[msg.room_id];
-                    updateGlobalMentionBadge();
-                }
-                if (state.currentRoomId === msg.room_id) {
-                    if (state.voice.joinedRoomId === msg.room_id) {
-                        leaveVoiceRoom();
-                    }
-                    state.currentRoomId = null;
-                    state.currentRoomName = null;
-                    state.currentRoomKind = null;
-                    messagesContainer.innerHTML = "";
-                    messageInputArea.classList.add("hidden");
-                    voiceRoomPanel.classList.add("hidden");
-                    currentRoomName.textContent = "Sélectionnez un salon";
-                    roomKindIcon.textContent = "#";
-                    deleteRoomBtn.classList.add("hidden");
-                    updateVoiceQuickStatus();
+            updateGlobalMentionBadge();
+            scheduleRoomsRender();
+        }
+        if (msg.type === "join") {
+            if (msg.user_id && msg.username) {
+                const existing = state.users[msg.user_id];
+                const nextStatus = normalizePresence(msg.status || "online");
+                const changed = !existing
+                    || existing.username !== msg.username
+                    || (existing.avatar_color || 0) !== (msg.avatar_color || 0)
+                    || (existing.avatar_url || null) !== (msg.avatar_url || null)
+                    || (existing.banner_url || null) !== (msg.banner_url || null)
+                    || (existing.role || "user") !== (msg.role || "user")
+                    || (existing.about || null) !== (msg.about || null)
+                    || normalizePresence(existing.status || "online") !== nextStatus;
+
+                state.users[msg.user_id] = {
+                    username: msg.username,
+                    avatar_color: msg.avatar_color || 0,
+                    avatar_url: msg.avatar_url || null,
+                    banner_url: msg.banner_url || null,
+                    status: nextStatus,
+                    role: msg.role || "user",
+                    about: msg.about || null,
+                };
+                if (changed) {
+                    scheduleMembersRender();
                 }
-                loadRooms();
-            }
-            else if (msg.type === "room_updated") {
-                if (msg.room_id) {
-                    const room = state.rooms.find((r) => r.id === msg.room_id);
-                    if (room) {
-                        if (msg.name) room.name = String(msg.name);
-                        if (msg.kind) room.kind = String(msg.kind) === "voice" ? "voice" : "text";
-                        if (msg.required_role) room.required_role = String(msg.required_role).toLowerCase();
-
-                        if (state.currentRoomId === room.id) {
-                            state.currentRoomName = room.name;
-                            state.currentRoomKind = room.kind;
-                            currentRoomName.textContent = room.name;
-                            messageInput.placeholder = `Envoyer un message dans #${room.name}`;
-                            updateRoomModeUI(room.kind, room.name);
-                            if (room.kind === "text") {
-                                loadMessages(room.id);
-                            }
-                        }
 
-                        scheduleRoomsRender();
-                    } else {
-                        loadRooms();
-                    }
+                // Update popout if open for this user
+                if (currentPopoutUserId === msg.user_id) {
+                    renderUserPopoutContent(msg.user_id, state.users[msg.user_id]);
                 }
             }
-            else if (msg.type === "message_deleted") {
-                if (msg.room_id === state.currentRoomId) {
-                    const el = messagesContainer.querySelector(`.message[data-id="${msg.id}"]`);
-                    if (el) el.remove();
-                }
-                if (msg.id && document.getElementById(MESSAGE_REACTION_PICKER_ID)?.getAttribute("data-message-id") === msg.id) {
-                    closeMessageReactionPicker();
-                }
-                if (msg.id && state.messageMetaById[msg.id]) {
-                    delete state.messageMetaById[msg.id];
-                }
-                if (msg.id) {
-                    state.pinnedMessageIds.delete(msg.id);
+        }
+        else if (msg.type === "presence_snapshot") {
+            const users = {};
+            for (const u of msg.users || []) {
+                if (!u.user_id || !u.username) continue;
+                users[u.user_id] = {
+                    username: u.username,
+                    avatar_color: u.avatar_color || 0,
+                    avatar_url: u.avatar_url || null,
+                    banner_url: u.banner_url || null,
+                    status: normalizePresence(u.status || "online"),
+                    role: u.role || "user",
+                    about: u.about || null,
+                };
+            }
+            state.users = users;
+            scheduleMembersRender();
+        }
+        else if (msg.type === "presence") {
+            if (msg.user_id && state.users[msg.user_id]) {
+                const nextStatus = normalizePresence(msg.status || "online");
+                if (state.users[msg.user_id].status !== nextStatus) {
+                    state.users[msg.user_id].status = nextStatus;
+                    scheduleMembersRender();
                 }
-                if (state.threadRootId === msg.id) {
-                    hideThreadPanel();
-                } else if (state.threadRootId) {
-                    renderThreadPanel();
+                if (currentPopoutUserId === msg.user_id) {
+                    renderUserPopoutContent(msg.user_id, state.users[msg.user_id]);
                 }
-                if (state.replyingTo?.id === msg.id) {
-                    clearReplyTarget();
+            }
+        }
+        else if (msg.type === "leave") {
+            if (msg.user_id) {
+                delete state.users[msg.user_id];
+                cleanupRemotePeer(msg.user_id);
+                delete state.voice.members[msg.user_id];
+                scheduleVoiceMembersRender();
+                scheduleMembersRender();
+            }
+        }
+        else if (msg.type === "room_deleted") {
+            if (msg.room_id) {
+                delete state.unreadByRoom[msg.room_id];
+                delete state.mentionByRoom[msg.room_id];
+                updateGlobalMentionBadge();
+            }
+            if (state.currentRoomId === msg.room_id) {
+                if (state.voice.joinedRoomId === msg.room_id) {
+                    leaveVoiceRoom();
                 }
+                state.currentRoomId = null;
+                state.currentRoomName = null;
+                state.currentRoomKind = null;
+                messagesContainer.innerHTML = "";
+                messageInputArea.classList.add("hidden");
+                voiceRoomPanel.classList.add("hidden");
+                currentRoomName.textContent = "Sélectionnez un salon";
+                roomKindIcon.textContent = "#";
+                deleteRoomBtn.classList.add("hidden");
+                updateVoiceQuickStatus();
             }
-            else if (msg.type === "message_pinned") {
-                if (msg.room_id === state.currentRoomId && msg.id) {
-                    state.pinnedMessageIds.add(msg.id);
-                    const hasFlag = messagesContainer.querySelector(`.message[data-id="${msg.id}"] .message-pinned-flag`);
-                    if (!hasFlag) {
-                        const body = messagesContainer.querySelector(`.message[data-id="${msg.id}"] .message-body`);
-                        if (body) {
-                            const flag = document.createElement("div");
-                            flag.className = "message-pinned-flag";
-                            flag.textContent = "📌 Message épinglé";
-                            body.prepend(flag);
+            loadRooms();
+        }
+        else if (msg.type === "room_updated") {
+            if (msg.room_id) {
+                const room = state.rooms.find((r) => r.id === msg.room_id);
+                if (room) {
+                    if (msg.name) room.name = String(msg.name);
+                    if (msg.kind) room.kind = String(msg.kind) === "voice" ? "voice" : "text";
+                    if (msg.required_role) room.required_role = String(msg.required_role).toLowerCase();
+                    room.topic = msg.topic || null;
+
+                    if (state.currentRoomId === room.id) {
+                        state.currentRoomName = room.name;
+                        state.currentRoomKind = room.kind;
+                        currentRoomName.textContent = room.name;
+                        messageInput.placeholder = `Envoyer un message dans #${room.name}`;
+                        updateRoomModeUI(room.kind, room.name);
+                        if (room.kind === "text") {
+                            loadMessages(room.id);
                         }
                     }
-                    if (pinnedModal && !pinnedModal.classList.contains("hidden")) {
-                        loadPinnedMessages();
-                    }
+
+                    scheduleRoomsRender();
+                } else {
+                    loadRooms();
                 }
             }
-            else if (msg.type === "message_unpinned") {
-                if (msg.room_id === state.currentRoomId && msg.id) {
-                    state.pinnedMessageIds.delete(msg.id);
-                    const flag = messagesContainer.querySelector(`.message[data-id="${msg.id}"] .message-pinned-flag`);
-                    if (flag) flag.remove();
-                    if (pinnedModal && !pinnedModal.classList.contains("hidden")) {
-                        loadPinnedMessages();
-                    }
-                }
+        }
+        else if (msg.type === "message_deleted") {
+            if (msg.room_id === state.currentRoomId) {
+                const el = messagesContainer.querySelector(`.message[data-id="${msg.id}"]`);
+                if (el) el.remove();
+            }
+            if (msg.id && document.getElementById(MESSAGE_REACTION_PICKER_ID)?.getAttribute("data-message-id") === msg.id) {
+                closeMessageReactionPicker();
+            }
+            if (msg.id && state.messageMetaById[msg.id]) {
+                delete state.messageMetaById[msg.id];
+            }
+            if (msg.id) {
+                state.pinnedMessageIds.delete(msg.id);
+            }
+            if (state.threadRootId === msg.id) {
+                hideThreadPanel();
+            } else if (state.threadRootId) {
+                renderThreadPanel();
             }
-            else if (msg.type === "message_reaction_updated") {
-                if (msg.message_id && msg.emoji) {
-                    mergeMessageReaction(msg.message_id, msg.emoji, msg.count, msg.user_ids);
-                    if (msg.room_id === state.currentRoomId) {
-                        refreshMessageReactionUI(msg.message_id);
+            if (state.replyingTo?.id === msg.id) {
+                clearReplyTarget();
+            }
+        }
+        else if (msg.type === "message_pinned") {
+            if (msg.room_id === state.currentRoomId && msg.id) {
+                state.pinnedMessageIds.add(msg.id);
+                const hasFlag = messagesContainer.querySelector(`.message[data-id="${msg.id}"] .message-pinned-flag`);
+                if (!hasFlag) {
+                    const body = messagesContainer.querySelector(`.message[data-id="${msg.id}"] .message-body`);
+                    if (body) {
+                        const flag = document.createElement("div");
+                        flag.className = "message-pinned-flag";
+                        flag.textContent = "📌 Message épinglé";
+                        body.prepend(flag);
                     }
                 }
+                if (pinnedModal && !pinnedModal.classList.contains("hidden")) {
+                    loadPinnedMessages();
+                }
             }
-            else if (msg.type === "messages_purged") {
-                if (state.currentRoomKind === "text") {
-                    const selector = `.message[data-user-id="${msg.user_id}"]`;
-                    messagesContainer.querySelectorAll(selector).forEach((el) => el.remove());
+        }
+        else if (msg.type === "message_unpinned") {
+            if (msg.room_id === state.currentRoomId && msg.id) {
+                state.pinnedMessageIds.delete(msg.id);
+                const flag = messagesContainer.querySelector(`.message[data-id="${msg.id}"] .message-pinned-flag`);
+                if (flag) flag.remove();
+                if (pinnedModal && !pinnedModal.classList.contains("hidden")) {
+                    loadPinnedMessages();
                 }
-                Object.keys(state.messageMetaById).forEach((id) => {
-                    if (state.messageMetaById[id]?.user_id === msg.user_id) {
-                        delete state.messageMetaById[id];
-                        state.pinnedMessageIds.delete(id);
-                    }
-                });
-                if (state.threadRootId) {
-                    renderThreadPanel();
+            }
+        }
+        else if (msg.type === "message_reaction_updated") {
+            if (msg.message_id && msg.emoji) {
+                mergeMessageReaction(msg.message_id, msg.emoji, msg.count, msg.user_ids);
+                if (msg.room_id === state.currentRoomId) {
+                    refreshMessageReactionUI(msg.message_id);
                 }
             }
-            else if (msg.type === "typing") {
-                if (msg.username !== state.username && msg.room_id === state.currentRoomId) {
-                    showTypingIndicator(msg.username);
+        }
+        else if (msg.type === "messages_purged") {
+            if (state.currentRoomKind === "text") {
+                const selector = `.message[data-user-id="${msg.user_id}"]`;
+                messagesContainer.querySelectorAll(selector).forEach((el) => el.remove());
+            }
+            Object.keys(state.messageMetaById).forEach((id) => {
+                if (state.messageMetaById[id]?.user_id === msg.user_id) {
+                    delete state.messageMetaById[id];
+                    state.pinnedMessageIds.delete(id);
                 }
+            });
+            if (state.threadRootId) {
+                renderThreadPanel();
             }
-            else if (msg.type === "voice_join" || msg.type === "voice_leave" || msg.type === "voice_state" || msg.type === "voice_signal") {
-                handleVoiceWsEvent(msg);
+        }
+        else if (msg.type === "typing") {
+            if (msg.username !== state.username && msg.room_id === state.currentRoomId) {
+                showTypingIndicator(msg.username);
             }
-        } catch (err) {
-            console.error("WS error:", err);
         }
-    };
-
-    state.ws.onclose = () => {
-        resetVoiceConnections();
-        setTimeout(connectWebSocket, 3000);
-    };
+        else if (msg.type === "voice_join" || msg.type === "voice_leave" || msg.type === "voice_state" || msg.type === "voice_signal") {
+            handleVoiceWsEvent(msg);
+        }
+    } catch (err) {
+        console.error("WS error:", err);
+    }
 }
 
 function wsSend(payload) {
-    if (!state.ws || state.ws.readyState !== WebSocket.OPEN) return;
-    state.ws.send(JSON.stringify(payload));
+    if (state.ws && state.ws.readyState === WebSocket.OPEN) {
+        state.ws.send(JSON.stringify(payload));
+    } else if (state.events) {
+        sendViaRest(payload);
+    }
 }
 
 voiceController = window.VoxiumVoice.createVoiceController({
@@ -1431,7 +1634,7 @@ function renderMembers() {
                 <div class="status-dot ${presenceDotClass(status)}"></div>
             </div>
             <div class="member-meta">
-                <div class="name">${escapeHtml(u.username)}</div>
+                <div class="name">${escapeHtml(u.username)}${u.is_bot ? ` <span class="badge badge-bot">Bot</span>` : ""}</div>
                 <div class="member-status-label">${presenceLabel(status)}</div>
             </div>
         `;
@@ -1445,21 +1648,42 @@ function escapeRegExp(value) {
     return (value || "").replace(/[.*+?^${}()|[\]\\]/g, "\\$&");
 }
 
-function messageMentionsCurrentUser(content) {
+function messageMentionsCurrentUser(msg) {
+    if (msg.mention_everyone) return true;
+    if (Array.isArray(msg.mentions) && msg.mentions.includes(state.userId)) return true;
+    if (Array.isArray(msg.mention_roles) && state.role && msg.mention_roles.includes(state.role)) return true;
+    const content = msg.content || "";
     if (!content || !state.username) return false;
     const usernamePattern = escapeRegExp(state.username);
     const mentionRegex = new RegExp(`(^|\\s)@${usernamePattern}(?=\\b|\\s|$)`, "i");
     return mentionRegex.test(content);
 }
 
+function mentionedUsername(userId) {
+    const online = state.users[userId];
+    if (online?.username) return online.username;
+    const known = (state.serverUsers || []).find((u) => u.id === userId);
+    return known?.username || "utilisateur inconnu";
+}
+
 function renderMessageContentHtml(content) {
     const escaped = escapeHtml(content || "");
-    return escaped.replace(/(^|\s)(@[\w-]{2,32})/g, (match, prefix, tag) => {
-        const selfTag = `@${state.username || ""}`;
-        const isSelf = selfTag.length > 1 && tag.toLowerCase() === selfTag.toLowerCase();
-        const cls = isSelf ? "mention-token is-self" : "mention-token";
-        return `${prefix}<span class="${cls}">${tag}</span>`;
-    });
+    return escaped
+        .replace(/(^|\s)(@[\w-]{2,32})/g, (match, prefix, tag) => {
+            const selfTag = `@${state.username || ""}`;
+            const isSelf = selfTag.length > 1 && tag.toLowerCase() === selfTag.toLowerCase();
+            const cls = isSelf ? "mention-token is-self" : "mention-token";
+            return `${prefix}<span class="${cls}">${tag}</span>`;
+        })
+        // Server-side mention syntax: <@user_id> and <@&role>
+        .replace(/&lt;@(&amp;)?([\w-]{1,64})&gt;/g, (match, roleMarker, target) => {
+            if (roleMarker) {
+                const isSelf = target === state.role;
+                return `<span class="mention-token${isSelf ? " is-self" : ""}">@${escapeHtml(target)}</span>`;
+            }
+            const isSelf = target === state.userId;
+            return `<span class="mention-token${isSelf ? " is-self" : ""}">@${escapeHtml(mentionedUsername(target))}</span>`;
+        });
 }
 
 function normalizeReactions(reactions) {
@@ -1702,7 +1926,7 @@ function renderThreadPanel() {
     threadPanel.classList.remove("hidden");
     chatArea?.classList.add("thread-open");
 
-    const rootText = (rootMsg.content && rootMsg.content.trim()) || (rootMsg.image_url ? "[Image]" : "Message");
+    const rootText = (rootMsg.content && rootMsg.content.trim()) || (hasMedia(rootMsg) ? "[Image]" : "Message");
     threadRoot.innerHTML = `
         <div class="thread-item">
             <div class="thread-item-user">${escapeHtml(rootMsg.username || "Utilisateur")}</div>
@@ -1721,7 +1945,7 @@ function renderThreadPanel() {
 
     threadReplies.innerHTML = "";
     replies.forEach((reply) => {
-        const content = (reply.content && reply.content.trim()) || (reply.image_url ? "[Image]" : "Message");
+        const content = (reply.content && reply.content.trim()) || (hasMedia(reply) ? "[Image]" : "Message");
         const row = document.createElement("div");
         row.className = "thread-item";
         row.innerHTML = `
@@ -1768,6 +1992,7 @@ function appendMessage(msg, isFirstInGroup = true, parent = messagesContainer) {
         user_id: msg.user_id,
         content: msg.content || "",
         image_url: msg.image_url || null,
+        attachments: msg.attachments || [],
         created_at: msg.created_at || null,
         reply_to_id: msg.reply_to_id || null,
         avatar_url: msg.avatar_url || null,
@@ -1816,15 +2041,18 @@ function appendMessage(msg, isFirstInGroup = true, parent = messagesContainer) {
     `;
 
     // Build image HTML if present
-    const imageHtml = msg.image_url ? `
+    const imageUrl = msg.image_url ? escapeHtml(mediaUrl(msg.image_url)) : "";
+    const imageHtml = imageUrl ? `
         <div class="message-image-wrapper">
-            <img class="message-image" src="${API}${msg.image_url}" alt="image" onclick="openLightbox('${API}${msg.image_url}')" />
+            <img class="message-image" src="${imageUrl}" alt="image" onclick="openLightbox('${imageUrl}')" />
         </div>
     ` : '';
+    const attachmentsHtml = renderAttachmentsHtml(msg.attachments);
+    const embedsHtml = renderEmbedsHtml(msg.embeds);
 
     // Detect emoji-only messages for jumbo display
     const emojiClass = msg.content ? getEmojiClass(msg.content) : '';
-    const mentionsMe = msg.username !== state.username && messageMentionsCurrentUser(msg.content || "");
+    const mentionsMe = msg.username !== state.username && messageMentionsCurrentUser(msg);
     if (mentionsMe) {
         div.classList.add("message-mentioned");
     }
@@ -1840,7 +2068,7 @@ function appendMessage(msg, isFirstInGroup = true, parent = messagesContainer) {
         const parent = state.messageMetaById[msg.reply_to_id];
         const parentName = parent?.username || "Message";
         const parentSnippet = parent
-            ? ((parent.content && parent.content.trim()) || (parent.image_url ? "[Image]" : "Message"))
+            ? ((parent.content && parent.content.trim()) || (hasMedia(parent) ? "[Image]" : "Message"))
             : "Message introuvable";
 
         replyRefHtml = `
@@ -1854,7 +2082,7 @@ function appendMessage(msg, isFirstInGroup = true, parent = messagesContainer) {
 
     // Avatar content — show profile image if available
     const avatarContent = msg.avatar_url
-        ? `<img src="${API}${msg.avatar_url}" style="width:100%;height:100%;border-radius:50%;object-fit:cover">`
+        ? `<img src="${escapeHtml(mediaUrl(msg.avatar_url))}" style="width:100%;height:100%;border-radius:50%;object-fit:cover">`
         : msg.username[0].toUpperCase();
 
     if (isFirstInGroup) {
@@ -1863,13 +2091,15 @@ function appendMessage(msg, isFirstInGroup = true, parent = messagesContainer) {
             <div class="message-avatar avatar-bg-${colorIndex}">${avatarContent}</div>
             <div class="message-body">
                 <div class="message-header">
-                    <span class="message-username name-color-${colorIndex}">${escapeHtml(msg.username)}</span>
+                    <span class="message-username name-color-${colorIndex}">${escapeHtml(msg.username)}</span>${msg.is_bot ? `<span class="badge badge-bot">${msg.webhook_id ? "Webhook" : "Bot"}</span>` : ""}
                     <span class="message-time">${time}</span>
                 </div>
                 ${pinnedFlagHtml}
                 ${replyRefHtml}
                 ${contentHtml}
                 ${imageHtml}
+                ${attachmentsHtml}
+                ${embedsHtml}
                 ${reactionsHtml}
             </div>
         `;
@@ -1882,6 +2112,8 @@ function appendMessage(msg, isFirstInGroup = true, parent = messagesContainer) {
                 ${replyRefHtml}
                 ${contentHtml}
                 ${imageHtml}
+                ${attachmentsHtml}
+                ${embedsHtml}
                 ${reactionsHtml}
             </div>
         `;
@@ -1914,7 +2146,7 @@ function appendMessage(msg, isFirstInGroup = true, parent = messagesContainer) {
         replyBtn.addEventListener("click", (event) => {
             event.preventDefault();
             event.stopPropagation();
-            const previewSnippet = (msg.content && msg.content.trim()) || (msg.image_url ? "[Image]" : "Message");
+            const previewSnippet = (msg.content && msg.content.trim()) || (hasMedia(msg) ? "[Image]" : "Message");
             setReplyTarget({
                 id: msg.id,
                 username: msg.username,
@@ -2041,9 +2273,9 @@ messageForm.addEventListener("submit", async (e) => {
     }
 
     if (state.currentRoomKind !== "text") return;
-    if (!state.currentRoomId || !state.ws) return;
+    if (!state.currentRoomId || !isRealtimeOpen()) return;
 
-    let imageUrl = null;
+    let uploadId = null;
 
     // Upload image first if there is one
     if (file) {
@@ -2057,7 +2289,7 @@ messageForm.addEventListener("submit", async (e) => {
             });
             if (res.ok) {
                 const data = await res.json();
-                imageUrl = data.url;
+                uploadId = data.id;
             } else {
                 const data = await res.json();
                 alert(data.error || "Erreur d'upload");
@@ -2075,14 +2307,15 @@ messageForm.addEventListener("submit", async (e) => {
         user_id: state.userId,
         username: state.username,
         content: content || "",
-        avatar_color: state.avatarColor
+        avatar_color: state.avatarColor,
+        nonce: crypto.randomUUID()
     };
     if (state.replyingTo?.id) {
         msg.reply_to_id = state.replyingTo.id;
     }
-    if (imageUrl) msg.image_url = imageUrl;
+    if (uploadId) msg.attachments = [{ upload_id: uploadId }];
 
-    state.ws.send(JSON.stringify(msg));
+    wsSend(msg);
     messageInput.value = "";
     fileInput.value = "";
     uploadPreview.classList.add("hidden");
@@ -2121,7 +2354,7 @@ async function loadPinnedMessages() {
                     <span class="pinned-item-user">${escapeHtml(item.username || "Utilisateur")}</span>
                     <span class="pinned-item-time">${escapeHtml(formatTime(item.created_at))}</span>
                 </div>
-                <div class="pinned-item-content">${escapeHtml((item.content && item.content.trim()) || (item.image_url ? "[Image]" : "Message"))}</div>
+                <div class="pinned-item-content">${escapeHtml((item.content && item.content.trim()) || (hasMedia(item) ? "[Image]" : "Message"))}</div>
             `;
             row.addEventListener("click", () => {
                 pinnedModal.classList.add("hidden");
@@ -2175,7 +2408,7 @@ if (threadForm) {
         event.preventDefault();
         const content = threadInput?.value?.trim() || "";
         if (!content || !state.threadRootId || state.currentRoomKind !== "text") return;
-        if (!state.currentRoomId || !state.ws) return;
+        if (!state.currentRoomId || !isRealtimeOpen()) return;
 
         const msg = {
             type: "message",
@@ -2185,9 +2418,10 @@ if (threadForm) {
             content,
             avatar_color: state.avatarColor,
             reply_to_id: state.threadRootId,
+            nonce: crypto.randomUUID(),
         };
 
-        state.ws.send(JSON.stringify(msg));
+        wsSend(msg);
         threadInput.value = "";
     });
 }
@@ -2197,14 +2431,14 @@ let typingTimeout = null;
 let isTyping = false;
 
 messageInput.addEventListener("input", () => {
-    if (!state.ws || !state.currentRoomId) return;
+    if (!isRealtimeOpen() || !state.currentRoomId) return;
     if (!isTyping) {
         isTyping = true;
-        state.ws.send(JSON.stringify({
+        wsSend({
             type: "typing",
             room_id: state.currentRoomId,
             username: state.username
-        }));
+        });
     }
     clearTimeout(typingTimeout);
     typingTimeout = setTimeout(() => { isTyping = false; }, 3000);
@@ -3419,6 +3653,68 @@ function escapeHtml(s) {
         .replace(/'/g, "&#039;");
 }
 
+// Uploads are served by the backend; webhook media may also be absolute http(s) URLs
+function mediaUrl(path) {
+    return /^https?:\/\//i.test(path || "") ? path : `${API}${path}`;
+}
+
+function hasMedia(msg) {
+    return !!msg?.image_url || !!msg?.attachments?.length;
+}
+
+function renderAttachmentsHtml(attachments) {
+    if (!Array.isArray(attachments) || !attachments.length) return "";
+    return attachments.map((attachment) => {
+        const url = escapeHtml(mediaUrl(attachment.url));
+        const spoiler = attachment.spoiler ? " spoiler" : "";
+        const reveal = attachment.spoiler ? `onclick="this.classList.remove('spoiler')"` : "";
+        if ((attachment.mime_type || "").startsWith("image/")) {
+            // History shows a thumbnail over the average color; the lightbox opens the original
+            const thumbUrl = escapeHtml(mediaUrl(`/uploads/${encodeURIComponent(attachment.id)}/thumb?size=640`));
+            const size = attachment.width && attachment.height ? ` width="${attachment.width}" height="${attachment.height}"` : "";
+            const placeholder = Number.isInteger(attachment.dominant_color)
+                ? ` style="background:#${attachment.dominant_color.toString(16).padStart(6, "0")}"`
+                : "";
+            return `
+                <div class="message-image-wrapper${spoiler}" ${reveal}>
+                    <img class="message-image" src="${thumbUrl}"${size}${placeholder} alt="${escapeHtml(attachment.alt_text || attachment.filename)}" loading="lazy" onclick="openLightbox('${url}')" />
+                </div>
+            `;
+        }
+        return `<div class="message-attachment-file${spoiler}" ${reveal}><a href="${url}" target="_blank" rel="noopener noreferrer">${escapeHtml(attachment.filename)}</a> <span class="dc-file-size">(${formatFileSize(attachment.size)})</span></div>`;
+    }).join("");
+}
+
+function renderEmbedsHtml(embeds) {
+    if (!Array.isArray(embeds) || !embeds.length) return "";
+    return embeds.map((embed) => {
+        const color = Number.isInteger(embed.color) ? `#${embed.color.toString(16).padStart(6, "0")}` : "var(--accent)";
+        const title = embed.title
+            ? (embed.url
+                ? `<a class="message-embed-title" href="${escapeHtml(mediaUrl(embed.url))}" target="_blank" rel="noopener noreferrer">${escapeHtml(embed.title)}</a>`
+                : `<div class="message-embed-title">${escapeHtml(embed.title)}</div>`)
+            : "";
+        const description = embed.description ? `<div class="message-embed-description">${renderMessageContentHtml(embed.description)}</div>` : "";
+        const fields = (embed.fields || []).map((field) => `
+            <div class="message-embed-field${field.inline ? " inline" : ""}">
+                <div class="message-embed-field-name">${escapeHtml(field.name)}</div>
+                <div class="message-embed-field-value">${escapeHtml(field.value)}</div>
+            </div>
+        `).join("");
+        const image = embed.image_url ? `<img class="message-embed-image" src="${escapeHtml(mediaUrl(embed.image_url))}" alt="" />` : "";
+        const footer = embed.footer ? `<div class="message-embed-footer">${escapeHtml(embed.footer)}</div>` : "";
+        return `
+            <div class="message-embed" style="border-left-color:${color}">
+                ${title}
+                ${description}
+                ${fields ? `<div class="message-embed-fields">${fields}</div>` : ""}
+                ${image}
+                ${footer}
+            </div>
+        `;
+    }).join("");
+}
+
 function hashString(str) {
     let hash = 0;
     for (let i = 0; i < str.length; i++) {
@@ -3524,7 +3820,7 @@ function renderSearchResults(items) {
         row.className = "search-result-item";
         const room = state.rooms.find((r) => r.id === item.room_id);
         const roomLabel = room ? `#${room.name}` : "salon";
-        const content = (item.content && item.content.trim()) || (item.image_url ? "[Image]" : "Message");
+        const content = (item.content && item.content.trim()) || (hasMedia(item) ? "[Image]" : "Message");
 
         row.innerHTML = `
             <div class="search-result-head">
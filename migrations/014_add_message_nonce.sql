@@ -0,0 +1,5 @@
+ALTER TABLE messages ADD COLUMN nonce TEXT DEFAULT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_user_nonce
+    ON messages(user_id, nonce)
+    WHERE nonce IS NOT NULL;
@@ -0,0 +1,20 @@
+-- Incoming webhooks post into one room, authenticated by a secret token in the URL
+CREATE TABLE IF NOT EXISTS webhooks (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    avatar_url TEXT DEFAULT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_by TEXT,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhooks_room_id ON webhooks(room_id);
+
+-- All webhook messages are authored by this bot user, with the display name kept per message
+INSERT OR IGNORE INTO users (id, username, password_hash, role, is_bot) VALUES ('webhook', '__webhook__', '!', 'user', 1);
+
+ALTER TABLE messages ADD COLUMN webhook_id TEXT DEFAULT NULL;
+ALTER TABLE messages ADD COLUMN author_avatar_url TEXT DEFAULT NULL;
+ALTER TABLE messages ADD COLUMN embeds TEXT DEFAULT NULL;
@@ -0,0 +1,5 @@
+-- Short room description, set with PATCH /api/rooms/{id} or /topic
+ALTER TABLE rooms ADD COLUMN topic TEXT DEFAULT NULL;
+
+-- Unix time in milliseconds until which the user may not post, set with /timeout
+ALTER TABLE users ADD COLUMN timeout_until INTEGER DEFAULT NULL;
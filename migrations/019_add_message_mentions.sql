@@ -0,0 +1,11 @@
+-- Mentions parsed from message content when it is stored.
+-- kind is user (target = user id), role (target = role name) or everyone (target = '')
+CREATE TABLE IF NOT EXISTS message_mentions (
+    message_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    target TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (message_id, kind, target),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_mentions_target ON message_mentions(kind, target);
@@ -0,0 +1,12 @@
+-- Last message each user has read in each room.
+-- last_read_at is that message's created_at, kept so counts survive its deletion
+CREATE TABLE IF NOT EXISTS read_states (
+    user_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    last_read_message_id TEXT NOT NULL,
+    last_read_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    PRIMARY KEY (user_id, room_id),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE
+);
@@ -0,0 +1,22 @@
+-- Server-wide notification defaults per user. level is all, mentions or nothing
+CREATE TABLE IF NOT EXISTS notification_settings (
+    user_id TEXT PRIMARY KEY,
+    level TEXT NOT NULL DEFAULT 'all',
+    suppress_everyone INTEGER NOT NULL DEFAULT 0,
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Per-room overrides. A NULL level falls back to the server-wide one.
+-- muted_until is unix time in milliseconds, NULL while muted means until unmuted
+CREATE TABLE IF NOT EXISTS room_notification_settings (
+    user_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    level TEXT DEFAULT NULL,
+    muted INTEGER NOT NULL DEFAULT 0,
+    muted_until INTEGER DEFAULT NULL,
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    PRIMARY KEY (user_id, room_id),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE
+);
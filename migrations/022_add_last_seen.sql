@@ -0,0 +1,2 @@
+-- RFC 3339 time the user's last realtime connection closed, the start of their activity digest
+ALTER TABLE users ADD COLUMN last_seen_at TEXT DEFAULT NULL;
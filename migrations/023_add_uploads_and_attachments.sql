@@ -0,0 +1,28 @@
+-- Files stored by POST /api/upload. filename is the name given by the client,
+-- width and height are only known for images the server can decode
+CREATE TABLE IF NOT EXISTS uploads (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    mime_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    width INTEGER DEFAULT NULL,
+    height INTEGER DEFAULT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_uploads_user ON uploads(user_id);
+
+-- Uploads attached to messages, in display order. An upload belongs to one message at most
+CREATE TABLE IF NOT EXISTS attachments (
+    message_id TEXT NOT NULL,
+    upload_id TEXT NOT NULL UNIQUE,
+    position INTEGER NOT NULL,
+    alt_text TEXT DEFAULT NULL,
+    spoiler INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (message_id, position),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (upload_id) REFERENCES uploads(id) ON DELETE CASCADE
+);
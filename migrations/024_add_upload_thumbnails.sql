@@ -0,0 +1,16 @@
+-- Placeholder shown while an image loads: a blurhash string and the average RGB color
+ALTER TABLE uploads ADD COLUMN blurhash TEXT DEFAULT NULL;
+ALTER TABLE uploads ADD COLUMN dominant_color INTEGER DEFAULT NULL;
+
+-- Downscaled copies of uploaded images, one per size smaller than the original.
+-- size is the longest side in pixels, path is relative to the uploads directory
+CREATE TABLE IF NOT EXISTS upload_thumbnails (
+    upload_id TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    mime_type TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    PRIMARY KEY (upload_id, size),
+    FOREIGN KEY (upload_id) REFERENCES uploads(id) ON DELETE CASCADE
+);
@@ -0,0 +1,7 @@
+-- Server-wide settings changed by admins, one row per setting.
+-- value is JSON, missing rows take the default
+CREATE TABLE IF NOT EXISTS server_settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
@@ -0,0 +1,16 @@
+-- Uploaded files are stored once per content, named by their SHA-256.
+-- refs counts the uploads rows using each file, which is deleted at zero
+CREATE TABLE IF NOT EXISTS upload_blobs (
+    sha256 TEXT PRIMARY KEY,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    refs INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+-- NULL for files stored before deduplication, under their own name
+ALTER TABLE uploads ADD COLUMN sha256 TEXT DEFAULT NULL;
+CREATE INDEX IF NOT EXISTS idx_uploads_sha256 ON uploads(sha256);
+
+-- Storage limit of a user in bytes, overriding the one of their role
+ALTER TABLE users ADD COLUMN upload_quota INTEGER DEFAULT NULL;